@@ -4,10 +4,17 @@ use esp_idf_sys::camera;
 use std::{thread, time::Duration, sync::Arc, sync::Mutex};
 use std::fs;
 use std::path::Path;
-use std::time::SystemTime;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::autofocus::AutoFocus;
-use crate::imagefiles::{ ImageFiles, OpenMode, delete_file, WriteThread };
+use crate::emmc::get_storage_space;
+use timeleapcam_core::imagefiles::{ ImageFiles, OpenMode, delete_file, WriteThread, FrameInfo, FOCUS_UNKNOWN };
+use timeleapcam_core::retention::{ apply_retention, RetentionPolicy, RetentionReport };
+use timeleapcam_core::tracks::{update_track_settings, TrackLocks};
+
+// Old frames are removed even if only a few frames are removable, when the free space is less than 1/10
+const LOW_SPACE_RATIO: u64 = 10;
 
 #[derive(Debug, Clone)]
 pub struct CaptureInfo {
@@ -27,15 +34,23 @@ pub struct CaptureInfo {
     open_mode: OpenMode,
     direct_write_mode: bool,
     jpeg_quality: u32,
+    segment_size: u32,
+    segment_frames: u32,
+    retention: RetentionPolicy,
+    retention_report: Option<RetentionReport>,
+    track_settings: BTreeMap<String, String>,
+    battery_voltage: f32,
+    temperature: f32,
 }
 
 pub struct Capture {
     camera: Arc<Mutex<Camera<'static>>>,
     info: Arc<Mutex<CaptureInfo>>,
+    track_locks: TrackLocks,        // the retention does not rewrite a track which is read by the web server
 }
 
 impl Capture {
-    pub fn new(camera: Camera<'static>, dir: &str) -> Self {
+    pub fn new(camera: Camera<'static>, dir: &str, track_locks: TrackLocks) -> Self {
         Capture {
             camera: Arc::new(Mutex::new(camera)),
             info: Arc::new(Mutex::new(CaptureInfo {
@@ -55,13 +70,22 @@ impl Capture {
                 open_mode: OpenMode::Append,
                 direct_write_mode: false,
                 jpeg_quality: 12,
+                segment_size: 0,
+                segment_frames: 0,
+                retention: RetentionPolicy::default(),
+                retention_report: None,
+                track_settings: BTreeMap::new(),
+                battery_voltage: 0.0,
+                temperature: 0.0,
              })),
+            track_locks,
         }
     }
 
     pub fn start(&mut self) {
         let camera = self.camera.clone();
         let info = self.info.clone();
+        let track_locks = self.track_locks.clone();
         let _th = thread::spawn(move || {
             info!("Capturing Frame Thread Start...");
             let camera = camera.lock().unwrap();
@@ -86,6 +110,10 @@ impl Capture {
                     // create directory
                     let dir = format!("{}/T{}", infolk.capture_dir, infolk.track_id);
                     fs::create_dir_all(&dir).expect("Failed to create directory");
+                    // capture settings are shown in the track list
+                    if let Err(e) = update_track_settings(&dir, &infolk.track_settings) {
+                        info!("Failed to save track settings: {:?}", e);
+                    }
                 }
                 if current_status && !infolk.request {
                     current_status = infolk.request;
@@ -104,21 +132,47 @@ impl Capture {
                     let jpeg_quality = infolk.jpeg_quality as i32;
                     let _ = sensor.set_quality(jpeg_quality);
                     info!("JPEG Quality: {}", jpeg_quality);        
+                    let mut focus_result = FOCUS_UNKNOWN;
                     if infolk.wait_focus {
                         autofocus.autofocus();
-                        let _autofocus_result = autofocus.get_focus_result();
+                        focus_result = autofocus.get_focus_result();
                     }
+                    let mut frame_info = FrameInfo {
+                        jpeg_quality: jpeg_quality as u8,
+                        focus: focus_result,
+                        battery_voltage: (infolk.battery_voltage * 1000.0) as u16,
+                        temperature: (infolk.temperature * 100.0) as i16,
+                        ..Default::default()
+                    };
                     let mut loop_count = 0;
                     infolk.status = false;
-                    let filename = format!("{}/T{}/capture.dat", infolk.capture_dir, infolk.track_id);
+                    let track_id = infolk.track_id;
+                    let filename = format!("{}/T{}/capture.dat", infolk.capture_dir, track_id);
                     let mode = match infolk.open_mode {
                         OpenMode::Append => OpenMode::Append,
                         OpenMode::Write =>  OpenMode::Write,
                         _ => OpenMode::Append,
                     };
                     let direct_write_mode = infolk.direct_write_mode;
+                    let segment_size = infolk.segment_size as u64 * 1024 * 1024;
+                    let segment_frames = infolk.segment_frames;
                     drop(infolk);
-                    let mut write_thread = WriteThread::new(filename, mode, direct_write_mode);
+                    // the track is not deleted or compacted during the session, an overwrite also waits for the readers
+                    let session_lock = match mode {
+                        OpenMode::Write => track_locks.write(track_id),
+                        _ => track_locks.append(track_id),
+                    };
+                    if session_lock.is_none() {
+                        info!("Capture skipped, track {} is in use", track_id);
+                        let mut infolk = info.lock().unwrap();
+                        infolk.status = true;
+                        infolk.request = false;
+                        drop(infolk);
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                    let mut write_thread = WriteThread::new(filename.clone(), mode, direct_write_mode);
+                    write_thread.set_segment_limit(segment_size, segment_frames);
                     write_thread.start();
                     let mut average_capture_time = 0;
                     let mut average_write_time = 0;
@@ -140,9 +194,15 @@ impl Capture {
                                 write_data_size += size;
                                 width = frame.width();
                                 height = frame.height();
+                                frame_info.capture_time = match start_capture.duration_since(UNIX_EPOCH) {
+                                    Ok(t) => t.as_millis() as i64,
+                                    Err(_) => 0,
+                                };
+                                frame_info.width = width as u16;
+                                frame_info.height = height as u16;
                                 let start_write = SystemTime::now();
                                 // write_thread.push_data(buffer.to_vec());
-                                write_thread.push_data(&buffer);
+                                write_thread.push_data(&buffer, &frame_info);
                                 success_count += 1;
                                 let end_write = start_write.elapsed().unwrap().as_micros();
                                 average_write_time += end_write;
@@ -180,6 +240,7 @@ impl Capture {
                         }
                     }
                     write_thread.wait_thread();
+                    drop(session_lock);
                     let write_images = write_thread.get_nof_images();
                     let capture_duration = start_capture_time.elapsed().unwrap().as_micros();
                     info!("Capture Frames: {} Total Frames: {} {}fps {}KB", 
@@ -190,6 +251,31 @@ impl Capture {
                         info!("Average Capture Time: {:.2}ms", average_capture_time as f32 / loop_count as f32 / 1000.0);
                         info!("Average Write Time: {:.2}ms", average_write_time as f32 / loop_count as f32 / 1000.0);
                     }
+                    // remove old frames by the retention policy
+                    let mut write_images = write_images;
+                    let retention = info.lock().unwrap().retention.clone();
+                    // the track is compacted only when no request reads it, else at the next session
+                    let track_lock = if retention.is_enabled() { track_locks.write(track_id) } else { None };
+                    if retention.is_enabled() && track_lock.is_none() {
+                        info!("Retention skipped, track {} is in use", track_id);
+                    }
+                    if track_lock.is_some() {
+                        let low_space = match get_storage_space() {
+                            Some((total, free)) => free < total / LOW_SPACE_RATIO,
+                            None => false,
+                        };
+                        match apply_retention(&filename, &retention, SystemTime::now(), low_space) {
+                            Ok(report) => {
+                                info!("Retention: {} frames removed, {}KB reclaimed", report.removed_frames, report.reclaimed_bytes / 1024);
+                                write_images = report.frames;
+                                info.lock().unwrap().retention_report = Some(report);
+                            }
+                            Err(e) => {
+                                info!("Retention failed: {:?}", e);
+                            }
+                        }
+                    }
+                    drop(track_lock);
                     let mut infolk = info.lock().unwrap();
                     infolk.capture_id = if write_images > 0 { write_images - 1 } else { 0 };
                     infolk.size = size;
@@ -285,4 +371,35 @@ impl Capture {
         let mut info = self.info.lock().unwrap();
         info.jpeg_quality = quality;
     }
+
+    // size in MB (0: up to the file size limit) and number of frames (0: no limit) of a segment file
+    pub fn set_segment_limit(&self, size: u32, frames: u32) {
+        let mut info = self.info.lock().unwrap();
+        info.segment_size = size;
+        info.segment_frames = frames;
+    }
+
+    pub fn set_retention_policy(&self, policy: RetentionPolicy) {
+        let mut info = self.info.lock().unwrap();
+        info.retention = policy;
+    }
+
+    // Result of the retention after the last capture session (once)
+    pub fn take_retention_report(&self) -> Option<RetentionReport> {
+        let mut info = self.info.lock().unwrap();
+        info.retention_report.take()
+    }
+
+    // capture settings saved in the track directory (e.g. "resolution" => "UXGA")
+    pub fn set_track_settings(&self, settings: BTreeMap<String, String>) {
+        let mut info = self.info.lock().unwrap();
+        info.track_settings = settings;
+    }
+
+    // battery voltage and temperature recorded with the captured frames
+    pub fn set_sensor_values(&self, battery_voltage: f32, temperature: f32) {
+        let mut info = self.info.lock().unwrap();
+        info.battery_voltage = battery_voltage;
+        info.temperature = temperature;
+    }
 }
@@ -0,0 +1,202 @@
+// Device side of the capture loop: sleep, camera, RTC memory and the reports to the server
+use log::info;
+use std::{thread, time::Duration};
+use std::time::SystemTime;
+use esp_idf_hal::gpio::{Gpio44, Output, PinDriver};
+
+use crate::capture::Capture;
+use crate::monitoring::Monitoring;
+use crate::server::{ControlServer, ControlServerInfo};
+use crate::wifi;
+use timeleapcam_core::capture_loop::{CameraBackend, CaptureResult, Reporter, RetainedState, Sleeper, Storage};
+use timeleapcam_core::rtc_state::RtcStateBlock;
+
+#[derive(PartialEq)]
+pub enum SleepMode {
+    SleepModeLight,
+    SleepModeDeep,
+}
+
+// State of the capture loop, it is kept over the deep sleep
+#[link_section = ".rtc.data"]
+static mut RTC_STATE: RtcStateBlock = RtcStateBlock::ZERO;
+
+pub struct RtcStorage;
+
+impl Storage for RtcStorage {
+    // None when the block is not valid (power on, brown out or another firmware version)
+    fn load(&mut self) -> Option<RetainedState> {
+        let block = unsafe { RTC_STATE };
+        match block.load() {
+            Ok(state) => Some(state),
+            Err(e) => {
+                info!("{:?}", e);
+                None
+            }
+        }
+    }
+
+    fn store(&mut self, state: &RetainedState) {
+        let block = RtcStateBlock::new(state);
+        unsafe {
+            RTC_STATE = block;
+        }
+    }
+}
+
+pub struct EspSleeper<'a> {
+    emmc_cam_power: PinDriver<'a, Gpio44, Output>,
+}
+
+impl<'a> EspSleeper<'a> {
+    pub fn new(emmc_cam_power: PinDriver<'a, Gpio44, Output>) -> Self {
+        EspSleeper { emmc_cam_power }
+    }
+}
+
+impl Sleeper for EspSleeper<'_> {
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn deep_sleep(&mut self, duration: Option<Duration>) {
+        self.emmc_cam_power.set_high().expect("Set emmc_cam_power high failure");
+        deep_and_light_sleep_start(SleepMode::SleepModeDeep, duration.map_or(0, |d| d.as_secs()));
+    }
+}
+
+pub struct EspCamera<'a> {
+    capture: &'a Capture,
+}
+
+impl<'a> EspCamera<'a> {
+    pub fn new(capture: &'a Capture) -> Self {
+        EspCamera { capture }
+    }
+}
+
+impl CameraBackend for EspCamera<'_> {
+    fn change_resolution(&mut self, resolution: u32) {
+        self.capture.change_resolution(resolution);
+    }
+
+    fn autofocus(&mut self) {
+        self.capture.autofocus_request();
+    }
+
+    fn set_overwrite_saved(&mut self, overwrite: bool) {
+        self.capture.set_overwrite_saved(overwrite);
+    }
+
+    fn request_capture(&mut self, track_id: u32, capture_id: u32) {
+        self.capture.capture_request(track_id, capture_id);
+    }
+
+    fn wait_capture(&mut self) -> CaptureResult {
+        while !self.capture.get_capture_status() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        // get last capture id
+        let capture_id = self.capture.get_capture_id();
+        let capture_info = self.capture.get_capture_info();
+        if capture_info.status {
+            info!("Write Frame ID {}: width:{} height:{} image_size:{}", capture_id, capture_info.width, capture_info.height, capture_info.size);
+        }
+        CaptureResult { capture_id, written: capture_info.status }
+    }
+}
+
+// Reports to the web server and the monitoring service, server is None when the server is not running
+pub struct ServerReporter<'a> {
+    pub server: Option<&'a mut ControlServer>,
+    pub server_info: &'a mut ControlServerInfo,
+    pub monitoring: &'a Monitoring,
+    pub battery_voltage: f32,
+}
+
+impl Reporter for ServerReporter<'_> {
+    fn captured(&mut self, track_id: u32, result: &CaptureResult, time: SystemTime) -> Option<SystemTime> {
+        let mut posted_time = None;
+        if self.server_info.query_openai {
+            info!("Query OpenAI: Track :{} frame No.:{}", track_id, result.capture_id);
+            self.monitoring.set_query_start(self.server_info.query_prompt.clone(), track_id, result.capture_id);
+            while self.monitoring.get_query_status() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            info!("Query reply: {}", self.monitoring.get_query_reply());
+            if self.monitoring.get_posted_status() {
+                let now = SystemTime::now();
+                self.server_info.last_posted_date_time = now;
+                if let Some(server) = self.server.as_mut() {
+                    server.set_last_posted_date_time(now);
+                }
+                self.monitoring.set_last_posted_date_time(now, self.server_info.post_interval);
+                posted_time = Some(now);
+            }
+        }
+        if result.written {
+            self.server_info.last_capture_date_time = time;
+            if let Some(server) = self.server.as_mut() {
+                server.set_last_capture_date_time(time);
+            }
+            self.server_info.current_capture_id = result.capture_id;
+        }
+        posted_time
+    }
+
+    fn status_report(&mut self, track_id: u32, capture_id: u32, time: SystemTime) {
+        // capture time
+        let capture_time = crate::local_time_string(&self.server_info.time_zone(), time);
+        let message = format!("STATUS REPORT: {}:{} {} {}V {}dBm",
+            track_id, capture_id, capture_time,
+            self.battery_voltage, wifi::get_rssi());
+        self.monitoring.post_message_request(message, track_id, capture_id);
+        while self.monitoring.get_post_message_status() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn missed(&mut self, missed_captures: u32, last_missed_time: SystemTime) {
+        self.server_info.missed_captures = missed_captures;
+        self.server_info.last_missed_date_time = last_missed_time;
+        if let Some(server) = self.server.as_mut() {
+            server.set_missed_captures(missed_captures, last_missed_time);
+        }
+    }
+}
+
+pub fn deep_and_light_sleep_start(sleep_mode: SleepMode, wakeup_interval: u64) {
+    info!("Sleep Now...");
+    unsafe {
+        esp_idf_sys::esp_wifi_stop();
+    }
+    thread::sleep(Duration::from_millis(1000));
+    unsafe {
+        // light sleep mode
+        if sleep_mode == SleepMode::SleepModeLight {
+            // gpio wakeup enable
+            // esp_idf_sys::gpio_wakeup_enable(GPIO_WAKEUP_INT_PIN_4, esp_idf_sys::gpio_int_type_t_GPIO_INTR_LOW_LEVEL);
+            // esp_idf_sys::esp_sleep_enable_gpio_wakeup();
+            // wakeup from rtc timer
+            if wakeup_interval > 0 {
+                esp_idf_sys::esp_sleep_enable_timer_wakeup(wakeup_interval * 1000 * 1000);
+            }
+        }
+        else {
+            info!("Deep Sleep Start");
+            esp_idf_sys::esp_sleep_enable_gpio_wakeup();
+            esp_idf_sys::esp_sleep_enable_touchpad_wakeup();
+            if wakeup_interval > 0 {
+                esp_idf_sys::esp_sleep_enable_timer_wakeup(wakeup_interval * 1000 * 1000);
+            }
+            esp_idf_sys::esp_deep_sleep_start();
+        }
+
+        // deep sleep mode (not here)
+        if sleep_mode == SleepMode::SleepModeLight {
+            let _result = esp_idf_sys::esp_light_sleep_start();
+            // gpio interrupt enable
+            // esp_idf_sys::gpio_set_intr_type(GPIO_WAKEUP_INT_PIN_4, esp_idf_sys::gpio_int_type_t_GPIO_INTR_ANYEDGE);
+        }
+    }
+}
@@ -45,6 +45,20 @@ const VFS_MOUNT_ALLOC_UNIT_SIZE: usize = 32 * 1024;
 
 const MOUNT_POINT : &[u8] = b"/eMMC\0";
 
+// Total and free bytes of the mounted eMMC/SD card
+pub fn get_storage_space() -> Option<(u64, u64)> {
+    let mut total: u64 = 0;
+    let mut free: u64 = 0;
+    let ret = unsafe {
+        esp_idf_sys::esp_vfs_fat_info(MOUNT_POINT.as_ptr() as *const i8, &mut total, &mut free)
+    };
+    if ret != esp_idf_sys::ESP_OK {
+        info!("Failed to get storage space: {}", ret);
+        return None;
+    }
+    Some((total, free))
+}
+
 pub struct SDSPIHost {
     host: *mut sdmmc_host_t,
     card: esp_idf_hal::sys::sdmmc_card_t,
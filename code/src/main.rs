@@ -9,72 +9,45 @@ use esp_idf_svc::wifi::EspWifi;
 use esp_idf_sys::camera;
 use log::info;
 use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault, EspNvs, NvsPartitionId};
 use esp_idf_svc::sntp::{EspSntp, SyncStatus, SntpConf, OperatingMode, SyncMode};
 use esp_idf_hal::adc::{config::Config as AdcConfig, AdcChannelDriver, AdcDriver};
 use std::time::{SystemTime, UNIX_EPOCH};
 use chrono::{DateTime, Utc};
-use chrono::{Local, Duration as ChronoDuration, FixedOffset, NaiveDate, Datelike, Timelike};
 
 mod wifi;
 mod capture;
 mod autofocus;
 mod emmc;
-mod imagefiles;
 mod server;
 mod config;
 mod touchpad;
 mod monitoring;
+mod device;
+mod secrets;
+mod tls;
 
 use touchpad::{TouchPad, KeyEvent, Key};
-use config::ConfigData;
+use config::{ConfigData, CONFIG_VERSION};
 use capture::Capture;
 use emmc::EMMCHost;
-use server::LeapTime;
 use monitoring::Monitoring;
-
-#[derive(PartialEq)]
-enum SleepMode {
-    SleepModeLight,
-    SleepModeDeep,
-}
+use device::{EspCamera, EspSleeper, RtcStorage, ServerReporter};
+use secrets::SecretStore;
+use tls::TlsStore;
+use timeleapcam_core::capture_loop::{from_seconds, to_seconds, CaptureLoop, Devices, LoopSettings, Step, Storage, SystemClock};
+use timeleapcam_core::imagefiles;
+use timeleapcam_core::retention::RetentionPolicy;
+use timeleapcam_core::schedule::{CaptureSchedule, MissedPolicy};
+use timeleapcam_core::sun::daylight_setting;
+use timeleapcam_core::timezone::TimeZone;
+use timeleapcam_core::tracks::TrackLocks;
 
 const MAX_NVS_STR_SIZE : usize = 3072;
 
-#[link_section = ".rtc.data"]
-static mut IMAGE_COUNT_ID: u32 = 0;
-
-#[link_section = ".rtc.data"]
-static mut DEEP_SLEEP_AUTO_CAPTURE: bool = false;
-
-#[link_section = ".rtc.data"]
-static mut NEXT_CAPTURE_TIME: u64 = 0;
-
-#[link_section = ".rtc.data"]
-static mut DURATION_TIME: u32 = 0;
-
-#[link_section = ".rtc.data"]
-static mut CURRENT_RESOLUTION: u32 = 0;
-
-#[link_section = ".rtc.data"]
-static mut CURRENT_TRACK_ID: u32 = 0;
-
-#[link_section = ".rtc.data"]
-static mut LAST_CAPTURE_TIME: u64 = 0;
-
-#[link_section = ".rtc.data"]
-static mut LAST_POSTED_TIME: u64 = 0;
-
-#[link_section = ".rtc.data"]
-static mut CAPTURE_START_TIME: u64 = 0;
-
-#[link_section = ".rtc.data"]
-static mut CAPTURE_END_TIME: u64 = 0;
-
-#[link_section = ".rtc.data"]
-static mut LAST_STATUS_POSTED_TIME: u64 = 0;
-
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -123,15 +96,28 @@ fn main() -> anyhow::Result<()> {
         }
     }
     // Initialize Configuration Data
-    let mut config_data = ConfigData::new();
+    let default_config = config::default_config();
+    let mut config_data = default_config.clone();
 
     // Initialize NVS
     let nvs_default_partition = EspNvsPartition::<NvsDefault>::take().unwrap();
-    let mut nvs = match EspNvs::new(nvs_default_partition, "storage", true) {
+    let mut nvs = match EspNvs::new(nvs_default_partition.clone(), "storage", true) {
         Ok(nvs) => { info!("NVS storage area initialized"); nvs },
         Err(ref e) => {
             panic!("NVS initialization failed {:?}", e); }
     };
+    let mut secret_store = match SecretStore::new(nvs_default_partition.clone(), config::secrets_encryption()) {
+        Ok(secret_store) => { info!("NVS secrets area initialized"); secret_store },
+        Err(ref e) => {
+            panic!("NVS secrets initialization failed {:?}", e); }
+    };
+    let tls_store = match TlsStore::new(nvs_default_partition, config::secrets_encryption()) {
+        Ok(tls_store) => { info!("NVS TLS area initialized"); Arc::new(Mutex::new(tls_store)) },
+        Err(ref e) => {
+            panic!("NVS TLS initialization failed {:?}", e); }
+    };
+    // tracks read by the web server and rewritten by the retention, the delete and the trim
+    let track_locks = TrackLocks::new();
 
     // Load config
     let mut nvs_buf : [u8 ; MAX_NVS_STR_SIZE] = [0; MAX_NVS_STR_SIZE];
@@ -139,21 +125,64 @@ fn main() -> anyhow::Result<()> {
         Ok(value) => { info!("Try to read NVS config"); value },
         Err(ref e) => { info!("NVS config not found {:?}", e); None }
     };
+    // saved after the secrets are loaded, the secrets store is not overwritten by the defaults
+    let mut need_to_save_config = false;
     if nvs_value == None {
         info!("NVS config not found. Set default config");
-        set_default_config(&mut config_data, &mut nvs);
+        set_default_config(&mut config_data);
+        need_to_save_config = true;
         thread::sleep(Duration::from_millis(1000));
     }
     else {
         // info!("NVS config found {:?}", nvs_value);
-        match config_data.load_config(nvs_value) {
-            Ok(_) => { info!("Config load success"); },
-            Err(ref e) => { 
+        let stored_version = config::stored_version(nvs_value.unwrap()).unwrap_or(CONFIG_VERSION);
+        match ConfigData::from_toml(nvs_value.unwrap(), &default_config) {
+            Ok((config, errors)) => {
+                info!("Config load success");
+                config_data = config;
+                // only the invalid values are the defaults
+                for error in &errors {
+                    info!("Config {} is the default: {}", error.field, error.message);
+                }
+                // the config of an older firmware is saved with the current version
+                if stored_version < CONFIG_VERSION {
+                    info!("Config migrated from version {} to {}", stored_version, CONFIG_VERSION);
+                }
+                need_to_save_config = !errors.is_empty() || stored_version < CONFIG_VERSION;
+            },
+            Err(ref e) => {
                 info!("Config load failed {:?}", e);
-                set_default_config(&mut config_data, &mut nvs);
+                set_default_config(&mut config_data);
+                need_to_save_config = true;
                 thread::sleep(Duration::from_millis(1000));
             },
-        }    
+        }
+    }
+    // the secrets of cfg.toml or of the config of an older version are saved when the store is not saved yet,
+    // a store which cannot be read is left as it is
+    match secret_store.load(&mut config_data) {
+        Ok(Some(errors)) => {
+            for error in &errors {
+                info!("Secret {} is not set: {}", error.field, error.message);
+            }
+        },
+        Ok(None) => {
+            info!("Secrets store not found. Save the secrets of the config");
+            secret_store.save(&config_data);
+        },
+        Err(ref e) => {
+            info!("Secrets store is not loaded and kept: {}", e);
+        },
+    }
+    if config::initial_passwords(&mut config_data) {
+        info!("Set the passwords of cfg.toml");
+        need_to_save_config = true;
+    }
+    if config_data.admin_password_hash.is_empty() {
+        info!("No admin password, the web server is not protected");
+    }
+    if need_to_save_config {
+        save_config(&config_data, &mut nvs, &mut secret_store);
     }
 
     // Initialize Temperature Sensor
@@ -193,36 +222,43 @@ fn main() -> anyhow::Result<()> {
     }
     // emmc.format();
     // imagefiles::delete_all_files(Path::new("/eMMC"));
+    // capture files may be left inconsistent by a brownout or a reset during capturing
+    imagefiles::recover_all(Path::new("/eMMC"));
 
     let mut server_info = server::ControlServerInfo::new();
     // config_data
     // info!("Start config_data: {:?}", config_data);
-    info!("Auto Capture: {:?}", unsafe { DEEP_SLEEP_AUTO_CAPTURE } );
-    info!("Image Count ID: {:?}", unsafe { IMAGE_COUNT_ID });
-
-    let mut current_resolution = camera::framesize_t_FRAMESIZE_QSXGA;
-    if operating_mode {
-        // operating mode
+    // the state is not resumed when the RTC memory is not valid
+    let retained_state = RtcStorage.load();
+    let mut capture_loop = CaptureLoop::new(retained_state.unwrap_or_default());
+    info!("Auto Capture: {:?}", capture_loop.state.auto_capture);
+    info!("Image Count ID: {:?}", capture_loop.state.capture_id);
+
+    if operating_mode || retained_state.is_none() {
+        // operating mode or the settings of the config
         server_info.duration = config_data.duration;
         server_info.resolution = config_data.resolution;
         server_info.track_id = config_data.track_id;
+        // frame size have to be maximum resolution
+        capture_loop.state.resolution = camera::framesize_t_FRAMESIZE_QSXGA;
+        capture_loop.state.track_id = server_info.track_id;
     }
     else {
         // wakeup
-        server_info.duration = unsafe { DURATION_TIME };
-        server_info.resolution = unsafe { CURRENT_RESOLUTION };
-        server_info.track_id = unsafe { CURRENT_TRACK_ID };
-        current_resolution = server_info.resolution;
-
+        server_info.duration = capture_loop.state.duration;
+        server_info.resolution = capture_loop.state.resolution;
+        server_info.track_id = capture_loop.state.track_id;
     }
     server_info.auto_capture = config_data.auto_capture;
     server_info.idle_in_sleep_time = config_data.idle_in_sleep_time;
-    server_info.timezone = config_data.timezone_offset;
+    server_info.timezone = config_data.timezone.clone();
     server_info.autofocus_once = config_data.autofocus_once;
-    server_info.last_capture_date_time = SystemTime::UNIX_EPOCH + Duration::from_secs(unsafe { LAST_CAPTURE_TIME });
-    server_info.last_posted_date_time = SystemTime::UNIX_EPOCH + Duration::from_secs(unsafe { LAST_POSTED_TIME });
-    server_info.capture_start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(unsafe { CAPTURE_START_TIME });
-    server_info.capture_end_time = SystemTime::UNIX_EPOCH + Duration::from_secs(unsafe { CAPTURE_END_TIME });    
+    server_info.last_capture_date_time = from_seconds(capture_loop.state.last_capture_time);
+    server_info.last_posted_date_time = from_seconds(capture_loop.state.last_posted_time);
+    server_info.capture_start_time = from_seconds(capture_loop.state.capture_start_time);
+    server_info.capture_end_time = from_seconds(capture_loop.state.capture_end_time);
+    server_info.missed_captures = capture_loop.state.missed_captures;
+    server_info.last_missed_date_time = from_seconds(capture_loop.state.last_missed_time);
     server_info.query_prompt = config_data.query_prompt.clone();
     server_info.query_openai = config_data.query_openai;
     server_info.openai_model = config_data.model.clone();
@@ -231,28 +267,28 @@ fn main() -> anyhow::Result<()> {
     server_info.post_interval = config_data.post_interval;
     server_info.capture_frames_at_once = config_data.capture_frames_at_once;
     server_info.jpeg_quality = config_data.jpeg_quality;
+    server_info.segment_size = config_data.segment_size;
+    server_info.segment_frames = config_data.segment_frames;
+    server_info.retention_days = config_data.retention_days;
+    server_info.retention_frames = config_data.retention_frames;
+    server_info.thin_after_days = config_data.thin_after_days;
+    server_info.thin_interval = config_data.thin_interval;
+    server_info.latitude = config_data.latitude;
+    server_info.longitude = config_data.longitude;
+    server_info.sun_schedule = config_data.sun_schedule.clone();
+    server_info.sunrise_margin = config_data.sunrise_margin;
+    server_info.sunset_margin = config_data.sunset_margin;
+    server_info.missed_policy = config_data.missed_policy.clone();
     server_info.overwrite_saved = config_data.overwrite_saved;
     server_info.direct_write_mode = config_data.direct_write_mode;
-    let mut last_status_posted_time = SystemTime::UNIX_EPOCH + Duration::from_secs(unsafe { LAST_STATUS_POSTED_TIME });
-    let mut next_capture_time = UNIX_EPOCH + Duration::from_secs(unsafe { NEXT_CAPTURE_TIME });
-    let mut capture_id = unsafe { IMAGE_COUNT_ID };
-    let mut current_track_id = server_info.track_id;
-    let mut current_duration = server_info.duration;
-    let dt_utc : DateTime<Utc> = DateTime::<Utc>::from(next_capture_time);
-    let fixed_offset = FixedOffset::east_opt(config_data.timezone_offset * 3600).unwrap();
-    let dt_local = DateTime::<Local>::from_naive_utc_and_offset(dt_utc.naive_utc(), fixed_offset);
-    info!("Next Capture Time: {} Capture Count: {}", dt_local.format("%Y-%m-%d %H:%M:%S"), capture_id);
+    server_info.https = config_data.https;
+    server_info.config = config_data.clone();
+    info!("Next Capture Time: {} Capture Count: {}",
+        local_time_string(&server_info.time_zone(), from_seconds(capture_loop.state.next_capture_time)), capture_loop.state.capture_id);
 
-    let status_post_need = server_info.status_report && (capture_id % server_info.status_report_interval) == 0;
+    let status_post_need = server_info.status_report && (capture_loop.state.capture_id % server_info.status_report_interval) == 0;
     // current_settings into server_info
-    server_info.leap_time = LeapTime {
-        year: -1,
-        month: -1,
-        day: config_data.leap_day,
-        hour: config_data.leap_hour,
-        minute: config_data.leap_minute,
-        second: 0,
-    };
+    server_info.schedule = config_data.schedule.clone();
 
     // wifi initialize
     let mut wifi_dev : Result<Box<EspWifi>, anyhow::Error> = Result::Err(anyhow::anyhow!("WiFi not connected"));
@@ -305,8 +341,10 @@ fn main() -> anyhow::Result<()> {
                         let formatted = format!("{}", dt_now.format("%Y-%m-%d %H:%M:%S"));
                         info!("NTP Sync Completed: {}", formatted);
             
-                        // HTTP Server
-                        let mut server = match server::ControlServer::new(&server_info) {
+                        // HTTP Server, the certificate of HTTPS has the names and the address of the device
+                        let ip_name = ip_addr.to_string();
+                        let names = [server::HOST_NAME, server::LOCAL_HOST_NAME, ip_name.as_str()];
+                        let mut server = match server::ControlServer::new(&server_info, tls_store.clone(), track_locks.clone(), &names) {
                             Ok(server_ctx) => {
                                 info!("HTTP Server started");
                                 server_ctx
@@ -361,7 +399,7 @@ fn main() -> anyhow::Result<()> {
         2,                          // Frame buffer count (back to 2 for double buffering)
         camera::camera_grab_mode_t_CAMERA_GRAB_LATEST,        // grab mode
         //camera::camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,    // grab mode
-        current_resolution,        // frame size have to be maximum resolution
+        capture_loop.state.resolution,     // frame size have to be maximum resolution
     );
     let camera_device : Camera = match cam {
         Ok(cam) => {
@@ -373,7 +411,7 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
-    let mut capture = Capture::new(camera_device, "/eMMC");
+    let mut capture = Capture::new(camera_device, "/eMMC", track_locks.clone());
     capture.start();
     let monitoring_thread = Monitoring::new(config_data.model.clone(), config_data.api_key.clone());
     monitoring_thread.set_post_access_token(config_data.post_account.clone(),
@@ -383,8 +421,14 @@ fn main() -> anyhow::Result<()> {
                                                config_data.storage_signed_key.clone());
     monitoring_thread.set_last_posted_date_time(server_info.last_posted_date_time, server_info.post_interval);
     monitoring_thread.start();
+    let mut devices = Devices {
+        clock: SystemClock,
+        sleeper: EspSleeper::new(emmc_cam_power),
+        camera: EspCamera::new(&capture),
+        storage: RtcStorage,
+    };
     if operating_mode {
-        unsafe { DEEP_SLEEP_AUTO_CAPTURE = false; }
+        capture_loop.state.auto_capture = false;
         config_data.auto_capture = false;
         server_info.last_access_time = SystemTime::now();
         if server_enabled {
@@ -399,7 +443,7 @@ fn main() -> anyhow::Result<()> {
     let mut last_reconnect_time = SystemTime::now();
     loop {
         // imagefiles::list_files(Path::new("/eMMC"));
-        if config_data.auto_capture || unsafe { DEEP_SLEEP_AUTO_CAPTURE } {
+        if config_data.auto_capture || capture_loop.state.auto_capture {
             server_info.capture_started = true;
         }
         // read battery voltage
@@ -421,49 +465,14 @@ fn main() -> anyhow::Result<()> {
                 // check save config
                 if server_info.need_to_save {
                     server_info.need_to_save = false;
-                    config_data.auto_capture = server_info.auto_capture;
-                    config_data.duration = server_info.duration;
-                    config_data.resolution = server_info.resolution;
-                    config_data.track_id = server_info.track_id;
-                    config_data.timezone_offset = server_info.timezone;
-                    config_data.idle_in_sleep_time = server_info.idle_in_sleep_time;
-                    config_data.query_openai = server_info.query_openai;
-                    config_data.query_prompt = server_info.query_prompt.clone();
-                    config_data.model = server_info.openai_model.clone();
-                    config_data.autofocus_once = server_info.autofocus_once;
-                    config_data.status_report = server_info.status_report;
-                    config_data.status_report_interval = server_info.status_report_interval;
-                    config_data.post_interval = server_info.post_interval;
-                    config_data.leap_day = server_info.leap_time.day;
-                    config_data.leap_hour = server_info.leap_time.hour;
-                    config_data.leap_minute = server_info.leap_time.minute;
-                    config_data.capture_frames_at_once = server_info.capture_frames_at_once;
-                    config_data.overwrite_saved = server_info.overwrite_saved;
-                    config_data.direct_write_mode = server_info.direct_write_mode;
-                    config_data.jpeg_quality = server_info.jpeg_quality;
-                    let save_config = config_data.get_all_config();
-                    let toml_cfg = convert_config_to_toml_string(&save_config);
-                    match nvs.set_str("config", toml_cfg.as_str()) {
-                        Ok(_) => { info!("Save config"); },
-                        Err(ref e) => { info!("Set default config failed {:?}", e); }
-                    }
+                    // imported WiFi and secrets are used after the restart
+                    config_data = server_info.current_config();
+                    save_config(&config_data, &mut nvs, &mut secret_store);
+                    server_info.config = config_data.clone();
                     server.as_mut().unwrap().set_server_info(server_info.clone());
                 }
                 server.as_mut().unwrap().set_server_capture_started(server_info.capture_started);
             }
-            current_duration = server_info.duration;
-            if !server_info.capture_started {
-                // when idle, check last access time
-                if server_info.last_access_time.duration_since(UNIX_EPOCH).unwrap().as_millis() > 1700000000 {
-                    let last_access_time = server_info.last_access_time.elapsed().unwrap().as_secs();
-                    if last_access_time > config_data.idle_in_sleep_time as u64 {
-                        operating_mode = false;
-                        info!("Idle time {:?} over. Go to sleep", last_access_time);
-                        emmc_cam_power.set_high().expect("Set emmc_cam_power high failure");
-                        deep_and_light_sleep_start(SleepMode::SleepModeDeep, 0);
-                    }
-                }
-            }    
         }
 
         let key_event = touchpad.get_key_event_and_clear();
@@ -494,8 +503,7 @@ fn main() -> anyhow::Result<()> {
                             server.as_mut().unwrap().set_capture_frames_at_once(server_info.capture_frames_at_once);
                         }
                     }
-                    if server_info.duration == 0 && server_info.leap_time.day < 0
-                        && server_info.leap_time.hour < 0 && server_info.leap_time.minute < 0 {
+                    if server_info.duration == 0 && server_info.schedule.is_empty() {
                         server_info.duration = 90;
                     }                
                 },
@@ -509,23 +517,34 @@ fn main() -> anyhow::Result<()> {
             server_info.capture_frames_at_once = 0;
         }
     
-        if server_info.resolution != current_resolution {
-            info!("Resolution changed: {} -> {}", current_resolution, server_info.resolution);
-            current_resolution = server_info.resolution;
-            capture.change_resolution(current_resolution);
-        }
         capture.set_capturing_duration(server_info.capture_frames_at_once);
         let mut tempval : f32 = 0.0;
+        unsafe {
+            esp_idf_svc::hal::sys::temperature_sensor_get_celsius(&mut *temp_sensor_ptr, &mut tempval);
+        }
         if server_enabled {
-            unsafe {
-                esp_idf_svc::hal::sys::temperature_sensor_get_celsius(&mut *temp_sensor_ptr, &mut tempval);
-                server.as_mut().unwrap().set_temperature(tempval);
+            server.as_mut().unwrap().set_temperature(tempval);
+        }
+        capture.set_sensor_values(battery_voltage, tempval);
+        if let Some(report) = capture.take_retention_report() {
+            server_info.retention_removed_frames = report.removed_frames;
+            server_info.retention_reclaimed_bytes = report.reclaimed_bytes;
+            if server_enabled {
+                server.as_mut().unwrap().set_retention_report(report.removed_frames, report.reclaimed_bytes);
             }
         }
 
         if server_info.capture_started {
             capture.set_direct_write_mode(server_info.direct_write_mode);
             capture.set_jpeg_quality(server_info.jpeg_quality);    
+            capture.set_segment_limit(server_info.segment_size, server_info.segment_frames);
+            capture.set_retention_policy(RetentionPolicy {
+                keep_days: server_info.retention_days,
+                keep_frames: server_info.retention_frames,
+                thin_after_days: server_info.thin_after_days,
+                thin_interval: server_info.thin_interval.saturating_mul(60),
+            });
+            capture.set_track_settings(server_info.track_settings());
             if !capture_indicator_on {
                 // indicator on
                 // led_ind.set_low().expect("Set indicator low failure");
@@ -535,351 +554,70 @@ fn main() -> anyhow::Result<()> {
                 // led_ind.set_high().expect("Set indicator high failure");
                 capture_indicator_on = false;
             }
-            if current_track_id != server_info.track_id {
-                info!("Track ID changed: {} -> {}", current_track_id, server_info.track_id); 
-                current_track_id = server_info.track_id;
-                capture_id = 0;
-            }
-            if server_info.autofocus_once {
-                if capture_id == 0 {
-                    capture.autofocus_request();
-                }
-            }
-            else {
-                capture.autofocus_request();
-            }
-            if capture_id == 0 {
-                next_capture_time = SystemTime::now();
-                capture.set_overwrite_saved(server_info.overwrite_saved);
-            }
-            else {
-                capture.set_overwrite_saved(false);
-            }
-            if movie_mode && capture_id == 0 || !movie_mode {
-                // indicator on
-                // led_ind.set_low().expect("Set indicator low failure");
+            if !movie_mode {
                 log::info!("System Temperature: {:.2}°C", tempval);
-                info!("Capture Started Track ID: {} Count: {} Resolution: {}", current_track_id, capture_id, current_resolution);
-                capture.capture_request(current_track_id, capture_id);
-            }
-            if movie_mode {
-                capture_id += 1;
-                thread::sleep(Duration::from_millis(100));
-                continue;
-            }
-            loop {
-                if capture.get_capture_status() {
-                    info!("Capture done");
-                    break;
-                }
-                thread::sleep(Duration::from_millis(100));
-            }
-            // get last capture id
-            capture_id = capture.get_capture_id();
-            if server_info.query_openai {
-                info!("Query OpenAI: Track :{} frame No.:{}", current_track_id, capture_id);
-                monitoring_thread.set_query_start(server_info.query_prompt.clone(), current_track_id, capture_id);
-                loop {
-                    if !monitoring_thread.get_query_status() {
-                        let reply = monitoring_thread.get_query_reply();
-                        info!("Query reply: {}", reply);
-                        break;
-                    }
-                    thread::sleep(Duration::from_millis(10));
-                }
-                if monitoring_thread.get_posted_status() {
-                    server_info.last_posted_date_time = SystemTime::now();
-                    if server_enabled {
-                        server.as_mut().unwrap().set_last_posted_date_time(server_info.last_posted_date_time);
-                    }
-                    monitoring_thread.set_last_posted_date_time(server_info.last_posted_date_time, server_info.post_interval);
-                }
-            }
-            let capture_info = capture.get_capture_info();
-            if capture_info.status {
-                info!("Write Frame ID {}: width:{} height:{} image_size:{}", capture_id, capture_info.width, capture_info.height, capture_info.size);
-                if server_info.status_report && (last_status_posted_time.elapsed().unwrap().as_secs() > server_info.status_report_interval as u64) {
-                    // capture time
-                    let dt_utc : DateTime<Utc> = DateTime::<Utc>::from(SystemTime::now());
-                    let fixed_offset = FixedOffset::east_opt(server_info.timezone * 3600).unwrap();
-                    let dt_local = DateTime::<Local>::from_naive_utc_and_offset(dt_utc.naive_utc(), fixed_offset);
-                    let capture_time = dt_local.format("%Y-%m-%d %H:%M:%S").to_string();
-                    let message = format!("STATUS REPORT: {}:{} {} {}V {}dBm", 
-                        current_track_id, capture_id, capture_time,
-                         battery_voltage, wifi::get_rssi());
-                    monitoring_thread.post_message_request(message, current_track_id, capture_id);
-                    loop {
-                        if !monitoring_thread.get_post_message_status() {
-                            break;
-                        }
-                        thread::sleep(Duration::from_millis(10));
-                    }
-                    last_status_posted_time = SystemTime::now();
-                }    
-                server_info.last_capture_date_time = SystemTime::now();
-                if server_enabled {
-                    server.as_mut().unwrap().set_last_capture_date_time(server_info.last_capture_date_time);
-                }
-                server_info.current_capture_id = capture_id;
-                // increment capture_id
-                capture_id += 1;
-            }
-
-            // indicator off
-            // led_ind.set_high().expect("Set indicator high failure");
-            capture_indicator_on = false;            
-            
-            if one_shot {
-                server_info.capture_started = false;
-                capture_id = 0;
-                if server_enabled {
-                    server.as_mut().unwrap().set_one_shot_completed();
-                }
-                thread::sleep(Duration::from_millis(100));
-                continue;
             }
+        }
 
-            next_capture_time = match get_next_wake_time(
-                    server_info.leap_time,
-                    server_info.timezone,
-                    next_capture_time,
-                    server_info.duration,
-                    server_info.capture_start_time,
-                    server_info.capture_end_time) {
-                Some(time) => time,
-                None => {
-                    info!("Capture end");
-                    server_info.capture_started = false;
-                    if server_enabled {
-                        server.as_mut().unwrap().set_server_capture_started(server_info.capture_started);
-                    }
-                    capture_id = 0;
-                    unsafe {
-                        IMAGE_COUNT_ID = capture_id;
-                        DEEP_SLEEP_AUTO_CAPTURE = server_info.capture_started;
-                        DURATION_TIME = current_duration;
-                        CURRENT_RESOLUTION = current_resolution;
-                        CURRENT_TRACK_ID = current_track_id;
-                        LAST_CAPTURE_TIME = server_info.last_capture_date_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        LAST_POSTED_TIME = server_info.last_posted_date_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        CAPTURE_START_TIME = server_info.capture_start_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        CAPTURE_END_TIME = server_info.capture_end_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        LAST_STATUS_POSTED_TIME = last_status_posted_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                    }
-                    emmc_cam_power.set_high().expect("Set emmc_cam_power high failure");
-                    deep_and_light_sleep_start(SleepMode::SleepModeDeep, 0);
-                    SystemTime::now() // not reached
-                }
-            };
-            // parse next_capture_time to string
-            let dt_utc : DateTime<Utc> = DateTime::<Utc>::from(next_capture_time);
-            let fixed_offset = FixedOffset::east_opt(server_info.timezone * 3600).unwrap();
-            let dt_local = DateTime::<Local>::from_naive_utc_and_offset(dt_utc.naive_utc(), fixed_offset);
-            info!("Next Capture Time: {}", dt_local.format("%Y-%m-%d %H:%M:%S"));
-            let sleep_time = match next_capture_time.duration_since(SystemTime::now()) {
-                Ok(duration) => duration,
-                Err(e) => {
-                    info!("Time calculation error: {:?}", e);
-                    Duration::from_secs(1)
-                }
-            };
-            info!("Sleep: {:?}", sleep_time);
-            match sleep_time.as_secs() {
-                0 => {
-                    thread::sleep(Duration::from_millis(1000));
-                },
-                1..=60 => { // 0-60s is OS sleep
-                    thread::sleep(sleep_time);
-                }
-                _ => {
-                    unsafe {
-                        IMAGE_COUNT_ID = capture_id;
-                        DEEP_SLEEP_AUTO_CAPTURE = true;
-                        NEXT_CAPTURE_TIME = next_capture_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        DURATION_TIME = current_duration;
-                        CURRENT_RESOLUTION = current_resolution;
-                        CURRENT_TRACK_ID = current_track_id;
-                        LAST_CAPTURE_TIME = server_info.last_capture_date_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        LAST_POSTED_TIME = server_info.last_posted_date_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        CAPTURE_START_TIME = server_info.capture_start_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        CAPTURE_END_TIME = server_info.capture_end_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                        LAST_STATUS_POSTED_TIME = last_status_posted_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                    }
-                    emmc_cam_power.set_high().expect("Set emmc_cam_power high failure");
-                    deep_and_light_sleep_start(SleepMode::SleepModeDeep, sleep_time.as_secs());
-                }
+        let settings = LoopSettings {
+            capture_started: server_info.capture_started,
+            one_shot: operating_mode && one_shot,
+            movie_mode,
+            resolution: server_info.resolution,
+            track_id: server_info.track_id,
+            duration: server_info.duration,
+            autofocus_once: server_info.autofocus_once,
+            overwrite_saved: server_info.overwrite_saved,
+            status_report: server_info.status_report,
+            status_report_interval: server_info.status_report_interval,
+            schedule: if server_info.capture_started { capture_schedule(&server_info) } else { None },
+            capture_start_time: server_info.capture_start_time,
+            capture_end_time: server_info.capture_end_time,
+            idle_in_sleep_time: if operating_mode { Some(config_data.idle_in_sleep_time) } else { None },
+            last_access_time: server_info.last_access_time,
+        };
+        let mut reporter = ServerReporter {
+            server: if server_enabled { server.as_mut() } else { None },
+            server_info: &mut server_info,
+            monitoring: &monitoring_thread,
+            battery_voltage,
+        };
+        let step = capture_loop.step(&settings, &mut devices, &mut reporter);
+        if step == Step::OneShot {
+            server_info.capture_started = false;
+            if server_enabled {
+                server.as_mut().unwrap().set_one_shot_completed();
             }
         }
-        else {
-            if capture_id > 0 {
-                capture_id = 0;
-            }
-            thread::sleep(Duration::from_millis(100));
+        if step != Step::Movie {
+            // indicator off
+            // led_ind.set_high().expect("Set indicator high failure");
+            capture_indicator_on = false;
         }
     }
 }
 
-// duration: > 0: Capture every duration seconds, = 0: Capture at specific time by LeapTime
-fn get_next_wake_time(lt: LeapTime, timezone: i32, mut next_capture_time: SystemTime, duration: u32,
-    capture_start_time: SystemTime, capture_end_time: SystemTime ) -> Option<SystemTime> {
-    let now = SystemTime::now();
-    let start_time_utc_str = DateTime::<Utc>::from(capture_start_time).format("%Y-%m-%d %H:%M:%S").to_string();
-    let end_time_utc_str = DateTime::<Utc>::from(capture_end_time).format("%Y-%m-%d %H:%M:%S").to_string();
-    let now_utc_str = DateTime::<Utc>::from(now).format("%Y-%m-%d %H:%M:%S").to_string();
-    info!("Start {} - {} Now: {}", start_time_utc_str, end_time_utc_str, now_utc_str);    
-    if duration > 0 {
-        next_capture_time = next_capture_time + Duration::from_secs(duration as u64);
-        if capture_start_time < capture_end_time {
-            if next_capture_time < capture_start_time {
-                next_capture_time = capture_start_time;
-            }
-            else if next_capture_time > capture_end_time {
-                // stop capture
-                return None;
-            }
-        }
-        return Some(next_capture_time);
-    }
-    else {
-        let offset = FixedOffset::east_opt(timezone * 60 * 60).unwrap();
-        let now_with_offset = Local::now().with_timezone(&offset);
-        // parse now_with_offset to string
-        let now_str = now_with_offset.format("%Y-%m-%d %H:%M:%S").to_string();
-        info!("Now with offset: {}", now_str);
-        // parse now_str to year, day, month, hour, minute, second
-        let now_year = now_with_offset.year();
-        let now_month = now_with_offset.month();
-        let now_day = now_with_offset.day();
-        let now_hour = now_with_offset.hour();
-        let now_naive = now_with_offset.naive_local();
-        let mut lt_hour = lt.hour;
-        let mut lt_minute = lt.minute;
-        let lt_day = lt.day;
-        if lt_day > 0 {
-            // LeapTime is specific day
-            if lt_hour < 0 { lt_hour = 0; }
-            if lt_minute < 0 { lt_minute = 0; }
-            let leap_time = NaiveDate::from_ymd_opt(now_year, now_month, lt_day as u32).unwrap()
-                .and_hms_opt(lt_hour as u32, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-            if now_naive < leap_time.naive_local() {
-                // convert leap_time to UTC as DateTime
-                next_capture_time = leap_time.into();
-            }
-            else {
-                // LeapTime is interval time, get duration from now to LeapTime
-                let mut next_yaer = now_year;
-                let mut next_month = now_month;
-                if now_month == 12 {
-                    next_yaer += 1;
-                    next_month = 1;
-                }
-                else {
-                    next_month += 1;
-                }
-                let today_leap_time = NaiveDate::from_ymd_opt(now_year, now_month, lt_day as u32)
-                    .unwrap().and_hms_opt(lt_hour as u32, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                if now_naive < today_leap_time.naive_local() {
-                    // today
-                    next_capture_time = today_leap_time.into();
-                }
-                else {
-                    // next month
-                    let next_month_leap_time = NaiveDate::from_ymd_opt(next_yaer, next_month, lt_day as u32)
-                        .unwrap().and_hms_opt(lt_hour as u32, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                    next_capture_time = next_month_leap_time.into();
-                }
-            }
-        }
-        else {
-            if lt.hour >= 0 {
-                // LeapTime is specific time
-                if lt_minute < 0 { lt_minute = 0; }
-                let leap_time = NaiveDate::from_ymd_opt(now_year, now_month, now_day)
-                    .unwrap().and_hms_opt(lt_hour as u32, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                if now_naive < leap_time.naive_local() {
-                    // convert leap_time to UTC as DateTime
-                    next_capture_time = leap_time.into();
-                }
-                else {
-                    // LeapTime is interval time, get duration from now to LeapTime
-                    let tomorrow = now_naive.date() + ChronoDuration::days(1);
-                    let tomorrow_leap_time = NaiveDate::from_ymd_opt(tomorrow.year(), tomorrow.month(), tomorrow.day())
-                        .unwrap().and_hms_opt(lt_hour as u32, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                    next_capture_time = tomorrow_leap_time.into();
-                }
-            }
-            else {
-                if lt.minute > 0 {
-                    // LeapTime is specific time 
-                    let leap_time = NaiveDate::from_ymd_opt(now_year, now_month, now_day)
-                        .unwrap().and_hms_opt(now_hour, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                    if now_naive < leap_time.naive_local() {
-                        // convert leap_time to UTC as DateTime
-                        next_capture_time = leap_time.into();
-                    }
-                    else {
-                        // LeapTime is interval time, get duration from now to LeapTime
-                        if now_hour == 23 {
-                            let tomorrow = now_naive.date() + ChronoDuration::days(1);
-                            let tomorrow_leap_time = NaiveDate::from_ymd_opt(tomorrow.year(), tomorrow.month(), tomorrow.day())
-                                .unwrap().and_hms_opt(0, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                            next_capture_time = tomorrow_leap_time.into();
-                        }
-                        else {
-                            let next_hour = now_hour + 1;
-                            let next_leap_time = NaiveDate::from_ymd_opt(now_year, now_month, now_day)
-                                .unwrap().and_hms_opt(next_hour, lt_minute as u32, 0).unwrap().and_local_timezone(offset).unwrap();
-                            next_capture_time = next_leap_time.into();
-                        }
-                    }
-                }
-            }
-        }
-        if next_capture_time < capture_start_time {
-            next_capture_time = capture_start_time;
-        }
-        else if next_capture_time > capture_end_time && capture_end_time > now.into() {
-            // stop capture
-            return None;
+// duration: > 0: Capture every duration seconds, = 0: Capture at the times of the schedule (cron expression)
+// daylight: capture only from sunrise to sunset, or at the golden hour
+fn capture_schedule(server_info: &server::ControlServerInfo) -> Option<CaptureSchedule> {
+    let seconds = |time: SystemTime| to_seconds(time) as i64;
+    match CaptureSchedule::new(server_info.duration, &server_info.schedule, server_info.time_zone(),
+            seconds(server_info.capture_start_time), seconds(server_info.capture_end_time)) {
+        Ok(schedule) => Some(schedule
+            .with_daylight(daylight_setting(&server_info.sun_schedule, server_info.latitude, server_info.longitude,
+                server_info.sunrise_margin, server_info.sunset_margin))
+            .with_missed_policy(MissedPolicy::parse(&server_info.missed_policy).unwrap_or_default())),
+        Err(e) => {
+            info!("Invalid schedule: {:?}", e);
+            None
         }
-        return Some(next_capture_time);
     }
 }
 
-fn deep_and_light_sleep_start(sleep_mode: SleepMode, wakeup_interval: u64) {
-    info!("Sleep Now...");
-    unsafe {
-        esp_idf_sys::esp_wifi_stop();
-    }
-    thread::sleep(Duration::from_millis(1000));
-    unsafe {
-        // light sleep mode
-        if sleep_mode == SleepMode::SleepModeLight {
-            // gpio wakeup enable
-            // esp_idf_sys::gpio_wakeup_enable(GPIO_WAKEUP_INT_PIN_4, esp_idf_sys::gpio_int_type_t_GPIO_INTR_LOW_LEVEL);
-            // esp_idf_sys::esp_sleep_enable_gpio_wakeup();
-            // wakeup from rtc timer
-            if wakeup_interval > 0 {
-                esp_idf_sys::esp_sleep_enable_timer_wakeup(wakeup_interval * 1000 * 1000);
-            }
-        }
-        else {
-            info!("Deep Sleep Start");
-            esp_idf_sys::esp_sleep_enable_gpio_wakeup();            
-            esp_idf_sys::esp_sleep_enable_touchpad_wakeup();
-            if wakeup_interval > 0 {
-                esp_idf_sys::esp_sleep_enable_timer_wakeup(wakeup_interval * 1000 * 1000);
-            }
-            esp_idf_sys::esp_deep_sleep_start();
-        }
-
-        // deep sleep mode (not here)
-        if sleep_mode == SleepMode::SleepModeLight {
-            let _result = esp_idf_sys::esp_light_sleep_start();
-            // gpio interrupt enable
-            // esp_idf_sys::gpio_set_intr_type(GPIO_WAKEUP_INT_PIN_4, esp_idf_sys::gpio_int_type_t_GPIO_INTR_ANYEDGE);
-        }
-    }
+// local time with the zone name (2024-03-31 03:00:00 CEST)
+fn local_time_string(timezone: &TimeZone, time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    format!("{} {}", timezone.format(time, "%Y-%m-%d %H:%M:%S"), timezone.abbreviation_at(time))
 }
 
 fn wifi_reconnect(wifi_dev: &mut EspWifi) -> bool{
@@ -903,38 +641,17 @@ fn wifi_reconnect(wifi_dev: &mut EspWifi) -> bool{
     }
 }
 
-fn set_default_config<T : NvsPartitionId>(config: &mut ConfigData, nvs: &mut EspNvs<T>){
-    let default_config = config.set_default_config();
-    let toml_cfg = convert_config_to_toml_string(&default_config);
-    match nvs.set_str("config", toml_cfg.as_str()) {
-        Ok(_) => { info!("Set default config"); },
-        Err(ref e) => { info!("Set default config failed {:?}", e); }
-    }
-    match config.load_config(Some(toml_cfg.as_str())) {
-        Ok(_) => { info!("Config load success"); },
-        Err(ref e) => { info!("Config load failed {:?}", e);
-            unsafe {
-                esp_idf_sys::nvs_flash_erase();
-            }
-        }
-    };
+fn set_default_config(config: &mut ConfigData) {
+    *config = config::default_config();
+    info!("Set default config");
 }
 
-fn convert_config_to_toml_string(keyval: &Vec<(String, String)>) -> String {
-    let mut toml_string = String::new();
-    for it in keyval {
-        toml_string.push_str(&format!("{} = \"{}\"\n", it.0, it.1));
-    }
-    toml_string
-}
-
-// save config
-#[allow(dead_code)]
-fn save_config<T : NvsPartitionId>(config: &ConfigData, nvs: &mut EspNvs<T>) {
-    let save_config = config.get_all_config();
-    let toml_cfg = convert_config_to_toml_string(&save_config);
+// save config, the secrets are saved in the secrets store before the config without them
+fn save_config<T : NvsPartitionId>(config: &ConfigData, nvs: &mut EspNvs<T>, secret_store: &mut SecretStore) {
+    secret_store.save(config);
+    let toml_cfg = config.export_toml(true);
     match nvs.set_str("config", toml_cfg.as_str()) {
         Ok(_) => { info!("Save config"); },
         Err(ref e) => { info!("Set default config failed {:?}", e); }
     }
-}
\ No newline at end of file
+}
@@ -16,7 +16,7 @@ use base64::prelude::*;
 type HmacSha256 = Hmac<Sha256>;
 const EXPIRATION: u64 = 60 * 60 * 24; // 1 day
 
-use crate::imagefiles::{ImageFiles, OpenMode};
+use timeleapcam_core::imagefiles::{ImageFiles, OpenMode};
 
 struct QueryOpenAI {
     pub api_key: String,
@@ -476,7 +476,7 @@ fn post_message(post_url: String, post_to: String, access_token: String, image_u
 // Generate signed URL
 fn generate_signed_url(mut url: Url, key: &str) -> String {
     // Get current UNIX timestamp and add expiration
-    info!("URL: {:?} key:{:?}", url, key);
+    info!("URL: {:?}", url);
     let expiry = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + EXPIRATION;
     url.query_pairs_mut().append_pair("exp", &expiry.to_string());
 
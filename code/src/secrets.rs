@@ -0,0 +1,88 @@
+// Secrets store: the passwords and the tokens in their own NVS namespace, not in the configuration
+use log::info;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+use timeleapcam_core::config::{ConfigData, ConfigError};
+use timeleapcam_core::secrets::{is_sealed, SecretKey, NONCE_SIZE};
+
+const SECRETS_NAMESPACE: &str = "secrets";
+const SECRETS_KEY: &str = "secrets";
+// encrypted store is the hex of the secrets
+const MAX_SECRETS_SIZE: usize = 4000;
+
+pub struct SecretStore {
+    nvs: EspNvs<NvsDefault>,
+    key: SecretKey,
+    encryption: bool,
+    unreadable: Option<String>,     // secrets of the config when the store could not be read
+}
+
+// MAC address in the eFuse, unique to the device
+pub fn device_id() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    mac
+}
+
+// random numbers of the hardware RNG, e.g. the nonces, the salts and the session tokens
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    unsafe {
+        esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut core::ffi::c_void, N);
+    }
+    bytes
+}
+
+impl SecretStore {
+    pub fn new(partition: EspNvsPartition<NvsDefault>, encryption: bool) -> anyhow::Result<SecretStore> {
+        let nvs = EspNvs::new(partition, SECRETS_NAMESPACE, true)?;
+        Ok(SecretStore { nvs, key: SecretKey::derive(&device_id()), encryption, unreadable: None })
+    }
+
+    // Secrets to the config with the invalid secrets, None when the store is not saved yet. An error of the read,
+    // the decryption or the TOML keeps the stored secrets, they are not overwritten by the secrets of the config
+    // until one of them is changed. The values are not logged.
+    pub fn load(&mut self, config: &mut ConfigData) -> anyhow::Result<Option<Vec<ConfigError>>> {
+        let mut buf = vec![0u8; MAX_SECRETS_SIZE];
+        let result = match self.nvs.get_str(SECRETS_KEY, &mut buf) {
+            Ok(Some(stored)) => {
+                match self.key.open(stored).and_then(|text| config.set_secrets(&text)) {
+                    Ok(errors) => Ok(Some((errors, is_sealed(stored)))),
+                    Err(e) => Err(e),
+                }
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Secrets store read failed {:?}", e)),
+        };
+        match result {
+            Ok(Some((errors, sealed))) => {
+                // saved with the other setting of the encryption
+                if sealed != self.encryption {
+                    self.save(config);
+                }
+                Ok(Some(errors))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.unreadable = Some(config.secrets_toml());
+                Err(e)
+            }
+        }
+    }
+
+    pub fn save(&mut self, config: &ConfigData) {
+        let text = config.secrets_toml();
+        if self.unreadable.as_ref() == Some(&text) {
+            info!("Secrets are not changed, the unreadable secrets store is kept");
+            return;
+        }
+        self.unreadable = None;
+        let stored = if self.encryption { self.key.seal(&random_bytes::<NONCE_SIZE>(), &text) } else { text };
+        match self.nvs.set_str(SECRETS_KEY, &stored) {
+            Ok(_) => { info!("Save secrets"); },
+            Err(ref e) => { info!("Save secrets failed {:?}", e); }
+        }
+    }
+}
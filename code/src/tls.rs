@@ -0,0 +1,120 @@
+// Certificate of the HTTPS server in its own NVS namespace, the private key is encrypted like the secrets store
+use log::info;
+use chrono::Utc;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+use timeleapcam_core::secrets::{SecretKey, NONCE_SIZE};
+use timeleapcam_core::tls::{generate_certificate, needs_renewal, parse_certificate, CertificateInfo, DeviceCertificate,
+    KEY_SIZE, SERIAL_SIZE};
+use crate::secrets::{device_id, random_bytes};
+
+const TLS_NAMESPACE: &str = "tls";
+const CERTIFICATE_KEY: &str = "cert";
+const PRIVATE_KEY_KEY: &str = "key";
+const UPLOADED_KEY: &str = "uploaded";
+// PEM with the chain, the encrypted key is the hex of it
+const MAX_PEM_SIZE: usize = 8192;
+// random bytes which are not a P-256 key are very rare
+const MAX_KEY_TRIES: usize = 4;
+
+pub struct TlsStore {
+    nvs: EspNvs<NvsDefault>,
+    key: SecretKey,
+    encryption: bool,
+}
+
+impl TlsStore {
+    pub fn new(partition: EspNvsPartition<NvsDefault>, encryption: bool) -> anyhow::Result<TlsStore> {
+        let nvs = EspNvs::new(partition, TLS_NAMESPACE, true)?;
+        Ok(TlsStore { nvs, key: SecretKey::derive(&device_id()), encryption })
+    }
+
+    fn read(&self, name: &str) -> Option<String> {
+        let mut buf = vec![0u8; MAX_PEM_SIZE * 2];
+        match self.nvs.get_raw(name, &mut buf) {
+            Ok(Some(value)) => String::from_utf8(value.to_vec()).ok(),
+            Ok(None) => None,
+            Err(ref e) => {
+                info!("TLS store read failed {:?}", e);
+                None
+            }
+        }
+    }
+
+    // Saved certificate and key, and true when it is uploaded
+    fn load(&self) -> Option<(DeviceCertificate, bool)> {
+        let certificate = self.read(CERTIFICATE_KEY)?;
+        let key = match self.key.open(&self.read(PRIVATE_KEY_KEY)?) {
+            Ok(key) => key,
+            Err(e) => {
+                info!("TLS key failed: {}", e);
+                return None;
+            }
+        };
+        let uploaded = matches!(self.nvs.get_u8(UPLOADED_KEY), Ok(Some(1)));
+        Some((DeviceCertificate { certificate, key }, uploaded))
+    }
+
+    fn save(&mut self, certificate: &DeviceCertificate, uploaded: bool) -> anyhow::Result<()> {
+        let key = if self.encryption { self.key.seal(&random_bytes::<NONCE_SIZE>(), &certificate.key) } else { certificate.key.clone() };
+        self.nvs.set_raw(CERTIFICATE_KEY, certificate.certificate.as_bytes())?;
+        self.nvs.set_raw(PRIVATE_KEY_KEY, key.as_bytes())?;
+        self.nvs.set_u8(UPLOADED_KEY, uploaded as u8)?;
+        Ok(())
+    }
+
+    // Saved certificate, and true when it is uploaded
+    pub fn info(&self) -> Option<(CertificateInfo, bool)> {
+        let certificate = self.read(CERTIFICATE_KEY)?;
+        let uploaded = matches!(self.nvs.get_u8(UPLOADED_KEY), Ok(Some(1)));
+        parse_certificate(&certificate).ok().map(|info| (info, uploaded))
+    }
+
+    // Certificate of the server. The uploaded one is used as it is, the generated one is made again when it expires
+    // soon or the names of the device are changed. None when no certificate can be made.
+    pub fn certificate(&mut self, names: &[&str]) -> Option<DeviceCertificate> {
+        let now = Utc::now();
+        if let Some((certificate, uploaded)) = self.load() {
+            match parse_certificate(&certificate.certificate) {
+                Ok(info) if uploaded => {
+                    if !info.is_valid_at(now) {
+                        info!("Uploaded certificate is not valid now (until {})", info.not_after);
+                    }
+                    return Some(certificate);
+                }
+                Ok(info) if !needs_renewal(&info, names, now) => return Some(certificate),
+                Ok(_) => info!("Certificate expires soon or the names are changed"),
+                Err(e) => info!("Saved certificate failed: {}", e),
+            }
+        }
+        for _ in 0..MAX_KEY_TRIES {
+            match generate_certificate(&random_bytes::<KEY_SIZE>(), &random_bytes::<SERIAL_SIZE>(), names, now) {
+                Ok(certificate) => {
+                    info!("Self-signed certificate for {:?}", names);
+                    if let Err(e) = self.save(&certificate, false) {
+                        info!("Save certificate failed {:?}", e);
+                    }
+                    return Some(certificate);
+                }
+                Err(e) => info!("Certificate generation failed: {}", e),
+            }
+        }
+        None
+    }
+
+    // Checked certificate of the user, it is used after the restart
+    pub fn upload(&mut self, certificate: &DeviceCertificate) -> anyhow::Result<()> {
+        self.save(certificate, true)?;
+        info!("Certificate uploaded");
+        Ok(())
+    }
+
+    // A self-signed certificate is made at the next start of HTTPS
+    pub fn remove(&mut self) -> anyhow::Result<()> {
+        for name in [CERTIFICATE_KEY, PRIVATE_KEY_KEY, UPLOADED_KEY] {
+            self.nvs.remove(name)?;
+        }
+        info!("Certificate removed");
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+// REST API of the control web server (/api/v1)
+//
+// The request and the response bodies are the types of this module. They are read and written by serde with the
+// JSON types (booleans are true/false, numbers are numbers), and the unknown keys are errors. Every error is
+// {"error": {"code": "...", "message": "...", "fields": [{"field": "...", "message": "..."}]}}.
+// The types are declared by api_object!, which also makes their JSON schemas, and openapi_document() puts the
+// schemas and the endpoints together, so the OpenAPI description is the same as the types.
+// The settings of the configuration are optional in ApiConfig: a PATCH changes only the settings in the body.
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::config::ConfigData;
+use crate::schedule::CronSchedule;
+
+pub const API_PREFIX: &str = "/api/v1";
+pub const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+
+// Error codes
+pub const INVALID_REQUEST: &str = "invalid_request";    // not JSON, unknown key or a value of another type
+pub const INVALID_VALUE: &str = "invalid_value";        // the fields have the errors
+pub const UNAUTHORIZED: &str = "unauthorized";
+pub const FORBIDDEN: &str = "forbidden";
+pub const TOO_LARGE: &str = "too_large";
+pub const INTERNAL_ERROR: &str = "internal_error";
+
+// Keys of the configuration and the names of the API
+pub const CONFIG_API_FIELDS: [(&str, &str); 31] = [
+    ("resolution",          "resolution"),
+    ("trackid",             "trackId"),
+    ("duration",            "duration"),
+    ("timezone",            "timezone"),
+    ("idlesleep",           "idleSleep"),
+    ("autocapture",         "autoCapture"),
+    ("queryopenai",         "queryOpenai"),
+    ("queryprompt",         "queryPrompt"),
+    ("model",               "openaiModel"),
+    ("autofocusonce",       "autofocusOnce"),
+    ("statusreport",        "statusReport"),
+    ("statusreportinterval", "statusReportInterval"),
+    ("postinterval",        "postInterval"),
+    ("schedule",            "schedule"),
+    ("captureframesatonce", "captureFramesAtOnce"),
+    ("overwritesaved",      "overwriteSaved"),
+    ("directwritemode",     "directWriteMode"),
+    ("jpegquality",         "jpegQuality"),
+    ("segmentsize",         "segmentSize"),
+    ("segmentframes",       "segmentFrames"),
+    ("retentiondays",       "retentionDays"),
+    ("retentionframes",     "retentionFrames"),
+    ("thinafterdays",       "thinAfterDays"),
+    ("thininterval",        "thinInterval"),
+    ("latitude",            "latitude"),
+    ("longitude",           "longitude"),
+    ("sunschedule",         "sunSchedule"),
+    ("sunrisemargin",       "sunriseMargin"),
+    ("sunsetmargin",        "sunsetMargin"),
+    ("missedpolicy",        "missedPolicy"),
+    ("https",               "https"),
+];
+
+// JSON schema of a type of a field
+pub trait ApiType {
+    const REQUIRED: bool = true;
+
+    fn schema() -> Value;
+
+    // None is not written
+    fn is_absent(&self) -> bool {
+        false
+    }
+}
+
+// Schema of an object, it is in the components of the OpenAPI document
+pub trait ApiSchema {
+    fn name() -> &'static str;
+    fn definition() -> Value;
+}
+
+impl ApiType for bool {
+    fn schema() -> Value {
+        json!({"type": "boolean"})
+    }
+}
+
+impl ApiType for u32 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int64", "minimum": 0, "maximum": u32::MAX})
+    }
+}
+
+impl ApiType for u64 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int64", "minimum": 0})
+    }
+}
+
+impl ApiType for i32 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int32"})
+    }
+}
+
+impl ApiType for f64 {
+    fn schema() -> Value {
+        json!({"type": "number", "format": "double"})
+    }
+}
+
+impl ApiType for String {
+    fn schema() -> Value {
+        json!({"type": "string"})
+    }
+}
+
+impl<T: ApiType> ApiType for Option<T> {
+    const REQUIRED: bool = false;
+
+    fn schema() -> Value {
+        T::schema()
+    }
+
+    fn is_absent(&self) -> bool {
+        self.is_none()
+    }
+}
+
+impl<T: ApiType> ApiType for Vec<T> {
+    fn schema() -> Value {
+        json!({"type": "array", "items": T::schema()})
+    }
+}
+
+fn description(docs: &[&str]) -> String {
+    docs.iter().map(|line| line.trim()).collect::<Vec<&str>>().join(" ")
+}
+
+// Object of the API with its schema, the doc comments are the descriptions
+macro_rules! api_object {
+    ($(#[doc = $doc:literal])* $name:ident { $($(#[doc = $field_doc:literal])* $field:ident: $type:ty => $key:literal,)* }) => {
+        $(#[doc = $doc])*
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct $name {
+            $(
+                $(#[doc = $field_doc])*
+                #[serde(rename = $key, skip_serializing_if = "ApiType::is_absent")]
+                pub $field: $type,
+            )*
+        }
+
+        impl ApiSchema for $name {
+            fn name() -> &'static str {
+                stringify!($name)
+            }
+
+            fn definition() -> Value {
+                let mut properties = Map::new();
+                let mut required: Vec<&str> = Vec::new();
+                $(
+                    let mut schema = <$type as ApiType>::schema();
+                    let field_description = description(&[$($field_doc),*]);
+                    if !field_description.is_empty() {
+                        schema["description"] = Value::from(field_description);
+                    }
+                    properties.insert($key.to_string(), schema);
+                    if <$type as ApiType>::REQUIRED {
+                        required.push($key);
+                    }
+                )*
+                let mut schema = json!({"type": "object", "properties": properties, "additionalProperties": false});
+                if !required.is_empty() {
+                    schema["required"] = Value::from(required);
+                }
+                let object_description = description(&[$($doc),*]);
+                if !object_description.is_empty() {
+                    schema["description"] = Value::from(object_description);
+                }
+                schema
+            }
+        }
+
+        impl ApiType for $name {
+            fn schema() -> Value {
+                json!({"$ref": format!("#/components/schemas/{}", stringify!($name))})
+            }
+        }
+    };
+}
+
+// String values of an enum
+macro_rules! api_enum {
+    ($(#[doc = $doc:literal])* $name:ident { $($variant:ident => $value:literal,)* }) => {
+        $(#[doc = $doc])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        pub enum $name {
+            $(#[serde(rename = $value)] $variant,)*
+        }
+
+        impl ApiType for $name {
+            fn schema() -> Value {
+                json!({"type": "string", "enum": [$($value),*]})
+            }
+        }
+    };
+}
+
+api_enum! {
+    CaptureAction {
+        Start => "start",
+        Stop => "stop",
+    }
+}
+
+api_object! {
+    /// Start or stop the capture. The settings are used when the capture is started, the current settings are kept
+    /// when they are not given.
+    CaptureRequest {
+        action: CaptureAction => "action",
+        trackid: Option<u32> => "trackId",
+        /// Seconds between the frames, 0: the schedule
+        duration: Option<u32> => "duration",
+        /// Name of the resolution, e.g. VGA
+        resolution: Option<String> => "resolution",
+        /// Cron expression in the local time, used when the duration is 0
+        schedule: Option<String> => "schedule",
+        /// Local time, YYYY-MM-DDTHH:MM or YYYY-MM-DDTHH:MM:SS, default: now
+        capture_start_time: Option<String> => "captureStartTime",
+        /// Local time, default: the start time
+        capture_end_time: Option<String> => "captureEndTime",
+        /// Frames of a capture, -1: movie
+        capture_frames_at_once: Option<i32> => "captureFramesAtOnce",
+        overwrite_saved: Option<bool> => "overwriteSaved",
+    }
+}
+
+api_object! {
+    CaptureStatus {
+        capturing: bool => "capturing",
+    }
+}
+
+api_object! {
+    Resolution {
+        /// Name of the resolution, e.g. VGA
+        resolution: String => "resolution",
+    }
+}
+
+api_object! {
+    /// Monitoring by OpenAI
+    Monitor {
+        enabled: bool => "enabled",
+        /// Question to OpenAI, the current prompt when it is not given
+        prompt: Option<String> => "prompt",
+    }
+}
+
+api_object! {
+    /// State of the camera, the times are RFC 3339 in UTC and they are not given before the first time
+    State {
+        capturing: bool => "capturing",
+        /// dBm
+        rssi: i32 => "rssi",
+        /// V
+        battery_voltage: f64 => "batteryVoltage",
+        /// Celsius
+        temperature: f64 => "temperature",
+        capture_id: u32 => "captureId",
+        last_capture_time: Option<String> => "lastCaptureTime",
+        last_posted_time: Option<String> => "lastPostedTime",
+        capture_frames_at_once: i32 => "captureFramesAtOnce",
+        overwrite_saved: bool => "overwriteSaved",
+        missed_captures: u32 => "missedCaptures",
+        last_missed_time: Option<String> => "lastMissedTime",
+        retention_removed_frames: u32 => "retentionRemovedFrames",
+        retention_reclaimed_bytes: u64 => "retentionReclaimedBytes",
+        /// Bytes of the eMMC
+        storage_total: u64 => "storageTotal",
+        storage_free: u64 => "storageFree",
+    }
+}
+
+api_object! {
+    /// Settings of the camera without the secrets. A PATCH changes only the settings in the body, the response has
+    /// all of them.
+    ApiConfig {
+        /// Name of the resolution, e.g. VGA
+        resolution: Option<String> => "resolution",
+        track_id: Option<u32> => "trackId",
+        /// Seconds between the frames, 0: the schedule
+        duration: Option<u32> => "duration",
+        /// IANA name, POSIX TZ string or offset in hours
+        timezone: Option<String> => "timezone",
+        /// Seconds
+        idle_sleep: Option<u32> => "idleSleep",
+        auto_capture: Option<bool> => "autoCapture",
+        query_openai: Option<bool> => "queryOpenai",
+        query_prompt: Option<String> => "queryPrompt",
+        openai_model: Option<String> => "openaiModel",
+        autofocus_once: Option<bool> => "autofocusOnce",
+        status_report: Option<bool> => "statusReport",
+        status_report_interval: Option<u32> => "statusReportInterval",
+        post_interval: Option<u32> => "postInterval",
+        /// Cron expression in the local time
+        schedule: Option<String> => "schedule",
+        capture_frames_at_once: Option<i32> => "captureFramesAtOnce",
+        overwrite_saved: Option<bool> => "overwriteSaved",
+        direct_write_mode: Option<bool> => "directWriteMode",
+        jpeg_quality: Option<u32> => "jpegQuality",
+        /// MB, 0: up to the file size limit of FAT
+        segment_size: Option<u32> => "segmentSize",
+        segment_frames: Option<u32> => "segmentFrames",
+        retention_days: Option<u32> => "retentionDays",
+        retention_frames: Option<u32> => "retentionFrames",
+        thin_after_days: Option<u32> => "thinAfterDays",
+        /// Minutes
+        thin_interval: Option<u32> => "thinInterval",
+        latitude: Option<f64> => "latitude",
+        longitude: Option<f64> => "longitude",
+        /// off, daylight or golden
+        sun_schedule: Option<String> => "sunSchedule",
+        /// Minutes
+        sunrise_margin: Option<i32> => "sunriseMargin",
+        sunset_margin: Option<i32> => "sunsetMargin",
+        /// align, skip or catchup
+        missed_policy: Option<String> => "missedPolicy",
+        /// Applied after the restart
+        https: Option<bool> => "https",
+    }
+}
+
+api_object! {
+    FieldError {
+        field: String => "field",
+        message: String => "message",
+    }
+}
+
+api_object! {
+    ErrorBody {
+        code: String => "code",
+        message: String => "message",
+        fields: Vec<FieldError> => "fields",
+    }
+}
+
+api_object! {
+    /// Error of every request
+    ApiError {
+        error: ErrorBody => "error",
+    }
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> FieldError {
+        FieldError { field: field.to_string(), message: message.to_string() }
+    }
+}
+
+impl ApiError {
+    pub fn new(code: &str, message: &str) -> ApiError {
+        ApiError::with_fields(code, message, Vec::new())
+    }
+
+    pub fn with_fields(code: &str, message: &str, fields: Vec<FieldError>) -> ApiError {
+        ApiError { error: ErrorBody { code: code.to_string(), message: message.to_string(), fields } }
+    }
+
+    // Errors of the values
+    pub fn invalid_values(fields: Vec<FieldError>) -> ApiError {
+        ApiError::with_fields(INVALID_VALUE, "Invalid values", fields)
+    }
+
+    // HTTP status of the error
+    pub fn status(&self) -> u16 {
+        match self.error.code.as_str() {
+            INVALID_REQUEST | INVALID_VALUE => 400,
+            UNAUTHORIZED => 401,
+            FORBIDDEN => 403,
+            TOO_LARGE => 413,
+            _ => 500,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json(self)
+    }
+}
+
+pub fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+// Body of a request, a body which is not the type is an invalid_request error
+pub fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| ApiError::new(INVALID_REQUEST, &e.to_string()))
+}
+
+// Time of the state, None before the first time
+pub fn api_time(seconds: i64) -> Option<String> {
+    if seconds <= 0 {
+        return None;
+    }
+    DateTime::<Utc>::from_timestamp(seconds, 0).map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// Local time of the capture request
+pub fn parse_local_time(text: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S"))
+        .ok()
+}
+
+// Name of the API of a key of the configuration
+pub fn api_field(key: &str) -> &str {
+    CONFIG_API_FIELDS.iter().find(|(config_key, _)| *config_key == key).map_or(key, |(_, name)| *name)
+}
+
+fn resolution_value(resolutions: &[(&str, u32)], name: &str) -> Option<u32> {
+    resolutions.iter().find(|(resolution, _)| *resolution == name).map(|(_, value)| *value)
+}
+
+impl CaptureRequest {
+    // Values of a start request, the resolutions are the names and the values of the camera
+    pub fn validate(&self, resolutions: &[(&str, u32)]) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.action == CaptureAction::Stop {
+            return errors;
+        }
+        if let Some(resolution) = &self.resolution {
+            if resolution_value(resolutions, resolution).is_none() {
+                errors.push(FieldError::new("resolution", "Unknown resolution"));
+            }
+        }
+        if let Some(schedule) = self.schedule.as_deref().filter(|schedule| !schedule.trim().is_empty()) {
+            if self.duration.unwrap_or(0) == 0 {
+                if let Err(e) = CronSchedule::parse(schedule) {
+                    errors.push(FieldError::new("schedule", &e.to_string()));
+                }
+            }
+        }
+        for (field, time) in [("captureStartTime", &self.capture_start_time), ("captureEndTime", &self.capture_end_time)] {
+            if time.as_deref().is_some_and(|time| parse_local_time(time).is_none()) {
+                errors.push(FieldError::new(field, "Must be YYYY-MM-DDTHH:MM or YYYY-MM-DDTHH:MM:SS"));
+            }
+        }
+        if self.capture_frames_at_once.is_some_and(|frames| frames < -1) {
+            errors.push(FieldError::new("captureFramesAtOnce", "Must be -1 (movie) or more"));
+        }
+        errors
+    }
+}
+
+impl ApiConfig {
+    pub fn from_config(config: &ConfigData, resolutions: &[(&str, u32)]) -> ApiConfig {
+        let resolution = resolutions.iter().find(|(_, value)| *value == config.resolution).map(|(name, _)| name.to_string());
+        ApiConfig {
+            resolution,
+            track_id: Some(config.track_id),
+            duration: Some(config.duration),
+            timezone: Some(config.timezone.clone()),
+            idle_sleep: Some(config.idle_in_sleep_time),
+            auto_capture: Some(config.auto_capture),
+            query_openai: Some(config.query_openai),
+            query_prompt: Some(config.query_prompt.clone()),
+            openai_model: Some(config.model.clone()),
+            autofocus_once: Some(config.autofocus_once),
+            status_report: Some(config.status_report),
+            status_report_interval: Some(config.status_report_interval),
+            post_interval: Some(config.post_interval),
+            schedule: Some(config.schedule.clone()),
+            capture_frames_at_once: Some(config.capture_frames_at_once),
+            overwrite_saved: Some(config.overwrite_saved),
+            direct_write_mode: Some(config.direct_write_mode),
+            jpeg_quality: Some(config.jpeg_quality),
+            segment_size: Some(config.segment_size),
+            segment_frames: Some(config.segment_frames),
+            retention_days: Some(config.retention_days),
+            retention_frames: Some(config.retention_frames),
+            thin_after_days: Some(config.thin_after_days),
+            thin_interval: Some(config.thin_interval),
+            latitude: Some(config.latitude),
+            longitude: Some(config.longitude),
+            sun_schedule: Some(config.sun_schedule.clone()),
+            sunrise_margin: Some(config.sunrise_margin),
+            sunset_margin: Some(config.sunset_margin),
+            missed_policy: Some(config.missed_policy.clone()),
+            https: Some(config.https),
+        }
+    }
+
+    // The settings in the body to the configuration, the errors have the names of the API. The configuration must
+    // not be used when there are errors.
+    pub fn apply(&self, config: &mut ConfigData, resolutions: &[(&str, u32)]) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(resolution) = &self.resolution {
+            match resolution_value(resolutions, resolution) {
+                Some(value) => config.resolution = value,
+                None => errors.push(FieldError::new("resolution", "Unknown resolution")),
+            }
+        }
+        fn set<T: Clone>(value: &Option<T>, target: &mut T) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        set(&self.track_id, &mut config.track_id);
+        set(&self.duration, &mut config.duration);
+        set(&self.timezone, &mut config.timezone);
+        set(&self.idle_sleep, &mut config.idle_in_sleep_time);
+        set(&self.auto_capture, &mut config.auto_capture);
+        set(&self.query_openai, &mut config.query_openai);
+        set(&self.query_prompt, &mut config.query_prompt);
+        set(&self.openai_model, &mut config.model);
+        set(&self.autofocus_once, &mut config.autofocus_once);
+        set(&self.status_report, &mut config.status_report);
+        set(&self.status_report_interval, &mut config.status_report_interval);
+        set(&self.post_interval, &mut config.post_interval);
+        set(&self.schedule, &mut config.schedule);
+        set(&self.capture_frames_at_once, &mut config.capture_frames_at_once);
+        set(&self.overwrite_saved, &mut config.overwrite_saved);
+        set(&self.direct_write_mode, &mut config.direct_write_mode);
+        set(&self.jpeg_quality, &mut config.jpeg_quality);
+        set(&self.segment_size, &mut config.segment_size);
+        set(&self.segment_frames, &mut config.segment_frames);
+        set(&self.retention_days, &mut config.retention_days);
+        set(&self.retention_frames, &mut config.retention_frames);
+        set(&self.thin_after_days, &mut config.thin_after_days);
+        set(&self.thin_interval, &mut config.thin_interval);
+        set(&self.latitude, &mut config.latitude);
+        set(&self.longitude, &mut config.longitude);
+        set(&self.sun_schedule, &mut config.sun_schedule);
+        set(&self.sunrise_margin, &mut config.sunrise_margin);
+        set(&self.sunset_margin, &mut config.sunset_margin);
+        set(&self.missed_policy, &mut config.missed_policy);
+        set(&self.https, &mut config.https);
+        errors.extend(config.validate().iter().map(|error| FieldError::new(api_field(&error.field), &error.message)));
+        errors
+    }
+}
+
+// Operation of the OpenAPI document, the viewer can use GET
+fn operation(summary: &str, request: Option<Value>, response: Value, admin: bool) -> Value {
+    let error = json!({"description": "Error", "content": {"application/json": {"schema": schema_of::<ApiError>()}}});
+    let mut responses = json!({
+        "200": {"description": "OK", "content": {"application/json": {"schema": response}}},
+        "401": error.clone(),
+        "403": error.clone(),
+    });
+    let mut operation = json!({"summary": summary});
+    if let Some(request) = request {
+        operation["requestBody"] = json!({"required": true, "content": {"application/json": {"schema": request}}});
+        responses["400"] = error.clone();
+    }
+    if admin {
+        operation["description"] = Value::from("Needs the admin role");
+    }
+    operation["responses"] = responses;
+    operation
+}
+
+fn schema_of<T: ApiType>() -> Value {
+    T::schema()
+}
+
+// OpenAPI 3.0 description of /api/v1, the version is the version of the firmware
+pub fn openapi_document(version: &str) -> Value {
+    let mut schemas = Map::new();
+    for (name, schema) in [
+        (CaptureRequest::name(), CaptureRequest::definition()),
+        (CaptureStatus::name(), CaptureStatus::definition()),
+        (Resolution::name(), Resolution::definition()),
+        (Monitor::name(), Monitor::definition()),
+        (State::name(), State::definition()),
+        (ApiConfig::name(), ApiConfig::definition()),
+        (FieldError::name(), FieldError::definition()),
+        (ErrorBody::name(), ErrorBody::definition()),
+        (ApiError::name(), ApiError::definition()),
+    ] {
+        schemas.insert(name.to_string(), schema);
+    }
+    let path = |name: &str| format!("{}{}", API_PREFIX, name);
+    let mut paths = Map::new();
+    paths.insert(path("/state"), json!({
+        "get": operation("State of the camera", None, schema_of::<State>(), false),
+    }));
+    paths.insert(path("/capture"), json!({
+        "get": operation("Capture status", None, schema_of::<CaptureStatus>(), false),
+        "post": operation("Start or stop the capture", Some(schema_of::<CaptureRequest>()), schema_of::<CaptureStatus>(), true),
+    }));
+    paths.insert(path("/resolution"), json!({
+        "get": operation("Resolution of the camera", None, schema_of::<Resolution>(), false),
+        "put": operation("Set the resolution", Some(schema_of::<Resolution>()), schema_of::<Resolution>(), true),
+    }));
+    paths.insert(path("/monitor"), json!({
+        "get": operation("Monitoring by OpenAI", None, schema_of::<Monitor>(), false),
+        "put": operation("Start or stop the monitoring", Some(schema_of::<Monitor>()), schema_of::<Monitor>(), true),
+    }));
+    paths.insert(path("/config"), json!({
+        "get": operation("Settings of the camera", None, schema_of::<ApiConfig>(), false),
+        "patch": operation("Change the settings in the body", Some(schema_of::<ApiConfig>()), schema_of::<ApiConfig>(), true),
+    }));
+    paths.insert(OPENAPI_PATH.to_string(), json!({
+        "get": {"summary": "This document", "security": [], "responses": {"200": {"description": "OpenAPI document"}}},
+    }));
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "Time Leap Cam API", "version": version},
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer", "description": "Token of POST /login"},
+                "session": {"type": "apiKey", "in": "cookie", "name": crate::auth::SESSION_COOKIE},
+            },
+        },
+        "security": [{"bearer": []}, {"session": []}],
+    })
+}
@@ -0,0 +1,384 @@
+// Uncompressed ZIP and TAR (ustar) writers of the frames
+//
+// Like the AVI writer, the archive is written front to back without seeking, so it can be streamed to
+// a HTTP response one frame at a time. The size of the archive is known from the record headers
+// before writing (for Content-Length).
+//
+// ZIP: local header + JPEG for each frame, then the central directory. The CRC32 of the frame is
+//      calculated before its local header is written, so no data descriptor is needed.
+//      ZIP64 records are used when the archive exceeds 4GB or 65535 frames.
+// TAR: 512 bytes ustar header + JPEG padded to 512 bytes for each frame, then two zero blocks.
+
+use anyhow;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::io::Write;
+
+use crate::imagefiles::ImageFiles;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    // "zip" or "tar"
+    pub fn from_name(name: &str) -> Option<ArchiveFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar" => Some(ArchiveFormat::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+const ZIP_CENTRAL_HEADER_SIZE: u64 = 46;
+const ZIP_END_SIZE: u64 = 22;
+const ZIP64_END_SIZE: u64 = 56;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+// extended timestamp (0x5455) with the modification time
+const ZIP_TIMESTAMP_EXTRA_SIZE: u64 = 9;
+// ZIP64 extended information (0x0001): sizes in the local header, sizes and offset in the central header
+const ZIP64_LOCAL_EXTRA_SIZE: u64 = 20;
+const ZIP64_CENTRAL_EXTRA_SIZE: u64 = 28;
+const ZIP_VERSION: u16 = 10;
+const ZIP64_VERSION: u16 = 45;
+// made by Unix, the files are rw-r--r--
+const ZIP_MADE_BY_UNIX: u16 = 3 << 8;
+const ZIP_FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+// The plan keeps the size, the capture time and the CRC32 of each frame (about 20 bytes) until the end of the archive
+pub const MAX_ARCHIVE_FRAMES: u32 = 10000;
+
+// A frame in the archive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArchiveEntry {
+    pub size: u32,
+    pub capture_time: i64,      // UTC ms, 0: unknown
+}
+
+// Frames of the capture file to be put in an archive
+pub struct ArchivePlan {
+    pub format: ArchiveFormat,
+    pub track_id: u32,
+    pub from: u32,
+    pub entries: Vec<ArchiveEntry>,
+    pub zip64: bool,
+}
+
+fn capture_date_time(capture_time: i64) -> Option<DateTime<Utc>> {
+    if capture_time <= 0 {
+        return None;
+    }
+    DateTime::<Utc>::from_timestamp_millis(capture_time)
+}
+
+// T3_000012_20240101T000000.000Z.jpg (T3_000012.jpg without the capture time)
+pub fn entry_name(track_id: u32, frame: u32, capture_time: i64) -> String {
+    match capture_date_time(capture_time) {
+        Some(time) => format!("T{}_{:06}_{}.jpg", track_id, frame, time.format("%Y%m%dT%H%M%S%.3fZ")),
+        None => format!("T{}_{:06}.jpg", track_id, frame),
+    }
+}
+
+// modification time in seconds, 0 if unknown
+fn entry_mtime(capture_time: i64) -> u32 {
+    match capture_date_time(capture_time) {
+        Some(time) => time.timestamp().clamp(0, u32::MAX as i64) as u32,
+        None => 0,
+    }
+}
+
+// MS-DOS time and date (UTC), 1980-01-01 00:00:00 if unknown
+fn dos_date_time(capture_time: i64) -> (u16, u16) {
+    match capture_date_time(capture_time).filter(|t| (1980..2108).contains(&t.year())) {
+        Some(t) => (
+            ((t.hour() << 11) | (t.minute() << 5) | (t.second() / 2)) as u16,
+            ((((t.year() - 1980) as u32) << 9) | (t.month() << 5) | t.day()) as u16,
+        ),
+        None => (0, (1 << 5) | 1),
+    }
+}
+
+fn zip_timestamp_extra_size(capture_time: i64) -> u64 {
+    if entry_mtime(capture_time) > 0 { ZIP_TIMESTAMP_EXTRA_SIZE } else { 0 }
+}
+
+fn tar_padding(size: u64) -> u64 {
+    (TAR_BLOCK_SIZE - size % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE
+}
+
+impl ArchivePlan {
+    pub fn entry_name(&self, index: usize) -> String {
+        entry_name(self.track_id, self.from + index as u32, self.entries[index].capture_time)
+    }
+
+    // local headers and the frames (offset of the central directory)
+    fn zip_data_size(&self, zip64: bool) -> u64 {
+        (0..self.entries.len()).map(|n| {
+            let entry = &self.entries[n];
+            ZIP_LOCAL_HEADER_SIZE + self.entry_name(n).len() as u64 + zip_timestamp_extra_size(entry.capture_time)
+                + if zip64 { ZIP64_LOCAL_EXTRA_SIZE } else { 0 }
+                + entry.size as u64
+        }).sum()
+    }
+
+    fn zip_central_size(&self, zip64: bool) -> u64 {
+        (0..self.entries.len()).map(|n| {
+            ZIP_CENTRAL_HEADER_SIZE + self.entry_name(n).len() as u64 + zip_timestamp_extra_size(self.entries[n].capture_time)
+                + if zip64 { ZIP64_CENTRAL_EXTRA_SIZE } else { 0 }
+        }).sum()
+    }
+
+    fn zip_size(&self, zip64: bool) -> u64 {
+        self.zip_data_size(zip64) + self.zip_central_size(zip64) + ZIP_END_SIZE
+            + if zip64 { ZIP64_END_SIZE + ZIP64_LOCATOR_SIZE } else { 0 }
+    }
+
+    // Size of the archive (for Content-Length)
+    pub fn file_size(&self) -> u64 {
+        match self.format {
+            ArchiveFormat::Zip => self.zip_size(self.zip64),
+            ArchiveFormat::Tar => {
+                self.entries.iter()
+                    .map(|e| TAR_BLOCK_SIZE + e.size as u64 + tar_padding(e.size as u64))
+                    .sum::<u64>() + 2 * TAR_BLOCK_SIZE
+            }
+        }
+    }
+}
+
+// Number of the frames from..=to (inclusive) in a track of nimages frames
+pub fn archive_frames(nimages: u32, from: u32, to: u32) -> u32 {
+    if nimages == 0 || from > to || from >= nimages {
+        return 0;
+    }
+    to.min(nimages - 1) - from + 1
+}
+
+// Walk the record headers from..=to (inclusive) and prepare the archive
+pub fn plan_archive(images: &mut ImageFiles, format: ArchiveFormat, track_id: u32, from: u32, to: u32) -> Result<ArchivePlan, anyhow::Error> {
+    let frames = archive_frames(images.get_nof_images(), from, to);
+    if frames == 0 {
+        return Err(anyhow::Error::msg("No frames in the range"));
+    }
+    if frames > MAX_ARCHIVE_FRAMES {
+        return Err(anyhow::anyhow!("Too many frames in the range, {} frames at most", MAX_ARCHIVE_FRAMES));
+    }
+    let to = from + frames - 1;
+    images.seek_image(from)?;
+    let mut entries = Vec::with_capacity(frames as usize);
+    for _ in from..=to {
+        let capture_time = images.get_frame_info()?.capture_time;
+        let size = images.get_image_size();
+        if size == 0 {
+            return Err(anyhow::Error::msg("Failed to get image size"));
+        }
+        entries.push(ArchiveEntry { size: size as u32, capture_time });
+        images.skip_image()?;
+    }
+    let mut plan = ArchivePlan {
+        format,
+        track_id,
+        from,
+        entries,
+        zip64: false,
+    };
+    if format == ArchiveFormat::Zip {
+        plan.zip64 = plan.entries.len() >= u16::MAX as usize || plan.zip_size(false) >= u32::MAX as u64;
+    }
+    Ok(plan)
+}
+
+// Stream the planned frames as an archive
+pub fn write_archive<W: Write>(images: &mut ImageFiles, plan: &ArchivePlan, mut out: W) -> Result<W, anyhow::Error> {
+    images.seek_image(plan.from)?;
+    let mut crcs = Vec::with_capacity(plan.entries.len());
+    for (n, entry) in plan.entries.iter().enumerate() {
+        let image = images.read_image()?;
+        if image.len() != entry.size as usize {
+            return Err(anyhow::Error::msg("Frame size does not match"));
+        }
+        let name = plan.entry_name(n);
+        match plan.format {
+            ArchiveFormat::Zip => {
+                let crc = crc32fast::hash(&image);
+                out.write_all(&zip_local_header(&name, entry, crc, plan.zip64))?;
+                out.write_all(&image)?;
+                crcs.push(crc);
+            }
+            ArchiveFormat::Tar => {
+                out.write_all(&tar_header(&name, entry)?)?;
+                out.write_all(&image)?;
+                out.write_all(&[0; TAR_BLOCK_SIZE as usize][..tar_padding(image.len() as u64) as usize])?;
+            }
+        }
+    }
+    match plan.format {
+        ArchiveFormat::Zip => write_zip_central_directory(plan, &crcs, &mut out)?,
+        ArchiveFormat::Tar => out.write_all(&[0; 2 * TAR_BLOCK_SIZE as usize])?,
+    }
+    out.flush()?;
+    Ok(out)
+}
+
+fn zip_timestamp_extra(buffer: &mut Vec<u8>, capture_time: i64) {
+    let mtime = entry_mtime(capture_time);
+    if mtime > 0 {
+        buffer.extend_from_slice(&0x5455u16.to_le_bytes());
+        buffer.extend_from_slice(&5u16.to_le_bytes());
+        buffer.push(0x01);                              // modification time
+        buffer.extend_from_slice(&mtime.to_le_bytes());
+    }
+}
+
+fn zip_local_header(name: &str, entry: &ArchiveEntry, crc: u32, zip64: bool) -> Vec<u8> {
+    let (time, date) = dos_date_time(entry.capture_time);
+    let size = if zip64 { u32::MAX } else { entry.size };
+    let extra_size = zip_timestamp_extra_size(entry.capture_time) + if zip64 { ZIP64_LOCAL_EXTRA_SIZE } else { 0 };
+    let mut header = Vec::with_capacity((ZIP_LOCAL_HEADER_SIZE + extra_size) as usize + name.len());
+    header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+    header.extend_from_slice(&(if zip64 { ZIP64_VERSION } else { ZIP_VERSION }).to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());      // flags
+    header.extend_from_slice(&0u16.to_le_bytes());      // stored
+    header.extend_from_slice(&time.to_le_bytes());
+    header.extend_from_slice(&date.to_le_bytes());
+    header.extend_from_slice(&crc.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());      // compressed size
+    header.extend_from_slice(&size.to_le_bytes());      // uncompressed size
+    header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(extra_size as u16).to_le_bytes());
+    header.extend_from_slice(name.as_bytes());
+    zip_timestamp_extra(&mut header, entry.capture_time);
+    if zip64 {
+        header.extend_from_slice(&0x0001u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(&(entry.size as u64).to_le_bytes());
+        header.extend_from_slice(&(entry.size as u64).to_le_bytes());
+    }
+    header
+}
+
+fn write_zip_central_directory<W: Write>(plan: &ArchivePlan, crcs: &[u32], out: &mut W) -> Result<(), anyhow::Error> {
+    let zip64 = plan.zip64;
+    let version = if zip64 { ZIP64_VERSION } else { ZIP_VERSION };
+    let mut offset: u64 = 0;
+    for (n, entry) in plan.entries.iter().enumerate() {
+        let name = plan.entry_name(n);
+        let (time, date) = dos_date_time(entry.capture_time);
+        let size = if zip64 { u32::MAX } else { entry.size };
+        let extra_size = zip_timestamp_extra_size(entry.capture_time) + if zip64 { ZIP64_CENTRAL_EXTRA_SIZE } else { 0 };
+        let mut header = Vec::with_capacity((ZIP_CENTRAL_HEADER_SIZE + extra_size) as usize + name.len());
+        header.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        header.extend_from_slice(&(ZIP_MADE_BY_UNIX | version).to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());  // flags
+        header.extend_from_slice(&0u16.to_le_bytes());  // stored
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&crcs[n].to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra_size as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());  // comment
+        header.extend_from_slice(&0u16.to_le_bytes());  // disk
+        header.extend_from_slice(&0u16.to_le_bytes());  // internal attributes
+        header.extend_from_slice(&ZIP_FILE_ATTRIBUTES.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { u32::MAX } else { offset as u32 }).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        zip_timestamp_extra(&mut header, entry.capture_time);
+        if zip64 {
+            header.extend_from_slice(&0x0001u16.to_le_bytes());
+            header.extend_from_slice(&24u16.to_le_bytes());
+            header.extend_from_slice(&(entry.size as u64).to_le_bytes());
+            header.extend_from_slice(&(entry.size as u64).to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+        }
+        out.write_all(&header)?;
+        offset += ZIP_LOCAL_HEADER_SIZE + name.len() as u64 + zip_timestamp_extra_size(entry.capture_time)
+            + if zip64 { ZIP64_LOCAL_EXTRA_SIZE } else { 0 } + entry.size as u64;
+    }
+    let entries = plan.entries.len() as u64;
+    let central_offset = offset;
+    let central_size = plan.zip_central_size(zip64);
+    let mut end = Vec::with_capacity((ZIP64_END_SIZE + ZIP64_LOCATOR_SIZE + ZIP_END_SIZE) as usize);
+    if zip64 {
+        end.extend_from_slice(&0x06064b50u32.to_le_bytes());
+        end.extend_from_slice(&(ZIP64_END_SIZE - 12).to_le_bytes());
+        end.extend_from_slice(&(ZIP_MADE_BY_UNIX | ZIP64_VERSION).to_le_bytes());
+        end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());     // disk
+        end.extend_from_slice(&0u32.to_le_bytes());     // disk of the central directory
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&central_size.to_le_bytes());
+        end.extend_from_slice(&central_offset.to_le_bytes());
+        // locator
+        end.extend_from_slice(&0x07064b50u32.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());
+        end.extend_from_slice(&(central_offset + central_size).to_le_bytes());
+        end.extend_from_slice(&1u32.to_le_bytes());
+    }
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    let entries16 = if zip64 { u16::MAX } else { entries as u16 };
+    end.extend_from_slice(&entries16.to_le_bytes());
+    end.extend_from_slice(&entries16.to_le_bytes());
+    end.extend_from_slice(&(if zip64 { u32::MAX } else { central_size as u32 }).to_le_bytes());
+    end.extend_from_slice(&(if zip64 { u32::MAX } else { central_offset as u32 }).to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());         // comment
+    out.write_all(&end)?;
+    Ok(())
+}
+
+// octal number with the terminating NUL in the field
+fn tar_octal(field: &mut [u8], value: u64) -> Result<(), anyhow::Error> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() != field.len() - 1 {
+        return Err(anyhow::Error::msg("Value too large for the TAR header"));
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+fn tar_header(name: &str, entry: &ArchiveEntry) -> Result<[u8; TAR_BLOCK_SIZE as usize], anyhow::Error> {
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+    if name.len() > 100 {
+        return Err(anyhow::Error::msg("File name too long for the TAR header"));
+    }
+    header[0..name.len()].copy_from_slice(name.as_bytes());
+    tar_octal(&mut header[100..108], 0o644)?;               // mode
+    tar_octal(&mut header[108..116], 0)?;                   // uid
+    tar_octal(&mut header[116..124], 0)?;                   // gid
+    tar_octal(&mut header[124..136], entry.size as u64)?;
+    tar_octal(&mut header[136..148], entry_mtime(entry.capture_time) as u64)?;
+    header[156] = b'0';                                     // regular file
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // checksum with the checksum field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    tar_octal(&mut header[148..155], checksum as u64)?;
+    header[155] = b' ';
+    Ok(header)
+}
@@ -0,0 +1,263 @@
+// Authentication of the control web server
+//
+// The admin and the viewer passwords are stored as PBKDF2-HMAC-SHA256 hashes with a random salt
+// ("pbkdf2-sha256$iterations$salt$hash", hex). A login creates a session with a random token, the token is sent back
+// as a cookie for the pages and it is also accepted as a bearer token (Authorization: Bearer ...) for the tools.
+// The viewer can use the read only requests (GET), the other requests and the configuration export need the admin.
+// Without an admin password every client is a viewer. The initial admin password comes from cfg.toml, or it is set by
+// POST /auth/password without a login in the setup window after a power on or a reset of the camera (SETUP_SECONDS),
+// so it needs someone who has the camera. A wakeup from the deep sleep does not open the window.
+// The logins are limited for each client address: after MAX_FREE_FAILURES failed logins, the login of the client is
+// locked for a time which doubles with every failure, up to MAX_LOCK_SECONDS. The failures of MAX_CLIENTS clients
+// are kept.
+
+use anyhow;
+use std::net::IpAddr;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const PASSWORD_ITERATIONS: u32 = 10000;
+pub const SALT_SIZE: usize = 16;
+pub const TOKEN_SIZE: usize = 32;
+pub const SESSION_SECONDS: u64 = 24 * 60 * 60;
+pub const SESSION_COOKIE: &str = "tlcsession";
+const MAX_SESSIONS: usize = 8;
+const MAX_FREE_FAILURES: u32 = 3;
+const MAX_LOCK_SECONDS: u64 = 300;
+const MAX_CLIENTS: usize = 16;
+pub const SETUP_SECONDS: u64 = 600;
+pub const SETUP_PATH: &str = "/auth/password";
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Viewer < Admin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Role> {
+        match text {
+            "viewer" => Some(Role::Viewer),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+fn pbkdf2(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let prf = HmacSha256::new_from_slice(password.as_bytes()).expect("HMAC can take key of any size");
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut result = block;
+    for _ in 1..iterations {
+        let mut mac = prf.clone();
+        mac.update(&block);
+        block = mac.finalize().into_bytes().into();
+        result.iter_mut().zip(block.iter()).for_each(|(r, b)| *r ^= b);
+    }
+    result
+}
+
+pub fn hash_password(password: &str, salt: &[u8; SALT_SIZE], iterations: u32) -> String {
+    format!("{}${}${}${}", HASH_SCHEME, iterations, hex::encode(salt), hex::encode(pbkdf2(password, salt, iterations)))
+}
+
+// iterations, salt and hash of a stored hash
+fn parse_hash(stored: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut parts = stored.split('$');
+    if parts.next() != Some(HASH_SCHEME) {
+        return None;
+    }
+    let iterations = parts.next()?.parse().ok().filter(|iterations| *iterations > 0)?;
+    let salt = hex::decode(parts.next()?).ok()?;
+    let hash = hex::decode(parts.next()?).ok().filter(|hash| hash.len() == 32)?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((iterations, salt, hash))
+}
+
+pub fn is_password_hash(stored: &str) -> bool {
+    parse_hash(stored).is_some()
+}
+
+// Compared in constant time, only the length tells when they differ
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    match parse_hash(stored) {
+        Some((iterations, salt, hash)) => same_bytes(&pbkdf2(password, &salt, iterations), &hash),
+        None => false,
+    }
+}
+
+pub fn check_password(password: &str) -> Result<(), anyhow::Error> {
+    if password.len() < MIN_PASSWORD_LEN {
+        return Err(anyhow::anyhow!("Password must be {} characters or more", MIN_PASSWORD_LEN));
+    }
+    Ok(())
+}
+
+// Role required by a request, None for the login
+pub fn required_role(method: &str, path: &str) -> Option<Role> {
+    match (method, path) {
+        (_, "/login") | (_, "/logout") | ("GET", "/login.html") | ("GET", "/api/v1/openapi.json") => None,
+        ("GET", "/config/export") => Some(Role::Admin),
+        ("GET", _) | ("HEAD", _) => Some(Role::Viewer),
+        _ => Some(Role::Admin),
+    }
+}
+
+// Setup window of a boot by a power on or a reset, not by a wakeup or a restart of the firmware
+pub fn in_setup_window(reset_boot: bool, uptime: u64) -> bool {
+    reset_boot && uptime < SETUP_SECONDS
+}
+
+// Role of a client without an admin password, the admin only sets the password in the setup window
+pub fn unprotected_role(path: &str, setup: bool) -> Role {
+    if path == SETUP_PATH && setup {
+        Role::Admin
+    } else {
+        Role::Viewer
+    }
+}
+
+// Pages are redirected to the login page, the other requests are 401
+pub fn is_page(path: &str) -> bool {
+    path == "/" || path.ends_with(".html")
+}
+
+// Token of the Authorization header or the session cookie
+pub fn request_token<'a>(authorization: Option<&'a str>, cookie: Option<&'a str>) -> Option<&'a str> {
+    if let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(token.trim());
+    }
+    cookie?.split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token)
+}
+
+// Secure when the server uses HTTPS, so the browser does not send the cookie over HTTP
+pub fn session_cookie(token: &str, secure: bool) -> String {
+    format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, token, SESSION_SECONDS,
+        if secure { "; Secure" } else { "" })
+}
+
+pub fn expired_cookie(secure: bool) -> String {
+    format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, if secure { "; Secure" } else { "" })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    Locked(u64),        // seconds to wait
+    Invalid,
+}
+
+struct Session {
+    token: String,
+    role: Role,
+    expires: u64,
+}
+
+// failed logins of a client address
+struct Failures {
+    client: IpAddr,
+    count: u32,
+    locked_until: u64,
+    last: u64,
+}
+
+#[derive(Default)]
+pub struct Auth {
+    sessions: Vec<Session>,
+    failures: Vec<Failures>,
+}
+
+impl Auth {
+    pub fn new() -> Auth {
+        Auth::default()
+    }
+
+    // The token is random, the role is the one of the password. The admin password is checked first.
+    pub fn login(&mut self, password: &str, admin_hash: &str, viewer_hash: &str, token: &[u8; TOKEN_SIZE], client: IpAddr,
+        now: u64) -> Result<(String, Role), LoginError> {
+        if let Some(failures) = self.failures.iter().find(|failures| failures.client == client) {
+            if now < failures.locked_until {
+                return Err(LoginError::Locked(failures.locked_until - now));
+            }
+        }
+        let role = if verify_password(password, admin_hash) {
+            Role::Admin
+        } else if verify_password(password, viewer_hash) {
+            Role::Viewer
+        } else {
+            self.failed(client, now);
+            return Err(LoginError::Invalid);
+        };
+        self.failures.retain(|failures| failures.client != client);
+        self.sessions.retain(|session| session.expires > now);
+        // the oldest session is closed
+        if self.sessions.len() >= MAX_SESSIONS {
+            self.sessions.remove(0);
+        }
+        let token = hex::encode(token);
+        self.sessions.push(Session { token: token.clone(), role, expires: now + SESSION_SECONDS });
+        Ok((token, role))
+    }
+
+    fn failed(&mut self, client: IpAddr, now: u64) {
+        let index = match self.failures.iter().position(|failures| failures.client == client) {
+            Some(index) => index,
+            None => {
+                // the client of the oldest failure is forgotten
+                if self.failures.len() >= MAX_CLIENTS {
+                    let oldest = self.failures.iter().enumerate().min_by_key(|(_, failures)| failures.last).map(|(index, _)| index);
+                    if let Some(oldest) = oldest {
+                        self.failures.remove(oldest);
+                    }
+                }
+                self.failures.push(Failures { client, count: 0, locked_until: 0, last: now });
+                self.failures.len() - 1
+            }
+        };
+        let failures = &mut self.failures[index];
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= MAX_FREE_FAILURES {
+            let lock = 1u64.checked_shl(failures.count - MAX_FREE_FAILURES).unwrap_or(u64::MAX).min(MAX_LOCK_SECONDS);
+            failures.locked_until = now + lock;
+        }
+    }
+
+    pub fn role(&mut self, token: Option<&str>, now: u64) -> Option<Role> {
+        self.sessions.retain(|session| session.expires > now);
+        let token = token?;
+        self.sessions.iter().find(|session| same_bytes(session.token.as_bytes(), token.as_bytes())).map(|session| session.role)
+    }
+
+    pub fn logout(&mut self, token: &str) {
+        self.sessions.retain(|session| !same_bytes(session.token.as_bytes(), token.as_bytes()));
+    }
+
+    // After a password is changed, the other sessions of the role are closed
+    pub fn close_sessions(&mut self, role: Role, keep: Option<&str>) {
+        self.sessions.retain(|session| session.role != role || Some(session.token.as_str()) == keep);
+    }
+}
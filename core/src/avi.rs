@@ -0,0 +1,302 @@
+// MJPEG AVI (RIFF) writer
+//
+// The AVI is written front to back without seeking, so it can be streamed to a HTTP response.
+// The size of every frame must be known before writing. It is taken from the record headers of the capture file.
+//
+// RIFF 'AVI '
+//   LIST 'hdrl'
+//     'avih' main header
+//     LIST 'strl'
+//       'strh' video stream header (MJPG)
+//       'strf' BITMAPINFOHEADER
+//   LIST 'movi'
+//     '00dc' JPEG image ... (padded to even size)
+//   'idx1' index of the '00dc' chunks
+
+use anyhow;
+use std::io::Write;
+
+use crate::imagefiles::ImageFiles;
+
+pub const MAX_FPS: u32 = 120;
+// Frames of a video served by the camera, the plan keeps 4 bytes of each frame in the memory
+pub const MAX_VIDEO_FRAMES: u32 = 50000;
+
+const AVIH_SIZE: u32 = 56;
+const STRH_SIZE: u32 = 56;
+const STRF_SIZE: u32 = 40;
+const STRL_LIST_SIZE: u32 = 4 + (8 + STRH_SIZE) + (8 + STRF_SIZE);
+const HDRL_LIST_SIZE: u32 = 4 + (8 + AVIH_SIZE) + (8 + STRL_LIST_SIZE);
+const IDX1_ENTRY_SIZE: u64 = 16;
+const AVIIF_KEYFRAME: u32 = 0x10;
+const AVIF_HASINDEX: u32 = 0x10;
+
+// RIFF size is 32bit
+const MAX_RIFF_SIZE: u64 = u32::MAX as u64;
+
+fn chunk_size(frame_size: u32) -> u64 {
+    8 + frame_size as u64 + (frame_size as u64 & 1)
+}
+
+fn movi_size(frame_sizes: &[u32]) -> u64 {
+    4 + frame_sizes.iter().map(|size| chunk_size(*size)).sum::<u64>()
+}
+
+fn riff_size(frame_sizes: &[u32]) -> u64 {
+    4 + (8 + HDRL_LIST_SIZE as u64) + (8 + movi_size(frame_sizes)) + (8 + IDX1_ENTRY_SIZE * frame_sizes.len() as u64)
+}
+
+// Size of the AVI file (for Content-Length)
+pub fn avi_file_size(frame_sizes: &[u32]) -> u64 {
+    8 + riff_size(frame_sizes)
+}
+
+// Number of frames from the top of frame_sizes which fit in an AVI file
+pub fn max_frames(frame_sizes: &[u32]) -> usize {
+    let fixed = 8 + 4 + (8 + HDRL_LIST_SIZE as u64) + (8 + 4) + 8;
+    let mut total = fixed;
+    for (n, size) in frame_sizes.iter().enumerate() {
+        total += chunk_size(*size) + IDX1_ENTRY_SIZE;
+        if total > MAX_RIFF_SIZE + 8 {
+            return n;
+        }
+    }
+    frame_sizes.len()
+}
+
+// Width and height from the SOF marker of the JPEG image
+pub fn jpeg_dimensions(data: &[u8]) -> Option<(u16, u16)> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            // fill byte
+            pos += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        // SOF0..SOF15 except DHT(C4), JPG(C8) and DAC(CC)
+        if (0xC0..=0xCF).contains(&marker) && marker != 0xC4 && marker != 0xC8 && marker != 0xCC {
+            if pos + 9 > data.len() {
+                return None;
+            }
+            let height = u16::from_be_bytes([data[pos + 5], data[pos + 6]]);
+            let width = u16::from_be_bytes([data[pos + 7], data[pos + 8]]);
+            return Some((width, height));
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            // start of scan or end of image before SOF
+            return None;
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+pub struct AviWriter<W: Write> {
+    out: W,
+    frame_sizes: Vec<u32>,
+    written: usize,
+}
+
+impl<W: Write> AviWriter<W> {
+    // Write the headers. frame_sizes is the size of every frame to be written.
+    pub fn new(mut out: W, width: u16, height: u16, fps: u32, frame_sizes: Vec<u32>) -> Result<AviWriter<W>, anyhow::Error> {
+        if fps == 0 || fps > MAX_FPS {
+            return Err(anyhow::Error::msg("Invalid frame rate"));
+        }
+        if max_frames(&frame_sizes) < frame_sizes.len() {
+            return Err(anyhow::Error::msg("AVI file exceeds 4GB"));
+        }
+        let nframes = frame_sizes.len() as u32;
+        let max_size = frame_sizes.iter().map(|size| size + (size & 1)).max().unwrap_or(0);
+        let mut header = Vec::with_capacity(8 + 12 + HDRL_LIST_SIZE as usize + 12);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(riff_size(&frame_sizes) as u32).to_le_bytes());
+        header.extend_from_slice(b"AVI ");
+        header.extend_from_slice(b"LIST");
+        header.extend_from_slice(&HDRL_LIST_SIZE.to_le_bytes());
+        header.extend_from_slice(b"hdrl");
+        // avih
+        header.extend_from_slice(b"avih");
+        header.extend_from_slice(&AVIH_SIZE.to_le_bytes());
+        for value in [
+            1_000_000 / fps,            // dwMicroSecPerFrame
+            max_size.saturating_mul(fps), // dwMaxBytesPerSec
+            0,                          // dwPaddingGranularity
+            AVIF_HASINDEX,              // dwFlags
+            nframes,                    // dwTotalFrames
+            0,                          // dwInitialFrames
+            1,                          // dwStreams
+            max_size + 8,               // dwSuggestedBufferSize
+            width as u32,               // dwWidth
+            height as u32,              // dwHeight
+            0, 0, 0, 0,                 // dwReserved
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(b"LIST");
+        header.extend_from_slice(&STRL_LIST_SIZE.to_le_bytes());
+        header.extend_from_slice(b"strl");
+        // strh
+        header.extend_from_slice(b"strh");
+        header.extend_from_slice(&STRH_SIZE.to_le_bytes());
+        header.extend_from_slice(b"vids");
+        header.extend_from_slice(b"MJPG");
+        for value in [
+            0,                          // dwFlags
+            0,                          // wPriority, wLanguage
+            0,                          // dwInitialFrames
+            1,                          // dwScale
+            fps,                        // dwRate
+            0,                          // dwStart
+            nframes,                    // dwLength
+            max_size + 8,               // dwSuggestedBufferSize
+            u32::MAX,                   // dwQuality (default)
+            0,                          // dwSampleSize
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        // rcFrame
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        // strf (BITMAPINFOHEADER)
+        header.extend_from_slice(b"strf");
+        header.extend_from_slice(&STRF_SIZE.to_le_bytes());
+        header.extend_from_slice(&STRF_SIZE.to_le_bytes());           // biSize
+        header.extend_from_slice(&(width as u32).to_le_bytes());      // biWidth
+        header.extend_from_slice(&(height as u32).to_le_bytes());     // biHeight
+        header.extend_from_slice(&1u16.to_le_bytes());                // biPlanes
+        header.extend_from_slice(&24u16.to_le_bytes());               // biBitCount
+        header.extend_from_slice(b"MJPG");                            // biCompression
+        header.extend_from_slice(&(width as u32 * height as u32 * 3).to_le_bytes()); // biSizeImage
+        header.extend_from_slice(&[0; 16]);                           // biXPelsPerMeter .. biClrImportant
+        // movi
+        header.extend_from_slice(b"LIST");
+        header.extend_from_slice(&(movi_size(&frame_sizes) as u32).to_le_bytes());
+        header.extend_from_slice(b"movi");
+        out.write_all(&header)?;
+        Ok(AviWriter {
+            out,
+            frame_sizes,
+            written: 0,
+        })
+    }
+
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> Result<(), anyhow::Error> {
+        if self.written >= self.frame_sizes.len() || self.frame_sizes[self.written] as usize != jpeg.len() {
+            return Err(anyhow::Error::msg("Frame size does not match"));
+        }
+        self.out.write_all(b"00dc")?;
+        self.out.write_all(&(jpeg.len() as u32).to_le_bytes())?;
+        self.out.write_all(jpeg)?;
+        if jpeg.len() & 1 == 1 {
+            self.out.write_all(&[0])?;
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    // Write the index and return the output
+    pub fn finish(mut self) -> Result<W, anyhow::Error> {
+        if self.written != self.frame_sizes.len() {
+            return Err(anyhow::Error::msg("Not all frames are written"));
+        }
+        self.out.write_all(b"idx1")?;
+        self.out.write_all(&((IDX1_ENTRY_SIZE * self.frame_sizes.len() as u64) as u32).to_le_bytes())?;
+        // offset from the 'movi' fourcc
+        let mut offset: u32 = 4;
+        for size in &self.frame_sizes {
+            let mut entry = [0u8; IDX1_ENTRY_SIZE as usize];
+            entry[0..4].copy_from_slice(b"00dc");
+            entry[4..8].copy_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+            entry[8..12].copy_from_slice(&offset.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+            self.out.write_all(&entry)?;
+            offset += chunk_size(*size) as u32;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Frames of the capture file to be put in an AVI
+pub struct AviPlan {
+    pub from: u32,
+    pub frame_sizes: Vec<u32>,
+    pub width: u16,
+    pub height: u16,
+    pub truncated: bool,    // the range was cut to fit in 4GB
+}
+
+impl AviPlan {
+    pub fn file_size(&self) -> u64 {
+        avi_file_size(&self.frame_sizes)
+    }
+}
+
+// Number of the frames from..=to (inclusive) in a track of nimages frames
+pub fn avi_frames(nimages: u32, from: u32, to: u32) -> u32 {
+    if nimages == 0 || from > to || from >= nimages {
+        return 0;
+    }
+    to.min(nimages - 1) - from + 1
+}
+
+// Walk the record headers from..=to (inclusive) and prepare the AVI headers
+pub fn plan_avi(images: &mut ImageFiles, from: u32, to: u32) -> Result<AviPlan, anyhow::Error> {
+    let frames = avi_frames(images.get_nof_images(), from, to);
+    if frames == 0 {
+        return Err(anyhow::Error::msg("No frames in the range"));
+    }
+    let to = from + frames - 1;
+    images.seek_image(from)?;
+    let first = images.get_frame_info()?;
+    let mut frame_sizes = Vec::with_capacity(frames as usize);
+    for _ in from..=to {
+        let size = images.get_image_size();
+        if size == 0 {
+            return Err(anyhow::Error::msg("Failed to get image size"));
+        }
+        frame_sizes.push(size as u32);
+        images.skip_image()?;
+    }
+    let (width, height) = if first.width > 0 {
+        (first.width, first.height)
+    }
+    else {
+        // v1 record has no metadata
+        images.seek_image(from)?;
+        jpeg_dimensions(&images.read_image()?).unwrap_or((0, 0))
+    };
+    let nframes = max_frames(&frame_sizes);
+    let truncated = nframes < frame_sizes.len();
+    frame_sizes.truncate(nframes);
+    Ok(AviPlan {
+        from,
+        frame_sizes,
+        width,
+        height,
+        truncated,
+    })
+}
+
+// Stream the planned frames as an AVI file
+pub fn write_avi<W: Write>(images: &mut ImageFiles, plan: AviPlan, fps: u32, out: W) -> Result<W, anyhow::Error> {
+    let from = plan.from;
+    let nframes = plan.frame_sizes.len();
+    let mut writer = AviWriter::new(out, plan.width, plan.height, fps, plan.frame_sizes)?;
+    images.seek_image(from)?;
+    for _ in 0..nframes {
+        let image = images.read_image()?;
+        writer.write_frame(&image)?;
+    }
+    writer.finish()
+}
@@ -0,0 +1,310 @@
+// Capture main loop
+//
+// The state machine of the main loop of the firmware: auto capture, one-shot, movie mode, the status report cadence
+// and the sleep after each capture. The device is accessed through the traits below. The firmware implements them
+// with ESP-IDF, and the host tests implement them with a simulated clock, so weeks of deep sleep cycles run in
+// milliseconds.
+// A deep sleep restarts the firmware: the state is stored before it, and the loop is created again from the stored
+// state at the wake up.
+
+use log::info;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::schedule::CaptureSchedule;
+
+// A wait longer than this (seconds) is a deep sleep, shorter waits are OS sleeps
+pub const DEEP_SLEEP_THRESHOLD: u64 = 60;
+
+// Wait of the loop when idle, after a one-shot and between the movie frames
+pub const LOOP_WAIT: Duration = Duration::from_millis(100);
+
+// Wait when the next capture is now (catch up)
+pub const CATCH_UP_WAIT: Duration = Duration::from_millis(1000);
+
+// The last access time is valid after the clock is set
+const VALID_ACCESS_TIME_MS: u128 = 1700000000;
+
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+pub trait Sleeper {
+    // OS sleep, the loop continues after it
+    fn sleep(&mut self, duration: Duration);
+    // Deep sleep, None: wake up only by the touchpad. It does not return on the device.
+    fn deep_sleep(&mut self, duration: Option<Duration>);
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct CaptureResult {
+    pub capture_id: u32,            // last frame id of the request
+    pub written: bool,
+}
+
+pub trait CameraBackend {
+    fn change_resolution(&mut self, resolution: u32);
+    fn autofocus(&mut self);
+    fn set_overwrite_saved(&mut self, overwrite: bool);
+    // Start capturing at the frame id. In the movie mode the frames are captured until the capture is stopped.
+    fn request_capture(&mut self, track_id: u32, capture_id: u32);
+    // Wait for the requested capture
+    fn wait_capture(&mut self) -> CaptureResult;
+}
+
+// State kept over the deep sleep (RTC memory on the device)
+pub trait Storage {
+    fn load(&mut self) -> Option<RetainedState>;
+    fn store(&mut self, state: &RetainedState);
+}
+
+// Notifications to the web server and the monitoring service
+pub trait Reporter {
+    // After each capture request, returns the time when the frame was posted to the monitoring service
+    fn captured(&mut self, track_id: u32, result: &CaptureResult, time: SystemTime) -> Option<SystemTime>;
+    fn status_report(&mut self, track_id: u32, capture_id: u32, time: SystemTime);
+    // Missed capture times of the capture, 0 when a capture starts
+    fn missed(&mut self, missed_captures: u32, last_missed_time: SystemTime);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub struct Devices<C: Clock, S: Sleeper, B: CameraBackend, T: Storage> {
+    pub clock: C,
+    pub sleeper: S,
+    pub camera: B,
+    pub storage: T,
+}
+
+// Times are UTC seconds
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct RetainedState {
+    pub auto_capture: bool,             // capturing, wake up by the timer
+    pub capture_id: u32,                // next frame id
+    pub next_capture_time: u64,
+    pub duration: u32,
+    pub resolution: u32,
+    pub track_id: u32,
+    pub last_capture_time: u64,
+    pub last_posted_time: u64,
+    pub capture_start_time: u64,
+    pub capture_end_time: u64,
+    pub last_status_posted_time: u64,
+    pub missed_captures: u32,
+    pub last_missed_time: u64,
+}
+
+pub fn to_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub fn from_seconds(seconds: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+// Settings of a loop, from the configuration, the web server and the keys
+#[derive(Debug, Clone)]
+pub struct LoopSettings {
+    pub capture_started: bool,
+    pub one_shot: bool,
+    pub movie_mode: bool,
+    pub resolution: u32,
+    pub track_id: u32,
+    pub duration: u32,
+    pub autofocus_once: bool,
+    pub overwrite_saved: bool,
+    pub status_report: bool,
+    pub status_report_interval: u32,    // seconds
+    pub schedule: Option<CaptureSchedule>,  // None: invalid schedule, the capture ends
+    pub capture_start_time: SystemTime,
+    pub capture_end_time: SystemTime,
+    pub idle_in_sleep_time: Option<u32>,    // seconds, the device sleeps when idle in the operating mode
+    pub last_access_time: SystemTime,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+    Idle,               // not capturing
+    Movie,              // a movie frame
+    OneShot,            // the one-shot capture is done
+    Waiting,            // captured, waited for the next capture
+    DeepSleep,          // captured, slept until the next capture
+    Ended,              // the capture ended, slept until the touchpad
+    IdleSleep,          // idle in the operating mode, slept until the touchpad
+}
+
+pub struct CaptureLoop {
+    pub state: RetainedState,
+}
+
+impl CaptureLoop {
+    pub fn new(state: RetainedState) -> CaptureLoop {
+        CaptureLoop { state }
+    }
+
+    // Run a loop of the main loop
+    pub fn step<C, S, B, T, R>(&mut self, settings: &LoopSettings, devices: &mut Devices<C, S, B, T>, reporter: &mut R) -> Step
+        where C: Clock, S: Sleeper, B: CameraBackend, T: Storage, R: Reporter {
+        if settings.resolution != self.state.resolution {
+            info!("Resolution changed: {} -> {}", self.state.resolution, settings.resolution);
+            self.state.resolution = settings.resolution;
+            devices.camera.change_resolution(self.state.resolution);
+        }
+        self.state.duration = settings.duration;
+        self.state.capture_start_time = to_seconds(settings.capture_start_time);
+        self.state.capture_end_time = to_seconds(settings.capture_end_time);
+
+        if !settings.capture_started {
+            self.state.capture_id = 0;
+            if let Some(idle_in_sleep_time) = settings.idle_in_sleep_time {
+                let valid = settings.last_access_time.duration_since(UNIX_EPOCH).is_ok_and(|d| d.as_millis() > VALID_ACCESS_TIME_MS);
+                let idle_time = devices.clock.now().duration_since(settings.last_access_time).map_or(0, |d| d.as_secs());
+                if valid && idle_time > idle_in_sleep_time as u64 {
+                    info!("Idle time {:?} over. Go to sleep", idle_time);
+                    self.deep_sleep(devices, None);
+                    return Step::IdleSleep;
+                }
+            }
+            devices.sleeper.sleep(LOOP_WAIT);
+            return Step::Idle;
+        }
+
+        if self.state.track_id != settings.track_id {
+            info!("Track ID changed: {} -> {}", self.state.track_id, settings.track_id);
+            self.state.track_id = settings.track_id;
+            self.state.capture_id = 0;
+        }
+        let track_id = self.state.track_id;
+        if self.state.capture_id > 0 && !settings.movie_mode && !settings.one_shot {
+            // woke up late, the missed policy chooses to capture now or to wait
+            let now = devices.clock.now();
+            let next = settings.schedule.as_ref()
+                .and_then(|schedule| schedule.capture_at_wake_up(self.state.next_capture_time as i64, to_seconds(now) as i64));
+            let next = match next {
+                Some(next) => next,
+                None => return self.end(devices),
+            };
+            self.add_missed(next.missed, now, reporter);
+            self.state.next_capture_time = next.time.max(0) as u64;
+            if next.time > to_seconds(now) as i64 {
+                return self.wait(settings, now, devices);
+            }
+        }
+        if !settings.autofocus_once || self.state.capture_id == 0 {
+            devices.camera.autofocus();
+        }
+        if self.state.capture_id == 0 {
+            let now = devices.clock.now();
+            self.state.next_capture_time = to_seconds(now);
+            devices.camera.set_overwrite_saved(settings.overwrite_saved);
+            self.state.missed_captures = 0;
+            self.state.last_missed_time = 0;
+            reporter.missed(0, UNIX_EPOCH);
+        }
+        else {
+            devices.camera.set_overwrite_saved(false);
+        }
+
+        if settings.movie_mode {
+            if self.state.capture_id == 0 {
+                info!("Movie Started Track ID: {} Resolution: {}", track_id, self.state.resolution);
+                devices.camera.request_capture(track_id, 0);
+            }
+            self.state.capture_id += 1;
+            devices.sleeper.sleep(LOOP_WAIT);
+            return Step::Movie;
+        }
+
+        info!("Capture Started Track ID: {} Count: {} Resolution: {}", track_id, self.state.capture_id, self.state.resolution);
+        devices.camera.request_capture(track_id, self.state.capture_id);
+        let result = devices.camera.wait_capture();
+        info!("Capture done");
+        self.state.capture_id = result.capture_id;
+        let now = devices.clock.now();
+        if let Some(posted_time) = reporter.captured(track_id, &result, now) {
+            self.state.last_posted_time = to_seconds(posted_time);
+        }
+        if result.written {
+            let report_elapsed = now.duration_since(from_seconds(self.state.last_status_posted_time)).map_or(0, |d| d.as_secs());
+            if settings.status_report && report_elapsed > settings.status_report_interval as u64 {
+                reporter.status_report(track_id, result.capture_id, now);
+                self.state.last_status_posted_time = to_seconds(devices.clock.now());
+            }
+            self.state.last_capture_time = to_seconds(now);
+            self.state.capture_id += 1;
+        }
+
+        if settings.one_shot {
+            self.state.capture_id = 0;
+            devices.sleeper.sleep(LOOP_WAIT);
+            return Step::OneShot;
+        }
+
+        let now = devices.clock.now();
+        let next = settings.schedule.as_ref()
+            .and_then(|schedule| schedule.next_capture(self.state.next_capture_time as i64, to_seconds(now) as i64));
+        let next = match next {
+            Some(next) => next,
+            None => return self.end(devices),
+        };
+        self.add_missed(next.missed, now, reporter);
+        self.state.next_capture_time = next.time.max(0) as u64;
+        self.wait(settings, now, devices)
+    }
+
+    fn add_missed<R: Reporter>(&mut self, missed: u32, now: SystemTime, reporter: &mut R) {
+        if missed > 0 {
+            info!("Missed {} capture times", missed);
+            self.state.missed_captures = self.state.missed_captures.saturating_add(missed);
+            self.state.last_missed_time = to_seconds(now);
+            reporter.missed(self.state.missed_captures, now);
+        }
+    }
+
+    // Sleep until the next capture time
+    fn wait<C, S, B, T>(&mut self, settings: &LoopSettings, now: SystemTime, devices: &mut Devices<C, S, B, T>) -> Step
+        where C: Clock, S: Sleeper, B: CameraBackend, T: Storage {
+        if let Some(schedule) = &settings.schedule {
+            let next = self.state.next_capture_time as i64;
+            info!("Next Capture Time: {} {}", schedule.timezone.format(next, "%Y-%m-%d %H:%M:%S"), schedule.timezone.abbreviation_at(next));
+        }
+        // the next time may be now to catch up
+        let sleep_time = from_seconds(self.state.next_capture_time).duration_since(now).unwrap_or(Duration::ZERO);
+        info!("Sleep: {:?}", sleep_time);
+        match sleep_time.as_secs() {
+            0 => {
+                devices.sleeper.sleep(CATCH_UP_WAIT);
+                Step::Waiting
+            }
+            1..=DEEP_SLEEP_THRESHOLD => {
+                devices.sleeper.sleep(sleep_time);
+                Step::Waiting
+            }
+            _ => {
+                self.state.auto_capture = true;
+                self.deep_sleep(devices, Some(sleep_time));
+                Step::DeepSleep
+            }
+        }
+    }
+
+    fn end<C, S, B, T>(&mut self, devices: &mut Devices<C, S, B, T>) -> Step
+        where C: Clock, S: Sleeper, B: CameraBackend, T: Storage {
+        info!("Capture end");
+        self.state.capture_id = 0;
+        self.state.auto_capture = false;
+        self.deep_sleep(devices, None);
+        Step::Ended
+    }
+
+    fn deep_sleep<C, S, B, T>(&mut self, devices: &mut Devices<C, S, B, T>, duration: Option<Duration>)
+        where C: Clock, S: Sleeper, B: CameraBackend, T: Storage {
+        devices.storage.store(&self.state);
+        devices.sleeper.deep_sleep(duration);
+    }
+}
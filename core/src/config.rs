@@ -0,0 +1,588 @@
+// Configuration of the camera
+//
+// The configuration is stored in the NVS as a TOML table of strings (KEY = "value"). Every key has a default, so a
+// missing key or a value which cannot be read falls back to the default of that key only, and the other settings
+// like the WiFi credentials are kept. validate() checks the ranges of the values, the errors have the key of the
+// value and they are sent back to the configuration page.
+//
+// The stored table has the version of its keys (CONFIGVERSION). A table of an older firmware is migrated version by
+// version before it is read, so the settings added later have the behavior of the older firmware instead of the
+// defaults. The versions before 7 have no CONFIGVERSION, they are found by their keys:
+//   0: v0.3.0, leap day/hour/minute
+//   1: v0.3.1, capture frames at once, overwrite saved
+//   2: v0.3.3, direct write mode, JPEG quality
+//   3: segment files
+//   4: retention policy
+//   5: cron schedule instead of leap day/hour/minute
+//   6: sunrise/sunset schedule
+//   7: missed capture policy, the first version with CONFIGVERSION
+//   8: the secrets (SECRET_KEYS) are in the secrets store, the table of an older version has them
+//   9: HTTPS of the web server, CONFIG_VERSION
+//
+// The secrets are saved apart from the table (secrets_toml() and set_secrets()), and they are masked in the changes
+// and in the debug output, so they are not in the logs.
+
+use anyhow;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use toml::{Table, Value};
+
+use crate::schedule::{leap_time_expression, CronSchedule, MissedPolicy};
+use crate::secrets::secret_state;
+use crate::auth::is_password_hash;
+use crate::timezone::TimeZone;
+
+pub const JPEG_QUALITY_RANGE: RangeInclusive<u32> = 4..=63;
+pub const SEGMENT_SIZE_RANGE: RangeInclusive<u32> = 0..=4095;        // MB, up to the file size limit of FAT
+pub const LATITUDE_RANGE: RangeInclusive<f64> = -90.0..=90.0;
+pub const LONGITUDE_RANGE: RangeInclusive<f64> = -180.0..=180.0;
+pub const SUN_MARGIN_RANGE: RangeInclusive<i32> = -720..=720;        // minutes
+pub const SUN_SCHEDULES: [&str; 3] = ["off", "daylight", "golden"];
+// names and framesize_t values of esp32-camera of the resolutions the camera captures
+pub const ACCEPTABLE_RESOLUTIONS: [(&str, u32); 14] = [
+    ("QVGA",    5),     // 320x240
+    ("CIF",     6),     // 400x296
+    ("HVGA",    7),     // 480x320
+    ("VGA",     8),     // 640x480
+    ("SVGA",    9),     // 800x600
+    ("XGA",     10),    // 1024x768
+    ("HD",      11),    // 1280x720
+    ("SXGA",    12),    // 1280x1024
+    ("UXGA",    13),    // 1600x1200
+    ("FHD",     14),    // 1920x1080
+    ("QXGA",    17),    // 2048x1536
+    ("QSXGA",   21),    // 2592x1944
+    ("WQXGA",   19),    // 2560x1600
+    ("QHD",     18),    // 2560x1440
+];
+pub const MAX_TRACK_ID: u32 = 9999;
+const MAX_SSID_LEN: usize = 32;
+const PSK_LEN_RANGE: RangeInclusive<usize> = 8..=64;
+
+// Increment with a migration when a key is added or changed
+pub const CONFIG_VERSION: u32 = 9;
+const VERSION_KEY: &str = "configversion";
+
+// Keys of the passwords and the tokens, they are not exported with the redaction and not shown in the changes
+pub const SECRET_KEYS: [&str; 7] = ["psk", "apikey", "postaccesstoken", "storageaccesstoken", "storagesignedkey",
+    "adminpasswordhash", "viewerpasswordhash"];
+const MASKED_SECRET: &str = "********";
+
+// day/hour/minute setting of the old versions, converted to the schedule
+const LEGACY_LEAP_KEYS: [&str; 3] = ["leapday", "leaphour", "leapminute"];
+
+// MIGRATIONS[n] migrates a table of the version n to n + 1
+const MIGRATIONS: [fn(&mut Table); CONFIG_VERSION as usize] = [
+    migrate_capture_frames,
+    migrate_jpeg_quality,
+    migrate_segments,
+    migrate_retention,
+    migrate_schedule,
+    migrate_sun_schedule,
+    migrate_missed_policy,
+    migrate_secrets,
+    migrate_https,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub field: String,          // key of the value, e.g. "jpegquality"
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: &str, message: &str) -> ConfigError {
+        ConfigError { field: field.to_string(), message: message.to_string() }
+    }
+}
+
+// A value changed by an import
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+// Keys are the lower case names of the stored keys, the values are read from strings or TOML values
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigData {
+    #[serde(rename = "ssid", deserialize_with = "from_text")]
+    pub wifi_ssid: String,
+    #[serde(rename = "psk", deserialize_with = "from_text")]
+    pub wifi_psk: String,
+    #[serde(rename = "timezone", deserialize_with = "from_text")]
+    pub timezone: String,
+    #[serde(rename = "idlesleep", deserialize_with = "from_text")]
+    pub idle_in_sleep_time: u32,
+    #[serde(rename = "autocapture", deserialize_with = "from_text")]
+    pub auto_capture: bool,
+    #[serde(rename = "resolution", deserialize_with = "from_text")]
+    pub resolution: u32,
+    #[serde(rename = "trackid", deserialize_with = "from_text")]
+    pub track_id: u32,
+    #[serde(rename = "duration", deserialize_with = "from_text")]
+    pub duration: u32,
+    #[serde(rename = "model", deserialize_with = "from_text")]
+    pub model: String,
+    #[serde(rename = "apikey", deserialize_with = "from_text")]
+    pub api_key: String,
+    #[serde(rename = "queryopenai", deserialize_with = "from_text")]
+    pub query_openai: bool,
+    #[serde(rename = "queryprompt", deserialize_with = "from_text")]
+    pub query_prompt: String,
+    #[serde(rename = "postaccount", deserialize_with = "from_text")]
+    pub post_account: String,
+    #[serde(rename = "postaccesstoken", deserialize_with = "from_text")]
+    pub post_access_token: String,
+    #[serde(rename = "storageaccount", deserialize_with = "from_text")]
+    pub storage_account: String,
+    #[serde(rename = "storageaccesstoken", deserialize_with = "from_text")]
+    pub storage_access_token: String,
+    #[serde(rename = "storagesignedkey", deserialize_with = "from_text")]
+    pub storage_signed_key: String,
+    #[serde(rename = "adminpasswordhash", deserialize_with = "from_text")]
+    pub admin_password_hash: String,    // empty: the web server is not protected
+    #[serde(rename = "viewerpasswordhash", deserialize_with = "from_text")]
+    pub viewer_password_hash: String,   // empty: no viewer login
+    #[serde(rename = "postmessagetrigger", deserialize_with = "from_text")]
+    pub post_message_trigger: String,
+    #[serde(rename = "autofocusonce", deserialize_with = "from_text")]
+    pub autofocus_once: bool,
+    #[serde(rename = "statusreport", deserialize_with = "from_text")]
+    pub status_report: bool,
+    #[serde(rename = "statusreportinterval", deserialize_with = "from_text")]
+    pub status_report_interval: u32,
+    #[serde(rename = "postinterval", deserialize_with = "from_text")]
+    pub post_interval: u32,
+    #[serde(rename = "schedule", deserialize_with = "from_text")]
+    pub schedule: String,
+    #[serde(rename = "captureframesatonce", deserialize_with = "from_text")]
+    pub capture_frames_at_once: i32,
+    #[serde(rename = "overwritesaved", deserialize_with = "from_text")]
+    pub overwrite_saved: bool,
+    #[serde(rename = "directwritemode", deserialize_with = "from_text")]
+    pub direct_write_mode: bool,
+    #[serde(rename = "jpegquality", deserialize_with = "from_text")]
+    pub jpeg_quality: u32,
+    #[serde(rename = "segmentsize", deserialize_with = "from_text")]
+    pub segment_size: u32,
+    #[serde(rename = "segmentframes", deserialize_with = "from_text")]
+    pub segment_frames: u32,
+    #[serde(rename = "retentiondays", deserialize_with = "from_text")]
+    pub retention_days: u32,
+    #[serde(rename = "retentionframes", deserialize_with = "from_text")]
+    pub retention_frames: u32,
+    #[serde(rename = "thinafterdays", deserialize_with = "from_text")]
+    pub thin_after_days: u32,
+    #[serde(rename = "thininterval", deserialize_with = "from_text")]
+    pub thin_interval: u32,
+    #[serde(rename = "latitude", deserialize_with = "from_text")]
+    pub latitude: f64,
+    #[serde(rename = "longitude", deserialize_with = "from_text")]
+    pub longitude: f64,
+    #[serde(rename = "sunschedule", deserialize_with = "from_text")]
+    pub sun_schedule: String,
+    #[serde(rename = "sunrisemargin", deserialize_with = "from_text")]
+    pub sunrise_margin: i32,
+    #[serde(rename = "sunsetmargin", deserialize_with = "from_text")]
+    pub sunset_margin: i32,
+    #[serde(rename = "missedpolicy", deserialize_with = "from_text")]
+    pub missed_policy: String,
+    #[serde(rename = "https", deserialize_with = "from_text")]
+    pub https: bool,                    // applied after the restart
+}
+
+// The secrets are masked, e.g. in the logs
+impl fmt::Debug for ConfigData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let table = self.stored_table();
+        f.write_str("ConfigData ")?;
+        f.debug_map().entries(table.iter().map(|(key, value)| (key, mask_secret(key, &text_value(value))))).finish()
+    }
+}
+
+// Same as the defaults of cfg.toml
+impl Default for ConfigData {
+    fn default() -> ConfigData {
+        ConfigData {
+            wifi_ssid: String::new(),
+            wifi_psk: String::new(),
+            timezone: "0".to_string(),
+            idle_in_sleep_time: 300,
+            auto_capture: false,
+            resolution: 8,
+            track_id: 0,
+            duration: 0,
+            model: "gpt-4-turbo".to_string(),
+            api_key: "api-key".to_string(),
+            query_openai: false,
+            query_prompt: String::new(),
+            post_account: String::new(),
+            post_access_token: String::new(),
+            storage_account: String::new(),
+            storage_access_token: String::new(),
+            storage_signed_key: String::new(),
+            admin_password_hash: String::new(),
+            viewer_password_hash: String::new(),
+            post_message_trigger: String::new(),
+            autofocus_once: true,
+            status_report: false,
+            status_report_interval: 600,
+            post_interval: 3600,
+            schedule: String::new(),
+            capture_frames_at_once: 0,
+            overwrite_saved: false,
+            direct_write_mode: false,
+            jpeg_quality: 12,
+            segment_size: 0,
+            segment_frames: 0,
+            retention_days: 0,
+            retention_frames: 0,
+            thin_after_days: 0,
+            thin_interval: 60,
+            latitude: 0.0,
+            longitude: 0.0,
+            sun_schedule: "off".to_string(),
+            sunrise_margin: 0,
+            sunset_margin: 0,
+            missed_policy: "align".to_string(),
+            https: false,
+        }
+    }
+}
+
+// The stored values are strings ("600"), the values of the other sources may be TOML values (600)
+fn from_text<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where D: Deserializer<'de>, T: FromStr, T::Err: fmt::Display {
+    let text = match Value::deserialize(deserializer)? {
+        Value::String(text) => text,
+        Value::Array(_) | Value::Table(_) => return Err(serde::de::Error::custom("Not a single value")),
+        value => value.to_string(),
+    };
+    text.parse().map_err(serde::de::Error::custom)
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+// Keys added by a version, with the value of the behavior before it
+fn insert_missing(table: &mut Table, values: &[(&str, &str)]) {
+    for (key, value) in values {
+        if !table.contains_key(*key) {
+            table.insert(key.to_string(), Value::String(value.to_string()));
+        }
+    }
+}
+
+fn migrate_capture_frames(table: &mut Table) {
+    insert_missing(table, &[("captureframesatonce", "0"), ("overwritesaved", "false")]);
+}
+
+fn migrate_jpeg_quality(table: &mut Table) {
+    insert_missing(table, &[("directwritemode", "false"), ("jpegquality", "12")]);
+}
+
+// one file up to the file size limit of FAT
+fn migrate_segments(table: &mut Table) {
+    insert_missing(table, &[("segmentsize", "0"), ("segmentframes", "0")]);
+}
+
+// all frames are kept
+fn migrate_retention(table: &mut Table) {
+    insert_missing(table, &[("retentiondays", "0"), ("retentionframes", "0"), ("thinafterdays", "0"), ("thininterval", "60")]);
+}
+
+fn migrate_schedule(table: &mut Table) {
+    let leap_value = |key: &str| table.get(key).and_then(|value| text_value(value).parse::<i32>().ok()).unwrap_or(-1);
+    let schedule = leap_time_expression(leap_value(LEGACY_LEAP_KEYS[0]), leap_value(LEGACY_LEAP_KEYS[1]), leap_value(LEGACY_LEAP_KEYS[2]));
+    for key in LEGACY_LEAP_KEYS {
+        table.remove(key);
+    }
+    insert_missing(table, &[("schedule", &schedule.unwrap_or_default())]);
+}
+
+fn migrate_sun_schedule(table: &mut Table) {
+    insert_missing(table, &[("latitude", "0"), ("longitude", "0"), ("sunschedule", "off"), ("sunrisemargin", "0"), ("sunsetmargin", "0")]);
+}
+
+fn migrate_missed_policy(table: &mut Table) {
+    insert_missing(table, &[("missedpolicy", "align")]);
+}
+
+// the secrets in the table are read, and they are moved to the secrets store when the table is saved
+fn migrate_secrets(_table: &mut Table) {
+}
+
+fn migrate_https(table: &mut Table) {
+    insert_missing(table, &[("https", "false")]);
+}
+
+// Version of a stored table with the lower case keys
+fn table_version(table: &Table) -> Result<u32, anyhow::Error> {
+    if let Some(value) = table.get(VERSION_KEY) {
+        let text = text_value(value);
+        return text.parse().map_err(|_| anyhow::anyhow!("Invalid config version {}", text));
+    }
+    let added_keys = [("missedpolicy", 7), ("sunschedule", 6), ("schedule", 5), ("retentiondays", 4), ("segmentsize", 3),
+        ("jpegquality", 2), ("captureframesatonce", 1)];
+    Ok(added_keys.iter().find(|(key, _)| table.contains_key(*key)).map_or(0, |(_, version)| *version))
+}
+
+// The errors of the toml crate have the line of the text, it can be a secret
+fn parse_table(text: &str) -> Result<Table, anyhow::Error> {
+    text.parse().map_err(|e: toml::de::Error| anyhow::anyhow!("Invalid TOML: {}", e.message()))
+}
+
+fn mask_secret(key: &str, value: &str) -> String {
+    if SECRET_KEYS.contains(&key) && !value.is_empty() { MASKED_SECRET.to_string() } else { value.to_string() }
+}
+
+fn lower_case_keys(table: Table) -> Table {
+    table.into_iter().map(|(key, value)| (key.to_ascii_lowercase(), value)).collect()
+}
+
+// Keys of an imported document, "JPEG_QUALITY" and "jpegQuality" are also "jpegquality"
+fn import_keys(table: Table) -> Table {
+    table.into_iter().map(|(key, value)| (key.to_ascii_lowercase().replace(['_', '-'], ""), value)).collect()
+}
+
+// Sets the values to the table, the values which cannot be read are not set and they are returned as the errors
+fn merge_values(table: &mut Table, values: Table) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    for (key, value) in values {
+        let mut candidate = table.clone();
+        candidate.insert(key.clone(), value);
+        match Value::Table(candidate.clone()).try_into::<ConfigData>() {
+            Ok(_) => *table = candidate,
+            Err(e) => errors.push(ConfigError::new(&key, e.message())),
+        }
+    }
+    errors
+}
+
+// Version of the stored configuration
+pub fn stored_version(text: &str) -> Result<u32, anyhow::Error> {
+    table_version(&lower_case_keys(parse_table(text)?))
+}
+
+fn out_of_range<T: fmt::Display>(range: &RangeInclusive<T>) -> String {
+    format!("Out of range ({} to {})", range.start(), range.end())
+}
+
+impl ConfigData {
+    // Stored configuration, keys are case insensitive. Err only when the text is not TOML, the values which cannot
+    // be read or are out of range are the defaults and they are returned as the errors.
+    pub fn from_toml(text: &str, defaults: &ConfigData) -> Result<(ConfigData, Vec<ConfigError>), anyhow::Error> {
+        let mut stored = lower_case_keys(parse_table(text)?);
+        let version = table_version(&stored)?;
+        for migrate in MIGRATIONS.iter().skip(version as usize) {
+            migrate(&mut stored);
+        }
+        ConfigData::from_table(stored, defaults)
+    }
+
+    // Key and value strings, e.g. the defaults of cfg.toml
+    pub fn from_strings(entries: &[(&str, &str)], defaults: &ConfigData) -> Result<(ConfigData, Vec<ConfigError>), anyhow::Error> {
+        let stored = entries.iter().map(|(key, value)| (key.to_string(), Value::String(value.to_string()))).collect();
+        ConfigData::from_table(stored, defaults)
+    }
+
+    fn from_table(stored: Table, defaults: &ConfigData) -> Result<(ConfigData, Vec<ConfigError>), anyhow::Error> {
+        let default_table = Table::try_from(defaults)?;
+        // unknown keys are ignored, e.g. the keys of the old versions
+        let stored = lower_case_keys(stored).into_iter().filter(|(key, _)| default_table.contains_key(key)).collect();
+        let mut table = default_table.clone();
+        let mut errors = merge_values(&mut table, stored);
+        let mut config: ConfigData = Value::Table(table.clone()).try_into()?;
+
+        // the values out of range are also the defaults
+        let invalid = config.validate();
+        if !invalid.is_empty() {
+            for error in &invalid {
+                if let Some(value) = default_table.get(&error.field) {
+                    table.insert(error.field.clone(), value.clone());
+                }
+            }
+            config = Value::Table(table).try_into()?;
+            errors.extend(invalid);
+        }
+        Ok((config, errors))
+    }
+
+    // Import of a TOML or JSON document, the keys which are not in the document keep the current values. Nothing is
+    // changed when a key is unknown or a value is not valid, all of the errors are returned.
+    pub fn import(text: &str, current: &ConfigData) -> Result<(ConfigData, Vec<ConfigChange>), Vec<ConfigError>> {
+        let document: Result<Table, String> = if text.trim_start().starts_with('{') {
+            serde_json::from_str(text).map_err(|e| e.to_string())
+        } else {
+            parse_table(text).map_err(|e| e.to_string())
+        };
+        let mut values = match document {
+            Ok(document) => import_keys(document),
+            Err(e) => return Err(vec![ConfigError::new("document", &e)]),
+        };
+        // an export of an older firmware is migrated
+        if let Some(value) = values.remove(VERSION_KEY) {
+            let version = match text_value(&value).parse::<u32>() {
+                Ok(version) if version <= CONFIG_VERSION => version,
+                _ => return Err(vec![ConfigError::new(VERSION_KEY, &format!("Version {} is not supported", text_value(&value)))]),
+            };
+            for migrate in MIGRATIONS.iter().skip(version as usize) {
+                migrate(&mut values);
+            }
+        }
+
+        let mut table = Table::try_from(current).map_err(|e| vec![ConfigError::new("document", &e.to_string())])?;
+        let (known, unknown): (Table, Table) = values.into_iter().partition(|(key, _)| table.contains_key(key));
+        let mut errors: Vec<ConfigError> = unknown.keys().map(|key| ConfigError::new(key, "Unknown key")).collect();
+        errors.extend(merge_values(&mut table, known));
+        let config: ConfigData = match Value::Table(table).try_into() {
+            Ok(config) => config,
+            Err(e) => return Err(vec![ConfigError::new("document", e.message())]),
+        };
+        errors.extend(config.validate());
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let changes = current.changes(&config);
+        Ok((config, changes))
+    }
+
+    // Values changed from self to other, the secrets are masked
+    pub fn changes(&self, other: &ConfigData) -> Vec<ConfigChange> {
+        let (old, new) = (self.stored_table(), other.stored_table());
+        new.iter()
+            .filter(|(key, value)| old.get(*key) != Some(*value))
+            .map(|(key, value)| {
+                let old_value = old.get(key).map(text_value).unwrap_or_default();
+                ConfigChange { field: key.clone(), old: mask_secret(key, &old_value), new: mask_secret(key, &text_value(value)) }
+            })
+            .collect()
+    }
+
+    // Keys and string values
+    fn stored_table(&self) -> Table {
+        let table = Table::try_from(self).unwrap_or_default();
+        table.iter().map(|(key, value)| (key.clone(), Value::String(text_value(value)))).collect()
+    }
+
+    // Stored format, upper case keys and string values with the version
+    pub fn to_toml(&self) -> String {
+        self.export_toml(false)
+    }
+
+    // Stored format without the secrets when redacted, an import of it keeps the secrets of the device
+    pub fn export_toml(&self, redact: bool) -> String {
+        let mut table = self.stored_table();
+        if redact {
+            table.retain(|key, _| !SECRET_KEYS.contains(&key));
+        }
+        table.insert(VERSION_KEY.to_string(), Value::String(CONFIG_VERSION.to_string()));
+        let stored: Table = table.into_iter().map(|(key, value)| (key.to_ascii_uppercase(), value)).collect();
+        toml::to_string(&stored).unwrap_or_default()
+    }
+
+    // Secrets store, upper case keys and string values of SECRET_KEYS
+    pub fn secrets_toml(&self) -> String {
+        let stored: Table = self.stored_table().into_iter()
+            .filter(|(key, _)| SECRET_KEYS.contains(&key.as_str()))
+            .map(|(key, value)| (key.to_ascii_uppercase(), value))
+            .collect();
+        toml::to_string(&stored).unwrap_or_default()
+    }
+
+    // "set" or "not set" of SECRET_KEYS, the values are not shown
+    pub fn secret_states(&self) -> Vec<(&'static str, &'static str)> {
+        let table = self.stored_table();
+        SECRET_KEYS.iter()
+            .map(|key| (*key, secret_state(&table.get(*key).map(text_value).unwrap_or_default())))
+            .collect()
+    }
+
+    // Secrets from the secrets store, the other keys are ignored. The invalid values are not set and they are
+    // returned as the errors.
+    pub fn set_secrets(&mut self, text: &str) -> Result<Vec<ConfigError>, anyhow::Error> {
+        let stored = lower_case_keys(parse_table(text)?).into_iter()
+            .filter(|(key, _)| SECRET_KEYS.contains(&key.as_str()))
+            .collect();
+        let (config, errors) = ConfigData::from_table(stored, self)?;
+        *self = config;
+        Ok(errors)
+    }
+
+    // Errors of the values out of range, empty when the configuration is valid
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if self.wifi_ssid.len() > MAX_SSID_LEN {
+            errors.push(ConfigError::new("ssid", &format!("Longer than {} bytes", MAX_SSID_LEN)));
+        }
+        if !self.wifi_psk.is_empty() && !PSK_LEN_RANGE.contains(&self.wifi_psk.len()) {
+            errors.push(ConfigError::new("psk", &format!("Length must be {} to {}", PSK_LEN_RANGE.start(), PSK_LEN_RANGE.end())));
+        }
+        if !ACCEPTABLE_RESOLUTIONS.iter().any(|(_, value)| *value == self.resolution) {
+            errors.push(ConfigError::new("resolution", "Not a resolution of the camera"));
+        }
+        if self.track_id > MAX_TRACK_ID {
+            errors.push(ConfigError::new("trackid", &format!("Must be {} or less", MAX_TRACK_ID)));
+        }
+        if let Err(e) = TimeZone::parse(&self.timezone) {
+            errors.push(ConfigError::new("timezone", &e.to_string()));
+        }
+        // the schedule is used when the duration is 0
+        if self.duration == 0 && !self.schedule.trim().is_empty() {
+            if let Err(e) = CronSchedule::parse(&self.schedule) {
+                errors.push(ConfigError::new("schedule", &e.to_string()));
+            }
+        }
+        if self.capture_frames_at_once < -1 {
+            errors.push(ConfigError::new("captureframesatonce", "Must be -1 (movie) or more"));
+        }
+        if !JPEG_QUALITY_RANGE.contains(&self.jpeg_quality) {
+            errors.push(ConfigError::new("jpegquality", &out_of_range(&JPEG_QUALITY_RANGE)));
+        }
+        if !SEGMENT_SIZE_RANGE.contains(&self.segment_size) {
+            errors.push(ConfigError::new("segmentsize", &out_of_range(&SEGMENT_SIZE_RANGE)));
+        }
+        if self.thin_interval == 0 {
+            errors.push(ConfigError::new("thininterval", "Must be 1 or more"));
+        }
+        if !LATITUDE_RANGE.contains(&self.latitude) {
+            errors.push(ConfigError::new("latitude", &out_of_range(&LATITUDE_RANGE)));
+        }
+        if !LONGITUDE_RANGE.contains(&self.longitude) {
+            errors.push(ConfigError::new("longitude", &out_of_range(&LONGITUDE_RANGE)));
+        }
+        if !SUN_SCHEDULES.contains(&self.sun_schedule.as_str()) {
+            errors.push(ConfigError::new("sunschedule", &format!("Must be one of {}", SUN_SCHEDULES.join(", "))));
+        }
+        if !SUN_MARGIN_RANGE.contains(&self.sunrise_margin) {
+            errors.push(ConfigError::new("sunrisemargin", &out_of_range(&SUN_MARGIN_RANGE)));
+        }
+        if !SUN_MARGIN_RANGE.contains(&self.sunset_margin) {
+            errors.push(ConfigError::new("sunsetmargin", &out_of_range(&SUN_MARGIN_RANGE)));
+        }
+        if MissedPolicy::parse(&self.missed_policy).is_none() {
+            errors.push(ConfigError::new("missedpolicy", "Must be one of align, skip, catchup"));
+        }
+        for (key, hash) in [("adminpasswordhash", &self.admin_password_hash), ("viewerpasswordhash", &self.viewer_password_hash)] {
+            if !hash.is_empty() && !is_password_hash(hash) {
+                errors.push(ConfigError::new(key, "Not a password hash"));
+            }
+        }
+        errors
+    }
+}
@@ -0,0 +1,112 @@
+// HTTP helpers to serve a single frame: byte ranges, ETag and Last-Modified
+
+use chrono::{DateTime, Utc};
+use std::ops::Range;
+
+use crate::imagefiles::FrameInfo;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ByteRange {
+    Full,                   // no Range header, or a header which is ignored (e.g. multiple ranges)
+    Partial(Range<u64>),    // 206 Partial Content
+    Unsatisfiable,          // 416 Range Not Satisfiable
+}
+
+// Parse the Range header (bytes=0-99, bytes=100-, bytes=-100) of a content of size bytes.
+// Only a single range is supported, the other forms send the whole content.
+pub fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) => spec.trim(),
+        None => return ByteRange::Full,
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+    let range = if start.is_empty() {
+        // last N bytes
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => size.saturating_sub(suffix)..size,
+            Err(_) => return ByteRange::Full,
+        }
+    }
+    else {
+        let start = match start.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return ByteRange::Full,
+        };
+        let end = if end.is_empty() {
+            size
+        }
+        else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(size),
+                _ => return ByteRange::Full,
+            }
+        };
+        start..end
+    };
+    if range.start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(range)
+}
+
+// Content-Range header of the partial response
+pub fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+// Strong ETag of the frame. The frame number changes by the deletion, so the ETag is made of
+// the capture time and the size. Frames without the capture time (v1) use the frame number instead.
+pub fn frame_etag(track_id: u32, frame: u32, info: &FrameInfo, size: usize) -> String {
+    if info.capture_time > 0 {
+        format!("\"T{}-{:x}-{:x}\"", track_id, info.capture_time, size)
+    }
+    else {
+        format!("\"T{}-f{}-{:x}\"", track_id, frame, size)
+    }
+}
+
+// HTTP date (IMF-fixdate) of UTC milliseconds, None if unknown
+pub fn http_date(time_ms: i64) -> Option<String> {
+    if time_ms <= 0 {
+        return None;
+    }
+    let time = DateTime::<Utc>::from_timestamp_millis(time_ms)?;
+    Some(time.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn parse_http_date(date: &str) -> Option<i64> {
+    DateTime::parse_from_rfc2822(date.trim()).ok().map(|t| t.timestamp_millis())
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag == etag || tag.strip_prefix("W/") == Some(etag))
+}
+
+// 304 Not Modified by If-None-Match, or If-Modified-Since when If-None-Match is not given
+pub fn not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>, etag: &str, last_modified_ms: i64) -> bool {
+    if let Some(header) = if_none_match {
+        return etag_matches(header, etag);
+    }
+    match (if_modified_since.and_then(parse_http_date), last_modified_ms > 0) {
+        // Last-Modified has the precision of seconds
+        (Some(since), true) => last_modified_ms / 1000 <= since / 1000,
+        _ => false,
+    }
+}
+
+// If-Range keeps the range only for the same ETag
+pub fn if_range_matches(if_range: Option<&str>, etag: &str) -> bool {
+    match if_range {
+        Some(tag) => tag.trim() == etag,
+        None => true,
+    }
+}
@@ -0,0 +1,1777 @@
+use anyhow;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{Write, Read, Seek};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const IMAGE_HEADER_SIZE: usize = 8;
+const FRAME_HEADER_SIZE: usize = 32;
+const MAX_QUEUE_SIZE: usize = 4 * 1024 * 1024;
+const MAX_TEMP_BUF_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum OpenMode {
+    Read,
+    Write,
+    Append,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WriteThreadStatus {
+    NotStarted,
+    Running,
+    Stopped,
+}
+
+// Focus result of the frame (0: focused, 0xFF: not checked)
+pub const FOCUS_UNKNOWN: u8 = 0xFF;
+
+// Metadata of the frame stored in the FRME record header
+#[derive(Debug, PartialEq, Clone)]
+pub struct FrameInfo {
+    pub capture_time: i64,          // UTC milliseconds since epoch (0: unknown)
+    pub width: u16,
+    pub height: u16,
+    pub jpeg_quality: u8,
+    pub focus: u8,
+    pub battery_voltage: u16,       // mV
+    pub temperature: i16,           // 0.01 degree Celsius
+    pub flags: u16,
+}
+
+impl Default for FrameInfo {
+    fn default() -> Self {
+        FrameInfo {
+            capture_time: 0,
+            width: 0,
+            height: 0,
+            jpeg_quality: 0,
+            focus: FOCUS_UNKNOWN,
+            battery_voltage: 0,
+            temperature: 0,
+            flags: 0,
+        }
+    }
+}
+
+impl FrameInfo {
+    pub fn capture_system_time(&self) -> Option<std::time::SystemTime> {
+        if self.capture_time <= 0 {
+            return None;
+        }
+        Some(std::time::UNIX_EPOCH + Duration::from_millis(self.capture_time as u64))
+    }
+
+    pub fn battery_voltage_volts(&self) -> f32 {
+        self.battery_voltage as f32 / 1000.0
+    }
+
+    pub fn temperature_celsius(&self) -> f32 {
+        self.temperature as f32 / 100.0
+    }
+}
+
+// Record header read from the capture file or from the raw bytes of a segment
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecordHeader {
+    pub header_size: usize,
+    pub size: usize,                // image size
+    pub info: FrameInfo,
+    pub crc: Option<u32>,           // stored CRC32 of a FRME record with FRAME_FLAG_CRC32
+}
+
+impl RecordHeader {
+    pub fn record_size(&self) -> u64 {
+        (self.header_size + self.size) as u64
+    }
+}
+
+// Header size of the record by its magic, None if it is not a DATA or FRME record
+pub fn record_header_size(magic: &[u8]) -> Option<usize> {
+    if magic.len() < 4 {
+        return None;
+    }
+    if magic[0..4] == DATA_MAGIC {
+        return Some(IMAGE_HEADER_SIZE);
+    }
+    if magic[0..4] == FRAME_MAGIC {
+        return Some(FRAME_HEADER_SIZE);
+    }
+    None
+}
+
+// Decode the DATA or FRME record header at the top of the buffer
+pub fn decode_record_header(buf: &[u8]) -> Option<RecordHeader> {
+    let header_size = record_header_size(buf)?;
+    if buf.len() < header_size {
+        return None;
+    }
+    let size = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if header_size == IMAGE_HEADER_SIZE {
+        return Some(RecordHeader { header_size, size, info: FrameInfo::default(), crc: None });
+    }
+    let flags = u16::from_le_bytes(buf[26..28].try_into().unwrap());
+    Some(RecordHeader {
+        header_size,
+        size,
+        info: FrameInfo {
+            capture_time: i64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            width: u16::from_le_bytes(buf[16..18].try_into().unwrap()),
+            height: u16::from_le_bytes(buf[18..20].try_into().unwrap()),
+            jpeg_quality: buf[20],
+            focus: buf[21],
+            battery_voltage: u16::from_le_bytes(buf[22..24].try_into().unwrap()),
+            temperature: i16::from_le_bytes(buf[24..26].try_into().unwrap()),
+            flags,
+        },
+        crc: if flags & FRAME_FLAG_CRC32 != 0 {
+            Some(u32::from_le_bytes(buf[FRAME_CRC_OFFSET..FRAME_HEADER_SIZE].try_into().unwrap()))
+        } else {
+            None
+        },
+    })
+}
+
+// Check the stored checksum of the record. Records without a checksum are accepted.
+pub fn verify_record(header: &[u8], image: &[u8]) -> bool {
+    let crc = match decode_record_header(header) {
+        Some(RecordHeader { crc: Some(crc), .. }) => crc,
+        Some(_) => return true,
+        None => return false,
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[0..FRAME_CRC_OFFSET]);
+    hasher.update(image);
+    hasher.finalize() == crc
+}
+
+pub struct WriteImageQueue {
+    buffer: Vec<(FrameInfo, Box<[u8]>)>,
+    data_size: usize,
+    thread_status: WriteThreadStatus,
+    write_done: bool,
+    images: u32,
+    queue_len: usize,
+}
+
+pub struct WriteThread {
+    file_path: String,
+    open_mode: OpenMode,
+    direct_write_mode: bool,
+    image_file: Option<ImageFiles>,
+    buffer_is_full: bool,
+    drop_frames: u32,
+    segment_max_size: u64,
+    segment_max_frames: u32,
+    write_image_queue: Arc<Mutex<WriteImageQueue>>,
+}
+
+impl WriteThread {
+    pub fn new(filepath: String, open_mode: OpenMode, direct_write_mode: bool) -> WriteThread {
+        WriteThread {
+            file_path: filepath.clone(),
+            write_image_queue: Arc::new(Mutex::new(WriteImageQueue {
+                buffer: Vec::new(),
+                data_size: 0,
+                thread_status: WriteThreadStatus::NotStarted,
+                write_done: false,
+                images: 0,
+                queue_len: 0,
+            })),
+            open_mode: open_mode.clone(),
+            direct_write_mode,
+            image_file: Option::None,
+            buffer_is_full: false,
+            drop_frames: 0,
+            segment_max_size: 0,
+            segment_max_frames: 0,
+        }
+    }
+
+    // Segment rollover limit of the track (0: no limit), must be set before start()
+    pub fn set_segment_limit(&mut self, max_size: u64, max_frames: u32) {
+        self.segment_max_size = max_size;
+        self.segment_max_frames = max_frames;
+    }
+
+    pub fn start(&mut self) {
+        if self.direct_write_mode {
+            let mut image_file = match ImageFiles::new(&self.file_path, self.open_mode.clone()) {
+                Ok(img) => img,
+                Err(e) => {
+                    info!("Error: {:?}", e);
+                    return;
+                }
+            };
+            image_file.set_segment_limit(self.segment_max_size, self.segment_max_frames);
+            self.image_file = Some(image_file);
+            return; // Direct write mode does not need a thread
+        }
+        let write_image_queue = self.write_image_queue.clone();
+        let file_path = self.file_path.clone();
+        let open_mode = self.open_mode.clone();
+        let (segment_max_size, segment_max_frames) = (self.segment_max_size, self.segment_max_frames);
+        let _th = std::thread::spawn(move || {
+            info!("WriteThread start");
+            let mut image_file = match ImageFiles::new(file_path, open_mode) {
+                Ok(img) => img,
+                Err(e) => {
+                    info!("Error: {:?}", e);
+                    return;
+                }
+            };
+            image_file.set_segment_limit(segment_max_size, segment_max_frames);
+            let mut write_image_time : u128 = 0;
+
+            let mut data_count = 0;
+            let start_time = std::time::SystemTime::now();
+            loop {
+                let mut wiqlk = write_image_queue.lock().unwrap();
+                wiqlk.thread_status = WriteThreadStatus::Running;
+                if !wiqlk.buffer.is_empty() {
+                    let (frame_info, data) = wiqlk.buffer.remove(0);
+                    let data_size = data.len();
+                    wiqlk.data_size += data_size;
+                    wiqlk.queue_len -= data_size;
+                    // let remaining = wiqlk.buffer.len();
+                    drop(wiqlk);
+                    let write_time = std::time::SystemTime::now();
+                    let _ = image_file.write_image(&data, &frame_info);
+                    let elapsed_time = write_time.elapsed().unwrap().as_micros();
+                    write_image_time += elapsed_time;
+                    data_count += 1;
+                    continue;
+                } else if wiqlk.write_done {
+                    break;
+                }
+                else {
+                    drop(wiqlk);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            let _ = image_file.write_image_end();
+            let elapsed_time = start_time.elapsed().unwrap().as_millis();
+            let mut qlk = write_image_queue.lock().unwrap();
+            qlk.images = image_file.get_nof_images();
+            info!("WriteThread Write images: {} time: {:?}ms {:.2}MB/s Ave: {:.2}ms",
+                qlk.images, 
+                elapsed_time, 
+                qlk.data_size as f32 / elapsed_time as f32 / 1024.0,
+                write_image_time as f32 / data_count as f32 / 1000.0);
+            qlk.thread_status = WriteThreadStatus::Stopped;
+            info!("WriteThread end");
+        });
+    }
+
+    pub fn push_data(&mut self, data: &[u8], frame_info: &FrameInfo) {
+        let mut wiqlk = self.write_image_queue.lock().unwrap();
+        if self.direct_write_mode {
+            let _ = self.image_file.as_mut().unwrap().write_image(data, frame_info);
+            return;
+        }
+        // delayed write, check the queue size
+        if wiqlk.queue_len + data.len() > MAX_QUEUE_SIZE {
+            self.drop_frames += 1;
+            if !self.buffer_is_full {
+                info!("WriteThread Queue is full: {:.2}KB {} Bufs.", wiqlk.queue_len / 1024, wiqlk.buffer.len());
+                self.buffer_is_full = true;
+            }
+            return;
+        }
+        if self.buffer_is_full && data.is_empty() {
+            self.buffer_is_full = false;
+        }
+        wiqlk.queue_len += data.len();
+        let binding = data.to_vec().into_boxed_slice();
+        wiqlk.buffer.push((frame_info.clone(), binding));
+    }
+
+    pub fn stop(&mut self) {
+        let mut wiqlk = self.write_image_queue.lock().unwrap();
+        if self.direct_write_mode {
+            let _ = self.image_file.as_mut().unwrap().write_image_end();
+            self.image_file.as_mut().unwrap().flush();
+            wiqlk.images = self.image_file.as_ref().unwrap().get_nof_images();
+        }
+        wiqlk.write_done = true;
+        info!("Drop Frames: {}", self.drop_frames);
+    }
+
+    pub fn get_nof_images(&self) -> u32 {
+        let wiqlk = self.write_image_queue.lock().unwrap();
+        wiqlk.images
+    }
+    
+    pub fn wait_thread(&self) {
+        loop {
+            let wiqlk = self.write_image_queue.lock().unwrap();
+            if self.direct_write_mode {
+                break;
+            }
+            if wiqlk.thread_status == WriteThreadStatus::Stopped {
+                break;
+            }
+            drop(wiqlk);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// One capture file (segment) of the track
+struct SegmentFile {
+    file: fs::File,
+    version: u32,
+    index_path: PathBuf,
+    index_entries: u32,
+    index_pending: Vec<u64>,
+    nimages: u32, 
+    read_pos: u64,
+    write_pos: u64,
+    total_size: u64,
+    total_copy_time: u128,
+    total_write_time: u128,
+    write_count: u32,
+    last_image_pos: u64,
+}
+
+// TCAM capture file format
+//
+// v1: 24 bytes file header followed by DATA records and an END marker.
+//     Frames can only be located by walking the DATA records from the top of the file.
+// v2: Same layout as v1 with the magic "TCM2". The offset of every record is kept in
+//     the sidecar index file (capture.idx) next to the capture file, so any frame can be
+//     located in constant time. v1 files are upgraded to v2 when they are opened to append.
+//     New frames are written as FRME records. DATA records of v1 are still readable.
+//
+// File header:
+//   0..4   Magic "TCAM"(v1) / "TCM2"(v2)
+//   4..8   Total number of images
+//   8..16  Total size of images
+//   16..24 Last Image Offset
+//
+// DATA record (v1):
+//   0..4   Magic "DATA"
+//   4..8   Size of image
+//   8..    JPEG image
+//
+// FRME record:
+//   0..4   Magic "FRME"
+//   4..8   Size of image
+//   8..16  Capture time (UTC milliseconds since epoch)
+//   16..18 Width
+//   18..20 Height
+//   20     JPEG quality
+//   21     Focus result (0: focused, 0xFF: not checked)
+//   22..24 Battery voltage (mV)
+//   24..26 Temperature (0.01 degree Celsius)
+//   26..28 Flags (0x8000: CRC32 is valid)
+//   28..32 CRC32 of 0..28 and the image
+//   32..   JPEG image
+//
+// Index file (capture.idx):
+//   0..4   Magic "TIDX"
+//   4..8   Format version
+//   8..    Offset of the DATA record of each frame (u64, little endian)
+pub const FORMAT_VERSION: u32 = 2;
+
+const FILE_HEADER_SIZE: usize = 24;
+const FILE_MAGIC_V1: [u8; 4] = [b'T', b'C', b'A', b'M'];
+const FILE_MAGIC_V2: [u8; 4] = [b'T', b'C', b'M', b'2'];
+const FILE_HEADER: [u8; FILE_HEADER_SIZE] = [b'T', b'C', b'M', b'2',
+                               0, 0, 0, 0,                  // Total number of images
+                               0, 0, 0, 0, 0, 0, 0, 0,      // Total size of images
+                               0, 0, 0, 0, 0, 0, 0, 0,      // Last Image Offset
+                               ];
+
+const DATA_MAGIC: [u8; 4] = [b'D', b'A', b'T', b'A'];
+const FRAME_MAGIC: [u8; 4] = [b'F', b'R', b'M', b'E'];
+const END_MAGIC: [u8; 4] = [b'E', b'N', b'D', 0];
+const END_RECORD: [u8; IMAGE_HEADER_SIZE] = [b'E', b'N', b'D', 0, 0, 0, 0, 0];
+
+// FRME flags
+// CRC32 of the record header (0..28) and the image is stored at the offset 28
+pub const FRAME_FLAG_CRC32: u16 = 0x8000;
+const FRAME_CRC_OFFSET: usize = 28;
+
+const INDEX_HEADER_SIZE: usize = 8;
+const INDEX_ENTRY_SIZE: usize = 8;
+const INDEX_HEADER: [u8; INDEX_HEADER_SIZE] = [b'T', b'I', b'D', b'X',
+                               FORMAT_VERSION as u8, 0, 0, 0,   // Format version
+                               ];
+
+// Update the file header. It is the commit point of the written images.
+fn write_file_header(file: &mut fs::File, version: u32, nimages: u32, total_size: u64, last_image_pos: u64) -> Result<(), anyhow::Error> {
+    let mut header = FILE_HEADER;
+    if version == 1 {
+        header[0..4].copy_from_slice(&FILE_MAGIC_V1);
+    }
+    header[4..8].copy_from_slice(&nimages.to_le_bytes());
+    header[8..16].copy_from_slice(&total_size.to_le_bytes());
+    header[16..24].copy_from_slice(&last_image_pos.to_le_bytes());
+    file.seek(std::io::SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    Ok(())
+}
+
+// capture.dat -> capture.idx
+pub fn index_file_path(file_path: impl AsRef<Path>) -> PathBuf {
+    file_path.as_ref().with_extension("idx")
+}
+
+impl SegmentFile {
+    pub fn new(directory: impl AsRef<Path>, mode: OpenMode) -> Result<SegmentFile, anyhow::Error> {
+        let file = match mode {
+            // Open the file for writing only
+            // Old images are discarded, so that stale records are never taken as new ones at recovery
+            OpenMode::Write => fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(directory.as_ref()),
+            OpenMode::Append => fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(directory.as_ref()),
+            OpenMode::Read => fs::OpenOptions::new()
+                .read(true)
+                .create(false)
+                .open(directory.as_ref()), 
+        };
+        match file {
+            Ok(mut file) => {
+                let mut header : [u8; FILE_HEADER_SIZE] = [0; FILE_HEADER_SIZE];
+                let _ = file.read(&mut header);
+                let mut total_size : u64 = 0;
+                let mut nimages : u32 = 0;
+                let mut last_image_pos : u64 = 0;
+                let mut version = FORMAT_VERSION;
+                let mut renew_header = mode == OpenMode::Write;
+                if !renew_header {
+                    if header[0..4] == FILE_MAGIC_V1 {
+                        version = 1;
+                    }
+                    else if header[0..4] != FILE_MAGIC_V2 {
+                        if mode == OpenMode::Read {
+                            info!("Invalid file header: {:?}", header);
+                            return Err(anyhow::Error::msg("Invalid file header"));
+                        }
+                        info!("Invalid file header: {:?} => Rewrite Header", header);
+                        renew_header = true;
+                    }
+                }
+                if renew_header {
+                    // renew the file header
+                    let _ = file.seek(std::io::SeekFrom::Start(0));
+                    let _ = file.write_all(&FILE_HEADER);
+                }
+                else {
+                    nimages = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                    total_size = u64::from_le_bytes(header[8..16].try_into().unwrap());
+                    last_image_pos = u64::from_le_bytes(header[16..24].try_into().unwrap());
+                }
+                let read_pos = FILE_HEADER.len() as u64;
+                let write_pos = match mode {
+                    OpenMode::Append => total_size + read_pos,
+                    _ => read_pos,
+                };
+                let mut image_files = SegmentFile {
+                    file,
+                    version,
+                    index_path: index_file_path(directory.as_ref()),
+                    index_entries: 0,
+                    index_pending: Vec::new(),
+                    nimages,
+                    read_pos,
+                    write_pos,
+                    total_size,
+                    total_copy_time: 0,
+                    total_write_time: 0,
+                    write_count: 0,
+                    last_image_pos,
+                };
+                image_files.open_index(renew_header, mode)?;
+                Ok(image_files)
+            }
+            Err(e) => {
+                info!("Failed to open file: {:?}", e);
+                Err(anyhow::Error::msg("Failed to open file"))
+            }
+        }
+    }
+
+    // Prepare the frame index for the opened file.
+    // Read mode never modifies the files. If the index is not usable, seek_image() falls back to walking the records.
+    fn open_index(&mut self, renew: bool, mode: OpenMode) -> Result<(), anyhow::Error> {
+        if renew {
+            // new (or broken) capture file, start with an empty index
+            let mut index_file = fs::File::create(&self.index_path)?;
+            index_file.write_all(&INDEX_HEADER)?;
+            self.index_entries = 0;
+            return Ok(());
+        }
+        if self.version == 1 {
+            if mode == OpenMode::Read {
+                return Ok(());
+            }
+            // lazy upgrade: build the index of the existing frames and mark the file as v2
+            info!("Upgrade capture file to v{}: {} images", FORMAT_VERSION, self.nimages);
+            let offsets = self.scan_offsets(FILE_HEADER_SIZE as u64, self.nimages)?;
+            let mut index_file = fs::File::create(&self.index_path)?;
+            index_file.write_all(&INDEX_HEADER)?;
+            self.index_entries = 0;
+            self.append_index(&offsets)?;
+            self.file.seek(std::io::SeekFrom::Start(0))?;
+            self.file.write_all(&FILE_MAGIC_V2)?;
+            self.version = FORMAT_VERSION;
+            return Ok(());
+        }
+        let index_size = match fs::metadata(&self.index_path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let entries = if index_size >= INDEX_HEADER_SIZE as u64 {
+            ((index_size - INDEX_HEADER_SIZE as u64) / INDEX_ENTRY_SIZE as u64) as u32
+        } else {
+            0
+        };
+        if mode == OpenMode::Read {
+            self.index_entries = entries;
+            return Ok(());
+        }
+        if index_size < INDEX_HEADER_SIZE as u64 {
+            let mut index_file = fs::File::create(&self.index_path)?;
+            index_file.write_all(&INDEX_HEADER)?;
+        }
+        if entries > self.nimages {
+            // entries of frames that were not committed to the file header
+            let index_file = fs::OpenOptions::new().write(true).open(&self.index_path)?;
+            index_file.set_len((INDEX_HEADER_SIZE + self.nimages as usize * INDEX_ENTRY_SIZE) as u64)?;
+            self.index_entries = self.nimages;
+        }
+        else if entries < self.nimages {
+            // index is behind the file header, index the missing frames
+            info!("Rebuild index: {}/{} images", entries, self.nimages);
+            self.index_entries = entries;
+            let start_pos = match entries {
+                0 => FILE_HEADER_SIZE as u64,
+                _ => {
+                    let pos = self.read_index_entry(entries - 1).ok_or(anyhow::Error::msg("Failed to read index"))?;
+                    self.read_pos = pos;
+                    let header = self.read_record_header().ok_or(anyhow::Error::msg("Failed to get image size at Index"))?;
+                    pos + header.record_size()
+                },
+            };
+            let offsets = self.scan_offsets(start_pos, self.nimages - entries)?;
+            self.append_index(&offsets)?;
+            self.read_pos = FILE_HEADER_SIZE as u64;
+        }
+        else {
+            self.index_entries = entries;
+        }
+        Ok(())
+    }
+
+    // Walk the DATA records from start_pos and return the offsets of count images
+    fn scan_offsets(&mut self, start_pos: u64, count: u32) -> Result<Vec<u64>, anyhow::Error> {
+        let save_read_pos = self.read_pos;
+        let mut offsets = Vec::with_capacity(count as usize);
+        self.read_pos = start_pos;
+        for _ in 0..count {
+            let header = match self.read_record_header() {
+                Some(header) => header,
+                None => {
+                    self.read_pos = save_read_pos;
+                    return Err(anyhow::Error::msg("Failed to get image size at Scan"));
+                }
+            };
+            offsets.push(self.read_pos);
+            self.read_pos += header.record_size();
+        }
+        self.read_pos = save_read_pos;
+        Ok(offsets)
+    }
+
+    fn append_index(&mut self, offsets: &[u64]) -> Result<(), anyhow::Error> {
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let mut entries = Vec::with_capacity(offsets.len() * INDEX_ENTRY_SIZE);
+        for offset in offsets {
+            entries.extend_from_slice(&offset.to_le_bytes());
+        }
+        let mut index_file = fs::OpenOptions::new().write(true).open(&self.index_path)?;
+        index_file.seek(std::io::SeekFrom::Start((INDEX_HEADER_SIZE + self.index_entries as usize * INDEX_ENTRY_SIZE) as u64))?;
+        index_file.write_all(&entries)?;
+        self.index_entries += offsets.len() as u32;
+        Ok(())
+    }
+
+    fn read_index_entry(&self, frame: u32) -> Option<u64> {
+        if self.version < 2 || frame >= self.index_entries {
+            return None;
+        }
+        let mut index_file = fs::File::open(&self.index_path).ok()?;
+        index_file.seek(std::io::SeekFrom::Start((INDEX_HEADER_SIZE + frame as usize * INDEX_ENTRY_SIZE) as u64)).ok()?;
+        let mut entry : [u8; INDEX_ENTRY_SIZE] = [0; INDEX_ENTRY_SIZE];
+        index_file.read_exact(&mut entry).ok()?;
+        Some(u64::from_le_bytes(entry))
+    }
+
+    #[allow(dead_code)]
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        if self.read_pos >= self.total_size + FILE_HEADER.len() as u64 {
+            return 0;
+        }
+        self.file.seek(std::io::SeekFrom::Start(self.read_pos)).unwrap();
+        let bytes_read = self.file.read(buffer).unwrap();
+        self.read_pos += bytes_read as u64;
+        bytes_read
+    }
+
+    #[allow(dead_code)]
+    pub fn write(&mut self, buffer: &[u8]) {
+        self.file.seek(std::io::SeekFrom::Start(self.write_pos)).unwrap();
+        self.file.write_all(buffer).unwrap();
+        self.write_pos += buffer.len() as u64;
+    }
+
+    #[allow(dead_code)]
+    pub fn seek(&mut self, pos: u64) {
+        self.read_pos = pos;
+    }
+
+    #[allow(dead_code)]
+    pub fn flush(&mut self) {
+        self.file.flush().unwrap();
+    }
+
+    pub fn write_image(&mut self, buffer: &[u8], frame_info: &FrameInfo) -> Result<(), anyhow::Error> {
+        let size = buffer.len();
+        let mut header : [u8; FRAME_HEADER_SIZE] = [0; FRAME_HEADER_SIZE];
+        header[0..4].copy_from_slice(&FRAME_MAGIC);
+        header[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+        header[8..16].copy_from_slice(&frame_info.capture_time.to_le_bytes());
+        header[16..18].copy_from_slice(&frame_info.width.to_le_bytes());
+        header[18..20].copy_from_slice(&frame_info.height.to_le_bytes());
+        header[20] = frame_info.jpeg_quality;
+        header[21] = frame_info.focus;
+        header[22..24].copy_from_slice(&frame_info.battery_voltage.to_le_bytes());
+        header[24..26].copy_from_slice(&frame_info.temperature.to_le_bytes());
+        header[26..28].copy_from_slice(&(frame_info.flags | FRAME_FLAG_CRC32).to_le_bytes());
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&header[0..FRAME_CRC_OFFSET]);
+        let save_write_pos = self.write_pos;
+        self.file.seek(std::io::SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(&header)?;
+        // Write the image data
+        // To copy the buffer to the temporary buffer, it is faster than writing directly.
+        // When data is written to the eMMC, the data read from the SPIRAM is slow.
+        // Workaround: Copy the data to the temporary buffer and write it to the eMMC. 
+        let mut cp = 0;
+        let mut data_size = size;
+        let mut tmp_buf : [u8; MAX_TEMP_BUF_SIZE] = [0; MAX_TEMP_BUF_SIZE];
+        loop {
+            let write_size: usize;
+            if data_size > MAX_TEMP_BUF_SIZE {
+                write_size = MAX_TEMP_BUF_SIZE;
+                let copy_start = std::time::Instant::now();
+                tmp_buf.copy_from_slice(&buffer[cp..(cp+write_size)]);
+                crc.update(&tmp_buf);
+                self.total_copy_time += copy_start.elapsed().as_micros();
+                let write_start = std::time::Instant::now();
+                self.file.write_all(&tmp_buf)?;
+                self.total_write_time += write_start.elapsed().as_micros();
+            }
+            else {
+                write_size = data_size;
+                let copy_start = std::time::Instant::now();
+                let (l, _) = tmp_buf.split_at_mut(write_size);
+                l.copy_from_slice(&buffer[cp..(cp+write_size)]);
+                crc.update(l);
+                self.total_copy_time += copy_start.elapsed().as_micros();
+                let write_start = std::time::Instant::now();
+                self.file.write_all(l)?;
+                self.total_write_time += write_start.elapsed().as_micros();
+            }
+            // info!("WriteThread data_size: {} Write size: {:?}bytes {}..{}", data_size, write_size, cp, cp+write_size);
+            data_size -= write_size;
+            cp += write_size;
+            if data_size == 0 {
+                break;
+            }
+        }
+        // The checksum is written after the image data. A torn record never has a valid checksum.
+        self.file.seek(std::io::SeekFrom::Start(save_write_pos + FRAME_CRC_OFFSET as u64))?;
+        self.file.write_all(&crc.finalize().to_le_bytes())?;
+        // let write_start = std::time::Instant::now();
+        // self.file.write(&buffer)?;
+        // self.total_write_time += write_start.elapsed().as_micros();
+        self.write_count += 1;
+        self.write_pos += (size + FRAME_HEADER_SIZE) as u64;
+        self.total_size += (size + FRAME_HEADER_SIZE) as u64;
+        self.last_image_pos = save_write_pos;
+        self.index_pending.push(save_write_pos);
+        self.nimages += 1;
+        Ok(())
+    }
+
+    pub fn write_image_end(&mut self) -> Result<(), anyhow::Error> {
+        self.file.seek(std::io::SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(&END_RECORD)?;
+        self.write_pos += IMAGE_HEADER_SIZE as u64;
+        // Append the offsets of new images to the index before the file header refers to them
+        let pending = std::mem::take(&mut self.index_pending);
+        self.append_index(&pending)?;
+        // Images must be on the media before the file header commits them
+        self.file.sync_data()?;
+        write_file_header(&mut self.file, self.version, self.nimages, self.total_size, self.last_image_pos)?;
+        self.file.sync_data()?;
+        info!("WriteThread Ave Copy time: {:.2}ms Ave Write time: {:.2}ms", 
+            self.total_copy_time as f32 / self.write_count as f32 / 1000.0, 
+            self.total_write_time as f32 / self.write_count as f32 / 1000.0);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_image_size(&mut self) -> usize {
+        match self.read_record_header() {
+            Some(header) => header.size,
+            None => 0,
+        }
+    }
+
+    // Read the record header at the read position. DATA and FRME records are accepted.
+    fn read_record_header(&mut self) -> Option<RecordHeader> {
+        if self.read_pos >= self.total_size + FILE_HEADER.len() as u64 {
+            return None;
+        }
+        let _ = self.file.seek(std::io::SeekFrom::Start(self.read_pos));
+        let mut header : [u8; FRAME_HEADER_SIZE] = [0; FRAME_HEADER_SIZE];
+        let hsize = match self.file.read(&mut header[0..IMAGE_HEADER_SIZE]){
+            Ok(hsize) => hsize,
+            Err(e) => {
+                info!("Failed to read header: {:?}", e);
+                return None;
+            }
+        };
+
+        if hsize != IMAGE_HEADER_SIZE {
+            info!("Failed to read header size: {:?}byte", hsize);
+            return None;
+        }
+        let header_size = match record_header_size(&header) {
+            Some(header_size) => header_size,
+            None => {
+                if header[0..4] != END_MAGIC {
+                    info!("Invalid Header {:?}", &header[0..IMAGE_HEADER_SIZE]);
+                }
+                return None;
+            }
+        };
+        if header_size == FRAME_HEADER_SIZE {
+            match self.file.read_exact(&mut header[IMAGE_HEADER_SIZE..FRAME_HEADER_SIZE]) {
+                Ok(_) => (),
+                Err(e) => {
+                    info!("Failed to read frame header: {:?}", e);
+                    return None;
+                }
+            }
+        }
+        decode_record_header(&header[0..header_size])
+    }
+
+    // Get the record header at the read position without moving it
+    #[allow(dead_code)]
+    pub fn get_record_header(&mut self) -> Result<RecordHeader, anyhow::Error> {
+        self.read_record_header().ok_or(anyhow::Error::msg("Failed to get record header"))
+    }
+
+    // Get the metadata of the image at the read position without moving it
+    #[allow(dead_code)]
+    pub fn get_frame_info(&mut self) -> Result<FrameInfo, anyhow::Error> {
+        match self.read_record_header() {
+            Some(header) => Ok(header.info),
+            None => Err(anyhow::Error::msg("Failed to get frame info")),
+        }
+    }
+
+    // Read the image and its metadata, then move to the next image
+    pub fn read_frame(&mut self) -> Result<(FrameInfo, Vec<u8>), anyhow::Error> {
+        let header = match self.read_record_header() {
+            Some(header) if header.size > 0 => header,
+            _ => return Err(anyhow::Error::msg("Failed to get image size at Read")),
+        };
+        let size = header.size;
+        let mut buffer = vec![0; size];
+        let _ = self.file.seek(std::io::SeekFrom::Start(self.read_pos + header.header_size as u64));
+        match self.file.read_exact(&mut buffer[0..size]){
+            Ok(_) => (),
+            Err(e) => {
+                info!("Failed to read image: {:?}", e);
+                return Err(anyhow::Error::msg("Failed to read image"));
+            }
+        };
+        self.read_pos += header.record_size();
+        Ok((header.info, buffer))
+    }
+
+    pub fn seek_image(&mut self, from_frame: u32) -> Result<(), anyhow::Error> {
+        if from_frame >= self.nimages {
+            return Err(anyhow::Error::msg("Frame out of range at Seek"));
+        }
+        if from_frame == self.nimages - 1 {
+            self.read_pos = self.last_image_pos;
+            return Ok(());
+        }
+        if let Some(pos) = self.read_index_entry(from_frame) {
+            self.read_pos = pos;
+            return Ok(());
+        }
+        // no index (v1 file), walk the records from the top of the file
+        self.read_pos = FILE_HEADER.len() as u64;
+        for _ in 0..from_frame {
+            let header = self.read_record_header().ok_or(anyhow::Error::msg("Failed to get image size at Seek"))?;
+            self.read_pos += header.record_size();
+        }
+        Ok(())
+    }
+
+    // Move to the next image without reading the image data
+    #[allow(dead_code)]
+    pub fn skip_image(&mut self) -> Result<(), anyhow::Error> {
+        let header = self.read_record_header().ok_or(anyhow::Error::msg("Failed to get image size at Skip"))?;
+        self.read_pos += header.record_size();
+        Ok(())
+    }
+
+    pub fn get_nof_images(&self) -> u32 {
+        self.nimages
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    // Offset of the record at the read position
+    #[allow(dead_code)]
+    pub fn get_image_offset(&self) -> u64 {
+        self.read_pos
+    }
+
+    #[allow(dead_code)]
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+}
+
+// Segments of a track
+//
+// The track is split into segment files to stay below the 4GB file size limit of FAT.
+//   T{n}/capture.dat       segment 0
+//   T{n}/capture-0001.dat  segment 1
+//   T{n}/capture-0002.dat  segment 2 ...
+// Each segment is a complete capture file with its own file header and index.
+// ImageFiles presents the segments as one continuous frame sequence.
+
+// Largest segment allowed on FAT (4GB - 1) with some margin
+pub const MAX_SEGMENT_SIZE: u64 = 0xFFF0_0000;
+
+// capture.dat -> capture-0001.dat
+pub fn segment_file_path(file_path: impl AsRef<Path>, segment: u32) -> PathBuf {
+    let file_path = file_path.as_ref();
+    if segment == 0 {
+        return file_path.to_path_buf();
+    }
+    let stem = file_path.file_stem().and_then(|s| s.to_str()).unwrap_or("capture");
+    let name = match file_path.extension().and_then(|s| s.to_str()) {
+        Some(ext) => format!("{}-{:04}.{}", stem, segment, ext),
+        None => format!("{}-{:04}", stem, segment),
+    };
+    file_path.with_file_name(name)
+}
+
+// Existing segment files of the track
+pub fn segment_file_paths(file_path: impl AsRef<Path>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    loop {
+        let path = segment_file_path(file_path.as_ref(), paths.len() as u32);
+        if !path.is_file() {
+            break;
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+// Read the image count and the total size from the file header of the segment
+fn read_segment_header(file_path: &Path) -> Result<(u32, u64), anyhow::Error> {
+    let mut file = fs::File::open(file_path)?;
+    let mut header : [u8; FILE_HEADER_SIZE] = [0; FILE_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    if header[0..4] != FILE_MAGIC_V1 && header[0..4] != FILE_MAGIC_V2 {
+        return Err(anyhow::Error::msg("Invalid file header"));
+    }
+    let nimages = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let total_size = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    Ok((nimages, total_size))
+}
+
+struct Segment {
+    path: PathBuf,
+    first_frame: u32,
+    nimages: u32,
+    total_size: u64,
+}
+
+pub struct ImageFiles {
+    base_path: PathBuf,
+    mode: OpenMode,
+    segments: Vec<Segment>,
+    current: SegmentFile,
+    current_segment: usize,
+    read_frame: u32,
+    nimages: u32,
+    segment_max_size: u64,
+    segment_max_frames: u32,
+}
+
+impl ImageFiles {
+    pub fn new(directory: impl AsRef<Path>, mode: OpenMode) -> Result<ImageFiles, anyhow::Error> {
+        let base_path = directory.as_ref().to_path_buf();
+        let mut segments = Vec::new();
+        let mut nimages = 0;
+        if mode == OpenMode::Write {
+            // start again from capture.dat
+            for path in segment_file_paths(&base_path).iter().skip(1) {
+                delete_file(path);
+                delete_file(&index_file_path(path));
+            }
+        }
+        else {
+            for path in segment_file_paths(&base_path) {
+                let (segment_images, total_size) = match read_segment_header(&path) {
+                    Ok(header) => header,
+                    Err(e) if mode == OpenMode::Read => return Err(e),
+                    // broken segment is renewed when it is opened to append
+                    Err(_) => (0, 0),
+                };
+                segments.push(Segment {
+                    path,
+                    first_frame: nimages,
+                    nimages: segment_images,
+                    total_size,
+                });
+                nimages += segment_images;
+            }
+        }
+        if segments.is_empty() {
+            segments.push(Segment {
+                path: base_path.clone(),
+                first_frame: 0,
+                nimages: 0,
+                total_size: 0,
+            });
+        }
+        // read from the first segment, write to the last segment
+        let current_segment = match mode {
+            OpenMode::Read => 0,
+            _ => segments.len() - 1,
+        };
+        let current = SegmentFile::new(&segments[current_segment].path, mode.clone())?;
+        if mode != OpenMode::Read {
+            let segment = &mut segments[current_segment];
+            nimages = segment.first_frame + current.get_nof_images();
+            segment.nimages = current.get_nof_images();
+            segment.total_size = current.get_total_size();
+        }
+        Ok(ImageFiles {
+            base_path,
+            mode,
+            segments,
+            current,
+            current_segment,
+            read_frame: 0,
+            nimages,
+            segment_max_size: MAX_SEGMENT_SIZE,
+            segment_max_frames: 0,
+        })
+    }
+
+    // Roll over to the next segment at max_size bytes or max_frames images (0: no limit)
+    pub fn set_segment_limit(&mut self, max_size: u64, max_frames: u32) {
+        self.segment_max_size = if max_size == 0 { MAX_SEGMENT_SIZE } else { max_size.min(MAX_SEGMENT_SIZE) };
+        self.segment_max_frames = max_frames;
+    }
+
+    pub fn write_image(&mut self, buffer: &[u8], frame_info: &FrameInfo) -> Result<(), anyhow::Error> {
+        let segment_images = self.current.get_nof_images();
+        let segment_size = (FILE_HEADER_SIZE + FRAME_HEADER_SIZE + IMAGE_HEADER_SIZE + buffer.len()) as u64 + self.current.get_total_size();
+        if segment_images > 0 &&
+           (segment_size > self.segment_max_size ||
+            (self.segment_max_frames > 0 && segment_images >= self.segment_max_frames)) {
+            self.next_segment()?;
+        }
+        self.current.write_image(buffer, frame_info)?;
+        let segment = &mut self.segments[self.current_segment];
+        segment.nimages = self.current.get_nof_images();
+        segment.total_size = self.current.get_total_size();
+        self.nimages += 1;
+        Ok(())
+    }
+
+    // Close the current segment and continue to write in the next one
+    fn next_segment(&mut self) -> Result<(), anyhow::Error> {
+        self.current.write_image_end()?;
+        let number = self.segments.len() as u32;
+        let path = segment_file_path(&self.base_path, number);
+        info!("Roll over to segment {:?}", path);
+        self.current = SegmentFile::new(&path, OpenMode::Write)?;
+        self.segments.push(Segment {
+            path,
+            first_frame: self.nimages,
+            nimages: 0,
+            total_size: 0,
+        });
+        self.current_segment = self.segments.len() - 1;
+        Ok(())
+    }
+
+    pub fn write_image_end(&mut self) -> Result<(), anyhow::Error> {
+        self.current.write_image_end()
+    }
+
+    #[allow(dead_code)]
+    pub fn flush(&mut self) {
+        self.current.flush();
+    }
+
+    // Open the segment which has the frame, and move to the frame
+    pub fn seek_image(&mut self, from_frame: u32) -> Result<(), anyhow::Error> {
+        if from_frame >= self.nimages {
+            return Err(anyhow::Error::msg("Frame out of range at Seek"));
+        }
+        let segment = self.segments.partition_point(|s| s.first_frame + s.nimages <= from_frame);
+        self.open_segment(segment)?;
+        self.current.seek_image(from_frame - self.segments[segment].first_frame)?;
+        self.read_frame = from_frame;
+        Ok(())
+    }
+
+    fn open_segment(&mut self, segment: usize) -> Result<(), anyhow::Error> {
+        if segment == self.current_segment {
+            return Ok(());
+        }
+        if self.mode != OpenMode::Read {
+            return Err(anyhow::Error::msg("Segment can be changed in Read mode only"));
+        }
+        self.current = SegmentFile::new(&self.segments[segment].path, OpenMode::Read)?;
+        self.current_segment = segment;
+        Ok(())
+    }
+
+    // Move to the next segment when all images of the current segment were read
+    fn prepare_read(&mut self) -> Result<(), anyhow::Error> {
+        if self.read_frame >= self.nimages {
+            return Err(anyhow::Error::msg("No more images"));
+        }
+        let segment = &self.segments[self.current_segment];
+        if self.read_frame >= segment.first_frame + segment.nimages {
+            let read_frame = self.read_frame;
+            let next = self.segments.partition_point(|s| s.first_frame + s.nimages <= read_frame);
+            self.open_segment(next)?;
+            self.current.seek_image(read_frame - self.segments[next].first_frame)?;
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_image_size(&mut self) -> usize {
+        match self.prepare_read() {
+            Ok(_) => self.current.get_image_size(),
+            Err(_) => 0,
+        }
+    }
+
+    // Get the metadata of the image at the read position without moving it
+    #[allow(dead_code)]
+    pub fn get_frame_info(&mut self) -> Result<FrameInfo, anyhow::Error> {
+        self.prepare_read()?;
+        self.current.get_frame_info()
+    }
+
+    // Get the record header at the read position without moving it
+    #[allow(dead_code)]
+    pub fn get_record_header(&mut self) -> Result<RecordHeader, anyhow::Error> {
+        self.prepare_read()?;
+        self.current.get_record_header()
+    }
+
+    pub fn read_image(&mut self) -> Result<Vec<u8>, anyhow::Error> {
+        let (_, buffer) = self.read_frame()?;
+        Ok(buffer)
+    }
+
+    // Read the image and its metadata, then move to the next image
+    pub fn read_frame(&mut self) -> Result<(FrameInfo, Vec<u8>), anyhow::Error> {
+        self.prepare_read()?;
+        let frame = self.current.read_frame()?;
+        self.read_frame += 1;
+        Ok(frame)
+    }
+
+    // Move to the next image without reading the image data
+    #[allow(dead_code)]
+    pub fn skip_image(&mut self) -> Result<(), anyhow::Error> {
+        self.prepare_read()?;
+        self.current.skip_image()?;
+        self.read_frame += 1;
+        Ok(())
+    }
+
+    pub fn get_nof_images(&self) -> u32 {
+        self.nimages
+    }
+
+    // Total size of images in all segments
+    #[allow(dead_code)]
+    pub fn get_total_size(&self) -> u64 {
+        self.segments.iter().map(|s| s.total_size).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_nof_segments(&self) -> u32 {
+        self.segments.len() as u32
+    }
+
+    // Segment number of the image at the read position
+    #[allow(dead_code)]
+    pub fn get_segment(&self) -> u32 {
+        self.current_segment as u32
+    }
+
+    // Offset of the record at the read position in the segment file
+    #[allow(dead_code)]
+    pub fn get_image_offset(&self) -> u64 {
+        self.current.get_image_offset()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_version(&self) -> u32 {
+        self.current.get_version()
+    }
+}
+
+// Result of the recovery scan of a capture file
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RecoveryReport {
+    pub frames: u32,                // images in the file after the recovery
+    pub recovered_frames: u32,      // images found after the committed file header
+    pub dropped_bytes: u64,         // bytes of partial or broken records removed from the tail
+    pub checksum_errors: Vec<u32>,  // frame numbers kept with a checksum mismatch, good frames follow them
+    pub repaired: bool,             // file header, end marker or index was rewritten
+}
+
+// Result of the record check
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RecordCheck {
+    Valid(u64, bool),       // record size, checksum was verified
+    ChecksumError(u64),     // record size
+    Invalid,                // partial record, broken header or end of the records
+}
+
+// Check the record at pos. A record is valid if it is complete and its checksum matches.
+fn check_record(file: &mut fs::File, pos: u64, file_len: u64, verify_checksum: bool) -> RecordCheck {
+    let mut header : [u8; FRAME_HEADER_SIZE] = [0; FRAME_HEADER_SIZE];
+    if pos + IMAGE_HEADER_SIZE as u64 > file_len ||
+       file.seek(std::io::SeekFrom::Start(pos)).is_err() ||
+       file.read_exact(&mut header[0..IMAGE_HEADER_SIZE]).is_err() {
+        return RecordCheck::Invalid;
+    }
+    let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+    if header[0..4] == DATA_MAGIC {
+        // v1 record has no checksum
+        if size == 0 || pos + IMAGE_HEADER_SIZE as u64 + size > file_len {
+            return RecordCheck::Invalid;
+        }
+        return RecordCheck::Valid(IMAGE_HEADER_SIZE as u64 + size, false);
+    }
+    if header[0..4] != FRAME_MAGIC || size == 0 || pos + FRAME_HEADER_SIZE as u64 + size > file_len ||
+       file.read_exact(&mut header[IMAGE_HEADER_SIZE..FRAME_HEADER_SIZE]).is_err() {
+        return RecordCheck::Invalid;
+    }
+    let record_size = FRAME_HEADER_SIZE as u64 + size;
+    let flags = u16::from_le_bytes(header[26..28].try_into().unwrap());
+    if !verify_checksum || flags & FRAME_FLAG_CRC32 == 0 {
+        return RecordCheck::Valid(record_size, false);
+    }
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&header[0..FRAME_CRC_OFFSET]);
+    let mut buf : [u8; MAX_TEMP_BUF_SIZE] = [0; MAX_TEMP_BUF_SIZE];
+    let mut remain = size as usize;
+    while remain > 0 {
+        let len = remain.min(MAX_TEMP_BUF_SIZE);
+        if file.read_exact(&mut buf[0..len]).is_err() {
+            return RecordCheck::Invalid;
+        }
+        crc.update(&buf[0..len]);
+        remain -= len;
+    }
+    let stored = u32::from_le_bytes(header[FRAME_CRC_OFFSET..FRAME_HEADER_SIZE].try_into().unwrap());
+    if crc.finalize() != stored {
+        info!("Checksum mismatch at {}", pos);
+        return RecordCheck::ChecksumError(record_size);
+    }
+    RecordCheck::Valid(record_size, true)
+}
+
+// Bring the capture file back to a consistent state after a power loss or a reset during capturing.
+// Complete records after the committed file header are taken in, the partial trailing record is truncated,
+// and the end marker, the file header and the index are rewritten. A record with a checksum mismatch is kept
+// when complete records follow it (a damaged frame), the mismatches at the tail are a torn write and truncated.
+pub fn recover_file(file_path: impl AsRef<Path>) -> Result<RecoveryReport, anyhow::Error> {
+    let file_path = file_path.as_ref();
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(file_path)?;
+    let file_len = file.metadata()?.len();
+    let mut report = RecoveryReport::default();
+    let mut header : [u8; FILE_HEADER_SIZE] = [0; FILE_HEADER_SIZE];
+    if file_len < FILE_HEADER_SIZE as u64 {
+        // the file was created but the header was not written
+        info!("Recovery: {:?} has no file header => Initialize", file_path);
+        file.set_len(0)?;
+        write_file_header(&mut file, FORMAT_VERSION, 0, 0, 0)?;
+        file.write_all(&END_RECORD)?;
+        file.sync_all()?;
+        let mut index_file = fs::File::create(index_file_path(file_path))?;
+        index_file.write_all(&INDEX_HEADER)?;
+        report.repaired = true;
+        return Ok(report);
+    }
+    file.read_exact(&mut header)?;
+    let version = if header[0..4] == FILE_MAGIC_V1 {
+        1
+    }
+    else if header[0..4] == FILE_MAGIC_V2 {
+        2
+    }
+    else {
+        info!("Recovery: Invalid file header: {:?}", header);
+        return Err(anyhow::Error::msg("Invalid file header"));
+    };
+    let nimages = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let total_size = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let last_image_pos = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    let committed_end = FILE_HEADER_SIZE as u64 + total_size;
+
+    // The committed part is trusted if the last image ends at the committed size.
+    // Its checksum is not verified here to keep the mount time short.
+    let committed_valid = if nimages == 0 {
+        total_size == 0
+    }
+    else {
+        committed_end <= file_len &&
+        last_image_pos >= FILE_HEADER_SIZE as u64 &&
+        check_record(&mut file, last_image_pos, file_len, false) == RecordCheck::Valid(committed_end - last_image_pos, false)
+    };
+    let (mut pos, mut frames, mut last_pos) = if committed_valid {
+        (committed_end, nimages, last_image_pos)
+    }
+    else {
+        info!("Recovery: {:?} file header is broken => Rescan all images", file_path);
+        (FILE_HEADER_SIZE as u64, 0, 0)
+    };
+    // Take in the complete records after the committed part
+    let (mut scan_pos, mut scan_frames) = (pos, frames);
+    let mut damaged = Vec::new();
+    loop {
+        match check_record(&mut file, scan_pos, file_len, true) {
+            RecordCheck::Valid(record_size, _) => {
+                report.checksum_errors.append(&mut damaged);
+                last_pos = scan_pos;
+                scan_pos += record_size;
+                scan_frames += 1;
+                pos = scan_pos;
+                frames = scan_frames;
+            },
+            RecordCheck::ChecksumError(record_size) => {
+                // kept only if a good record follows
+                damaged.push(scan_frames);
+                scan_pos += record_size;
+                scan_frames += 1;
+            },
+            RecordCheck::Invalid => break,
+        }
+    }
+    if !report.checksum_errors.is_empty() {
+        info!("Recovery: {:?} checksum errors in frames {:?}", file_path, report.checksum_errors);
+    }
+    let mut end_marker : [u8; IMAGE_HEADER_SIZE] = [0; IMAGE_HEADER_SIZE];
+    let has_end_marker = pos + IMAGE_HEADER_SIZE as u64 <= file_len &&
+        file.seek(std::io::SeekFrom::Start(pos)).is_ok() &&
+        file.read_exact(&mut end_marker).is_ok() &&
+        end_marker == END_RECORD;
+    let index_entries = match fs::metadata(index_file_path(file_path)) {
+        Ok(metadata) if metadata.len() >= INDEX_HEADER_SIZE as u64 => ((metadata.len() - INDEX_HEADER_SIZE as u64) / INDEX_ENTRY_SIZE as u64) as u32,
+        _ => 0,
+    };
+    report.frames = frames;
+    report.recovered_frames = frames.saturating_sub(if committed_valid { nimages } else { 0 });
+    report.dropped_bytes = file_len - pos - if has_end_marker { IMAGE_HEADER_SIZE as u64 } else { 0 };
+    if committed_valid && frames == nimages && has_end_marker && report.dropped_bytes == 0 &&
+        (version == 1 || index_entries == frames) {
+        return Ok(report);
+    }
+    info!("Recovery: {:?} images: {} -> {} dropped: {}bytes", file_path, nimages, frames, report.dropped_bytes);
+    report.repaired = true;
+    // Rewrite the end marker and commit the images
+    file.set_len(pos)?;
+    file.seek(std::io::SeekFrom::Start(pos))?;
+    file.write_all(&END_RECORD)?;
+    file.sync_data()?;
+    write_file_header(&mut file, version, frames, pos - FILE_HEADER_SIZE as u64, last_pos)?;
+    file.sync_all()?;
+    drop(file);
+    if version == 1 {
+        // v1 has no index
+        return Ok(report);
+    }
+    // Keep the index entries of the trusted part only, the rest is indexed again by scanning the records
+    let keep_entries = if committed_valid { index_entries.min(nimages).min(frames) } else { 0 };
+    let index_path = index_file_path(file_path);
+    if fs::metadata(&index_path).map(|m| m.len() < INDEX_HEADER_SIZE as u64).unwrap_or(true) {
+        let mut index_file = fs::File::create(&index_path)?;
+        index_file.write_all(&INDEX_HEADER)?;
+    }
+    let index_file = fs::OpenOptions::new().write(true).open(&index_path)?;
+    index_file.set_len((INDEX_HEADER_SIZE + keep_entries as usize * INDEX_ENTRY_SIZE) as u64)?;
+    drop(index_file);
+    let _ = SegmentFile::new(file_path, OpenMode::Append)?;
+    Ok(report)
+}
+
+// Result of the integrity check of a capture file
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct VerifyReport {
+    pub version: u32,
+    pub header_frames: u32,         // images committed by the file header
+    pub header_total_size: u64,
+    pub frames: u32,                // complete records found in the file
+    pub verified_frames: u32,       // records with a valid checksum
+    pub checksum_errors: Vec<u32>,  // frame numbers with a checksum mismatch
+    pub records_size: u64,          // size of the complete records
+    pub end_marker: bool,           // end marker follows the last record
+    pub trailing_bytes: u64,        // bytes after the records (and the end marker)
+    pub index_entries: Option<u32>, // None: v1 or no index file
+    pub index_errors: Vec<u32>,     // frame numbers whose index entry does not point to the record
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.checksum_errors.is_empty() &&
+        self.header_frames == self.frames &&
+        self.header_total_size == self.records_size &&
+        self.end_marker &&
+        self.trailing_bytes == 0 &&
+        self.index_errors.is_empty() &&
+        self.index_entries.map_or(self.version == 1, |entries| entries == self.frames)
+    }
+}
+
+// Walk all records of the capture file and check the checksum, the file header and the index.
+// The file is not modified.
+pub fn verify_file(file_path: impl AsRef<Path>) -> Result<VerifyReport, anyhow::Error> {
+    let file_path = file_path.as_ref();
+    let mut file = fs::File::open(file_path)?;
+    let file_len = file.metadata()?.len();
+    let mut header : [u8; FILE_HEADER_SIZE] = [0; FILE_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    let mut report = VerifyReport {
+        version: if header[0..4] == FILE_MAGIC_V1 {
+            1
+        }
+        else if header[0..4] == FILE_MAGIC_V2 {
+            2
+        }
+        else {
+            return Err(anyhow::Error::msg("Invalid file header"));
+        },
+        header_frames: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        header_total_size: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+        ..Default::default()
+    };
+    let mut offsets = Vec::new();
+    let mut pos = FILE_HEADER_SIZE as u64;
+    loop {
+        let record_size = match check_record(&mut file, pos, file_len, true) {
+            RecordCheck::Valid(record_size, verified) => {
+                if verified {
+                    report.verified_frames += 1;
+                }
+                record_size
+            },
+            RecordCheck::ChecksumError(record_size) => {
+                report.checksum_errors.push(report.frames);
+                record_size
+            },
+            RecordCheck::Invalid => break,
+        };
+        offsets.push(pos);
+        pos += record_size;
+        report.frames += 1;
+    }
+    report.records_size = pos - FILE_HEADER_SIZE as u64;
+    let mut end_marker : [u8; IMAGE_HEADER_SIZE] = [0; IMAGE_HEADER_SIZE];
+    report.end_marker = pos + IMAGE_HEADER_SIZE as u64 <= file_len &&
+        file.seek(std::io::SeekFrom::Start(pos)).is_ok() &&
+        file.read_exact(&mut end_marker).is_ok() &&
+        end_marker == END_RECORD;
+    report.trailing_bytes = file_len - pos - if report.end_marker { IMAGE_HEADER_SIZE as u64 } else { 0 };
+    if report.version == 1 {
+        return Ok(report);
+    }
+    let index = match fs::read(index_file_path(file_path)) {
+        Ok(index) if index.len() >= INDEX_HEADER_SIZE && index[0..4] == INDEX_HEADER[0..4] => index,
+        _ => return Ok(report),
+    };
+    let entries = (index.len() - INDEX_HEADER_SIZE) / INDEX_ENTRY_SIZE;
+    report.index_entries = Some(entries as u32);
+    for (frame, offset) in offsets.iter().enumerate().take(entries) {
+        let entry = INDEX_HEADER_SIZE + frame * INDEX_ENTRY_SIZE;
+        if u64::from_le_bytes(index[entry..entry + INDEX_ENTRY_SIZE].try_into().unwrap()) != *offset {
+            report.index_errors.push(frame as u32);
+        }
+    }
+    Ok(report)
+}
+
+// Result of removing frames from a track
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct CompactReport {
+    pub frames: u32,                // images in the track after the compaction
+    pub removed_frames: u32,
+    pub rewritten_segments: u32,
+    pub reclaimed_bytes: u64,       // decrease of the file size (capture files and indexes)
+}
+
+// Suffix of the segment being rewritten: capture-0001.dat -> capture-0001.dat.tmp (index: capture-0001.dat.idx)
+const COMPACT_SUFFIX: &str = ".tmp";
+
+fn compact_file_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.file_name().unwrap_or_default().to_os_string();
+    name.push(COMPACT_SUFFIX);
+    file_path.with_file_name(name)
+}
+
+fn file_size(file_path: &Path) -> u64 {
+    fs::metadata(file_path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn remove_if_exists(file_path: &Path) -> Result<(), anyhow::Error> {
+    match fs::remove_file(file_path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Sorted and merged ranges within 0..nimages
+fn normalize_ranges(ranges: &[std::ops::Range<u32>], nimages: u32) -> Vec<std::ops::Range<u32>> {
+    let mut sorted: Vec<std::ops::Range<u32>> = ranges.iter()
+        .map(|r| r.start.min(nimages)..r.end.min(nimages))
+        .filter(|r| r.start < r.end)
+        .collect();
+    sorted.sort_by_key(|r| r.start);
+    let mut merged: Vec<std::ops::Range<u32>> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Remove the frames in the ranges from the track.
+// Each segment which has removed frames is copied to capture-NNNN.dat.tmp without them, then swapped with the original.
+// The free space needed is the size of one segment. A segment may become empty, but it is kept to keep the numbering.
+pub fn remove_frames(file_path: impl AsRef<Path>, ranges: &[std::ops::Range<u32>]) -> Result<CompactReport, anyhow::Error> {
+    let file_path = file_path.as_ref();
+    let images = ImageFiles::new(file_path, OpenMode::Read)?;
+    let ranges = normalize_ranges(ranges, images.get_nof_images());
+    let segments: Vec<(PathBuf, u32, u32)> = images.segments.iter()
+        .map(|s| (s.path.clone(), s.first_frame, s.nimages))
+        .collect();
+    let mut report = CompactReport {
+        frames: images.get_nof_images(),
+        ..Default::default()
+    };
+    drop(images);
+    for (path, first_frame, nimages) in segments {
+        let end_frame = first_frame + nimages;
+        let removed: Vec<std::ops::Range<u32>> = ranges.iter()
+            .filter(|r| r.start < end_frame && r.end > first_frame)
+            .map(|r| (r.start.max(first_frame) - first_frame)..(r.end.min(end_frame) - first_frame))
+            .collect();
+        if removed.is_empty() {
+            continue;
+        }
+        let before = file_size(&path) + file_size(&index_file_path(&path));
+        let removed_frames = rewrite_segment(&path, nimages, &removed)?;
+        let after = file_size(&path) + file_size(&index_file_path(&path));
+        info!("Compact {:?}: {} -> {} images", path, nimages, nimages - removed_frames);
+        report.removed_frames += removed_frames;
+        report.rewritten_segments += 1;
+        report.reclaimed_bytes += before.saturating_sub(after);
+    }
+    report.frames -= report.removed_frames;
+    Ok(report)
+}
+
+// Remove the frames from_frame..=to_frame from the track
+pub fn delete_frames(file_path: impl AsRef<Path>, from_frame: u32, to_frame: u32) -> Result<CompactReport, anyhow::Error> {
+    if to_frame < from_frame {
+        return Err(anyhow::anyhow!("Invalid frame range: {}..={}", from_frame, to_frame));
+    }
+    let range = from_frame..to_frame.saturating_add(1);
+    remove_frames(file_path, std::slice::from_ref(&range))
+}
+
+// Keep only the frames from_frame..=to_frame of the track
+pub fn trim_frames(file_path: impl AsRef<Path>, from_frame: u32, to_frame: u32) -> Result<CompactReport, anyhow::Error> {
+    if to_frame < from_frame {
+        return Err(anyhow::anyhow!("Invalid frame range: {}..={}", from_frame, to_frame));
+    }
+    remove_frames(file_path, &[0..from_frame, to_frame.saturating_add(1)..u32::MAX])
+}
+
+// Delete the capture files of the track (segments, indexes and the copies of an interrupted compaction).
+// The segments are deleted from the last one, so a reset leaves the first segments as a valid track.
+pub fn delete_track(file_path: impl AsRef<Path>) -> Result<u64, anyhow::Error> {
+    let file_path = file_path.as_ref();
+    let mut reclaimed_bytes = 0;
+    for path in segment_file_paths(file_path).iter().rev() {
+        for path in [compact_file_path(path), index_file_path(compact_file_path(path)), index_file_path(path), path.clone()] {
+            reclaimed_bytes += file_size(&path);
+            remove_if_exists(&path)?;
+        }
+    }
+    info!("Delete track {:?}: {} bytes", file_path, reclaimed_bytes);
+    Ok(reclaimed_bytes)
+}
+
+// Copy the images of the segment except the removed ones, and replace the segment with the copy
+fn rewrite_segment(file_path: &Path, nimages: u32, removed: &[std::ops::Range<u32>]) -> Result<u32, anyhow::Error> {
+    let compact_path = compact_file_path(file_path);
+    let mut source = SegmentFile::new(file_path, OpenMode::Read)?;
+    let mut compact = SegmentFile::new(&compact_path, OpenMode::Write)?;
+    let mut removed_frames = 0;
+    let mut need_seek = false;
+    let mut ranges = removed.iter().peekable();
+    for frame in 0..nimages {
+        while ranges.peek().is_some_and(|r| r.end <= frame) {
+            ranges.next();
+        }
+        if ranges.peek().is_some_and(|r| r.contains(&frame)) {
+            removed_frames += 1;
+            need_seek = true;
+            continue;
+        }
+        if need_seek {
+            source.seek_image(frame)?;
+            need_seek = false;
+        }
+        let (frame_info, buffer) = source.read_frame()?;
+        compact.write_image(&buffer, &frame_info)?;
+    }
+    compact.write_image_end()?;
+    drop(source);
+    drop(compact);
+    fs::File::open(index_file_path(&compact_path))?.sync_all()?;
+    // The copy is complete. From here the copy replaces the original, also at the recovery after a reset.
+    fs::remove_file(file_path)?;
+    commit_compaction(file_path)?;
+    Ok(removed_frames)
+}
+
+// Move the completed copy (and its index) to the segment
+fn commit_compaction(file_path: &Path) -> Result<(), anyhow::Error> {
+    let compact_path = compact_file_path(file_path);
+    if compact_path.is_file() {
+        fs::rename(&compact_path, file_path)?;
+    }
+    let compact_index_path = index_file_path(&compact_path);
+    if compact_index_path.is_file() {
+        remove_if_exists(&index_file_path(file_path))?;
+        fs::rename(&compact_index_path, index_file_path(file_path))?;
+    }
+    Ok(())
+}
+
+// Complete or discard the compaction interrupted by a reset.
+// If the original segment still exists the copy may be partial, so it is discarded.
+// Otherwise the copy was complete and it replaces the original.
+pub fn finish_compaction(directory: &Path) -> Result<u32, anyhow::Error> {
+    let mut finished = 0;
+    for entry in fs::read_dir(directory)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_name = match name.strip_suffix(COMPACT_SUFFIX) {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        let file_path = directory.join(file_name);
+        let compact_path = entry.path();
+        if file_path.is_file() {
+            info!("Discard incomplete compaction {:?}", compact_path);
+            remove_if_exists(&compact_path)?;
+            remove_if_exists(&index_file_path(&compact_path))?;
+        }
+        else {
+            info!("Complete compaction {:?}", file_path);
+            commit_compaction(&file_path)?;
+        }
+        finished += 1;
+    }
+    // the copy was moved, but its index was not
+    for entry in fs::read_dir(directory)?.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(stem) = name.strip_suffix(".dat.idx") {
+            let file_path = directory.join(format!("{}.dat", stem));
+            if !compact_file_path(&file_path).exists() {
+                remove_if_exists(&index_file_path(&file_path))?;
+                fs::rename(&path, index_file_path(&file_path))?;
+                finished += 1;
+            }
+        }
+    }
+    Ok(finished)
+}
+
+// Recover the capture files of all tracks in the directory (e.g. /eMMC/T0/capture.dat, /eMMC/T0/capture-0001.dat ...)
+pub fn recover_all(directory: &Path) -> Vec<(PathBuf, Result<RecoveryReport, anyhow::Error>)> {
+    let mut results = Vec::new();
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            info!("Failed to read directory: {:?}", e);
+            return results;
+        }
+    };
+    for entry in entries.flatten() {
+        if entry.path().is_dir() {
+            if let Err(e) = finish_compaction(&entry.path()) {
+                info!("Failed to finish compaction {:?}: {:?}", entry.path(), e);
+            }
+        }
+        for file_path in segment_file_paths(entry.path().join("capture.dat")) {
+            let result = recover_file(&file_path);
+            match &result {
+                Ok(report) if report.repaired => info!("Recovered {:?}: {:?}", file_path, report),
+                Ok(_) => (),
+                Err(e) => info!("Failed to recover {:?}: {:?}", file_path, e),
+            }
+            results.push((file_path, result));
+        }
+    }
+    results
+}
+
+// Read the Capture file
+#[allow(dead_code)]
+pub fn read_file(filepath: &Path) -> Vec<u8> {
+    let file = fs::File::open(filepath);
+    match file {
+        Ok(mut file) => {
+            let mut buffer = Vec::new();
+            match file.read_to_end(&mut buffer) {
+                Ok(_) => {
+                    info!("File read successfully {:?} {:?} bytes", filepath, buffer.len());
+                    buffer
+                }
+                Err(e) => {
+                    info!("Failed to read file: {:?}", e);
+                    Vec::new()
+                }
+            }
+        }
+        Err(e) => {
+            info!("Failed to open file: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Write the Capture file
+#[allow(dead_code)]
+pub fn write_file(file: &Path, data: &[u8]) {
+    // info!("Writing file: {:?}", file);
+    let file = fs::File::create(file);
+    match file {
+        Ok(mut file) => {
+            match file.write_all(data) {
+                Ok(_) => {
+                    // info!("File written successfully");
+                }
+                Err(e) => {
+                    info!("Failed to write file: {:?}", e);
+                }
+            }
+            // file.flush().unwrap();
+        }
+        Err(e) => {
+            info!("Failed to create file: {:?}", e);
+        }
+    }
+}
+
+// List files in the directory
+#[allow(dead_code)]
+pub fn list_files(directory: &Path ) {
+    // Create a file
+    let file = fs::read_dir(directory);
+    match file {
+        Ok(file) => {
+            for entry in file {
+                let entry = entry.unwrap();
+                info!("{:?} Len: {:?}", entry.path().display(), entry.metadata().unwrap().len());
+            }        
+        }
+        Err(e) => {
+            info!("Failed to open file: {:?}", e);
+        }
+    }
+}
+
+// Delete the Capture file
+#[allow(dead_code)]
+pub fn delete_file(file: &Path) {
+    let result = fs::remove_file(file);
+    match result {
+        Ok(_) => {
+            info!("File deleted successfully");
+        }
+        Err(e) => {
+            info!("Failed to delete file: {:?}", e);
+        }
+    }
+}
+
+// Delete all files in the directory
+#[allow(dead_code)]
+pub fn delete_all_files(directory: &Path) {
+    let result = fs::remove_dir_all(directory);
+    match result {
+        Ok(_) => {
+            info!("Files deleted successfully");
+        }
+        Err(e) => {
+            info!("Failed to delete files: {:?}", e);
+        }
+    }
+}
\ No newline at end of file
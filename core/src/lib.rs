@@ -0,0 +1,20 @@
+// Platform independent part of Time Leap Cam.
+// This crate is shared by the firmware and the host tools, and it is tested on the host.
+
+pub mod imagefiles;
+pub mod avi;
+pub mod archive;
+pub mod retention;
+pub mod tracks;
+pub mod http;
+pub mod sync;
+pub mod schedule;
+pub mod sun;
+pub mod timezone;
+pub mod capture_loop;
+pub mod rtc_state;
+pub mod config;
+pub mod secrets;
+pub mod auth;
+pub mod tls;
+pub mod api;
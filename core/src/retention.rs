@@ -0,0 +1,190 @@
+// Retention policy of the captured frames
+//
+// A track grows until the eMMC is full. The retention policy removes old frames after each capture session:
+//   keep_days         remove frames captured more than N days ago
+//   keep_frames       keep the last N frames
+//   thin_after_days   frames older than N days are thinned to one frame per thin_interval seconds
+// 0 disables each rule. Frames without a valid capture time (v1 records, clock not set) are kept by the time based rules.
+// Capture times are assumed to increase with the frame number.
+
+use anyhow;
+use log::info;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::imagefiles::{remove_frames, ImageFiles, OpenMode};
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+// Capture time before 2020-01-01 means the clock was not set
+pub const MIN_VALID_TIME: i64 = 1_577_836_800_000;
+
+// Compaction rewrites the segments, so it is done when at least 1/16 of the frames can be removed
+const MIN_REMOVE_RATIO: u32 = 16;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RetentionPolicy {
+    pub keep_days: u32,
+    pub keep_frames: u32,
+    pub thin_after_days: u32,
+    pub thin_interval: u32,         // seconds
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.keep_days > 0 || self.keep_frames > 0 || (self.thin_after_days > 0 && self.thin_interval > 0)
+    }
+
+    // Frames captured before this time may be removed by the time based rules (None: no time based rule)
+    fn time_limit(&self, now: i64) -> Option<i64> {
+        let mut limit = None;
+        if self.keep_days > 0 {
+            limit = Some(now - self.keep_days as i64 * DAY_MS);
+        }
+        if self.thin_after_days > 0 && self.thin_interval > 0 {
+            let thin_limit = now - self.thin_after_days as i64 * DAY_MS;
+            limit = Some(limit.map_or(thin_limit, |l: i64| l.max(thin_limit)));
+        }
+        limit
+    }
+}
+
+fn valid_time(capture_time: i64) -> bool {
+    capture_time >= MIN_VALID_TIME
+}
+
+// Frames to be removed by the policy as sorted ranges.
+// capture_times has the capture time (UTC ms) of the frames from 0. The frames after them are
+// newer than the time limits of the policy, so only keep_frames applies to them.
+pub fn frames_to_remove(policy: &RetentionPolicy, capture_times: &[i64], nimages: u32, now: i64) -> Vec<Range<u32>> {
+    let keep_from = if policy.keep_frames > 0 { nimages.saturating_sub(policy.keep_frames) } else { 0 };
+    let keep_limit = if policy.keep_days > 0 { Some(now - policy.keep_days as i64 * DAY_MS) } else { None };
+    let thin_limit = if policy.thin_after_days > 0 && policy.thin_interval > 0 {
+        Some(now - policy.thin_after_days as i64 * DAY_MS)
+    } else {
+        None
+    };
+    let interval = policy.thin_interval as i64 * 1000;
+    let mut last_slot = None;
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    let mut push = |frame: u32| {
+        match ranges.last_mut() {
+            Some(last) if last.end == frame => last.end += 1,
+            _ => ranges.push(frame..frame + 1),
+        }
+    };
+    let scanned = (capture_times.len() as u32).min(nimages);
+    for frame in 0..scanned {
+        let time = capture_times[frame as usize];
+        let remove = if frame < keep_from {
+            true
+        }
+        else if !valid_time(time) {
+            false
+        }
+        else if keep_limit.is_some_and(|limit| time < limit) {
+            true
+        }
+        else if thin_limit.is_some_and(|limit| time < limit) {
+            // keep the first frame of each interval
+            let slot = time.div_euclid(interval);
+            if last_slot == Some(slot) {
+                true
+            }
+            else {
+                last_slot = Some(slot);
+                false
+            }
+        }
+        else {
+            false
+        };
+        if remove {
+            push(frame);
+        }
+    }
+    if keep_from > scanned {
+        match ranges.last_mut() {
+            Some(last) if last.end == scanned => last.end = keep_from,
+            _ => ranges.push(scanned..keep_from),
+        }
+    }
+    ranges
+}
+
+// Result of the retention run of a track
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RetentionReport {
+    pub frames: u32,                // images in the track after the run
+    pub removable_frames: u32,      // frames selected by the policy
+    pub removed_frames: u32,        // frames actually removed (0 if the compaction was skipped)
+    pub reclaimed_bytes: u64,
+}
+
+fn system_time_ms(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(t) => t.as_millis() as i64,
+        Err(_) => 0,
+    }
+}
+
+fn frame_time(images: &mut ImageFiles, frame: u32) -> Result<i64, anyhow::Error> {
+    images.seek_image(frame)?;
+    Ok(images.get_frame_info()?.capture_time)
+}
+
+// Apply the policy to the track (T{n}/capture.dat and its segments).
+// The compaction is skipped if only a few frames would be removed, unless force is set (e.g. the eMMC is almost full).
+pub fn apply_retention(file_path: impl AsRef<Path>, policy: &RetentionPolicy, now: SystemTime, force: bool) -> Result<RetentionReport, anyhow::Error> {
+    let file_path = file_path.as_ref();
+    let mut report = RetentionReport::default();
+    if !policy.is_enabled() {
+        return Ok(report);
+    }
+    // Without the clock the time based rules remove nothing
+    let now = system_time_ms(now);
+    let mut images = ImageFiles::new(file_path, OpenMode::Read)?;
+    let nimages = images.get_nof_images();
+    report.frames = nimages;
+    // Frames before the time limit are at the top of the track, find the boundary by binary search
+    let mut scan_end = 0;
+    if let Some(limit) = policy.time_limit(now).filter(|_| valid_time(now)) {
+        let (mut low, mut high) = (0, nimages);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let time = frame_time(&mut images, mid)?;
+            if !valid_time(time) || time < limit {
+                low = mid + 1;
+            }
+            else {
+                high = mid;
+            }
+        }
+        scan_end = low;
+    }
+    let mut capture_times = Vec::with_capacity(scan_end as usize);
+    if scan_end > 0 {
+        images.seek_image(0)?;
+        for _ in 0..scan_end {
+            capture_times.push(images.get_frame_info()?.capture_time);
+            images.skip_image()?;
+        }
+    }
+    drop(images);
+    let ranges = frames_to_remove(policy, &capture_times, nimages, now);
+    report.removable_frames = ranges.iter().map(|r| r.end - r.start).sum();
+    if report.removable_frames == 0 {
+        return Ok(report);
+    }
+    if !force && report.removable_frames < nimages / MIN_REMOVE_RATIO {
+        info!("Retention: {} of {} frames removable, compaction is postponed", report.removable_frames, nimages);
+        return Ok(report);
+    }
+    let compact = remove_frames(file_path, &ranges)?;
+    info!("Retention: {:?} removed {} frames, reclaimed {} bytes", file_path, compact.removed_frames, compact.reclaimed_bytes);
+    report.frames = compact.frames;
+    report.removed_frames = compact.removed_frames;
+    report.reclaimed_bytes = compact.reclaimed_bytes;
+    Ok(report)
+}
@@ -0,0 +1,177 @@
+// State block in the RTC memory
+//
+// The state of the capture loop is kept over the deep sleep in one block of the RTC memory. The block has a magic
+// number, the layout version and the CRC32 of the fields, so a block left by the power on, a brown out or another
+// firmware version is not resumed: the capture waits for the touchpad or the auto capture setting instead.
+// The fields are ordered without padding, and the CRC32 is computed over the little endian bytes of the fields
+// before it.
+
+use anyhow;
+use crc32fast::Hasher;
+
+use crate::capture_loop::RetainedState;
+
+// "TLRS"
+pub const RTC_STATE_MAGIC: u32 = 0x53524c54;
+
+// Increment when the layout is changed
+pub const RTC_STATE_VERSION: u32 = 1;
+
+pub const RTC_STATE_SIZE: usize = 96;
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RtcStateBlock {
+    pub magic: u32,
+    pub version: u32,
+    pub next_capture_time: u64,
+    pub last_capture_time: u64,
+    pub last_posted_time: u64,
+    pub capture_start_time: u64,
+    pub capture_end_time: u64,
+    pub last_status_posted_time: u64,
+    pub last_missed_time: u64,
+    pub auto_capture: u32,
+    pub capture_id: u32,
+    pub duration: u32,
+    pub resolution: u32,
+    pub track_id: u32,
+    pub missed_captures: u32,
+    pub reserved: u32,
+    pub crc: u32,
+}
+
+impl RtcStateBlock {
+    // Initial value of the RTC memory, it is not valid
+    pub const ZERO: RtcStateBlock = RtcStateBlock {
+        magic: 0,
+        version: 0,
+        next_capture_time: 0,
+        last_capture_time: 0,
+        last_posted_time: 0,
+        capture_start_time: 0,
+        capture_end_time: 0,
+        last_status_posted_time: 0,
+        last_missed_time: 0,
+        auto_capture: 0,
+        capture_id: 0,
+        duration: 0,
+        resolution: 0,
+        track_id: 0,
+        missed_captures: 0,
+        reserved: 0,
+        crc: 0,
+    };
+
+    pub fn new(state: &RetainedState) -> RtcStateBlock {
+        let mut block = RtcStateBlock {
+            magic: RTC_STATE_MAGIC,
+            version: RTC_STATE_VERSION,
+            next_capture_time: state.next_capture_time,
+            last_capture_time: state.last_capture_time,
+            last_posted_time: state.last_posted_time,
+            capture_start_time: state.capture_start_time,
+            capture_end_time: state.capture_end_time,
+            last_status_posted_time: state.last_status_posted_time,
+            last_missed_time: state.last_missed_time,
+            auto_capture: state.auto_capture as u32,
+            capture_id: state.capture_id,
+            duration: state.duration,
+            resolution: state.resolution,
+            track_id: state.track_id,
+            missed_captures: state.missed_captures,
+            reserved: 0,
+            crc: 0,
+        };
+        block.crc = block.checksum();
+        block
+    }
+
+    // The retained state, an error when the block is not valid
+    pub fn load(&self) -> Result<RetainedState, anyhow::Error> {
+        if self.magic != RTC_STATE_MAGIC {
+            return Err(anyhow::anyhow!("No state in the RTC memory (magic {:08x})", self.magic));
+        }
+        if self.version != RTC_STATE_VERSION {
+            return Err(anyhow::anyhow!("RTC state version {} is not supported", self.version));
+        }
+        if self.crc != self.checksum() {
+            return Err(anyhow::anyhow!("RTC state checksum error {:08x} != {:08x}", self.crc, self.checksum()));
+        }
+        if self.auto_capture > 1 {
+            return Err(anyhow::anyhow!("Invalid RTC state auto capture {}", self.auto_capture));
+        }
+        Ok(RetainedState {
+            auto_capture: self.auto_capture == 1,
+            capture_id: self.capture_id,
+            next_capture_time: self.next_capture_time,
+            duration: self.duration,
+            resolution: self.resolution,
+            track_id: self.track_id,
+            last_capture_time: self.last_capture_time,
+            last_posted_time: self.last_posted_time,
+            capture_start_time: self.capture_start_time,
+            capture_end_time: self.capture_end_time,
+            last_status_posted_time: self.last_status_posted_time,
+            missed_captures: self.missed_captures,
+            last_missed_time: self.last_missed_time,
+        })
+    }
+
+    pub fn store(&mut self, state: &RetainedState) {
+        *self = RtcStateBlock::new(state);
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.update(&self.to_bytes()[..RTC_STATE_SIZE - 4]);
+        hasher.finalize()
+    }
+
+    pub fn to_bytes(&self) -> [u8; RTC_STATE_SIZE] {
+        let mut bytes = [0u8; RTC_STATE_SIZE];
+        let mut offset = 0;
+        let mut put = |value: &[u8]| {
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+            offset += value.len();
+        };
+        put(&self.magic.to_le_bytes());
+        put(&self.version.to_le_bytes());
+        for value in [self.next_capture_time, self.last_capture_time, self.last_posted_time, self.capture_start_time,
+                self.capture_end_time, self.last_status_posted_time, self.last_missed_time] {
+            put(&value.to_le_bytes());
+        }
+        for value in [self.auto_capture, self.capture_id, self.duration, self.resolution, self.track_id,
+                self.missed_captures, self.reserved, self.crc] {
+            put(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<RtcStateBlock> {
+        if bytes.len() != RTC_STATE_SIZE {
+            return None;
+        }
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Some(RtcStateBlock {
+            magic: u32_at(0),
+            version: u32_at(4),
+            next_capture_time: u64_at(8),
+            last_capture_time: u64_at(16),
+            last_posted_time: u64_at(24),
+            capture_start_time: u64_at(32),
+            capture_end_time: u64_at(40),
+            last_status_posted_time: u64_at(48),
+            last_missed_time: u64_at(56),
+            auto_capture: u32_at(64),
+            capture_id: u32_at(68),
+            duration: u32_at(72),
+            resolution: u32_at(76),
+            track_id: u32_at(80),
+            missed_captures: u32_at(84),
+            reserved: u32_at(88),
+            crc: u32_at(92),
+        })
+    }
+}
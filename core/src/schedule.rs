@@ -0,0 +1,412 @@
+// Capture schedule
+//
+// The capture is repeated every `interval` seconds, or at the times of a cron expression in the local time:
+//   minute hour day-of-month month day-of-week
+//   */10 6-17 * * MON-FRI     every 10 minutes from 06:00 to 17:50 on weekdays
+//   0 9,15 * * *              09:00 and 15:00 daily
+//   30 12 1 * *               12:30 on the 1st of every month
+// Each field is *, a number, a range (a-b), a list (a,b,c) or a step (*/n, a-b/n, a/n). Months and days of week
+// can be names (JAN-DEC, SUN-SAT), and 0 and 7 are Sunday. When both the day of month and the day of week are
+// restricted, a day matching either of them fires, else a day matching both (same as cron, a field starting with *
+// like */2 is not restricted). @hourly, @daily, @weekly, @monthly and @yearly are also accepted.
+// The capture window (start_time..end_time) limits both. The capture ends after the window.
+// When the device could not capture at the scheduled times (brown out, long WiFi or NTP wait), the missed times are
+// counted and the next time is chosen by the missed policy: skip them, capture once to catch up, or keep the next
+// time on the original interval.
+// With the daylight setting, the capture runs only from sunrise - X minutes to sunset + Y minutes of each local day
+// (a capture time in the night is moved to the next morning), or fires only at the morning and evening golden hour.
+
+use anyhow;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use std::str::FromStr;
+
+use crate::sun::{Daylight, DaylightMode};
+use crate::timezone::TimeZone;
+
+const MONTH_NAMES: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const WEEKDAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// The next fire time is searched up to this number of days (Feb 29 may be 8 years apart)
+const MAX_SEARCH_DAYS: i64 = 366 * 8 + 1;
+
+// The next daylight is searched up to this number of days (the polar night is shorter than a year)
+const MAX_DAYLIGHT_DAYS: i64 = 370;
+
+// A capture time may be late by this number of seconds, the earlier times are missed
+pub const MISSED_GRACE: i64 = 5;
+
+// Missed times counted one by one (cron expression and daylight)
+const MAX_MISSED_TIMES: u32 = 100_000;
+
+// Fire times in the repeated hour at the end of DST are skipped
+const MAX_REPEATED_MINUTES: usize = 2 * 60;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CronSchedule {
+    minutes: u64,           // bit n: minute n
+    hours: u64,
+    days: u64,              // bit 1-31
+    months: u64,            // bit 1-12
+    weekdays: u64,          // bit 0-6, 0: Sunday
+    day_restricted: bool,   // day of month does not start with *
+    weekday_restricted: bool,
+}
+
+// Bits of a field. names[0] is the value `min`.
+fn parse_field(field: &str, name: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, anyhow::Error> {
+    let value = |s: &str| -> Result<u32, anyhow::Error> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(n) = names.iter().position(|name| *name == upper) {
+            return Ok(min + n as u32);
+        }
+        let n: u32 = s.parse().map_err(|_| anyhow::anyhow!("Invalid {} field: {}", name, field))?;
+        if n < min || n > max {
+            return Err(anyhow::anyhow!("{} out of range ({}-{}): {}", name, min, max, field));
+        }
+        Ok(n)
+    };
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| anyhow::anyhow!("Invalid {} step: {}", name, field))?;
+                // the steps larger than the range are errors, they would overflow the loop
+                if step == 0 || step > max - min + 1 {
+                    return Err(anyhow::anyhow!("Invalid {} step: {}", name, field));
+                }
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (value(first)?, value(last)?)
+        } else {
+            // a/n is from a to the max
+            let first = value(range)?;
+            (first, if step.is_some() { max } else { first })
+        };
+        if first > last {
+            return Err(anyhow::anyhow!("Invalid {} range: {}", name, field));
+        }
+        let mut n = first;
+        while n <= last {
+            bits |= 1 << n;
+            n += step.unwrap_or(1);
+        }
+    }
+    // 7 is also Sunday
+    if names.len() == 7 && bits & (1 << 7) != 0 {
+        bits = (bits & !(1 << 7)) | 1;
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<CronSchedule, anyhow::Error> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow::anyhow!("Cron expression needs 5 fields (minute hour day month weekday): {}", expression));
+        }
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], "minute", 0, 59, &[])?,
+            hours: parse_field(fields[1], "hour", 0, 23, &[])?,
+            days: parse_field(fields[2], "day", 1, 31, &[])?,
+            months: parse_field(fields[3], "month", 1, 12, &MONTH_NAMES)?,
+            weekdays: parse_field(fields[4], "weekday", 0, 7, &WEEKDAY_NAMES)?,
+            day_restricted: !fields[2].starts_with('*'),
+            weekday_restricted: !fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        if self.day_restricted && self.weekday_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    pub fn matches(&self, time: NaiveDateTime) -> bool {
+        self.matches_date(time.date()) && self.hours & (1 << time.hour()) != 0 && self.minutes & (1 << time.minute()) != 0
+    }
+
+    // The first fire time after the time, None if it never fires (e.g. Feb 30)
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut date = start.date();
+        let mut first_minute = start.hour() * 60 + start.minute();
+        for _ in 0..MAX_SEARCH_DAYS {
+            if self.matches_date(date) {
+                for minute in first_minute..24 * 60 {
+                    if self.hours & (1 << (minute / 60)) != 0 && self.minutes & (1 << (minute % 60)) != 0 {
+                        return date.and_hms_opt(minute / 60, minute % 60, 0);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            first_minute = 0;
+        }
+        None
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        CronSchedule::parse(expression)
+    }
+}
+
+// Cron expression of the old day/hour/minute setting (-1: not set), None if none is set
+//   day, hour, minute -> monthly, hour, minute -> daily, minute -> hourly
+pub fn leap_time_expression(day: i32, hour: i32, minute: i32) -> Option<String> {
+    if day > 0 {
+        return Some(format!("{} {} {} * *", minute.max(0), hour.max(0), day));
+    }
+    if hour >= 0 {
+        return Some(format!("{} {} * * *", minute.max(0), hour));
+    }
+    if minute > 0 {
+        return Some(format!("{} * * * *", minute));
+    }
+    None
+}
+
+// Scheduled times which are passed without a capture
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum MissedPolicy {
+    Skip,           // no capture for the missed times, the interval restarts from now
+    CatchUp,        // capture once now, the interval restarts from now
+    #[default]
+    Align,          // no capture for the missed times, the next time is on the original interval
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct NextCapture {
+    pub time: i64,                      // UTC seconds
+    pub missed: u32,                    // scheduled times missed after the last capture
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CaptureSchedule {
+    pub interval: u32,                  // seconds, 0: by the cron expression
+    pub cron: Option<CronSchedule>,
+    pub timezone: TimeZone,
+    pub start_time: i64,                // UTC seconds, the window is used when start_time < end_time
+    pub end_time: i64,
+    pub daylight: Option<Daylight>,
+    pub missed_policy: MissedPolicy,
+}
+
+impl CaptureSchedule {
+    // expression is used when interval is 0, an empty expression captures continuously
+    pub fn new(interval: u32, expression: &str, timezone: TimeZone, start_time: i64, end_time: i64) -> Result<CaptureSchedule, anyhow::Error> {
+        let cron = if interval == 0 && !expression.trim().is_empty() {
+            Some(CronSchedule::parse(expression)?)
+        } else {
+            None
+        };
+        Ok(CaptureSchedule { interval, cron, timezone, start_time, end_time, daylight: None, missed_policy: MissedPolicy::default() })
+    }
+
+    pub fn with_daylight(mut self, daylight: Option<Daylight>) -> CaptureSchedule {
+        self.daylight = daylight;
+        self
+    }
+
+    pub fn with_missed_policy(mut self, missed_policy: MissedPolicy) -> CaptureSchedule {
+        self.missed_policy = missed_policy;
+        self
+    }
+
+    fn has_window(&self) -> bool {
+        self.start_time < self.end_time
+    }
+
+    // Capture after capture without an interval
+    fn is_continuous(&self) -> bool {
+        self.interval == 0 && self.cron.is_none() && !matches!(self.daylight, Some(Daylight { mode: DaylightMode::GoldenHour, .. }))
+    }
+
+    fn local_date(&self, time: i64) -> Option<NaiveDate> {
+        Some(self.timezone.to_local(time)?.date())
+    }
+
+    // The first fire time of the cron expression after the time (UTC seconds). A fire time in the hour skipped at
+    // the start of DST is moved forward, the hour repeated at the end of DST fires once.
+    fn cron_after(&self, cron: &CronSchedule, time: i64) -> Option<i64> {
+        let mut local = self.timezone.to_local(time)?;
+        for _ in 0..MAX_REPEATED_MINUTES {
+            local = cron.next_after(local)?;
+            let next = self.timezone.to_utc(local);
+            if next > time {
+                return Some(next);
+            }
+        }
+        None
+    }
+
+    // The time in the daylight window, or the start of the next window
+    fn daylight_time(&self, daylight: &Daylight, time: i64) -> Option<i64> {
+        let mut date = self.local_date(time)?.pred_opt()?;
+        for _ in 0..MAX_DAYLIGHT_DAYS {
+            if let Some((start, end)) = daylight.window(date, &self.timezone) {
+                if time < end {
+                    return Some(time.max(start));
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    // The next golden hour after the time
+    fn golden_hour_after(&self, daylight: &Daylight, time: i64) -> Option<i64> {
+        let mut date = self.local_date(time)?.pred_opt()?;
+        for _ in 0..MAX_DAYLIGHT_DAYS {
+            if let Some(next) = daylight.golden_times(date).into_iter().find(|t| *t > time) {
+                return Some(next);
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    // The time in the daylight window, a cron time in the night is moved to the first cron time in the next window
+    fn in_daylight(&self, mut next: i64) -> Option<i64> {
+        let daylight = match &self.daylight {
+            Some(daylight @ Daylight { mode: DaylightMode::Window { .. }, .. }) => daylight,
+            _ => return Some(next),
+        };
+        for _ in 0..MAX_DAYLIGHT_DAYS {
+            let time = self.daylight_time(daylight, next)?;
+            if time == next {
+                return Some(next);
+            }
+            next = match &self.cron {
+                Some(cron) if self.interval == 0 => self.cron_after(cron, time - 1)?,
+                _ => time,
+            };
+        }
+        None
+    }
+
+    // The scheduled time after the time by the golden hour, the interval or the cron expression
+    fn time_after(&self, time: i64) -> Option<i64> {
+        let from = if self.has_window() { time.max(self.start_time - 1) } else { time };
+        let next = if let Some(daylight @ Daylight { mode: DaylightMode::GoldenHour, .. }) = &self.daylight {
+            self.golden_hour_after(daylight, from)?
+        } else if self.interval > 0 {
+            let next = time + self.interval as i64;
+            if self.has_window() { next.max(self.start_time) } else { next }
+        } else {
+            self.cron_after(self.cron.as_ref()?, from)?
+        };
+        self.in_daylight(next)
+    }
+
+    // The first scheduled time which is not missed and the number of the missed times from the first one
+    fn skip_missed(&self, first: i64, due: i64) -> Option<(i64, u32)> {
+        if self.interval > 0 && self.daylight.is_none() {
+            let interval = self.interval as i64;
+            let missed = (due - first + interval - 1) / interval;
+            return Some((first + missed * interval, missed.min(u32::MAX as i64) as u32));
+        }
+        let mut next = first;
+        let mut missed = 0;
+        while next < due {
+            missed += 1;
+            if missed >= MAX_MISSED_TIMES {
+                return Some((self.time_after(due - 1)?, missed));
+            }
+            next = self.time_after(next)?;
+        }
+        Some((next, missed))
+    }
+
+    // The capture after the last one, None when the capture ends. The scheduled times before now - MISSED_GRACE are
+    // missed (the device slept too long or the network was blocked), they are handled by the missed policy.
+    // last is 0 before the first capture.
+    pub fn next_capture(&self, last: i64, now: i64) -> Option<NextCapture> {
+        let next = if self.is_continuous() {
+            NextCapture { time: self.in_daylight(now)?, missed: 0 }
+        } else {
+            let due = now - MISSED_GRACE;
+            let first = self.time_after(if last > 0 { last } else { now })?;
+            if first >= due {
+                NextCapture { time: first, missed: 0 }
+            } else {
+                let (aligned, missed) = self.skip_missed(first, due)?;
+                let time = match self.missed_policy {
+                    MissedPolicy::Align => aligned,
+                    // the cron expression and the golden hour are always on the grid
+                    MissedPolicy::Skip if self.interval > 0 => self.time_after(now)?,
+                    MissedPolicy::Skip => aligned,
+                    MissedPolicy::CatchUp => self.in_daylight(now)?,
+                };
+                NextCapture { time, missed }
+            }
+        };
+        if self.has_window() && next.time > self.end_time {
+            return None;
+        }
+        Some(next)
+    }
+
+    // The capture at a wake up for the scheduled time. When the device woke up later than MISSED_GRACE, the scheduled
+    // time and the times after it are missed, and the time is chosen by the missed policy. The device captures now
+    // when the time is now or before.
+    pub fn capture_at_wake_up(&self, scheduled: i64, now: i64) -> Option<NextCapture> {
+        if self.is_continuous() {
+            return self.next_capture(scheduled, now);
+        }
+        if scheduled >= now - MISSED_GRACE {
+            if self.has_window() && scheduled > self.end_time {
+                return None;
+            }
+            return Some(NextCapture { time: scheduled.min(now), missed: 0 });
+        }
+        let next = self.next_capture(scheduled, now)?;
+        Some(NextCapture { time: next.time, missed: next.missed + 1 })
+    }
+
+    // The capture time after the last one (UTC seconds), None when the capture ends
+    pub fn next_capture_time(&self, last: i64, now: i64) -> Option<i64> {
+        Some(self.next_capture(last, now)?.time)
+    }
+}
+
+impl MissedPolicy {
+    pub fn parse(text: &str) -> Option<MissedPolicy> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "skip" => Some(MissedPolicy::Skip),
+            "catchup" => Some(MissedPolicy::CatchUp),
+            "align" => Some(MissedPolicy::Align),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MissedPolicy::Skip => "skip",
+            MissedPolicy::CatchUp => "catchup",
+            MissedPolicy::Align => "align",
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Encryption of the secrets store
+//
+// The passwords and the tokens are saved apart from the configuration. When the encryption is enabled, the stored
+// value is "enc1:" and the hex of the nonce, the ciphertext and the tag. The key stream is HMAC-SHA256 of the nonce
+// and a block counter, and the tag is HMAC-SHA256 of the nonce and the ciphertext with another key. Both keys are
+// derived from a unique id of the device (the MAC address in the eFuse), so a copy of the NVS cannot be read without
+// the id, e.g. on a host or on another camera. It does not protect the secrets from someone who has the device.
+// A stored value without the prefix is not encrypted, so the encryption can be enabled or disabled later.
+
+use anyhow;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_SIZE: usize = 16;
+const TAG_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 32;
+const SEALED_PREFIX: &str = "enc1:";
+const KEY_SALT: &[u8] = b"timeleapcam secrets";
+
+pub struct SecretKey {
+    encryption: [u8; 32],
+    authentication: [u8; 32],
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+fn digest(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    hmac(key, parts).finalize().into_bytes().into()
+}
+
+impl SecretKey {
+    pub fn derive(device_id: &[u8]) -> SecretKey {
+        let master = digest(KEY_SALT, &[device_id]);
+        SecretKey {
+            encryption: digest(&master, &[b"encryption"]),
+            authentication: digest(&master, &[b"authentication"]),
+        }
+    }
+
+    // XOR with the key stream, same for the encryption and the decryption
+    fn apply_key_stream(&self, nonce: &[u8], data: &mut [u8]) {
+        for (counter, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let stream = digest(&self.encryption, &[nonce, &(counter as u32).to_be_bytes()]);
+            block.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
+        }
+    }
+
+    // The nonce must be random, a nonce must not be used twice with the same key
+    pub fn seal(&self, nonce: &[u8; NONCE_SIZE], text: &str) -> String {
+        let mut data = text.as_bytes().to_vec();
+        self.apply_key_stream(nonce, &mut data);
+        let tag = digest(&self.authentication, &[nonce, &data]);
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&data);
+        sealed.extend_from_slice(&tag);
+        format!("{}{}", SEALED_PREFIX, hex::encode(sealed))
+    }
+
+    // Stored value to the text, a value without the prefix is returned as it is
+    pub fn open(&self, stored: &str) -> Result<String, anyhow::Error> {
+        let sealed = match stored.strip_prefix(SEALED_PREFIX) {
+            Some(sealed) => hex::decode(sealed.trim()).map_err(|_| anyhow::anyhow!("Invalid encrypted secrets"))?,
+            None => return Ok(stored.to_string()),
+        };
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(anyhow::anyhow!("Invalid encrypted secrets"));
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (data, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        // another device key or a broken value
+        hmac(&self.authentication, &[nonce, data]).verify_slice(tag)
+            .map_err(|_| anyhow::anyhow!("Secrets are not encrypted with the key of this device"))?;
+        let mut data = data.to_vec();
+        self.apply_key_stream(nonce, &mut data);
+        String::from_utf8(data).map_err(|_| anyhow::anyhow!("Invalid encrypted secrets"))
+    }
+}
+
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(SEALED_PREFIX)
+}
+
+// Only the state of a secret is shown
+pub fn secret_state(value: &str) -> &'static str {
+    if value.is_empty() { "not set" } else { "set" }
+}
@@ -0,0 +1,106 @@
+// Sunrise and sunset
+//
+// The times are computed offline by the sunrise equation (NOAA simplified), accurate to about 1-2 minutes
+// between the polar circles. Latitude is north positive and longitude is east positive in degrees.
+// Sunrise and sunset are the times when the upper limb of the sun touches the horizon (-0.833 degrees with the
+// refraction). Golden hour is the time when the sun is at 6 degrees above the horizon: the end of the morning
+// golden hour and the start of the evening golden hour.
+
+use chrono::NaiveDate;
+
+use crate::timezone::TimeZone;
+
+pub const SUNRISE_ELEVATION: f64 = -0.833;
+pub const GOLDEN_HOUR_ELEVATION: f64 = 6.0;
+
+// Julian day of 2000-01-01 12:00 UTC and of the unix epoch
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+const OBLIQUITY: f64 = 23.4397;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SunEvent {
+    Times { rise: i64, set: i64 },     // UTC seconds
+    AlwaysAbove,                        // midnight sun
+    AlwaysBelow,                        // polar night
+}
+
+// Times of the day when the sun crosses the elevation (degrees). The date is the local date, the times are around
+// the solar noon of the date at the longitude.
+pub fn sun_event(date: NaiveDate, latitude: f64, longitude: f64, elevation: f64) -> SunEvent {
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()).num_days() as f64;
+    // mean solar noon
+    let noon = days + 0.0009 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin();
+    let latitude = latitude.clamp(-90.0, 90.0).to_radians();
+    let cos_hour_angle = (elevation.to_radians().sin() - latitude.sin() * declination.sin()) / (latitude.cos() * declination.cos());
+    if cos_hour_angle < -1.0 {
+        return SunEvent::AlwaysAbove;
+    }
+    if cos_hour_angle > 1.0 {
+        return SunEvent::AlwaysBelow;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+    let unix_time = |jd: f64| ((jd - UNIX_EPOCH_JD) * 86400.0).round() as i64;
+    SunEvent::Times { rise: unix_time(transit - hour_angle), set: unix_time(transit + hour_angle) }
+}
+
+pub fn sunrise_sunset(date: NaiveDate, latitude: f64, longitude: f64) -> SunEvent {
+    sun_event(date, latitude, longitude, SUNRISE_ELEVATION)
+}
+
+pub fn golden_hour(date: NaiveDate, latitude: f64, longitude: f64) -> SunEvent {
+    sun_event(date, latitude, longitude, GOLDEN_HOUR_ELEVATION)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DaylightMode {
+    Window { before_sunrise: i32, after_sunset: i32 },     // minutes, from sunrise - before to sunset + after
+    GoldenHour,                                             // at the morning and evening golden hour
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Daylight {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub mode: DaylightMode,
+}
+
+impl Daylight {
+    // Capture window of the local date (UTC seconds), the whole day in the midnight sun, None in the polar night
+    pub fn window(&self, date: NaiveDate, timezone: &TimeZone) -> Option<(i64, i64)> {
+        let (before, after) = match self.mode {
+            DaylightMode::Window { before_sunrise, after_sunset } => (before_sunrise as i64 * 60, after_sunset as i64 * 60),
+            DaylightMode::GoldenHour => (0, 0),
+        };
+        match sunrise_sunset(date, self.latitude, self.longitude) {
+            SunEvent::Times { rise, set } => Some((rise - before, set + after)),
+            SunEvent::AlwaysAbove => {
+                Some((timezone.to_utc(date.and_hms_opt(0, 0, 0)?), timezone.to_utc(date.succ_opt()?.and_hms_opt(0, 0, 0)?)))
+            }
+            SunEvent::AlwaysBelow => None,
+        }
+    }
+
+    // Golden hour times of the local date (UTC seconds)
+    pub fn golden_times(&self, date: NaiveDate) -> Vec<i64> {
+        match golden_hour(date, self.latitude, self.longitude) {
+            SunEvent::Times { rise, set } => vec![rise, set],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Daylight of the setting: "off", "daylight" (from sunrise - before to sunset + after minutes) or "golden"
+pub fn daylight_setting(mode: &str, latitude: f64, longitude: f64, before_sunrise: i32, after_sunset: i32) -> Option<Daylight> {
+    let mode = match mode {
+        "daylight" => DaylightMode::Window { before_sunrise, after_sunset },
+        "golden" => DaylightMode::GoldenHour,
+        _ => return None,
+    };
+    Some(Daylight { latitude, longitude, mode })
+}
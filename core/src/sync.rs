@@ -0,0 +1,201 @@
+// Incremental sync of the tracks to a PC
+//
+// The device serves the manifest of the records of a track and the raw bytes of the segment files:
+//   GET /tracks/{id}/manifest?from=N&count=M   frame, segment, byte offset, record length and CRC32 of each record
+//   GET /tracks/{id}/segments/{s}              segment file (Range is supported)
+// The client keeps sync.txt in the local track directory and downloads only the records after the last synced
+// frame. The state is written after each frame, so an interrupted transfer resumes at the next record offset.
+//   frames=120
+//   lastCaptureTime=1700000000000
+//   lastLength=123456
+//   lastCrc=1a2b3c4d
+// When the frames of the track are deleted on the device, the last synced frame is searched by its capture time,
+// length and CRC32 to find the new resume point.
+
+use anyhow;
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::imagefiles::{decode_record_header, record_header_size, verify_record, FrameInfo, ImageFiles, FRAME_FLAG_CRC32};
+
+pub const SYNC_FILE_NAME: &str = "sync.txt";
+const SYNC_FILE_TMP_NAME: &str = "sync.txt.tmp";
+
+// Entries of a manifest response
+pub const MAX_MANIFEST_ENTRIES: u32 = 500;
+
+// Maximum header size of a record
+const MAX_RECORD_HEADER_SIZE: usize = 32;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ManifestEntry {
+    pub frame: u32,
+    pub segment: u32,
+    pub offset: u64,                // of the record in the segment file
+    pub length: u64,                // record header and image
+    pub capture_time: i64,          // UTC ms, 0: unknown
+    pub crc: Option<u32>,           // CRC32 of the record, None for the records without a checksum
+}
+
+impl ManifestEntry {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SyncState {
+    pub frames: u32,                // frames synced from the top of the track
+    pub last_capture_time: i64,
+    pub last_length: u64,
+    pub last_crc: Option<u32>,
+}
+
+impl SyncState {
+    // The entry is the last synced frame
+    pub fn is_last(&self, entry: &ManifestEntry) -> bool {
+        self.frames > 0 && entry.capture_time == self.last_capture_time && entry.length == self.last_length && entry.crc == self.last_crc
+    }
+
+    pub fn synced(&mut self, entry: &ManifestEntry) {
+        self.frames = entry.frame + 1;
+        self.last_capture_time = entry.capture_time;
+        self.last_length = entry.length;
+        self.last_crc = entry.crc;
+    }
+}
+
+// Walk the record headers from the frame. The read position is moved.
+pub fn manifest_entries(images: &mut ImageFiles, from: u32, count: u32) -> Result<Vec<ManifestEntry>, anyhow::Error> {
+    let mut entries = Vec::new();
+    let nimages = images.get_nof_images();
+    if from >= nimages {
+        return Ok(entries);
+    }
+    let to = nimages.min(from.saturating_add(count.min(MAX_MANIFEST_ENTRIES)));
+    images.seek_image(from)?;
+    for frame in from..to {
+        let header = images.get_record_header()?;
+        entries.push(ManifestEntry {
+            frame,
+            segment: images.get_segment(),
+            offset: images.get_image_offset(),
+            length: header.record_size(),
+            capture_time: header.info.capture_time,
+            crc: header.crc,
+        });
+        images.skip_image()?;
+    }
+    Ok(entries)
+}
+
+// {"frame":0,"segment":0,"offset":24,"length":1234,"captureTime":1700000000000,"crc":439041101}
+pub fn manifest_entry_json(entry: &ManifestEntry) -> String {
+    format!("{{\"frame\":{},\"segment\":{},\"offset\":{},\"length\":{},\"captureTime\":{},\"crc\":{}}}",
+        entry.frame, entry.segment, entry.offset, entry.length, entry.capture_time,
+        entry.crc.map(|crc| crc.to_string()).unwrap_or("null".to_string()))
+}
+
+// Manifest of the track, segments are the sizes of the segment files
+pub fn manifest_json(track_id: u32, frames: u32, segments: &[u64], entries: &[ManifestEntry]) -> String {
+    format!("{{\"trackid\":{},\"frames\":{},\"segments\":[{}],\"entries\":[{}]}}",
+        track_id, frames,
+        segments.iter().map(|size| size.to_string()).collect::<Vec<String>>().join(","),
+        entries.iter().map(manifest_entry_json).collect::<Vec<String>>().join(","))
+}
+
+// Frame number after the last synced frame in the entries
+pub fn resume_frame(state: &SyncState, entries: &[ManifestEntry]) -> Option<u32> {
+    entries.iter().find(|entry| state.is_last(entry)).map(|entry| entry.frame + 1)
+}
+
+// Runs of the entries which are contiguous in a segment file, each run is downloaded by one request
+pub fn record_runs(entries: &[ManifestEntry]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for n in 0..entries.len() {
+        match runs.last_mut() {
+            Some(run) if entries[n - 1].segment == entries[n].segment && entries[n - 1].end() == entries[n].offset => run.end = n + 1,
+            _ => runs.push(n..n + 1),
+        }
+    }
+    runs
+}
+
+// Read the record of the entry from the downloaded bytes and check it
+pub fn read_record<R: Read>(reader: &mut R, entry: &ManifestEntry) -> Result<(FrameInfo, Vec<u8>), anyhow::Error> {
+    let mut header: [u8; MAX_RECORD_HEADER_SIZE] = [0; MAX_RECORD_HEADER_SIZE];
+    reader.read_exact(&mut header[0..4])?;
+    let header_size = record_header_size(&header).ok_or(anyhow::anyhow!("Invalid record at frame {}", entry.frame))?;
+    reader.read_exact(&mut header[4..header_size])?;
+    let record = decode_record_header(&header[0..header_size]).ok_or(anyhow::anyhow!("Invalid record at frame {}", entry.frame))?;
+    if record.record_size() != entry.length || record.info.capture_time != entry.capture_time || record.crc != entry.crc {
+        return Err(anyhow::anyhow!("Record does not match the manifest at frame {}, the track was changed", entry.frame));
+    }
+    let mut image = vec![0; record.size];
+    reader.read_exact(&mut image)?;
+    if record.info.flags & FRAME_FLAG_CRC32 != 0 && !verify_record(&header[0..header_size], &image) {
+        return Err(anyhow::anyhow!("Checksum error at frame {}", entry.frame));
+    }
+    Ok((record.info, image))
+}
+
+// 20240101T000000.000Z.jpg, the name does not change when the frames before it are deleted on the device.
+// frame_000012.jpg without the capture time.
+pub fn local_file_name(frame: u32, capture_time: i64) -> String {
+    match DateTime::<Utc>::from_timestamp_millis(capture_time).filter(|_| capture_time > 0) {
+        Some(time) => format!("{}.jpg", time.format("%Y%m%dT%H%M%S%.3fZ")),
+        None => format!("frame_{:06}.jpg", frame),
+    }
+}
+
+fn parse_sync_state(text: &str) -> SyncState {
+    let mut state = SyncState::default();
+    for line in text.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match key {
+            "frames" => state.frames = value.parse().unwrap_or(0),
+            "lastCaptureTime" => state.last_capture_time = value.parse().unwrap_or(0),
+            "lastLength" => state.last_length = value.parse().unwrap_or(0),
+            "lastCrc" => state.last_crc = u32::from_str_radix(value, 16).ok(),
+            _ => (),
+        }
+    }
+    state
+}
+
+fn format_sync_state(state: &SyncState) -> String {
+    let mut text = format!("frames={}\nlastCaptureTime={}\nlastLength={}\n", state.frames, state.last_capture_time, state.last_length);
+    if let Some(crc) = state.last_crc {
+        text += &format!("lastCrc={:08x}\n", crc);
+    }
+    text
+}
+
+// The sync state of the local track directory, default if it does not exist
+pub fn read_sync_state(directory: impl AsRef<Path>) -> SyncState {
+    let directory = directory.as_ref();
+    for name in [SYNC_FILE_NAME, SYNC_FILE_TMP_NAME] {
+        if let Ok(text) = fs::read_to_string(directory.join(name)) {
+            return parse_sync_state(&text);
+        }
+    }
+    SyncState::default()
+}
+
+pub fn write_sync_state(directory: impl AsRef<Path>, state: &SyncState) -> Result<(), anyhow::Error> {
+    let directory = directory.as_ref();
+    let tmp_path = directory.join(SYNC_FILE_TMP_NAME);
+    let path = directory.join(SYNC_FILE_NAME);
+    fs::write(&tmp_path, format_sync_state(state))?;
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
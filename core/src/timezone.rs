@@ -0,0 +1,391 @@
+// Time zone
+//
+// The time zone setting is an IANA name (Europe/Berlin), a POSIX TZ string (CET-1CEST,M3.5.0,M10.5.0/3) or the
+// offset in hours of the old versions (9, -3.5). The IANA names are converted to the POSIX TZ strings of the table,
+// the device has no time zone database. A POSIX TZ string is
+//   std offset [dst [offset] [,start[/time],end[/time]]]
+// The offset is west positive (JST-9 is UTC+9), the names are 3 or more letters or <+0530>. The DST offset is the
+// standard offset + 1 hour when omitted. The start and end rules are Mm.w.d (day d of week w of month m, week 5 is
+// the last), Jn (day 1-365 without Feb 29) or n (day 0-365), the time is the local time (02:00 when omitted).
+// The US rules are used when a DST name has no rules.
+
+use anyhow;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime};
+use std::str::FromStr;
+
+// IANA names of the common zones and the POSIX TZ strings
+pub const TIME_ZONES: [(&str, &str); 76] = [
+    ("UTC", "UTC0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "GMT0IST,M3.5.0/1,M10.5.0"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Brussels", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Budapest", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Copenhagen", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Prague", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Vienna", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Warsaw", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Zurich", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Bucharest", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Kyiv", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Riga", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Sofia", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Tallinn", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Vilnius", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Istanbul", "<+03>-3"),
+    ("Europe/Moscow", "MSK-3"),
+    ("Africa/Cairo", "EET-2EEST,M4.5.5/0,M10.5.4/24"),
+    ("Africa/Johannesburg", "SAST-2"),
+    ("Africa/Lagos", "WAT-1"),
+    ("Africa/Nairobi", "EAT-3"),
+    ("Asia/Dubai", "<+04>-4"),
+    ("Asia/Tehran", "<+0330>-3:30"),
+    ("Asia/Jerusalem", "IST-2IDT,M3.4.4/26,M10.5.0"),
+    ("Asia/Karachi", "PKT-5"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Kathmandu", "<+0545>-5:45"),
+    ("Asia/Dhaka", "<+06>-6"),
+    ("Asia/Yangon", "<+0630>-6:30"),
+    ("Asia/Bangkok", "<+07>-7"),
+    ("Asia/Ho_Chi_Minh", "<+07>-7"),
+    ("Asia/Jakarta", "WIB-7"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Hong_Kong", "HKT-8"),
+    ("Asia/Taipei", "CST-8"),
+    ("Asia/Singapore", "<+08>-8"),
+    ("Asia/Manila", "PST-8"),
+    ("Australia/Perth", "AWST-8"),
+    ("Asia/Seoul", "KST-9"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Darwin", "ACST-9:30"),
+    ("Australia/Adelaide", "ACST-9:30ACDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Brisbane", "AEST-10"),
+    ("Australia/Hobart", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Melbourne", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Pacific/Guam", "ChST-10"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+    ("Pacific/Honolulu", "HST10"),
+    ("America/Anchorage", "AKST9AKDT,M3.2.0,M11.1.0"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Vancouver", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Mexico_City", "CST6"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Toronto", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Bogota", "<-05>5"),
+    ("America/Lima", "<-05>5"),
+    ("America/Halifax", "AST4ADT,M3.2.0,M11.1.0"),
+    ("America/Santiago", "<-04>4<-03>,M9.1.6/24,M4.1.6/24"),
+    ("America/St_Johns", "NST3:30NDT,M3.2.0,M11.1.0"),
+    ("America/Sao_Paulo", "<-03>3"),
+    ("America/Argentina/Buenos_Aires", "<-03>3"),
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Rule {
+    Julian(u32),                    // Jn: 1-365, Feb 29 is not counted
+    Day(u32),                       // n: 0-365
+    Week { month: u32, week: u32, weekday: u32 },  // Mm.w.d: week 1-5 (5: last), weekday 0: Sunday
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Transition {
+    pub rule: Rule,
+    pub time: i32,                  // local time of the day in seconds, may be negative or over 24 hours
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dst {
+    pub name: String,
+    pub offset: i32,                // local time - UTC in seconds
+    pub start: Transition,
+    pub end: Transition,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TimeZone {
+    pub name: String,
+    pub offset: i32,                // standard time, local time - UTC in seconds
+    pub dst: Option<Dst>,
+}
+
+impl Rule {
+    fn date(&self, year: i32) -> Option<NaiveDate> {
+        match *self {
+            Rule::Julian(day) => {
+                let date = NaiveDate::from_yo_opt(year, day)?;
+                // Feb 29 is not counted
+                if date.leap_year() && day >= 60 { date.succ_opt() } else { Some(date) }
+            }
+            Rule::Day(day) => NaiveDate::from_yo_opt(year, day + 1),
+            Rule::Week { month, week, weekday } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let first_weekday = (weekday + 7 - first.weekday().num_days_from_sunday()) % 7;
+                let mut date = first + Duration::days((first_weekday + (week - 1) * 7) as i64);
+                // week 5 is the last one in the month
+                while date.month() != month {
+                    date -= Duration::days(7);
+                }
+                Some(date)
+            }
+        }
+    }
+}
+
+impl Transition {
+    // UTC seconds of the transition in the year, offset is the one before the transition
+    fn utc(&self, year: i32, offset: i32) -> Option<i64> {
+        let midnight = self.rule.date(year)?.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+        Some(midnight + self.time as i64 - offset as i64)
+    }
+}
+
+// Parser of the POSIX TZ string
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> anyhow::Error {
+        anyhow::anyhow!("Invalid time zone at {}: {}", self.position, self.text)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            return true;
+        }
+        false
+    }
+
+    fn name(&mut self) -> Result<String, anyhow::Error> {
+        let rest = &self.text[self.position..];
+        let (name, length) = if let Some(quoted) = rest.strip_prefix('<') {
+            let end = quoted.find('>').ok_or_else(|| self.error())?;
+            (&quoted[..end], end + 2)
+        } else {
+            let end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.len() < 3 {
+            return Err(self.error());
+        }
+        self.position += length;
+        Ok(name.to_string())
+    }
+
+    fn number(&mut self) -> Result<i32, anyhow::Error> {
+        let rest = &self.text[self.position..];
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n = rest[..end].parse::<i32>().map_err(|_| self.error())?;
+        self.position += end;
+        Ok(n)
+    }
+
+    // number up to max, the larger numbers are errors before they are multiplied
+    fn number_up_to(&mut self, max: i32) -> Result<i32, anyhow::Error> {
+        let n = self.number()?;
+        if n > max {
+            return Err(self.error());
+        }
+        Ok(n)
+    }
+
+    // [+-]hh[:mm[:ss]] in seconds, hh is up to 167
+    fn time(&mut self) -> Result<i32, anyhow::Error> {
+        let sign = if self.eat('-') { -1 } else { self.eat('+'); 1 };
+        let mut seconds = self.number_up_to(167)? * 3600;
+        if self.eat(':') {
+            seconds += self.number_up_to(59)? * 60;
+            if self.eat(':') {
+                seconds += self.number_up_to(59)?;
+            }
+        }
+        Ok(sign * seconds)
+    }
+
+    fn transition(&mut self) -> Result<Transition, anyhow::Error> {
+        let rule = if self.eat('M') {
+            let month = self.number()? as u32;
+            if !self.eat('.') {
+                return Err(self.error());
+            }
+            let week = self.number()? as u32;
+            if !self.eat('.') {
+                return Err(self.error());
+            }
+            let weekday = self.number()? as u32;
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                return Err(self.error());
+            }
+            Rule::Week { month, week, weekday }
+        } else if self.eat('J') {
+            let day = self.number()? as u32;
+            if !(1..=365).contains(&day) {
+                return Err(self.error());
+            }
+            Rule::Julian(day)
+        } else {
+            let day = self.number()? as u32;
+            if day > 365 {
+                return Err(self.error());
+            }
+            Rule::Day(day)
+        };
+        let time = if self.eat('/') { self.time()? } else { 2 * 3600 };
+        Ok(Transition { rule, time })
+    }
+}
+
+impl TimeZone {
+    // Fixed offset in seconds
+    pub fn fixed(offset: i32) -> TimeZone {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let seconds = offset.abs();
+        let name = if seconds % 3600 == 0 {
+            format!("UTC{}{}", sign, seconds / 3600)
+        } else {
+            format!("UTC{}{}:{:02}", sign, seconds / 3600, seconds % 3600 / 60)
+        };
+        TimeZone { name, offset, dst: None }
+    }
+
+    // POSIX TZ string
+    pub fn parse_posix(text: &str) -> Result<TimeZone, anyhow::Error> {
+        let mut parser = Parser { text: text.trim(), position: 0 };
+        let name = parser.name()?;
+        let offset = -parser.time()?;
+        if parser.peek().is_none() {
+            return Ok(TimeZone { name, offset, dst: None });
+        }
+        let dst_name = parser.name()?;
+        let dst_offset = match parser.peek() {
+            Some(',') | None => offset + 3600,
+            _ => -parser.time()?,
+        };
+        let (start, end) = if parser.eat(',') {
+            let start = parser.transition()?;
+            if !parser.eat(',') {
+                return Err(parser.error());
+            }
+            (start, parser.transition()?)
+        } else {
+            (Transition { rule: Rule::Week { month: 3, week: 2, weekday: 0 }, time: 2 * 3600 },
+             Transition { rule: Rule::Week { month: 11, week: 1, weekday: 0 }, time: 2 * 3600 })
+        };
+        if parser.peek().is_some() {
+            return Err(parser.error());
+        }
+        Ok(TimeZone { name, offset, dst: Some(Dst { name: dst_name, offset: dst_offset, start, end }) })
+    }
+
+    // IANA name, POSIX TZ string or offset in hours
+    pub fn parse(text: &str) -> Result<TimeZone, anyhow::Error> {
+        let text = text.trim();
+        if let Some((_, posix)) = TIME_ZONES.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+            return TimeZone::parse_posix(posix);
+        }
+        if let Ok(hours) = text.parse::<f64>() {
+            if !(-12.0..=14.0).contains(&hours) {
+                return Err(anyhow::anyhow!("Time zone offset out of range (-12 to +14): {}", text));
+            }
+            return Ok(TimeZone::fixed((hours * 3600.0).round() as i32));
+        }
+        TimeZone::parse_posix(text)
+    }
+
+    // DST start and end in UTC seconds of the year
+    fn dst_period(&self, dst: &Dst, year: i32) -> Option<(i64, i64)> {
+        Some((dst.start.utc(year, self.offset)?, dst.end.utc(year, dst.offset)?))
+    }
+
+    fn is_dst(&self, time: i64) -> bool {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return false,
+        };
+        let year = match DateTime::from_timestamp(time + self.offset as i64, 0) {
+            Some(local) => local.year(),
+            None => return false,
+        };
+        match self.dst_period(dst, year) {
+            Some((start, end)) if start < end => start <= time && time < end,
+            // southern hemisphere, DST over the new year
+            Some((start, end)) => time < end || start <= time,
+            None => false,
+        }
+    }
+
+    // local time - UTC in seconds at the time (UTC seconds)
+    pub fn offset_at(&self, time: i64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_dst(time) => dst.offset,
+            _ => self.offset,
+        }
+    }
+
+    // Zone name at the time (JST, CEST)
+    pub fn abbreviation_at(&self, time: i64) -> &str {
+        match &self.dst {
+            Some(dst) if self.is_dst(time) => &dst.name,
+            _ => &self.name,
+        }
+    }
+
+    pub fn local_time(&self, time: i64) -> Option<DateTime<FixedOffset>> {
+        let offset = FixedOffset::east_opt(self.offset_at(time))?;
+        Some(DateTime::from_timestamp(time, 0)?.with_timezone(&offset))
+    }
+
+    // Local time string of the time (UTC seconds) by the chrono format
+    pub fn format(&self, time: i64, format: &str) -> String {
+        match self.local_time(time) {
+            Some(local) => local.format(format).to_string(),
+            None => String::new(),
+        }
+    }
+
+    pub fn to_local(&self, time: i64) -> Option<NaiveDateTime> {
+        Some(self.local_time(time)?.naive_local())
+    }
+
+    // UTC seconds of the local time. The earlier one in the repeated hour at the end of DST,
+    // the time in the skipped hour at the start of DST is moved forward.
+    pub fn to_utc(&self, local: NaiveDateTime) -> i64 {
+        let local = local.and_utc().timestamp();
+        let mut offsets = vec![self.offset];
+        if let Some(dst) = &self.dst {
+            offsets.push(dst.offset);
+        }
+        offsets.iter()
+            .map(|offset| local - *offset as i64)
+            .filter(|time| self.offset_at(*time) as i64 == local - *time)
+            .min()
+            .unwrap_or(local - self.offset as i64)
+    }
+}
+
+impl FromStr for TimeZone {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        TimeZone::parse(text)
+    }
+}
@@ -0,0 +1,438 @@
+// Certificate of the HTTPS server
+//
+// The device makes a self-signed certificate with a P-256 key when HTTPS is enabled and no certificate is uploaded.
+// The random key and serial number are given by the caller (the hardware RNG on the device), so the certificate is
+// the same for the same inputs on the host. The names of the certificate are the host names and the IP addresses of
+// the device, the generated certificate is made again when it expires soon or the IP address is changed.
+// An uploaded certificate (PEM, it can have the chain) is checked before it is saved: it must be valid now and its
+// key must be the pair of the certificate. The pair is checked for the P-256 keys, the other keys (e.g. RSA) are
+// checked by the TLS stack when the server is started.
+
+use anyhow;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use p256::SecretKey;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const KEY_SIZE: usize = 32;
+pub const SERIAL_SIZE: usize = 16;
+pub const CERTIFICATE_DAYS: i64 = 3650;
+// the generated certificate is made again before it expires
+pub const RENEW_DAYS: i64 = 30;
+
+// DER tags
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const T61_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const VERSION_TAG: u8 = 0xa0;
+const EXTENSIONS_TAG: u8 = 0xa3;
+const DNS_NAME_TAG: u8 = 0x82;
+const IP_ADDRESS_TAG: u8 = 0x87;
+
+// OIDs without the tag and the length
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+const CERTIFICATE_LABEL: &str = "CERTIFICATE";
+const PKCS8_KEY_LABEL: &str = "PRIVATE KEY";
+const SEC1_KEY_LABEL: &str = "EC PRIVATE KEY";
+const RSA_KEY_LABEL: &str = "RSA PRIVATE KEY";
+
+// Certificate and private key as PEM, the key is not in the debug output
+#[derive(Clone, PartialEq)]
+pub struct DeviceCertificate {
+    pub certificate: String,
+    pub key: String,
+}
+
+impl fmt::Debug for DeviceCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeviceCertificate").field("certificate", &self.certificate).field("key", &"********").finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateInfo {
+    pub subject: String,            // common name
+    pub issuer: String,
+    pub names: Vec<String>,         // DNS names and IP addresses
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub fingerprint: String,        // SHA-256 of the certificate, hex
+    pub key_type: &'static str,     // "P-256", "RSA" or "other"
+    public_key: Vec<u8>,            // uncompressed point of a P-256 key
+}
+
+impl CertificateInfo {
+    pub fn self_signed(&self) -> bool {
+        self.subject == self.issuer
+    }
+
+    pub fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before <= now && now < self.not_after
+    }
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend_from_slice(&bytes);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn sequence(parts: &[Vec<u8>]) -> Vec<u8> {
+    der(SEQUENCE, &parts.concat())
+}
+
+// BIT STRING without unused bits
+fn bit_string(content: &[u8]) -> Vec<u8> {
+    der(BIT_STRING, &[&[0u8][..], content].concat())
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    sequence(&[der(SET, &sequence(&[der(OID, COMMON_NAME), der(UTF8_STRING, common_name.as_bytes())]))])
+}
+
+// UTCTime until 2049, GeneralizedTime after it (RFC 5280)
+fn time(time: DateTime<Utc>) -> Vec<u8> {
+    if time.year() < 2050 {
+        der(UTC_TIME, time.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    } else {
+        der(GENERALIZED_TIME, time.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+}
+
+fn extension(oid: &[u8], critical: bool, value: Vec<u8>) -> Vec<u8> {
+    let mut parts = vec![der(OID, oid)];
+    if critical {
+        parts.push(der(0x01, &[0xff]));
+    }
+    parts.push(der(OCTET_STRING, &value));
+    sequence(&parts)
+}
+
+// IP addresses are iPAddress, the others are dNSName
+fn alt_names(names: &[&str]) -> Vec<u8> {
+    let names: Vec<Vec<u8>> = names.iter().map(|name| match name.parse::<Ipv4Addr>() {
+        Ok(address) => der(IP_ADDRESS_TAG, &address.octets()),
+        Err(_) => der(DNS_NAME_TAG, name.as_bytes()),
+    }).collect();
+    sequence(&names)
+}
+
+fn pem(label: &str, der: &[u8]) -> Result<String, anyhow::Error> {
+    pem_rfc7468::encode_string(label, LineEnding::LF, der).map_err(|e| anyhow::anyhow!("PEM encoding failed: {}", e))
+}
+
+// Self-signed certificate of the device. The key bytes must be a P-256 scalar (1 to n - 1), Err means that other
+// random bytes should be used. The first name is the common name.
+pub fn generate_certificate(key: &[u8; KEY_SIZE], serial: &[u8; SERIAL_SIZE], names: &[&str], not_before: DateTime<Utc>)
+    -> Result<DeviceCertificate, anyhow::Error> {
+    let common_name = names.first().ok_or_else(|| anyhow::anyhow!("No name of the certificate"))?;
+    let secret_key = SecretKey::from_bytes(key.into()).map_err(|_| anyhow::anyhow!("Invalid key bytes"))?;
+    let signing_key = SigningKey::from(&secret_key);
+    let public_key = secret_key.public_key().to_sec1_bytes();
+    // positive and the minimal encoding of INTEGER
+    let mut serial = *serial;
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+    let not_before = DateTime::from_timestamp(not_before.timestamp(), 0).unwrap_or(not_before);
+    let not_after = not_before + Duration::days(CERTIFICATE_DAYS);
+    let algorithm = sequence(&[der(OID, ECDSA_WITH_SHA256)]);
+
+    let extensions = sequence(&[
+        extension(BASIC_CONSTRAINTS, true, sequence(&[])),
+        extension(EXT_KEY_USAGE, false, sequence(&[der(OID, SERVER_AUTH)])),
+        extension(SUBJECT_ALT_NAME, false, alt_names(names)),
+    ]);
+    let tbs = sequence(&[
+        der(VERSION_TAG, &der(INTEGER, &[2])),
+        der(INTEGER, &serial),
+        algorithm.clone(),
+        name(common_name),
+        sequence(&[time(not_before), time(not_after)]),
+        name(common_name),
+        sequence(&[sequence(&[der(OID, EC_PUBLIC_KEY), der(OID, PRIME256V1)]), bit_string(&public_key)]),
+        der(EXTENSIONS_TAG, &extensions),
+    ]);
+    let signature: Signature = signing_key.sign(&tbs);
+    let certificate = sequence(&[tbs, algorithm, bit_string(signature.to_der().as_bytes())]);
+
+    let key = secret_key.to_pkcs8_pem(LineEnding::LF).map_err(|e| anyhow::anyhow!("Key encoding failed: {}", e))?;
+    Ok(DeviceCertificate { certificate: pem(CERTIFICATE_LABEL, &certificate)?, key: key.to_string() })
+}
+
+// Reader of DER values
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    // tag, content and the whole value
+    fn read(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), anyhow::Error> {
+        let invalid = || anyhow::anyhow!("Invalid certificate encoding");
+        let tag = *self.data.first().ok_or_else(invalid)?;
+        let first = *self.data.get(1).ok_or_else(invalid)? as usize;
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 3 {
+                return Err(invalid());
+            }
+            let bytes = self.data.get(2..2 + count).ok_or_else(invalid)?;
+            (bytes.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize), 2 + count)
+        };
+        let value = self.data.get(..header + len).ok_or_else(invalid)?;
+        self.data = &self.data[header + len..];
+        Ok((tag, &value[header..], value))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], anyhow::Error> {
+        match self.read()? {
+            (read, content, _) if read == tag => Ok(content),
+            _ => Err(anyhow::anyhow!("Invalid certificate encoding")),
+        }
+    }
+}
+
+fn string_value(tag: u8, content: &[u8]) -> Option<String> {
+    match tag {
+        UTF8_STRING | PRINTABLE_STRING | T61_STRING | IA5_STRING => Some(String::from_utf8_lossy(content).to_string()),
+        _ => None,
+    }
+}
+
+// Common name of a Name, or the first string of it
+fn common_name(content: &[u8]) -> Result<String, anyhow::Error> {
+    let mut first = None;
+    let mut sets = Reader::new(content);
+    while !sets.is_empty() {
+        let mut attributes = Reader::new(sets.expect(SET)?);
+        while !attributes.is_empty() {
+            let mut attribute = Reader::new(attributes.expect(SEQUENCE)?);
+            let oid = attribute.expect(OID)?;
+            let (tag, value, _) = attribute.read()?;
+            if let Some(value) = string_value(tag, value) {
+                if oid == COMMON_NAME {
+                    return Ok(value);
+                }
+                first.get_or_insert(value);
+            }
+        }
+    }
+    Ok(first.unwrap_or_default())
+}
+
+fn digits(text: &[u8], range: std::ops::Range<usize>) -> Option<u32> {
+    std::str::from_utf8(text.get(range)?).ok()?.parse().ok()
+}
+
+fn parse_time(tag: u8, text: &[u8]) -> Result<DateTime<Utc>, anyhow::Error> {
+    let (year, rest) = match (tag, text.len()) {
+        // two digits year, 50 to 99 are 19xx
+        (UTC_TIME, 13) => digits(text, 0..2).map(|year| if year >= 50 { 1900 + year } else { 2000 + year }).zip(Some(2)),
+        (GENERALIZED_TIME, 15) => digits(text, 0..4).zip(Some(4)),
+        _ => None,
+    }.ok_or_else(|| anyhow::anyhow!("Invalid certificate time"))?;
+    let field = |n: usize| digits(text, rest + n * 2..rest + n * 2 + 2);
+    let time = match (field(0), field(1), field(2), field(3), field(4)) {
+        (Some(month), Some(day), Some(hour), Some(minute), Some(second)) if text.last() == Some(&b'Z') =>
+            NaiveDate::from_ymd_opt(year as i32, month, day).and_then(|date| date.and_hms_opt(hour, minute, second)),
+        _ => None,
+    };
+    time.map(|time| time.and_utc()).ok_or_else(|| anyhow::anyhow!("Invalid certificate time"))
+}
+
+fn parse_alt_names(content: &[u8]) -> Result<Vec<String>, anyhow::Error> {
+    let mut names = Vec::new();
+    let mut reader = Reader::new(Reader::new(content).expect(SEQUENCE)?);
+    while !reader.is_empty() {
+        let (tag, value, _) = reader.read()?;
+        match (tag, value.len()) {
+            (DNS_NAME_TAG, _) => names.push(String::from_utf8_lossy(value).to_string()),
+            (IP_ADDRESS_TAG, 4) => names.push(Ipv4Addr::from(<[u8; 4]>::try_from(value)?).to_string()),
+            (IP_ADDRESS_TAG, 16) => names.push(Ipv6Addr::from(<[u8; 16]>::try_from(value)?).to_string()),
+            _ => (),
+        }
+    }
+    Ok(names)
+}
+
+fn parse_der_certificate(data: &[u8]) -> Result<CertificateInfo, anyhow::Error> {
+    let mut outer = Reader::new(data);
+    let (tag, certificate, whole) = outer.read()?;
+    if tag != SEQUENCE || !outer.is_empty() {
+        return Err(anyhow::anyhow!("Invalid certificate encoding"));
+    }
+    let mut tbs = Reader::new(Reader::new(certificate).expect(SEQUENCE)?);
+    if tbs.peek_tag() == Some(VERSION_TAG) {
+        tbs.read()?;
+    }
+    tbs.expect(INTEGER)?;
+    tbs.expect(SEQUENCE)?;
+    let issuer = common_name(tbs.expect(SEQUENCE)?)?;
+    let mut validity = Reader::new(tbs.expect(SEQUENCE)?);
+    let (tag, text, _) = validity.read()?;
+    let not_before = parse_time(tag, text)?;
+    let (tag, text, _) = validity.read()?;
+    let not_after = parse_time(tag, text)?;
+    let subject = common_name(tbs.expect(SEQUENCE)?)?;
+
+    let mut key_info = Reader::new(tbs.expect(SEQUENCE)?);
+    let mut algorithm = Reader::new(key_info.expect(SEQUENCE)?);
+    let algorithm_oid = algorithm.expect(OID)?;
+    let parameters = algorithm.read().ok();
+    let key_bits = key_info.expect(BIT_STRING)?;
+    let (key_type, public_key) = match (algorithm_oid, parameters) {
+        (EC_PUBLIC_KEY, Some((OID, PRIME256V1, _))) => ("P-256", key_bits.get(1..).unwrap_or_default().to_vec()),
+        (RSA_ENCRYPTION, _) => ("RSA", Vec::new()),
+        _ => ("other", Vec::new()),
+    };
+
+    let mut names = Vec::new();
+    while !tbs.is_empty() {
+        let (tag, content, _) = tbs.read()?;
+        if tag != EXTENSIONS_TAG {
+            continue;
+        }
+        let mut extensions = Reader::new(Reader::new(content).expect(SEQUENCE)?);
+        while !extensions.is_empty() {
+            let mut extension = Reader::new(extensions.expect(SEQUENCE)?);
+            let oid = extension.expect(OID)?;
+            let (mut tag, mut value, _) = extension.read()?;
+            // critical
+            if tag == 0x01 {
+                (tag, value, _) = extension.read()?;
+            }
+            if oid == SUBJECT_ALT_NAME && tag == OCTET_STRING {
+                names = parse_alt_names(value)?;
+            }
+        }
+    }
+    Ok(CertificateInfo {
+        subject,
+        issuer,
+        names,
+        not_before,
+        not_after,
+        fingerprint: certificate_fingerprint(whole),
+        key_type,
+        public_key,
+    })
+}
+
+// PEM blocks of a text, the label and the DER
+fn pem_blocks(text: &str) -> Result<Vec<(String, Vec<u8>)>, anyhow::Error> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("-----BEGIN ") {
+        let block = &rest[start..];
+        let end = block.find("-----END ")
+            .and_then(|end| block[end + 9..].find("-----").map(|dashes| end + 9 + dashes + 5))
+            .ok_or_else(|| anyhow::anyhow!("Invalid PEM"))?;
+        let (label, der) = pem_rfc7468::decode_vec(&block.as_bytes()[..end]).map_err(|_| anyhow::anyhow!("Invalid PEM"))?;
+        blocks.push((label.to_string(), der));
+        rest = &block[end..];
+    }
+    Ok(blocks)
+}
+
+// SHA-256 of the DER of a certificate, hex
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+// Fingerprint typed by the user, the hex of the configuration page or of openssl (AB:CD:...)
+pub fn parse_fingerprint(text: &str) -> Option<String> {
+    let fingerprint = text.trim().replace(':', "").to_ascii_lowercase();
+    if fingerprint.len() == 64 && fingerprint.bytes().all(|c| c.is_ascii_hexdigit()) {
+        Some(fingerprint)
+    } else {
+        None
+    }
+}
+
+// DER of the certificates of a PEM text
+pub fn pem_certificates(pem: &str) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    let blocks = pem_blocks(pem)?;
+    if blocks.is_empty() || blocks.iter().any(|(label, _)| label != CERTIFICATE_LABEL) {
+        return Err(anyhow::anyhow!("Not a PEM certificate"));
+    }
+    Ok(blocks.into_iter().map(|(_, der)| der).collect())
+}
+
+// First certificate of a PEM text, the other certificates are the chain and they must also be read
+pub fn parse_certificate(pem: &str) -> Result<CertificateInfo, anyhow::Error> {
+    let mut infos = pem_certificates(pem)?.iter().map(|der| parse_der_certificate(der)).collect::<Result<Vec<_>, _>>()?;
+    Ok(infos.remove(0))
+}
+
+// Uploaded certificate and key. The errors do not have the key.
+pub fn check_certificate(certificate: &str, key: &str, now: DateTime<Utc>) -> Result<CertificateInfo, anyhow::Error> {
+    let info = parse_certificate(certificate)?;
+    if !info.is_valid_at(now) {
+        return Err(anyhow::anyhow!("Certificate is not valid now (valid from {} to {})", info.not_before, info.not_after));
+    }
+    let blocks = pem_blocks(key).map_err(|_| anyhow::anyhow!("Invalid PEM key"))?;
+    let (label, der) = match blocks.as_slice() {
+        [block] => block,
+        _ => return Err(anyhow::anyhow!("Key must be one PEM private key")),
+    };
+    let secret_key = match label.as_str() {
+        PKCS8_KEY_LABEL => SecretKey::from_pkcs8_der(der).ok(),
+        SEC1_KEY_LABEL => SecretKey::from_sec1_der(der).ok(),
+        RSA_KEY_LABEL => None,
+        "ENCRYPTED PRIVATE KEY" => return Err(anyhow::anyhow!("Encrypted keys are not supported")),
+        _ => return Err(anyhow::anyhow!("Not a PEM private key")),
+    };
+    match (info.key_type, secret_key) {
+        ("P-256", Some(secret_key)) if *secret_key.public_key().to_sec1_bytes() == *info.public_key => Ok(info),
+        ("P-256", _) => Err(anyhow::anyhow!("Key is not the key of the certificate")),
+        (_, Some(_)) => Err(anyhow::anyhow!("Key is not the key of the certificate")),
+        (_, None) if label == SEC1_KEY_LABEL => Err(anyhow::anyhow!("Invalid EC private key")),
+        (_, None) => Ok(info),
+    }
+}
+
+// The generated certificate is made again when it expires soon or a name of the device is not in it
+pub fn needs_renewal(info: &CertificateInfo, names: &[&str], now: DateTime<Utc>) -> bool {
+    !info.is_valid_at(now + Duration::days(RENEW_DAYS)) || names.iter().any(|name| !info.names.iter().any(|known| known == name))
+}
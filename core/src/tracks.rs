@@ -0,0 +1,311 @@
+// Track directories (T0, T1, ...) and their statistics
+//
+// A track directory has the capture files and track.txt which keeps the label of the track and
+// the capture settings of the last session, one "key=value" per line:
+//   label=Garden in spring
+//   setting.resolution=UXGA
+//   setting.duration=60
+
+use anyhow;
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::imagefiles::{segment_file_paths, ImageFiles, OpenMode};
+
+pub const TRACK_FILE_NAME: &str = "track.txt";
+const TRACK_FILE_TMP_NAME: &str = "track.txt.tmp";
+const LABEL_KEY: &str = "label";
+const SETTING_PREFIX: &str = "setting.";
+
+// Long labels are cut to this number of characters
+pub const MAX_LABEL_LEN: usize = 64;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct TrackMeta {
+    pub label: String,
+    pub settings: BTreeMap<String, String>,     // capture settings of the last session
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct TrackInfo {
+    pub id: u32,
+    pub label: String,
+    pub frames: u32,
+    pub segments: u32,
+    pub bytes: u64,                 // all files in the track directory
+    pub first_capture_time: i64,    // UTC ms, 0: unknown
+    pub last_capture_time: i64,     // UTC ms, 0: unknown
+    pub width: u16,                 // of the last frame
+    pub height: u16,
+    pub jpeg_quality: u8,
+    pub settings: BTreeMap<String, String>,
+}
+
+// /eMMC, 3 -> /eMMC/T3
+pub fn track_directory(root: impl AsRef<Path>, id: u32) -> PathBuf {
+    root.as_ref().join(format!("T{}", id))
+}
+
+// /eMMC, 3 -> /eMMC/T3/capture.dat
+pub fn track_file_path(root: impl AsRef<Path>, id: u32) -> PathBuf {
+    track_directory(root, id).join("capture.dat")
+}
+
+// T3 -> Some(3)
+pub fn track_id(directory: impl AsRef<Path>) -> Option<u32> {
+    let name = directory.as_ref().file_name()?.to_str()?;
+    let digits = name.strip_prefix('T')?;
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// one line value without control characters
+fn sanitize(value: &str) -> String {
+    value.chars().map(|c| if c.is_control() { ' ' } else { c }).collect::<String>().trim().to_string()
+}
+
+pub fn sanitize_label(label: &str) -> String {
+    sanitize(label).chars().take(MAX_LABEL_LEN).collect()
+}
+
+fn parse_track_meta(text: &str) -> TrackMeta {
+    let mut meta = TrackMeta::default();
+    for line in text.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if key == LABEL_KEY {
+            meta.label = value.to_string();
+        }
+        else if let Some(name) = key.strip_prefix(SETTING_PREFIX) {
+            meta.settings.insert(name.to_string(), value.to_string());
+        }
+    }
+    meta
+}
+
+fn format_track_meta(meta: &TrackMeta) -> String {
+    let mut text = format!("{}={}\n", LABEL_KEY, sanitize_label(&meta.label));
+    for (name, value) in &meta.settings {
+        let name: String = sanitize(name).chars().filter(|c| *c != '=').collect();
+        text += &format!("{}{}={}\n", SETTING_PREFIX, name, sanitize(value));
+    }
+    text
+}
+
+// The track file of the directory, default if it does not exist
+pub fn read_track_meta(directory: impl AsRef<Path>) -> TrackMeta {
+    let directory = directory.as_ref();
+    // a reset during write_track_meta may leave only the new file
+    for name in [TRACK_FILE_NAME, TRACK_FILE_TMP_NAME] {
+        if let Ok(text) = fs::read_to_string(directory.join(name)) {
+            return parse_track_meta(&text);
+        }
+    }
+    TrackMeta::default()
+}
+
+// Write the new file, then replace the old one (FAT can not rename over an existing file)
+pub fn write_track_meta(directory: impl AsRef<Path>, meta: &TrackMeta) -> Result<(), anyhow::Error> {
+    let directory = directory.as_ref();
+    let tmp_path = directory.join(TRACK_FILE_TMP_NAME);
+    let path = directory.join(TRACK_FILE_NAME);
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        std::io::Write::write_all(&mut file, format_track_meta(meta).as_bytes())?;
+        file.sync_all()?;
+    }
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+pub fn set_track_label(directory: impl AsRef<Path>, label: &str) -> Result<(), anyhow::Error> {
+    let directory = directory.as_ref();
+    let mut meta = read_track_meta(directory);
+    meta.label = sanitize_label(label);
+    write_track_meta(directory, &meta)
+}
+
+// Save the capture settings of the session. The file is written only when the settings are changed.
+pub fn update_track_settings(directory: impl AsRef<Path>, settings: &BTreeMap<String, String>) -> Result<bool, anyhow::Error> {
+    let directory = directory.as_ref();
+    let mut meta = read_track_meta(directory);
+    let settings: BTreeMap<String, String> = settings.iter().map(|(k, v)| (k.clone(), sanitize(v))).collect();
+    if meta.settings == settings && directory.join(TRACK_FILE_NAME).exists() {
+        return Ok(false);
+    }
+    meta.settings = settings;
+    write_track_meta(directory, &meta)?;
+    Ok(true)
+}
+
+fn directory_size(directory: &Path) -> u64 {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries.flatten()
+            .filter_map(|e| e.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+// Statistics of the track /root/T{id}. The first and the last frame are read for the capture times.
+pub fn track_info(root: impl AsRef<Path>, id: u32) -> Result<TrackInfo, anyhow::Error> {
+    let directory = track_directory(&root, id);
+    if !directory.is_dir() {
+        return Err(anyhow::anyhow!("Track not found: {:?}", directory));
+    }
+    let meta = read_track_meta(&directory);
+    let mut info = TrackInfo {
+        id,
+        label: meta.label,
+        bytes: directory_size(&directory),
+        settings: meta.settings,
+        ..Default::default()
+    };
+    let file_path = track_file_path(&root, id);
+    info.segments = segment_file_paths(&file_path).len() as u32;
+    if info.segments == 0 {
+        return Ok(info);
+    }
+    let mut images = ImageFiles::new(&file_path, OpenMode::Read)?;
+    info.frames = images.get_nof_images();
+    if info.frames > 0 {
+        images.seek_image(0)?;
+        info.first_capture_time = images.get_frame_info()?.capture_time;
+        images.seek_image(info.frames - 1)?;
+        let last = images.get_frame_info()?;
+        info.last_capture_time = last.capture_time;
+        info.width = last.width;
+        info.height = last.height;
+        info.jpeg_quality = last.jpeg_quality;
+    }
+    Ok(info)
+}
+
+// All track directories under the root sorted by the id. Broken tracks are listed with their size only.
+pub fn list_tracks(root: impl AsRef<Path>) -> Vec<TrackInfo> {
+    let root = root.as_ref();
+    let mut ids: Vec<u32> = match fs::read_dir(root) {
+        Ok(entries) => entries.flatten()
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| track_id(e.path()))
+            .collect(),
+        Err(e) => {
+            info!("Failed to read directory: {:?}", e);
+            Vec::new()
+        }
+    };
+    ids.sort_unstable();
+    ids.into_iter()
+        .map(|id| match track_info(root, id) {
+            Ok(info) => info,
+            Err(e) => {
+                info!("Failed to read track {}: {:?}", id, e);
+                let directory = track_directory(root, id);
+                let meta = read_track_meta(&directory);
+                TrackInfo { id, label: meta.label, bytes: directory_size(&directory), settings: meta.settings, ..Default::default() }
+            }
+        })
+        .collect()
+}
+
+// Tracks in use, shared by the web server and the capture thread. The files of a track are read by many requests at
+// once, and while the capture appends frames to it. They are rewritten (retention compaction, delete, trim) only when
+// nothing else uses the track, because FAT cannot remove or rename the files which are open. The locks are not waited
+// for, the busy track is an error.
+#[derive(Clone, Default)]
+pub struct TrackLocks {
+    tracks: Arc<Mutex<HashMap<u32, TrackUse>>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TrackUse {
+    readers: u32,
+    appending: bool,
+    writing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Append,
+    Write,
+}
+
+// The track is used until the guard is dropped
+pub struct TrackGuard {
+    tracks: Arc<Mutex<HashMap<u32, TrackUse>>>,
+    id: u32,
+    access: Access,
+}
+
+impl TrackLocks {
+    pub fn new() -> TrackLocks {
+        TrackLocks::default()
+    }
+
+    fn lock(&self, id: u32, access: Access) -> Option<TrackGuard> {
+        let mut tracks = self.tracks.lock().unwrap();
+        let track = tracks.entry(id).or_default();
+        let free = match access {
+            Access::Read => !track.writing,
+            Access::Append => !track.writing && !track.appending,
+            Access::Write => !track.writing && !track.appending && track.readers == 0,
+        };
+        if !free {
+            return None;
+        }
+        match access {
+            Access::Read => track.readers += 1,
+            Access::Append => track.appending = true,
+            Access::Write => track.writing = true,
+        }
+        Some(TrackGuard { tracks: self.tracks.clone(), id, access })
+    }
+
+    // None while the track is rewritten
+    pub fn read(&self, id: u32) -> Option<TrackGuard> {
+        self.lock(id, Access::Read)
+    }
+
+    // Capture session, the track is read but not rewritten. None while the track is captured or rewritten.
+    pub fn append(&self, id: u32) -> Option<TrackGuard> {
+        self.lock(id, Access::Append)
+    }
+
+    // None while the track is read, captured or rewritten
+    pub fn write(&self, id: u32) -> Option<TrackGuard> {
+        self.lock(id, Access::Write)
+    }
+
+    pub fn is_busy(&self, id: u32) -> bool {
+        self.tracks.lock().unwrap().contains_key(&id)
+    }
+}
+
+impl Drop for TrackGuard {
+    fn drop(&mut self) {
+        let mut tracks = self.tracks.lock().unwrap();
+        if let Some(track) = tracks.get_mut(&self.id) {
+            match self.access {
+                Access::Read => track.readers -= 1,
+                Access::Append => track.appending = false,
+                Access::Write => track.writing = false,
+            }
+            if track.readers == 0 && !track.appending && !track.writing {
+                tracks.remove(&self.id);
+            }
+        }
+    }
+}
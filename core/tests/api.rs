@@ -0,0 +1,171 @@
+// REST API and its OpenAPI description
+
+use serde_json::{json, Value};
+use timeleapcam_core::api::{api_time, openapi_document, parse_body, to_json, ApiConfig, ApiError, CaptureAction,
+    CaptureRequest, Monitor, State, CONFIG_API_FIELDS, INVALID_REQUEST, INVALID_VALUE, UNAUTHORIZED};
+use timeleapcam_core::config::ConfigData;
+
+const RESOLUTIONS: [(&str, u32); 3] = [("QVGA", 5), ("VGA", 8), ("UXGA", 13)];
+
+fn config() -> ConfigData {
+    ConfigData {
+        resolution: 8,
+        track_id: 2,
+        duration: 300,
+        timezone: "Asia/Tokyo".to_string(),
+        jpeg_quality: 12,
+        latitude: 35.68,
+        sunrise_margin: -30,
+        api_key: "sk-test".to_string(),
+        ..ConfigData::default()
+    }
+}
+
+#[test]
+fn config_round_trip() {
+    let config = config();
+    let body = to_json(&ApiConfig::from_config(&config, &RESOLUTIONS));
+    let value: Value = serde_json::from_str(&body).unwrap();
+    // JSON types, not strings
+    assert_eq!(value["resolution"], "VGA");
+    assert_eq!(value["trackId"], 2);
+    assert_eq!(value["autoCapture"], false);
+    assert_eq!(value["latitude"], 35.68);
+    assert_eq!(value["sunriseMargin"], -30);
+    assert_eq!(value.as_object().unwrap().len(), CONFIG_API_FIELDS.len());
+    // no secrets
+    assert!(!body.contains("sk-test"));
+
+    // the secrets are kept
+    let mut applied = ConfigData { api_key: "sk-test".to_string(), ..ConfigData::default() };
+    let errors = parse_body::<ApiConfig>(body.as_bytes()).unwrap().apply(&mut applied, &RESOLUTIONS);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(applied, config);
+}
+
+#[test]
+fn config_patch() {
+    let mut config = config();
+    let patch: ApiConfig = parse_body(br#"{"jpegQuality": 20, "autoCapture": true, "resolution": "UXGA"}"#).unwrap();
+    assert!(patch.apply(&mut config, &RESOLUTIONS).is_empty());
+    assert_eq!(config, ConfigData { jpeg_quality: 20, auto_capture: true, resolution: 13, ..self::config() });
+}
+
+#[test]
+fn config_errors() {
+    // the names of the API
+    let patch: ApiConfig = parse_body(br#"{"jpegQuality": 100, "resolution": "8K", "latitude": 91}"#).unwrap();
+    let errors = patch.apply(&mut config(), &RESOLUTIONS);
+    let fields: Vec<&str> = errors.iter().map(|error| error.field.as_str()).collect();
+    assert_eq!(fields, ["resolution", "jpegQuality", "latitude"]);
+
+    // unknown keys, strings of the numbers and the booleans
+    for body in [r#"{"jpegquality": 20}"#, r#"{"jpegQuality": "20"}"#, r#"{"autoCapture": "true"}"#, r#"{"trackId": -1}"#,
+        "jpegQuality=20", ""] {
+        let error = parse_body::<ApiConfig>(body.as_bytes()).unwrap_err();
+        assert_eq!(error.error.code, INVALID_REQUEST, "{}", body);
+        assert_eq!(error.status(), 400);
+    }
+}
+
+#[test]
+fn capture_requests() {
+    let start: CaptureRequest = parse_body(br#"{"action": "start", "duration": 0, "schedule": "*/10 6-18 * * *",
+        "captureStartTime": "2027-05-01T06:00", "captureEndTime": "2027-05-31T18:00:00", "resolution": "VGA"}"#).unwrap();
+    assert_eq!(start.action, CaptureAction::Start);
+    assert!(start.validate(&RESOLUTIONS).is_empty());
+
+    let invalid: CaptureRequest = parse_body(br#"{"action": "start", "duration": 0, "schedule": "every minute",
+        "captureStartTime": "2027-05-01 06:00", "captureFramesAtOnce": -2, "resolution": "8K"}"#).unwrap();
+    let fields: Vec<String> = invalid.validate(&RESOLUTIONS).into_iter().map(|error| error.field).collect();
+    assert_eq!(fields, ["resolution", "schedule", "captureStartTime", "captureFramesAtOnce"]);
+
+    let stop: CaptureRequest = parse_body(br#"{"action": "stop"}"#).unwrap();
+    assert!(stop.validate(&RESOLUTIONS).is_empty());
+    assert_eq!(parse_body::<CaptureRequest>(br#"{"action": "pause"}"#).unwrap_err().error.code, INVALID_REQUEST);
+    assert_eq!(parse_body::<CaptureRequest>(br#"{"trackId": 1}"#).unwrap_err().error.code, INVALID_REQUEST);
+}
+
+#[test]
+fn error_objects() {
+    let error = ApiError::new(UNAUTHORIZED, "Login required");
+    assert_eq!(error.status(), 401);
+    assert_eq!(serde_json::from_str::<Value>(&error.to_json()).unwrap(),
+        json!({"error": {"code": "unauthorized", "message": "Login required", "fields": []}}));
+
+    let mut config = config();
+    let errors = parse_body::<ApiConfig>(br#"{"thinInterval": 0, "thinAfterDays": 7}"#).unwrap().apply(&mut config, &RESOLUTIONS);
+    let error = ApiError::invalid_values(errors);
+    assert_eq!(error.error.code, INVALID_VALUE);
+    assert_eq!(error.status(), 400);
+    let value: Value = serde_json::from_str(&error.to_json()).unwrap();
+    assert_eq!(value["error"]["fields"][0]["field"], "thinInterval");
+}
+
+#[test]
+fn state_times() {
+    assert_eq!(api_time(0), None);
+    assert_eq!(api_time(1809129600).as_deref(), Some("2027-05-01T00:00:00Z"));
+    let state = State {
+        capturing: true, rssi: -60, battery_voltage: 3.9, temperature: 31.5, capture_id: 120,
+        last_capture_time: api_time(1809129600), last_posted_time: api_time(0), capture_frames_at_once: 1,
+        overwrite_saved: false, missed_captures: 0, last_missed_time: None, retention_removed_frames: 0,
+        retention_reclaimed_bytes: 0, storage_total: 1 << 33, storage_free: 1 << 32,
+    };
+    let value: Value = serde_json::from_str(&to_json(&state)).unwrap();
+    assert_eq!(value["lastCaptureTime"], "2027-05-01T00:00:00Z");
+    // not given before the first time
+    assert!(value.get("lastPostedTime").is_none());
+    assert_eq!(value["storageTotal"], 1u64 << 33);
+    let monitor: Monitor = parse_body(br#"{"enabled": false}"#).unwrap();
+    assert_eq!(to_json(&monitor), r#"{"enabled":false}"#);
+}
+
+// every $ref of the document is in the schemas
+fn check_refs(value: &Value, schemas: &Value) {
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::String(reference)) = object.get("$ref") {
+                let name = reference.strip_prefix("#/components/schemas/").unwrap();
+                assert!(schemas.get(name).is_some(), "{}", reference);
+            }
+            object.values().for_each(|value| check_refs(value, schemas));
+        }
+        Value::Array(array) => array.iter().for_each(|value| check_refs(value, schemas)),
+        _ => {}
+    }
+}
+
+#[test]
+fn openapi_description() {
+    let document = openapi_document("1.2.3");
+    assert_eq!(document["openapi"], "3.0.3");
+    assert_eq!(document["info"]["version"], "1.2.3");
+    let schemas = &document["components"]["schemas"];
+    check_refs(&document, schemas);
+
+    let paths = document["paths"].as_object().unwrap();
+    for (path, methods) in [("/api/v1/state", vec!["get"]), ("/api/v1/capture", vec!["get", "post"]),
+        ("/api/v1/resolution", vec!["get", "put"]), ("/api/v1/monitor", vec!["get", "put"]),
+        ("/api/v1/config", vec!["get", "patch"]), ("/api/v1/openapi.json", vec!["get"])] {
+        let operations: Vec<&String> = paths[path].as_object().unwrap().keys().collect();
+        assert_eq!(operations, methods, "{}", path);
+    }
+    // the schemas are the types
+    let config = &schemas["ApiConfig"];
+    assert_eq!(config["properties"].as_object().unwrap().len(), CONFIG_API_FIELDS.len());
+    for (_, name) in CONFIG_API_FIELDS {
+        assert!(config["properties"].get(name).is_some(), "{}", name);
+    }
+    assert!(config.get("required").is_none());
+    assert_eq!(config["additionalProperties"], false);
+    assert_eq!(config["properties"]["autoCapture"]["type"], "boolean");
+    assert_eq!(config["properties"]["sunriseMargin"]["type"], "integer");
+    assert_eq!(config["properties"]["latitude"]["type"], "number");
+    assert_eq!(schemas["CaptureRequest"]["required"], json!(["action"]));
+    assert_eq!(schemas["CaptureRequest"]["properties"]["action"]["enum"], json!(["start", "stop"]));
+    assert_eq!(schemas["ApiError"]["properties"]["error"]["$ref"], "#/components/schemas/ErrorBody");
+    assert_eq!(schemas["ErrorBody"]["properties"]["fields"]["type"], "array");
+    // the document is public
+    assert_eq!(paths["/api/v1/openapi.json"]["get"]["security"], json!([]));
+}
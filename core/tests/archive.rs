@@ -0,0 +1,117 @@
+// ZIP and TAR export of the capture file
+
+mod common;
+
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use common::{frame_info, image, write_frames, BASE_TIME};
+use timeleapcam_core::archive::{archive_frames, entry_name, plan_archive, write_archive, ArchiveFormat, MAX_ARCHIVE_FRAMES};
+use timeleapcam_core::imagefiles::{FrameInfo, ImageFiles, OpenMode};
+
+// frame 0 has no capture time, the others have the milliseconds
+fn archive_capture_time(n: u32) -> i64 {
+    if n == 0 { 0 } else { BASE_TIME + n as i64 * 60_500 }
+}
+
+fn write_archive_track(dir: &Path, frames: u32) -> PathBuf {
+    let path = dir.join("capture.dat");
+    write_frames(&path, frames, 4, |n| FrameInfo { capture_time: archive_capture_time(n), ..frame_info(n) });
+    path
+}
+
+fn archive(path: &Path, format: ArchiveFormat, from: u32, to: u32, zip64: bool) -> Vec<u8> {
+    let mut images = ImageFiles::new(path, OpenMode::Read).unwrap();
+    let mut plan = plan_archive(&mut images, format, 3, from, to).unwrap();
+    assert!(!plan.zip64);
+    plan.zip64 = zip64;
+    let size = plan.file_size();
+    let data = write_archive(&mut images, &plan, Vec::new()).unwrap();
+    assert_eq!(data.len() as u64, size);
+    data
+}
+
+#[test]
+fn entry_names() {
+    assert_eq!(entry_name(3, 12, BASE_TIME + 1500), "T3_000012_20240101T000001.500Z.jpg");
+    assert_eq!(entry_name(0, 1234567, 0), "T0_1234567.jpg");
+    assert_eq!(ArchiveFormat::from_name("ZIP"), Some(ArchiveFormat::Zip));
+    assert_eq!(ArchiveFormat::from_name("tar"), Some(ArchiveFormat::Tar));
+    assert_eq!(ArchiveFormat::from_name("7z"), None);
+}
+
+fn check_zip(data: Vec<u8>, from: u32, to: u32) {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).unwrap();
+    assert_eq!(zip.len() as u32, to - from + 1);
+    for (i, n) in (from..=to).enumerate() {
+        let mut file = zip.by_index(i).unwrap();
+        assert_eq!(file.name(), entry_name(3, n, archive_capture_time(n)));
+        assert_eq!(file.compression(), zip::CompressionMethod::Stored);
+        if n > 0 {
+            // DOS time has the precision of 2 seconds
+            let time = file.last_modified().unwrap();
+            assert_eq!((time.year(), time.month(), time.day()), (2024, 1, 1));
+            assert_eq!(time.minute() as u32, (n * 60_500 / 1000 / 60) % 60);
+        }
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, image(n));
+    }
+}
+
+#[test]
+fn zip_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_archive_track(dir.path(), 10);
+    check_zip(archive(&path, ArchiveFormat::Zip, 0, 9, false), 0, 9);
+    check_zip(archive(&path, ArchiveFormat::Zip, 3, 100, false), 3, 9);
+}
+
+#[test]
+fn zip64_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_archive_track(dir.path(), 6);
+    check_zip(archive(&path, ArchiveFormat::Zip, 0, 5, true), 0, 5);
+}
+
+#[test]
+fn tar_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_archive_track(dir.path(), 7);
+    let data = archive(&path, ArchiveFormat::Tar, 0, 6, false);
+    assert_eq!(data.len() % 512, 0);
+    let mut tar = tar::Archive::new(Cursor::new(data));
+    let mut count = 0;
+    for (n, entry) in tar.entries().unwrap().enumerate() {
+        let mut entry = entry.unwrap();
+        let n = n as u32;
+        let capture_time = archive_capture_time(n);
+        assert_eq!(entry.path().unwrap().to_str().unwrap(), entry_name(3, n, capture_time));
+        assert_eq!(entry.header().mode().unwrap(), 0o644);
+        assert_eq!(entry.header().mtime().unwrap() as i64, capture_time / 1000);
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        assert_eq!(content, image(n));
+        count += 1;
+    }
+    assert_eq!(count, 7);
+}
+
+#[test]
+fn empty_range_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_archive_track(dir.path(), 3);
+    let mut images = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    assert!(plan_archive(&mut images, ArchiveFormat::Zip, 0, 3, 5).is_err());
+    assert!(plan_archive(&mut images, ArchiveFormat::Tar, 0, 2, 1).is_err());
+}
+
+#[test]
+fn archive_size_is_limited() {
+    assert_eq!(archive_frames(10, 3, 100), 7);
+    assert_eq!(archive_frames(10, 0, u32::MAX), 10);
+    assert_eq!(archive_frames(10, 10, 20), 0);
+    assert_eq!(archive_frames(0, 0, 0), 0);
+    assert_eq!(archive_frames(u32::MAX, 0, MAX_ARCHIVE_FRAMES - 1), MAX_ARCHIVE_FRAMES);
+    assert_eq!(archive_frames(u32::MAX, 1, u32::MAX), u32::MAX - 1);
+}
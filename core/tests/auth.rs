@@ -0,0 +1,203 @@
+// Authentication of the control web server
+
+use timeleapcam_core::auth::{check_password, expired_cookie, hash_password, in_setup_window, is_page, is_password_hash,
+    request_token, required_role, session_cookie, unprotected_role, verify_password, Auth, LoginError, Role, SESSION_SECONDS,
+    SETUP_SECONDS, TOKEN_SIZE};
+use timeleapcam_core::config::ConfigData;
+use std::net::{IpAddr, Ipv4Addr};
+
+const SALT: [u8; 16] = [3; 16];
+const NOW: u64 = 1714521600;
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+
+fn hashes() -> (String, String) {
+    (hash_password("admin-password", &SALT, 100), hash_password("viewer-password", &[4; 16], 100))
+}
+
+#[test]
+fn password_hash() {
+    let hash = hash_password("admin-password", &SALT, 100);
+    assert!(hash.starts_with("pbkdf2-sha256$100$03030303"));
+    assert!(!hash.contains("admin-password"));
+    assert!(is_password_hash(&hash));
+    assert!(verify_password("admin-password", &hash));
+    assert!(!verify_password("admin-passwore", &hash));
+    assert!(!verify_password("", &hash));
+    // another salt
+    assert_ne!(hash_password("admin-password", &[4; 16], 100), hash);
+    // not set or broken
+    assert!(!verify_password("admin-password", ""));
+    assert!(!is_password_hash("admin-password"));
+    assert!(!is_password_hash("pbkdf2-sha256$0$03$00"));
+    assert!(!is_password_hash(&format!("{}$00", hash)));
+}
+
+#[test]
+fn pbkdf2_test_vector() {
+    // RFC 7914, PBKDF2-HMAC-SHA256 P="passwd" S="salt" c=1
+    let hash = "pbkdf2-sha256$1$73616c74$55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc";
+    assert!(verify_password("passwd", hash));
+    assert!(check_password("short").is_err());
+    assert!(check_password("long enough").is_ok());
+}
+
+#[test]
+fn login_roles() {
+    let (admin, viewer) = hashes();
+    let mut auth = Auth::new();
+    let (admin_token, role) = auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW).unwrap();
+    assert_eq!(role, Role::Admin);
+    assert_eq!(admin_token.len(), TOKEN_SIZE * 2);
+    let (viewer_token, role) = auth.login("viewer-password", &admin, &viewer, &[2; TOKEN_SIZE], CLIENT, NOW).unwrap();
+    assert_eq!(role, Role::Viewer);
+    assert_eq!(auth.role(Some(&admin_token), NOW + 10), Some(Role::Admin));
+    assert_eq!(auth.role(Some(&viewer_token), NOW + 10), Some(Role::Viewer));
+    assert_eq!(auth.role(Some("0101"), NOW), None);
+    // a prefix or a longer token is not the token
+    assert_eq!(auth.role(Some(&admin_token[..admin_token.len() - 1]), NOW), None);
+    assert_eq!(auth.role(Some(&format!("{}0", admin_token)), NOW), None);
+    assert_eq!(auth.role(None, NOW), None);
+    // no viewer password
+    assert_eq!(auth.login("", &admin, "", &[3; TOKEN_SIZE], CLIENT, NOW), Err(LoginError::Invalid));
+
+    auth.logout(&viewer_token);
+    assert_eq!(auth.role(Some(&viewer_token), NOW), None);
+    // expired
+    assert_eq!(auth.role(Some(&admin_token), NOW + SESSION_SECONDS), None);
+}
+
+#[test]
+fn sessions_are_limited() {
+    let (admin, viewer) = hashes();
+    let mut auth = Auth::new();
+    let tokens: Vec<String> = (0..9u8)
+        .map(|n| auth.login("admin-password", &admin, &viewer, &[n; TOKEN_SIZE], CLIENT, NOW + n as u64).unwrap().0)
+        .collect();
+    // the oldest one is closed
+    assert_eq!(auth.role(Some(&tokens[0]), NOW + 10), None);
+    assert!(tokens[1..].iter().all(|token| auth.role(Some(token), NOW + 10) == Some(Role::Admin)));
+
+    // the other sessions of the role after the password is changed
+    let (viewer_token, _) = auth.login("viewer-password", &admin, &viewer, &[20; TOKEN_SIZE], CLIENT, NOW + 20).unwrap();
+    auth.close_sessions(Role::Admin, Some(&tokens[8]));
+    assert_eq!(auth.role(Some(&tokens[8]), NOW + 30), Some(Role::Admin));
+    assert_eq!(auth.role(Some(&tokens[7]), NOW + 30), None);
+    assert_eq!(auth.role(Some(&viewer_token), NOW + 30), Some(Role::Viewer));
+}
+
+#[test]
+fn login_rate_limit() {
+    let (admin, viewer) = hashes();
+    let mut auth = Auth::new();
+    for _ in 0..2 {
+        assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW), Err(LoginError::Invalid));
+    }
+    // locked after the third failure, for 1, 2, 4 ... seconds
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW), Err(LoginError::Invalid));
+    assert_eq!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW), Err(LoginError::Locked(1)));
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW + 1), Err(LoginError::Invalid));
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW + 2), Err(LoginError::Locked(1)));
+    let mut now = NOW + 3;
+    for _ in 0..20 {
+        assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now), Err(LoginError::Invalid));
+        match auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now) {
+            Err(LoginError::Locked(seconds)) => {
+                assert!(seconds <= 300);
+                now += seconds;
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+    // up to 5 minutes
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now), Err(LoginError::Invalid));
+    assert_eq!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now + 299), Err(LoginError::Locked(1)));
+    // reset by a login
+    assert!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now + 300).is_ok());
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now + 300), Err(LoginError::Invalid));
+    assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, now + 300), Err(LoginError::Invalid));
+}
+
+#[test]
+fn login_rate_limit_of_each_client() {
+    let (admin, viewer) = hashes();
+    let mut auth = Auth::new();
+    let other = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 30));
+    for _ in 0..3 {
+        assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], other, NOW), Err(LoginError::Invalid));
+    }
+    assert_eq!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], other, NOW), Err(LoginError::Locked(1)));
+    // the failures of the other client do not lock out the admin
+    assert!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW).is_ok());
+    // the failures are kept for a limited number of clients
+    for n in 0..100u8 {
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+        assert_eq!(auth.login("guess", &admin, &viewer, &[1; TOKEN_SIZE], client, NOW + 1), Err(LoginError::Invalid));
+    }
+    assert!(auth.login("admin-password", &admin, &viewer, &[1; TOKEN_SIZE], CLIENT, NOW + 1).is_ok());
+}
+
+#[test]
+fn unprotected_server() {
+    // only the admin password in the setup window after a power on or a reset
+    assert!(in_setup_window(true, 0));
+    assert!(in_setup_window(true, SETUP_SECONDS - 1));
+    assert!(!in_setup_window(true, SETUP_SECONDS));
+    // not after a wakeup from the deep sleep
+    assert!(!in_setup_window(false, 0));
+    assert_eq!(unprotected_role("/auth/password", true), Role::Admin);
+    assert_eq!(unprotected_role("/auth/password", false), Role::Viewer);
+    assert_eq!(unprotected_role("/config", true), Role::Viewer);
+    assert_eq!(unprotected_role("/capture", true), Role::Viewer);
+}
+
+#[test]
+fn roles_of_requests() {
+    assert_eq!(required_role("GET", "/data"), Some(Role::Viewer));
+    assert_eq!(required_role("GET", "/tracks/*"), Some(Role::Viewer));
+    assert_eq!(required_role("GET", "/config"), Some(Role::Viewer));
+    assert_eq!(required_role("GET", "/"), Some(Role::Viewer));
+    assert_eq!(required_role("POST", "/capture"), Some(Role::Admin));
+    assert_eq!(required_role("POST", "/config"), Some(Role::Admin));
+    assert_eq!(required_role("POST", "/oneshot"), Some(Role::Admin));
+    assert_eq!(required_role("DELETE", "/tracks/*"), Some(Role::Admin));
+    assert_eq!(required_role("PUT", "/tracks/*"), Some(Role::Admin));
+    assert_eq!(required_role("GET", "/config/export"), Some(Role::Admin));
+    assert_eq!(required_role("POST", "/login"), None);
+    assert_eq!(required_role("POST", "/logout"), None);
+    assert_eq!(required_role("GET", "/login.html"), None);
+    assert_eq!(required_role("GET", "/api/v1/openapi.json"), None);
+    assert_eq!(required_role("GET", "/api/v1/config"), Some(Role::Viewer));
+    assert_eq!(required_role("PATCH", "/api/v1/config"), Some(Role::Admin));
+    assert!(Role::Admin > Role::Viewer);
+    assert!(is_page("/") && is_page("/config.html"));
+    assert!(!is_page("/data"));
+}
+
+#[test]
+fn tokens_of_requests() {
+    assert_eq!(request_token(Some("Bearer abcd"), None), Some("abcd"));
+    assert_eq!(request_token(None, Some("theme=dark; tlcsession=abcd")), Some("abcd"));
+    assert_eq!(request_token(Some("Bearer abcd"), Some("tlcsession=efgh")), Some("abcd"));
+    assert_eq!(request_token(Some("Basic abcd"), Some("other=abcd")), None);
+    assert_eq!(request_token(None, None), None);
+    assert!(session_cookie("abcd", false).starts_with("tlcsession=abcd; Path=/;"));
+    assert!(session_cookie("abcd", false).contains("HttpOnly"));
+    assert!(!session_cookie("abcd", false).contains("Secure"));
+    assert!(expired_cookie(false).contains("Max-Age=0"));
+    // only over HTTPS
+    assert!(session_cookie("abcd", true).ends_with("; Secure"));
+    assert!(expired_cookie(true).ends_with("; Secure"));
+}
+
+#[test]
+fn password_hashes_are_secrets() {
+    let (admin, _) = hashes();
+    let config = ConfigData { admin_password_hash: admin.clone(), ..ConfigData::default() };
+    assert_eq!(config.validate(), []);
+    assert!(!config.export_toml(true).contains(&admin));
+    assert!(config.secrets_toml().contains(&admin));
+    assert!(!format!("{:?}", config).contains(&admin));
+    assert!(config.secret_states().contains(&("adminpasswordhash", "set")));
+    let config = ConfigData { viewer_password_hash: "plain-password".to_string(), ..ConfigData::default() };
+    assert_eq!(config.validate()[0].field, "viewerpasswordhash");
+}
@@ -0,0 +1,121 @@
+// MJPEG AVI export of the capture file
+
+use timeleapcam_core::avi::{avi_file_size, avi_frames, jpeg_dimensions, max_frames, plan_avi, write_avi, AviWriter, MAX_VIDEO_FRAMES};
+use timeleapcam_core::imagefiles::{FrameInfo, ImageFiles, OpenMode};
+
+// Minimal JPEG with a SOF0 segment
+fn jpeg(width: u16, height: u16, fill: u8, len: usize) -> Vec<u8> {
+    let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x0B, 0x08];
+    data.extend(height.to_be_bytes());
+    data.extend(width.to_be_bytes());
+    data.extend([0x01, 0x01, 0x11, 0x00]);
+    data.extend(std::iter::repeat(fill).take(len));
+    data.extend([0xFF, 0xD9]);
+    data
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+// Parse the AVI and return the frames by following idx1
+fn parse_avi(avi: &[u8]) -> (u32, u32, u32, Vec<Vec<u8>>) {
+    assert_eq!(&avi[0..4], b"RIFF");
+    assert_eq!(u32_at(avi, 4) as usize, avi.len() - 8);
+    assert_eq!(&avi[8..12], b"AVI ");
+    assert_eq!(&avi[12..16], b"LIST");
+    assert_eq!(&avi[20..24], b"hdrl");
+    assert_eq!(&avi[24..28], b"avih");
+    let usec_per_frame = u32_at(avi, 32);
+    let total_frames = u32_at(avi, 48);
+    let width = u32_at(avi, 64);
+    let hdrl_end = 20 + u32_at(avi, 16) as usize;
+    assert_eq!(&avi[hdrl_end..hdrl_end + 4], b"LIST");
+    let movi = hdrl_end + 8;
+    assert_eq!(&avi[movi..movi + 4], b"movi");
+    let idx1 = movi + u32_at(avi, hdrl_end + 4) as usize;
+    assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+    let entries = u32_at(avi, idx1 + 4) as usize / 16;
+    assert_eq!(idx1 + 8 + entries * 16, avi.len());
+    let mut frames = Vec::new();
+    for n in 0..entries {
+        let entry = idx1 + 8 + n * 16;
+        assert_eq!(&avi[entry..entry + 4], b"00dc");
+        let chunk = movi + u32_at(avi, entry + 8) as usize;
+        let size = u32_at(avi, entry + 12) as usize;
+        assert_eq!(&avi[chunk..chunk + 4], b"00dc");
+        assert_eq!(u32_at(avi, chunk + 4) as usize, size);
+        frames.push(avi[chunk + 8..chunk + 8 + size].to_vec());
+    }
+    (usec_per_frame, total_frames, width, frames)
+}
+
+#[test]
+fn jpeg_dimensions_from_sof() {
+    assert_eq!(jpeg_dimensions(&jpeg(1600, 1200, 0, 10)), Some((1600, 1200)));
+    assert_eq!(jpeg_dimensions(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+    assert_eq!(jpeg_dimensions(b"not a jpeg"), None);
+}
+
+#[test]
+fn export_frame_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    let images: Vec<Vec<u8>> = (0..6).map(|n| jpeg(640, 480, n as u8, 100 + n)).collect();
+    let mut files = ImageFiles::new(&path, OpenMode::Write).unwrap();
+    for (n, image) in images.iter().enumerate() {
+        let info = FrameInfo { capture_time: 1 + n as i64, width: 640, height: 480, ..Default::default() };
+        files.write_image(image, &info).unwrap();
+    }
+    files.write_image_end().unwrap();
+    drop(files);
+
+    let mut files = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    let plan = plan_avi(&mut files, 1, 4).unwrap();
+    assert_eq!((plan.width, plan.height), (640, 480));
+    let size = plan.file_size();
+    let avi = write_avi(&mut files, plan, 5, Vec::new()).unwrap();
+    assert_eq!(avi.len() as u64, size);
+    let (usec_per_frame, total_frames, width, frames) = parse_avi(&avi);
+    assert_eq!(usec_per_frame, 200_000);
+    assert_eq!(total_frames, 4);
+    assert_eq!(width, 640);
+    assert_eq!(frames, images[1..=4].to_vec());
+
+    // range beyond the last frame is clipped
+    let plan = plan_avi(&mut files, 4, 100).unwrap();
+    assert_eq!(plan.frame_sizes.len(), 2);
+    assert!(plan_avi(&mut files, 6, 10).is_err());
+}
+
+#[test]
+fn writer_checks_frame_sizes() {
+    let mut writer = AviWriter::new(Vec::new(), 320, 240, 10, vec![3, 4]).unwrap();
+    assert!(writer.write_frame(&[1, 2]).is_err());
+    writer.write_frame(&[1, 2, 3]).unwrap();
+    writer.write_frame(&[1, 2, 3, 4]).unwrap();
+    let avi = writer.finish().unwrap();
+    assert_eq!(avi.len() as u64, avi_file_size(&[3, 4]));
+    let (_, _, _, frames) = parse_avi(&avi);
+    assert_eq!(frames, vec![vec![1, 2, 3], vec![1, 2, 3, 4]]);
+    assert!(AviWriter::new(Vec::new(), 320, 240, 0, vec![1]).is_err());
+}
+
+#[test]
+fn frames_over_4gb_are_cut() {
+    let sizes = vec![1_000_000_000u32; 5];
+    assert_eq!(max_frames(&sizes), 4);
+    assert!(AviWriter::new(Vec::new(), 320, 240, 10, sizes).is_err());
+}
+
+#[test]
+fn video_frame_ranges() {
+    assert_eq!(avi_frames(10, 2, 5), 4);
+    assert_eq!(avi_frames(10, 2, u32::MAX), 8);
+    assert_eq!(avi_frames(10, 10, u32::MAX), 0);
+    assert_eq!(avi_frames(10, 5, 4), 0);
+    assert_eq!(avi_frames(0, 0, u32::MAX), 0);
+    // the default range of /video is the whole track
+    assert!(avi_frames(MAX_VIDEO_FRAMES + 1, 0, u32::MAX) > MAX_VIDEO_FRAMES);
+    assert_eq!(avi_frames(u32::MAX, 100, 100 + MAX_VIDEO_FRAMES - 1), MAX_VIDEO_FRAMES);
+}
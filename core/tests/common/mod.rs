@@ -0,0 +1,71 @@
+// Capture files shared by the tests
+//
+// Frame n has the image image(n) and was captured at capture_time(n), so a frame read back from a track tells
+// its original frame number.
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+use timeleapcam_core::imagefiles::{FrameInfo, ImageFiles, OpenMode};
+
+// 2024-01-01T00:00:00Z
+pub const BASE_TIME: i64 = 1_704_067_200_000;
+// one frame every 10 minutes
+pub const FRAME_INTERVAL_MS: i64 = 10 * 60 * 1000;
+
+pub fn image(n: u32) -> Vec<u8> {
+    // JPEG like payload, size differs per frame
+    let mut data = vec![0xFF, 0xD8];
+    data.extend((0..(100 + n % 7)).map(|i| (i as u8).wrapping_mul(31).wrapping_add(n as u8)));
+    data.extend([0xFF, 0xD9]);
+    data
+}
+
+pub fn capture_time(n: u32) -> i64 {
+    BASE_TIME + n as i64 * FRAME_INTERVAL_MS
+}
+
+pub fn frame_info(n: u32) -> FrameInfo {
+    FrameInfo {
+        capture_time: capture_time(n),
+        width: 640,
+        height: 480,
+        jpeg_quality: 12,
+        ..Default::default()
+    }
+}
+
+// frames 0..frames with segments of max_frames frames (0: no limit)
+pub fn write_frames(path: &Path, frames: u32, max_frames: u32, frame_info: impl Fn(u32) -> FrameInfo) {
+    let mut files = ImageFiles::new(path, OpenMode::Write).unwrap();
+    files.set_segment_limit(0, max_frames);
+    for n in 0..frames {
+        files.write_image(&image(n), &frame_info(n)).unwrap();
+    }
+    files.write_image_end().unwrap();
+}
+
+// capture.dat in dir
+pub fn write_track(dir: &Path, frames: u32, max_frames: u32) -> PathBuf {
+    let path = dir.join("capture.dat");
+    write_frames(&path, frames, max_frames, frame_info);
+    path
+}
+
+// original frame numbers remaining in the track
+pub fn remaining_frames(path: &Path) -> Vec<u32> {
+    let mut files = ImageFiles::new(path, OpenMode::Read).unwrap();
+    let mut frames = Vec::new();
+    if files.get_nof_images() == 0 {
+        return frames;
+    }
+    files.seek_image(0).unwrap();
+    for _ in 0..files.get_nof_images() {
+        let (info, data) = files.read_frame().unwrap();
+        let n = ((info.capture_time - BASE_TIME) / FRAME_INTERVAL_MS) as u32;
+        assert_eq!(data, image(n));
+        frames.push(n);
+    }
+    frames
+}
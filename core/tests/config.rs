@@ -0,0 +1,211 @@
+// Configuration schema, defaults and validation
+
+use timeleapcam_core::config::{ConfigData, ConfigError, MAX_TRACK_ID};
+
+// Stored by the previous firmware: upper case keys and string values
+const STORED: &str = r#"SSID = "camera-net"
+PSK = "secret-psk"
+TIMEZONE = "Asia/Tokyo"
+IDLESLEEP = "600"
+AUTOCAPTURE = "true"
+RESOLUTION = "17"
+TRACKID = "3"
+DURATION = "0"
+MODEL = "gpt-4o"
+APIKEY = "sk-key"
+QUERYOPENAI = "false"
+QUERYPROMPT = "Describe the sky"
+POSTACCOUNT = ""
+POSTACCESSTOKEN = ""
+STORAGEACCOUNT = ""
+STORAGEACCESSTOKEN = ""
+STORAGESIGNEDKEY = ""
+POSTMESSAGETRIGGER = ""
+AUTOFOCUSONCE = "true"
+STATUSREPORT = "true"
+STATUSREPORTINTERVAL = "3600"
+POSTINTERVAL = "3600"
+SCHEDULE = "*/10 6-17 * * MON-FRI"
+CAPTUREFRAMESATONCE = "0"
+OVERWRITESAVED = "false"
+DIRECTWRITEMODE = "false"
+JPEGQUALITY = "10"
+SEGMENTSIZE = "512"
+SEGMENTFRAMES = "0"
+RETENTIONDAYS = "30"
+RETENTIONFRAMES = "0"
+THINAFTERDAYS = "7"
+THININTERVAL = "60"
+LATITUDE = "35.68"
+LONGITUDE = "139.77"
+SUNSCHEDULE = "daylight"
+SUNRISEMARGIN = "30"
+SUNSETMARGIN = "-15"
+MISSEDPOLICY = "skip"
+"#;
+
+fn fields(errors: &[ConfigError]) -> Vec<&str> {
+    let mut fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    fields.sort();
+    fields
+}
+
+#[test]
+fn load_stored_config() {
+    let (config, errors) = ConfigData::from_toml(STORED, &ConfigData::default()).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.wifi_ssid, "camera-net");
+    assert_eq!(config.wifi_psk, "secret-psk");
+    assert_eq!(config.timezone, "Asia/Tokyo");
+    assert_eq!(config.idle_in_sleep_time, 600);
+    assert!(config.auto_capture);
+    assert_eq!(config.resolution, 17);
+    assert_eq!(config.schedule, "*/10 6-17 * * MON-FRI");
+    assert_eq!(config.jpeg_quality, 10);
+    assert_eq!(config.latitude, 35.68);
+    assert_eq!(config.sunset_margin, -15);
+    assert_eq!(config.missed_policy, "skip");
+}
+
+#[test]
+fn missing_keys_are_defaults() {
+    // the WiFi credentials are kept when the keys of a newer version are not stored
+    let stored: String = STORED.lines()
+        .filter(|line| !line.starts_with("JPEGQUALITY") && !line.starts_with("MISSEDPOLICY"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let defaults = ConfigData { jpeg_quality: 20, ..ConfigData::default() };
+    let (config, errors) = ConfigData::from_toml(&stored, &defaults).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.wifi_ssid, "camera-net");
+    assert_eq!(config.wifi_psk, "secret-psk");
+    assert_eq!(config.jpeg_quality, 20);
+    assert_eq!(config.missed_policy, "align");
+
+    let (config, errors) = ConfigData::from_toml("CONFIGVERSION = \"7\"\nSSID = \"only-wifi\"\n", &defaults).unwrap();
+    assert!(errors.is_empty());
+    assert_eq!(config, ConfigData { wifi_ssid: "only-wifi".to_string(), ..defaults });
+}
+
+#[test]
+fn invalid_values_are_defaults() {
+    let stored = STORED.replace("JPEGQUALITY = \"10\"", "JPEGQUALITY = \"high\"")
+        .replace("AUTOCAPTURE = \"true\"", "AUTOCAPTURE = \"yes\"")
+        .replace("TIMEZONE = \"Asia/Tokyo\"", "TIMEZONE = \"15\"")
+        .replace("LATITUDE = \"35.68\"", "LATITUDE = \"95\"")
+        .replace("SUNSCHEDULE = \"daylight\"", "SUNSCHEDULE = \"night\"")
+        .replace("RESOLUTION = \"17\"", "RESOLUTION = \"42\"")
+        .replace("TRACKID = \"3\"", "TRACKID = \"100000\"");
+    let (config, errors) = ConfigData::from_toml(&stored, &ConfigData::default()).unwrap();
+    assert_eq!(fields(&errors), ["autocapture", "jpegquality", "latitude", "resolution", "sunschedule", "timezone", "trackid"]);
+    assert_eq!(config.resolution, 8);
+    assert_eq!(config.track_id, 0);
+    assert_eq!(config.jpeg_quality, 12);
+    assert!(!config.auto_capture);
+    assert_eq!(config.timezone, "0");
+    assert_eq!(config.latitude, 0.0);
+    assert_eq!(config.sun_schedule, "off");
+    // the other values are kept
+    assert_eq!(config.wifi_ssid, "camera-net");
+    assert_eq!(config.longitude, 139.77);
+    assert_eq!(config.validate(), []);
+}
+
+#[test]
+fn keys_and_values() {
+    // lower case keys and TOML values, unknown keys are ignored
+    let stored = "ssid = \"net\"\njpegquality = 30\nlatitude = -33.9\nstatusreport = true\nunknown = \"x\"\n";
+    let (config, errors) = ConfigData::from_toml(stored, &ConfigData::default()).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.wifi_ssid, "net");
+    assert_eq!(config.jpeg_quality, 30);
+    assert_eq!(config.latitude, -33.9);
+    assert!(config.status_report);
+
+    assert!(ConfigData::from_toml("SSID = \"unterminated\n", &ConfigData::default()).is_err());
+}
+
+#[test]
+fn save_and_load() {
+    let config = ConfigData {
+        wifi_ssid: "my \"home\" net".to_string(),
+        wifi_psk: "pass\\word=1".to_string(),
+        query_prompt: "Is it raining?\nAnswer yes or no.".to_string(),
+        latitude: 51.5,
+        longitude: -0.12,
+        capture_frames_at_once: -1,
+        ..ConfigData::default()
+    };
+    let stored = config.to_toml();
+    assert!(stored.contains("JPEGQUALITY = \"12\""));
+    assert!(stored.contains("LATITUDE = \"51.5\""));
+    assert!(stored.contains("CAPTUREFRAMESATONCE = \"-1\""));
+    let (loaded, errors) = ConfigData::from_toml(&stored, &ConfigData::default()).unwrap();
+    assert!(errors.is_empty());
+    assert_eq!(loaded, config);
+}
+
+#[test]
+fn defaults_from_strings() {
+    let (config, errors) = ConfigData::from_strings(&[("ssid", "cfg-net"), ("jpeg_quality", "8"), ("jpegquality", "8"),
+        ("thininterval", "0")], &ConfigData::default()).unwrap();
+    assert_eq!(fields(&errors), ["thininterval"]);
+    assert_eq!(config.wifi_ssid, "cfg-net");
+    assert_eq!(config.jpeg_quality, 8);
+    assert_eq!(config.thin_interval, 60);
+}
+
+#[test]
+fn old_leap_time_is_schedule() {
+    let stored = "SSID = \"net\"\nLEAPDAY = \"-1\"\nLEAPHOUR = \"7\"\nLEAPMINUTE = \"15\"\n";
+    let (config, _) = ConfigData::from_toml(stored, &ConfigData::default()).unwrap();
+    assert_eq!(config.schedule, "15 7 * * *");
+    // the schedule is used when it is stored
+    let stored = "SCHEDULE = \"0 12 * * *\"\nLEAPHOUR = \"7\"\n";
+    let (config, _) = ConfigData::from_toml(stored, &ConfigData::default()).unwrap();
+    assert_eq!(config.schedule, "0 12 * * *");
+}
+
+#[test]
+fn validate_ranges() {
+    let valid = ConfigData::default();
+    assert_eq!(valid.validate(), []);
+    let check = |config: ConfigData| fields(&config.validate()).iter().map(|f| f.to_string()).collect::<Vec<String>>();
+
+    assert!(check(ConfigData { jpeg_quality: 4, ..valid.clone() }).is_empty());
+    assert!(check(ConfigData { jpeg_quality: 63, ..valid.clone() }).is_empty());
+    assert_eq!(check(ConfigData { jpeg_quality: 3, ..valid.clone() }), ["jpegquality"]);
+    assert_eq!(check(ConfigData { jpeg_quality: 64, ..valid.clone() }), ["jpegquality"]);
+
+    assert!(check(ConfigData { timezone: "-12".to_string(), ..valid.clone() }).is_empty());
+    assert!(check(ConfigData { timezone: "14".to_string(), ..valid.clone() }).is_empty());
+    assert!(check(ConfigData { timezone: "Europe/Berlin".to_string(), ..valid.clone() }).is_empty());
+    assert_eq!(check(ConfigData { timezone: "-13".to_string(), ..valid.clone() }), ["timezone"]);
+    assert_eq!(check(ConfigData { timezone: "14.5".to_string(), ..valid.clone() }), ["timezone"]);
+    assert_eq!(check(ConfigData { timezone: "Mars/Olympus".to_string(), ..valid.clone() }), ["timezone"]);
+
+    assert_eq!(check(ConfigData { schedule: "*/10 25 * * *".to_string(), ..valid.clone() }), ["schedule"]);
+    // not used with a duration
+    assert!(check(ConfigData { schedule: "*/10 25 * * *".to_string(), duration: 600, ..valid.clone() }).is_empty());
+
+    assert_eq!(check(ConfigData { wifi_ssid: "x".repeat(33), ..valid.clone() }), ["ssid"]);
+    assert_eq!(check(ConfigData { wifi_psk: "short".to_string(), ..valid.clone() }), ["psk"]);
+    assert_eq!(check(ConfigData { capture_frames_at_once: -2, ..valid.clone() }), ["captureframesatonce"]);
+    assert_eq!(check(ConfigData { segment_size: 4096, ..valid.clone() }), ["segmentsize"]);
+    assert_eq!(check(ConfigData { longitude: -180.5, ..valid.clone() }), ["longitude"]);
+    assert_eq!(check(ConfigData { latitude: f64::NAN, ..valid.clone() }), ["latitude"]);
+    assert_eq!(check(ConfigData { sunrise_margin: 721, sunset_margin: -721, ..valid.clone() }), ["sunrisemargin", "sunsetmargin"]);
+    assert_eq!(check(ConfigData { missed_policy: "later".to_string(), ..valid.clone() }), ["missedpolicy"]);
+
+    assert!(check(ConfigData { resolution: 5, ..valid.clone() }).is_empty());
+    assert!(check(ConfigData { resolution: 21, ..valid.clone() }).is_empty());
+    // 96x96 and the portrait sizes are not offered
+    assert_eq!(check(ConfigData { resolution: 0, ..valid.clone() }), ["resolution"]);
+    assert_eq!(check(ConfigData { resolution: 15, ..valid.clone() }), ["resolution"]);
+    assert_eq!(check(ConfigData { resolution: 99, ..valid.clone() }), ["resolution"]);
+    assert!(check(ConfigData { track_id: MAX_TRACK_ID, ..valid.clone() }).is_empty());
+    assert_eq!(check(ConfigData { track_id: MAX_TRACK_ID + 1, ..valid.clone() }), ["trackid"]);
+
+    let error = &ConfigData { jpeg_quality: 99, ..valid }.validate()[0];
+    assert_eq!(error.to_string(), "jpegquality: Out of range (4 to 63)");
+}
@@ -0,0 +1,105 @@
+// Export and import of the configuration for the provisioning of the cameras
+
+use timeleapcam_core::config::{ConfigChange, ConfigData, ConfigError, CONFIG_VERSION, SECRET_KEYS};
+
+fn current() -> ConfigData {
+    ConfigData {
+        wifi_ssid: "camera-net".to_string(),
+        wifi_psk: "secret-psk".to_string(),
+        api_key: "sk-key".to_string(),
+        timezone: "Asia/Tokyo".to_string(),
+        jpeg_quality: 10,
+        ..ConfigData::default()
+    }
+}
+
+fn fields(errors: &[ConfigError]) -> Vec<&str> {
+    let mut fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    fields.sort();
+    fields
+}
+
+fn change(field: &str, old: &str, new: &str) -> ConfigChange {
+    ConfigChange { field: field.to_string(), old: old.to_string(), new: new.to_string() }
+}
+
+#[test]
+fn export_and_import() {
+    let exported = current().export_toml(false);
+    assert!(exported.contains("PSK = \"secret-psk\""));
+    assert!(exported.contains(&format!("CONFIGVERSION = \"{}\"", CONFIG_VERSION)));
+    // same as the stored configuration
+    assert_eq!(exported, current().to_toml());
+    let (config, changes) = ConfigData::import(&exported, &ConfigData::default()).unwrap();
+    assert_eq!(config, current());
+    assert!(changes.contains(&change("jpegquality", "12", "10")));
+    assert!(changes.contains(&change("psk", "", "********")));
+    // nothing is changed by the import of the own export
+    let (config, changes) = ConfigData::import(&exported, &current()).unwrap();
+    assert_eq!(config, current());
+    assert_eq!(changes, []);
+}
+
+#[test]
+fn redacted_export_keeps_secrets() {
+    let exported = current().export_toml(true);
+    for key in SECRET_KEYS {
+        assert!(!exported.contains(&format!("{} =", key.to_ascii_uppercase())), "{}", key);
+    }
+    assert!(exported.contains("SSID = \"camera-net\""));
+    let other = ConfigData { wifi_psk: "other-psk-1".to_string(), api_key: "sk-other".to_string(), ..ConfigData::default() };
+    let (config, changes) = ConfigData::import(&exported, &other).unwrap();
+    assert_eq!(config.wifi_psk, "other-psk-1");
+    assert_eq!(config.api_key, "sk-other");
+    assert_eq!(config.timezone, "Asia/Tokyo");
+    assert!(changes.iter().all(|c| !SECRET_KEYS.contains(&c.field.as_str())));
+}
+
+#[test]
+fn partial_json_document() {
+    let document = r#"{ "jpegQuality": 20, "sun_schedule": "daylight", "LATITUDE": 35.68, "statusReport": true, "schedule": "0 6 * * *" }"#;
+    let (config, changes) = ConfigData::import(document, &current()).unwrap();
+    assert_eq!(config.jpeg_quality, 20);
+    assert_eq!(config.sun_schedule, "daylight");
+    assert_eq!(config.latitude, 35.68);
+    assert!(config.status_report);
+    // the keys which are not in the document are kept
+    assert_eq!(config.wifi_ssid, "camera-net");
+    assert_eq!(config.wifi_psk, "secret-psk");
+    assert_eq!(changes, [
+        change("jpegquality", "10", "20"),
+        change("latitude", "0.0", "35.68"),
+        change("schedule", "", "0 6 * * *"),
+        change("statusreport", "false", "true"),
+        change("sunschedule", "off", "daylight"),
+    ]);
+}
+
+#[test]
+fn secrets_are_masked_in_changes() {
+    let (_, changes) = ConfigData::import("PSK = \"new-secret-1\"\nAPIKEY = \"\"\n", &current()).unwrap();
+    assert_eq!(changes, [change("apikey", "********", ""), change("psk", "********", "********")]);
+}
+
+#[test]
+fn invalid_document_is_not_applied() {
+    let document = "JPEGQUALITY = \"99\"\nAUTOCAPTURE = \"yes\"\nTIMEZONE = \"UTC\"\nUNKNOWNKEY = \"1\"\n";
+    let errors = ConfigData::import(document, &current()).unwrap_err();
+    assert_eq!(fields(&errors), ["autocapture", "jpegquality", "unknownkey"]);
+
+    assert_eq!(fields(&ConfigData::import("SSID = \"unterminated\n", &current()).unwrap_err()), ["document"]);
+    assert_eq!(fields(&ConfigData::import("{ \"ssid\": ", &current()).unwrap_err()), ["document"]);
+    assert_eq!(fields(&ConfigData::import("{ \"ssid\": [\"a\", \"b\"] }", &current()).unwrap_err()), ["ssid"]);
+}
+
+#[test]
+fn versions_of_document() {
+    // an export of an older firmware is migrated
+    let document = "CONFIGVERSION = \"4\"\nLEAPDAY = \"-1\"\nLEAPHOUR = \"7\"\nLEAPMINUTE = \"15\"\n";
+    let (config, _) = ConfigData::import(document, &current()).unwrap();
+    assert_eq!(config.schedule, "15 7 * * *");
+    assert_eq!(config.missed_policy, "align");
+    // a newer firmware
+    let document = format!("CONFIGVERSION = \"{}\"\nSSID = \"net\"\n", CONFIG_VERSION + 1);
+    assert_eq!(fields(&ConfigData::import(&document, &current()).unwrap_err()), ["configversion"]);
+}
@@ -0,0 +1,161 @@
+// Migration of the configuration stored by the older firmware versions
+
+use timeleapcam_core::config::{stored_version, ConfigData, CONFIG_VERSION};
+
+const V0: &str = include_str!("fixtures/config/v0.toml");
+const V1: &str = include_str!("fixtures/config/v1.toml");
+const V2: &str = include_str!("fixtures/config/v2.toml");
+const V3: &str = include_str!("fixtures/config/v3.toml");
+const V4: &str = include_str!("fixtures/config/v4.toml");
+const V5: &str = include_str!("fixtures/config/v5.toml");
+const V6: &str = include_str!("fixtures/config/v6.toml");
+const V7: &str = include_str!("fixtures/config/v7.toml");
+const V8: &str = include_str!("fixtures/config/v8.toml");
+
+// cfg.toml of the new firmware, the migrated settings keep the behavior of the old firmware instead
+fn defaults() -> ConfigData {
+    ConfigData {
+        jpeg_quality: 8,
+        capture_frames_at_once: 2,
+        segment_size: 256,
+        retention_days: 30,
+        thin_interval: 120,
+        schedule: "*/5 * * * *".to_string(),
+        latitude: 51.5,
+        sun_schedule: "daylight".to_string(),
+        missed_policy: "skip".to_string(),
+        https: true,
+        ..ConfigData::default()
+    }
+}
+
+fn migrate(text: &str, version: u32) -> ConfigData {
+    assert_eq!(stored_version(text).unwrap(), version);
+    let (config, errors) = ConfigData::from_toml(text, &defaults()).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    // the settings of all versions are kept
+    assert_eq!(config.wifi_ssid, "garden-ap");
+    assert_eq!(config.wifi_psk, "tomato-1234");
+    assert_eq!(config.api_key, "sk-test");
+    assert_eq!(config.query_prompt, "How tall is the plant?");
+    assert_eq!(config.resolution, 13);
+    assert_eq!(config.track_id, 2);
+    assert!(config.auto_capture);
+    // saved with the current version, loaded without a migration
+    let stored = config.to_toml();
+    assert!(stored.contains(&format!("CONFIGVERSION = \"{}\"", CONFIG_VERSION)));
+    assert_eq!(stored_version(&stored).unwrap(), CONFIG_VERSION);
+    assert_eq!(ConfigData::from_toml(&stored, &ConfigData::default()).unwrap(), (config.clone(), vec![]));
+    config
+}
+
+#[test]
+fn version_0() {
+    let config = migrate(V0, 0);
+    assert_eq!(config.timezone, "9");
+    assert_eq!(config.schedule, "30 6 * * *");
+    assert_eq!(config.capture_frames_at_once, 0);
+    assert!(!config.overwrite_saved);
+    assert!(!config.direct_write_mode);
+    assert_eq!(config.jpeg_quality, 12);
+    assert_eq!((config.segment_size, config.segment_frames), (0, 0));
+    assert_eq!((config.retention_days, config.retention_frames, config.thin_after_days, config.thin_interval), (0, 0, 0, 60));
+    assert_eq!((config.latitude, config.longitude), (0.0, 0.0));
+    assert_eq!(config.sun_schedule, "off");
+    assert_eq!(config.missed_policy, "align");
+    assert!(!config.to_toml().contains("LEAP"));
+}
+
+#[test]
+fn version_1() {
+    let config = migrate(V1, 1);
+    assert_eq!(config.capture_frames_at_once, 5);
+    assert!(config.overwrite_saved);
+    assert!(!config.direct_write_mode);
+    assert_eq!(config.jpeg_quality, 12);
+    assert_eq!(config.schedule, "30 6 * * *");
+}
+
+#[test]
+fn version_2() {
+    let config = migrate(V2, 2);
+    assert!(config.direct_write_mode);
+    assert_eq!(config.jpeg_quality, 20);
+    assert_eq!(config.segment_size, 0);
+    assert_eq!(config.retention_days, 0);
+}
+
+#[test]
+fn version_3() {
+    let config = migrate(V3, 3);
+    assert_eq!(config.segment_size, 1024);
+    assert_eq!((config.retention_days, config.thin_interval), (0, 60));
+    assert_eq!(config.schedule, "30 6 * * *");
+}
+
+#[test]
+fn version_4() {
+    let config = migrate(V4, 4);
+    assert_eq!((config.retention_days, config.retention_frames, config.thin_after_days, config.thin_interval), (90, 0, 14, 30));
+    assert_eq!(config.schedule, "30 6 * * *");
+    assert_eq!(config.sun_schedule, "off");
+}
+
+#[test]
+fn version_5() {
+    let config = migrate(V5, 5);
+    assert_eq!(config.schedule, "0 */2 * * *");
+    assert_eq!((config.latitude, config.sun_schedule.as_str()), (0.0, "off"));
+    assert_eq!(config.missed_policy, "align");
+}
+
+#[test]
+fn version_6() {
+    let config = migrate(V6, 6);
+    assert_eq!(config.timezone, "Asia/Tokyo");
+    assert_eq!((config.latitude, config.longitude), (35.6895, 139.6917));
+    assert_eq!((config.sun_schedule.as_str(), config.sunrise_margin, config.sunset_margin), ("golden", 0, 10));
+    assert_eq!(config.missed_policy, "align");
+}
+
+#[test]
+fn version_7() {
+    let config = migrate(V7, 7);
+    assert_eq!(config.missed_policy, "catchup");
+    assert_eq!(config.jpeg_quality, 20);
+    assert_eq!(config.schedule, "0 */2 * * *");
+    // HTTP as before
+    assert!(!config.https);
+}
+
+#[test]
+fn version_8() {
+    // an exported table with the secrets
+    let config = migrate(V8, 8);
+    assert_eq!(config.missed_policy, "catchup");
+    assert!(!config.https);
+    // saved without the secrets in the table, they move to the secrets store
+    let stored = config.export_toml(true);
+    assert!(!stored.contains("tomato-1234") && !stored.contains("sk-test"));
+    let secrets = config.secrets_toml();
+    assert!(secrets.contains("PSK = \"tomato-1234\"") && secrets.contains("APIKEY = \"sk-test\""));
+    assert!(!secrets.contains("SSID"));
+    let (mut loaded, errors) = ConfigData::from_toml(&stored, &defaults()).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(loaded.wifi_psk, "");
+    assert!(loaded.set_secrets(&secrets).unwrap().is_empty());
+    assert_eq!(loaded, config);
+}
+
+#[test]
+fn current_version_is_not_migrated() {
+    // the keys missing in the current version are the defaults
+    let stored = format!("CONFIGVERSION = \"{}\"\nSSID = \"net\"\n", CONFIG_VERSION);
+    let (config, _) = ConfigData::from_toml(&stored, &defaults()).unwrap();
+    assert_eq!(config, ConfigData { wifi_ssid: "net".to_string(), ..defaults() });
+    // a newer version is read as it is
+    let stored = format!("CONFIGVERSION = \"{}\"\nSSID = \"net\"\nNEWKEY = \"1\"\n", CONFIG_VERSION + 1);
+    let (config, _) = ConfigData::from_toml(&stored, &defaults()).unwrap();
+    assert_eq!(config, ConfigData { wifi_ssid: "net".to_string(), ..defaults() });
+    assert!(ConfigData::from_toml("CONFIGVERSION = \"x\"\n", &defaults()).is_err());
+}
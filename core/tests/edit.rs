@@ -0,0 +1,52 @@
+// Deleting and trimming frames, deleting tracks
+
+mod common;
+
+use std::fs;
+
+use common::{remaining_frames, write_track};
+use timeleapcam_core::imagefiles::{
+    delete_frames, delete_track, segment_file_paths, trim_frames, ImageFiles, OpenMode,
+};
+
+#[test]
+fn delete_frame_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 12, 5);
+    let report = delete_frames(&path, 3, 6).unwrap();
+    assert_eq!(report.removed_frames, 4);
+    assert_eq!(report.frames, 8);
+    assert_eq!(remaining_frames(&path), vec![0, 1, 2, 7, 8, 9, 10, 11]);
+    // to beyond the last frame
+    let report = delete_frames(&path, 6, u32::MAX).unwrap();
+    assert_eq!(report.removed_frames, 2);
+    assert_eq!(remaining_frames(&path), vec![0, 1, 2, 7, 8, 9]);
+    assert!(delete_frames(&path, 2, 1).is_err());
+}
+
+#[test]
+fn trim_frame_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 12, 5);
+    let report = trim_frames(&path, 2, 8).unwrap();
+    assert_eq!(report.removed_frames, 5);
+    assert_eq!(remaining_frames(&path), (2..9).collect::<Vec<u32>>());
+    // keep everything
+    let report = trim_frames(&path, 0, u32::MAX).unwrap();
+    assert_eq!(report.removed_frames, 0);
+    assert_eq!(report.rewritten_segments, 0);
+}
+
+#[test]
+fn delete_all_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 10, 3);
+    fs::write(dir.path().join("capture-0002.dat.tmp"), b"partial").unwrap();
+    let size: u64 = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().metadata().unwrap().len()).sum();
+    assert_eq!(delete_track(&path).unwrap(), size);
+    assert!(segment_file_paths(&path).is_empty());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    assert!(ImageFiles::new(&path, OpenMode::Read).is_err());
+    // nothing to delete
+    assert_eq!(delete_track(&path).unwrap(), 0);
+}
@@ -0,0 +1,47 @@
+// Range, ETag and date helpers of the frame endpoint
+
+use timeleapcam_core::http::{
+    content_range, frame_etag, http_date, if_range_matches, not_modified, parse_range, ByteRange,
+};
+use timeleapcam_core::imagefiles::FrameInfo;
+
+#[test]
+fn byte_ranges() {
+    assert_eq!(parse_range(None, 1000), ByteRange::Full);
+    assert_eq!(parse_range(Some("bytes=0-99"), 1000), ByteRange::Partial(0..100));
+    assert_eq!(parse_range(Some("bytes=900-"), 1000), ByteRange::Partial(900..1000));
+    assert_eq!(parse_range(Some("bytes=-100"), 1000), ByteRange::Partial(900..1000));
+    assert_eq!(parse_range(Some("bytes=-2000"), 1000), ByteRange::Partial(0..1000));
+    assert_eq!(parse_range(Some("bytes=500-5000"), 1000), ByteRange::Partial(500..1000));
+    assert_eq!(parse_range(Some("bytes=1000-"), 1000), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=-0"), 1000), ByteRange::Unsatisfiable);
+    // ignored
+    assert_eq!(parse_range(Some("bytes=0-1,5-6"), 1000), ByteRange::Full);
+    assert_eq!(parse_range(Some("bytes=10-5"), 1000), ByteRange::Full);
+    assert_eq!(parse_range(Some("items=0-1"), 1000), ByteRange::Full);
+    assert_eq!(content_range(&(900..1000), 1000), "bytes 900-999/1000");
+}
+
+#[test]
+fn etag_and_conditional_requests() {
+    let info = FrameInfo { capture_time: 1_704_067_200_500, ..Default::default() };
+    let etag = frame_etag(3, 12, &info, 4096);
+    assert_eq!(etag, "\"T3-18cc251f5f4-1000\"");
+    assert_ne!(frame_etag(3, 12, &FrameInfo::default(), 4096), frame_etag(3, 13, &FrameInfo::default(), 4096));
+    assert!(not_modified(Some(&etag), None, &etag, info.capture_time));
+    assert!(not_modified(Some(&format!("\"x\", W/{}", etag)), None, &etag, info.capture_time));
+    assert!(!not_modified(Some("\"x\""), Some("Mon, 01 Jan 2024 00:00:00 GMT"), &etag, info.capture_time));
+    assert!(not_modified(None, Some("Mon, 01 Jan 2024 00:00:00 GMT"), &etag, info.capture_time));
+    assert!(!not_modified(None, Some("Sun, 31 Dec 2023 23:59:59 GMT"), &etag, info.capture_time));
+    assert!(!not_modified(None, Some("invalid"), &etag, info.capture_time));
+    assert!(!not_modified(None, None, &etag, info.capture_time));
+    assert!(if_range_matches(None, &etag));
+    assert!(if_range_matches(Some(&etag), &etag));
+    assert!(!if_range_matches(Some("Mon, 01 Jan 2024 00:00:00 GMT"), &etag));
+}
+
+#[test]
+fn http_dates() {
+    assert_eq!(http_date(1_704_067_200_500).unwrap(), "Mon, 01 Jan 2024 00:00:00 GMT");
+    assert_eq!(http_date(0), None);
+}
@@ -0,0 +1,103 @@
+// Missed capture times and the missed policy
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use timeleapcam_core::schedule::{CaptureSchedule, MissedPolicy, NextCapture, MISSED_GRACE};
+use timeleapcam_core::sun::{sunrise_sunset, Daylight, DaylightMode, SunEvent};
+use timeleapcam_core::timezone::TimeZone;
+
+fn utc(s: &str) -> i64 {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap().and_utc().timestamp()
+}
+
+fn interval(seconds: u32, policy: MissedPolicy) -> CaptureSchedule {
+    CaptureSchedule::new(seconds, "", TimeZone::fixed(0), 0, 0).unwrap().with_missed_policy(policy)
+}
+
+#[test]
+fn policies_with_interval() {
+    // 1600, 2200, 2800, 3400, 4000 and 4600 are missed
+    let (last, now) = (1000, 4700);
+    assert_eq!(interval(600, MissedPolicy::Align).next_capture(last, now), Some(NextCapture { time: 5200, missed: 6 }));
+    assert_eq!(interval(600, MissedPolicy::Skip).next_capture(last, now), Some(NextCapture { time: 5300, missed: 6 }));
+    assert_eq!(interval(600, MissedPolicy::CatchUp).next_capture(last, now), Some(NextCapture { time: 4700, missed: 6 }));
+    // align is the default
+    assert_eq!(interval(600, MissedPolicy::default()).next_capture_time(last, now), Some(5200));
+}
+
+#[test]
+fn late_capture_is_not_missed() {
+    let schedule = interval(600, MissedPolicy::Skip);
+    assert_eq!(schedule.next_capture(1000, 1600 + MISSED_GRACE), Some(NextCapture { time: 1600, missed: 0 }));
+    assert_eq!(schedule.next_capture(1000, 1601 + MISSED_GRACE), Some(NextCapture { time: 2201 + MISSED_GRACE, missed: 1 }));
+    // before the first capture
+    assert_eq!(schedule.next_capture(0, 5000), Some(NextCapture { time: 5600, missed: 0 }));
+}
+
+#[test]
+fn policies_with_cron() {
+    let cron = |policy| CaptureSchedule::new(0, "0 * * * *", TimeZone::fixed(0), 0, 0).unwrap().with_missed_policy(policy);
+    let (last, now) = (utc("2024-05-01 10:00"), utc("2024-05-01 13:30"));
+    // 11:00, 12:00 and 13:00 are missed
+    let next = NextCapture { time: utc("2024-05-01 14:00"), missed: 3 };
+    assert_eq!(cron(MissedPolicy::Align).next_capture(last, now), Some(next));
+    assert_eq!(cron(MissedPolicy::Skip).next_capture(last, now), Some(next));
+    assert_eq!(cron(MissedPolicy::CatchUp).next_capture(last, now), Some(NextCapture { time: now, missed: 3 }));
+    // on time
+    let now = utc("2024-05-01 10:00") + 30;
+    assert_eq!(cron(MissedPolicy::Align).next_capture(last, now), Some(NextCapture { time: utc("2024-05-01 11:00"), missed: 0 }));
+}
+
+#[test]
+fn capture_at_wake_up() {
+    // woke up at 4700 for 1600
+    let (scheduled, now) = (1600, 4700);
+    assert_eq!(interval(600, MissedPolicy::Align).capture_at_wake_up(scheduled, now), Some(NextCapture { time: 5200, missed: 6 }));
+    assert_eq!(interval(600, MissedPolicy::Skip).capture_at_wake_up(scheduled, now), Some(NextCapture { time: 5300, missed: 6 }));
+    assert_eq!(interval(600, MissedPolicy::CatchUp).capture_at_wake_up(scheduled, now), Some(NextCapture { time: 4700, missed: 6 }));
+    // a time on the interval is captured now
+    assert_eq!(interval(600, MissedPolicy::Align).capture_at_wake_up(scheduled, 4600), Some(NextCapture { time: 4600, missed: 5 }));
+    // on time, or woke up early
+    assert_eq!(interval(600, MissedPolicy::Skip).capture_at_wake_up(scheduled, 1600 + MISSED_GRACE), Some(NextCapture { time: 1600, missed: 0 }));
+    assert_eq!(interval(600, MissedPolicy::Skip).capture_at_wake_up(scheduled, 1599), Some(NextCapture { time: 1599, missed: 0 }));
+    // continuous capture is never missed
+    assert_eq!(interval(0, MissedPolicy::Align).capture_at_wake_up(scheduled, now), Some(NextCapture { time: now, missed: 0 }));
+    // after the window
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(0), 0, 1500).unwrap();
+    assert_eq!(schedule.capture_at_wake_up(scheduled, 1600), None);
+}
+
+#[test]
+fn missed_after_the_window() {
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(0), 0, 5000).unwrap();
+    assert_eq!(schedule.next_capture(1000, 4700), None);
+    let schedule = schedule.with_missed_policy(MissedPolicy::CatchUp);
+    assert_eq!(schedule.next_capture(1000, 4700), Some(NextCapture { time: 4700, missed: 6 }));
+}
+
+#[test]
+fn night_is_not_missed() {
+    // Tokyo, every hour from sunrise to sunset
+    let daylight = Some(Daylight { latitude: 35.6895, longitude: 139.6917, mode: DaylightMode::Window { before_sunrise: 0, after_sunset: 0 } });
+    let schedule = CaptureSchedule::new(3600, "", TimeZone::parse("Asia/Tokyo").unwrap(), 0, 0).unwrap().with_daylight(daylight);
+    let rise = match sunrise_sunset(NaiveDate::from_ymd_opt(2024, 6, 22).unwrap(), 35.6895, 139.6917) {
+        SunEvent::Times { rise, .. } => rise,
+        event => panic!("{:?}", event),
+    };
+    // 19:00 and the 4 times from sunrise are missed, not the night
+    let (last, now) = (utc("2024-06-21 09:00"), utc("2024-06-21 22:30"));
+    assert_eq!(schedule.next_capture(last, now), Some(NextCapture { time: rise + 4 * 3600, missed: 5 }));
+    // catch up in the night waits for the sunrise
+    let schedule = schedule.with_missed_policy(MissedPolicy::CatchUp);
+    let now = utc("2024-06-21 16:00");
+    assert_eq!(schedule.next_capture(last, now), Some(NextCapture { time: rise, missed: 1 }));
+}
+
+#[test]
+fn policy_names() {
+    for policy in [MissedPolicy::Skip, MissedPolicy::CatchUp, MissedPolicy::Align] {
+        assert_eq!(MissedPolicy::parse(policy.as_str()), Some(policy));
+    }
+    assert_eq!(MissedPolicy::parse(" CatchUp "), Some(MissedPolicy::CatchUp));
+    assert_eq!(MissedPolicy::parse("never"), None);
+}
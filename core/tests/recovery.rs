@@ -0,0 +1,294 @@
+// Recovery of capture files left by a power loss or a reset during capturing.
+// Fixture files are built with ImageFiles, then truncated or corrupted like a torn write.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use common::{frame_info, image};
+use timeleapcam_core::imagefiles::{
+    index_file_path, recover_all, recover_file, ImageFiles, OpenMode,
+};
+
+const FILE_HEADER_SIZE: u64 = 24;
+const FRAME_HEADER_SIZE: u64 = 32;
+const END_RECORD_SIZE: u64 = 8;
+
+fn record_size(n: u32) -> u64 {
+    FRAME_HEADER_SIZE + image(n).len() as u64
+}
+
+// Committed images 0..committed, then images committed..committed+pending are written without write_image_end()
+fn crashed_capture(dir: &Path, committed: u32, pending: u32) -> PathBuf {
+    let path = dir.join("capture.dat");
+    let mut files = ImageFiles::new(&path, OpenMode::Write).unwrap();
+    for n in 0..committed {
+        files.write_image(&image(n), &frame_info(n)).unwrap();
+    }
+    files.write_image_end().unwrap();
+    drop(files);
+    if pending > 0 {
+        let mut files = ImageFiles::new(&path, OpenMode::Append).unwrap();
+        for n in committed..committed + pending {
+            files.write_image(&image(n), &frame_info(n)).unwrap();
+        }
+        // power loss: the file header and the index are not updated
+    }
+    path
+}
+
+fn header_nimages(path: &Path) -> u32 {
+    let mut header = [0u8; 24];
+    fs::File::open(path).unwrap().read_exact(&mut header).unwrap();
+    u32::from_le_bytes(header[4..8].try_into().unwrap())
+}
+
+fn assert_frames(path: &Path, frames: u32) {
+    let mut files = ImageFiles::new(path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_nof_images(), frames);
+    for n in 0..frames {
+        files.seek_image(n).unwrap();
+        let (info, data) = files.read_frame().unwrap();
+        assert_eq!(data, image(n), "frame {}", n);
+        assert_eq!(info.capture_time, frame_info(n).capture_time);
+    }
+    // images can be appended after the recovery
+    drop(files);
+    let mut files = ImageFiles::new(path, OpenMode::Append).unwrap();
+    files.write_image(&image(frames), &frame_info(frames)).unwrap();
+    files.write_image_end().unwrap();
+    drop(files);
+    let mut files = ImageFiles::new(path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_nof_images(), frames + 1);
+    files.seek_image(frames).unwrap();
+    assert_eq!(files.read_image().unwrap(), image(frames));
+}
+
+#[test]
+fn clean_file_is_not_modified() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 5, 0);
+    let before = fs::read(&path).unwrap();
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 5);
+    assert_eq!(report.recovered_frames, 0);
+    assert_eq!(report.dropped_bytes, 0);
+    assert!(!report.repaired);
+    assert_eq!(fs::read(&path).unwrap(), before);
+    assert_frames(&path, 5);
+}
+
+#[test]
+fn complete_images_after_header_are_recovered() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 3, 4);
+    assert_eq!(header_nimages(&path), 3);
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 7);
+    assert_eq!(report.recovered_frames, 4);
+    assert_eq!(report.dropped_bytes, 0);
+    assert!(report.repaired);
+    assert_eq!(header_nimages(&path), 7);
+    // second run finds nothing to do
+    assert!(!recover_file(&path).unwrap().repaired);
+    assert_frames(&path, 7);
+}
+
+#[test]
+fn truncated_trailing_image_is_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 2, 3);
+    let len = fs::metadata(&path).unwrap().len();
+    // cut the last image in the middle
+    let cut = record_size(4) / 2;
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - cut).unwrap();
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 4);
+    assert_eq!(report.recovered_frames, 2);
+    assert_eq!(report.dropped_bytes, record_size(4) - cut);
+    let expected_len = FILE_HEADER_SIZE + (0..4).map(record_size).sum::<u64>() + END_RECORD_SIZE;
+    assert_eq!(fs::metadata(&path).unwrap().len(), expected_len);
+    assert_frames(&path, 4);
+}
+
+#[test]
+fn truncated_record_header_is_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 2, 1);
+    let committed_len = FILE_HEADER_SIZE + record_size(0) + record_size(1);
+    // only a part of the record header was written
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(committed_len + 5).unwrap();
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 2);
+    assert_eq!(report.recovered_frames, 0);
+    assert_eq!(report.dropped_bytes, 5);
+    assert!(report.repaired);
+    assert_frames(&path, 2);
+}
+
+// flip a byte in the image data of frame n
+fn damage_frame(path: &Path, n: u32) {
+    let pos = FILE_HEADER_SIZE + (0..n).map(record_size).sum::<u64>() + FRAME_HEADER_SIZE + 50;
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(pos)).unwrap();
+    file.write_all(&[!image(n)[50]]).unwrap();
+}
+
+#[test]
+fn corrupted_middle_image_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 2, 4);
+    damage_frame(&path, 3);
+    let len = fs::metadata(&path).unwrap().len();
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 6);
+    assert_eq!(report.recovered_frames, 4);
+    assert_eq!(report.checksum_errors, vec![3]);
+    assert_eq!(report.dropped_bytes, 0);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len + END_RECORD_SIZE);
+    let mut files = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_nof_images(), 6);
+    for n in [0, 1, 2, 4, 5] {
+        files.seek_image(n).unwrap();
+        assert_eq!(files.read_image().unwrap(), image(n), "frame {}", n);
+    }
+}
+
+#[test]
+fn corrupted_trailing_images_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 2, 4);
+    // torn writes of the last two frames
+    damage_frame(&path, 4);
+    damage_frame(&path, 5);
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 4);
+    assert_eq!(report.recovered_frames, 2);
+    assert!(report.checksum_errors.is_empty());
+    assert_eq!(report.dropped_bytes, (4..6).map(record_size).sum::<u64>());
+    assert_frames(&path, 4);
+}
+
+#[test]
+fn missing_checksum_is_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 1, 1);
+    // reset before the checksum was written
+    let pos = FILE_HEADER_SIZE + record_size(0) + 28;
+    let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(pos)).unwrap();
+    file.write_all(&[0u8; 4]).unwrap();
+    drop(file);
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 1);
+    assert_eq!(report.recovered_frames, 0);
+    assert_frames(&path, 1);
+}
+
+#[test]
+fn broken_file_header_is_rebuilt() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 4, 0);
+    let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(4)).unwrap();
+    file.write_all(&[0x55u8; 20]).unwrap();
+    drop(file);
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 4);
+    assert!(report.repaired);
+    assert_frames(&path, 4);
+}
+
+#[test]
+fn stale_index_entries_are_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = crashed_capture(dir.path(), 3, 0);
+    // the index was appended, but the file header was not updated
+    let mut index = fs::OpenOptions::new().append(true).open(index_file_path(&path)).unwrap();
+    index.write_all(&12345u64.to_le_bytes()).unwrap();
+    drop(index);
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 3);
+    assert!(report.repaired);
+    assert_eq!(fs::metadata(index_file_path(&path)).unwrap().len(), 8 + 3 * 8);
+    assert_frames(&path, 3);
+}
+
+#[test]
+fn empty_file_is_initialized() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    fs::write(&path, b"TCM2").unwrap();
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 0);
+    assert!(report.repaired);
+    assert_frames(&path, 0);
+}
+
+#[test]
+fn unknown_file_is_not_modified() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    let data = vec![0xA5u8; 100];
+    fs::write(&path, &data).unwrap();
+    assert!(recover_file(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), data);
+}
+
+#[test]
+fn v1_file_is_recovered_without_upgrade() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    // v1: header committed 2 images, the 3rd image is complete, the 4th is partial
+    let images: Vec<Vec<u8>> = (0..4).map(image).collect();
+    let mut body = Vec::new();
+    let mut offsets = Vec::new();
+    for data in &images {
+        offsets.push(FILE_HEADER_SIZE + body.len() as u64);
+        body.extend(b"DATA");
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(data);
+    }
+    let committed = (8 + images[0].len() + 8 + images[1].len()) as u64;
+    let mut file_data = b"TCAM".to_vec();
+    file_data.extend(2u32.to_le_bytes());
+    file_data.extend(committed.to_le_bytes());
+    file_data.extend(offsets[1].to_le_bytes());
+    file_data.extend(&body[..body.len() - 10]);
+    fs::write(&path, &file_data).unwrap();
+
+    let report = recover_file(&path).unwrap();
+    assert_eq!(report.frames, 3);
+    assert_eq!(report.recovered_frames, 1);
+    assert_eq!(report.dropped_bytes, 8 + images[3].len() as u64 - 10);
+    assert!(!index_file_path(&path).exists());
+    let mut files = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_version(), 1);
+    for n in 0..3 {
+        files.seek_image(n).unwrap();
+        assert_eq!(files.read_image().unwrap(), images[n as usize]);
+    }
+}
+
+#[test]
+fn recover_all_tracks() {
+    let dir = tempfile::tempdir().unwrap();
+    for (track, pending) in [(0, 0), (1, 2), (2, 1)] {
+        let track_dir = dir.path().join(format!("T{}", track));
+        fs::create_dir(&track_dir).unwrap();
+        crashed_capture(&track_dir, 2, pending);
+    }
+    fs::write(dir.path().join("readme.txt"), b"not a track").unwrap();
+    let mut results = recover_all(dir.path());
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let frames: Vec<(u32, bool)> = results
+        .iter()
+        .map(|(_, r)| {
+            let r = r.as_ref().unwrap();
+            (r.frames, r.repaired)
+        })
+        .collect();
+    assert_eq!(frames, vec![(2, false), (4, true), (3, true)]);
+}
@@ -0,0 +1,201 @@
+// Retention policy and compaction of the tracks
+
+#![allow(clippy::single_range_in_vec_init)]
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{capture_time, frame_info, image, remaining_frames, write_track, BASE_TIME};
+use timeleapcam_core::imagefiles::{
+    finish_compaction, index_file_path, recover_all, remove_frames, segment_file_paths, ImageFiles, OpenMode,
+};
+use timeleapcam_core::retention::{apply_retention, frames_to_remove, RetentionPolicy};
+
+const HOUR_MS: i64 = 60 * 60 * 1000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+fn track_size(path: &Path) -> u64 {
+    segment_file_paths(path)
+        .iter()
+        .map(|p| fs::metadata(p).unwrap().len() + fs::metadata(index_file_path(p)).map(|m| m.len()).unwrap_or(0))
+        .sum()
+}
+
+fn system_time(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms as u64)
+}
+
+#[test]
+fn keep_last_frames() {
+    let policy = RetentionPolicy { keep_frames: 3, ..Default::default() };
+    assert_eq!(frames_to_remove(&policy, &[], 10, BASE_TIME), vec![0..7]);
+    assert_eq!(frames_to_remove(&policy, &[], 3, BASE_TIME), vec![]);
+    let times: Vec<i64> = (0..4).map(capture_time).collect();
+    assert_eq!(frames_to_remove(&policy, &times, 10, BASE_TIME), vec![0..7]);
+}
+
+#[test]
+fn keep_days() {
+    let policy = RetentionPolicy { keep_days: 1, ..Default::default() };
+    let times: Vec<i64> = (0..6).map(|n| BASE_TIME + n * 12 * HOUR_MS).collect();
+    let now = BASE_TIME + 3 * DAY_MS;
+    // older than 2024-01-03T00:00:00Z
+    assert_eq!(frames_to_remove(&policy, &times, 6, now), vec![0..4]);
+}
+
+#[test]
+fn unknown_capture_time_is_kept() {
+    let policy = RetentionPolicy { keep_days: 1, ..Default::default() };
+    let times = [0, 1000, BASE_TIME, 0, BASE_TIME + HOUR_MS];
+    assert_eq!(frames_to_remove(&policy, &times, 5, BASE_TIME + 10 * DAY_MS), vec![2..3, 4..5]);
+}
+
+#[test]
+fn thin_old_frames() {
+    // one frame per hour after 7 days
+    let policy = RetentionPolicy { thin_after_days: 7, thin_interval: 3600, ..Default::default() };
+    let times: Vec<i64> = (0..30).map(capture_time).collect();
+    let now = capture_time(18) + 7 * DAY_MS;
+    // frames 0..18 are older than 7 days, keep 0, 6, 12 (top of each hour)
+    assert_eq!(frames_to_remove(&policy, &times, 30, now), vec![1..6, 7..12, 13..18]);
+    // thinning again removes nothing
+    let thinned: Vec<i64> = [0, 6, 12].iter().map(|n| capture_time(*n)).chain((18..30).map(capture_time)).collect();
+    assert_eq!(frames_to_remove(&policy, &thinned, thinned.len() as u32, now), vec![]);
+}
+
+#[test]
+fn rules_are_combined() {
+    let policy = RetentionPolicy { keep_days: 10, keep_frames: 20, thin_after_days: 1, thin_interval: 3600 };
+    let times: Vec<i64> = (0..24).map(capture_time).collect();
+    let now = capture_time(12) + DAY_MS;
+    assert_eq!(frames_to_remove(&policy, &times, 24, now), vec![0..4, 5..6, 7..12]);
+    assert!(!RetentionPolicy::default().is_enabled());
+    assert!(!RetentionPolicy { thin_after_days: 7, ..Default::default() }.is_enabled());
+}
+
+#[test]
+fn remove_frames_across_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 20, 6);
+    let before = track_size(&path);
+    let report = remove_frames(&path, &[2..4, 5..8, 15..100]).unwrap();
+    assert_eq!(report.removed_frames, 10);
+    assert_eq!(report.frames, 10);
+    assert_eq!(report.rewritten_segments, 4);
+    assert_eq!(report.reclaimed_bytes, before - track_size(&path));
+    assert_eq!(remaining_frames(&path), vec![0, 1, 4, 8, 9, 10, 11, 12, 13, 14]);
+    // no temporary files are left
+    let names: Vec<String> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    assert!(names.iter().all(|n| !n.contains(".dat.")), "{:?}", names);
+    // images can be appended after the compaction
+    let mut files = ImageFiles::new(&path, OpenMode::Append).unwrap();
+    files.write_image(&image(20), &frame_info(20)).unwrap();
+    files.write_image_end().unwrap();
+    drop(files);
+    assert_eq!(remaining_frames(&path).last(), Some(&20));
+}
+
+#[test]
+fn remove_all_frames_of_a_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 9, 3);
+    let report = remove_frames(&path, &[0..3]).unwrap();
+    assert_eq!(report.frames, 6);
+    // empty segment is kept
+    assert_eq!(segment_file_paths(&path).len(), 3);
+    assert_eq!(remaining_frames(&path), vec![3, 4, 5, 6, 7, 8]);
+    let mut files = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    files.seek_image(0).unwrap();
+    assert_eq!(files.get_segment(), 1);
+}
+
+#[test]
+fn apply_retention_to_track() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 100, 40);
+    let policy = RetentionPolicy { keep_frames: 30, ..Default::default() };
+    let report = apply_retention(&path, &policy, system_time(capture_time(100)), false).unwrap();
+    assert_eq!(report.removed_frames, 70);
+    assert_eq!(report.frames, 30);
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(remaining_frames(&path), (70..100).collect::<Vec<u32>>());
+}
+
+#[test]
+fn apply_retention_by_days_and_thinning() {
+    let dir = tempfile::tempdir().unwrap();
+    // 3 days of frames every 10 minutes
+    let path = write_track(dir.path(), 432, 100);
+    let policy = RetentionPolicy { keep_days: 2, thin_after_days: 1, thin_interval: 3600, ..Default::default() };
+    let now = system_time(BASE_TIME + 3 * DAY_MS);
+    let report = apply_retention(&path, &policy, now, false).unwrap();
+    // day 1 is removed, day 2 is thinned to 24 frames, day 3 is kept
+    assert_eq!(report.frames, 24 + 144);
+    let frames = remaining_frames(&path);
+    assert_eq!(&frames[0..3], &[144, 150, 156]);
+    assert_eq!(&frames[24..], &(288..432).collect::<Vec<u32>>()[..]);
+    // nothing to do on the second run
+    let report = apply_retention(&path, &policy, now, false).unwrap();
+    assert_eq!(report.removable_frames, 0);
+}
+
+#[test]
+fn small_removal_is_postponed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 100, 0);
+    let policy = RetentionPolicy { keep_frames: 98, ..Default::default() };
+    let now = system_time(capture_time(100));
+    let report = apply_retention(&path, &policy, now, false).unwrap();
+    assert_eq!(report.removable_frames, 2);
+    assert_eq!(report.removed_frames, 0);
+    assert_eq!(remaining_frames(&path).len(), 100);
+    // forced when the storage is almost full
+    let report = apply_retention(&path, &policy, now, true).unwrap();
+    assert_eq!(report.removed_frames, 2);
+    assert_eq!(remaining_frames(&path).len(), 98);
+}
+
+// Build the state left by a reset during the compaction of capture-0001.dat
+fn interrupted_compaction(dir: &Path, committed: bool) -> PathBuf {
+    let path = write_track(dir, 9, 3);
+    let segment = dir.join("capture-0001.dat");
+    let copy_dir = tempfile::tempdir().unwrap();
+    // make the compacted copy of the segment in another directory
+    let copy = copy_dir.path().join("capture.dat");
+    fs::copy(&segment, &copy).unwrap();
+    fs::copy(index_file_path(&segment), index_file_path(&copy)).unwrap();
+    remove_frames(&copy, &[1..2]).unwrap();
+    fs::copy(&copy, dir.join("capture-0001.dat.tmp")).unwrap();
+    fs::copy(index_file_path(&copy), dir.join("capture-0001.dat.idx")).unwrap();
+    if committed {
+        fs::remove_file(&segment).unwrap();
+    }
+    path
+}
+
+#[test]
+fn incomplete_compaction_is_discarded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = interrupted_compaction(dir.path(), false);
+    assert_eq!(finish_compaction(dir.path()).unwrap(), 1);
+    assert!(!dir.path().join("capture-0001.dat.tmp").exists());
+    assert!(!dir.path().join("capture-0001.dat.idx").exists());
+    assert_eq!(remaining_frames(&path), (0..9).collect::<Vec<u32>>());
+}
+
+#[test]
+fn committed_compaction_is_completed() {
+    let dir = tempfile::tempdir().unwrap();
+    let track = dir.path().join("T0");
+    fs::create_dir(&track).unwrap();
+    let path = interrupted_compaction(&track, true);
+    // the segment is missing until the compaction is completed
+    assert_eq!(segment_file_paths(&path).len(), 1);
+    recover_all(dir.path());
+    assert_eq!(segment_file_paths(&path).len(), 3);
+    assert_eq!(remaining_frames(&path), vec![0, 1, 2, 3, 5, 6, 7, 8]);
+    assert!(!track.join("capture-0001.dat.idx").exists());
+}
@@ -0,0 +1,82 @@
+// State block in the RTC memory
+
+use timeleapcam_core::capture_loop::RetainedState;
+use timeleapcam_core::rtc_state::{RtcStateBlock, RTC_STATE_MAGIC, RTC_STATE_SIZE, RTC_STATE_VERSION};
+
+fn state() -> RetainedState {
+    RetainedState {
+        auto_capture: true,
+        capture_id: 1234,
+        next_capture_time: 1714521600,
+        duration: 600,
+        resolution: 17,
+        track_id: 3,
+        last_capture_time: 1714521000,
+        last_posted_time: 1714500000,
+        capture_start_time: 1714435200,
+        capture_end_time: 1717200000,
+        last_status_posted_time: 1714518000,
+        missed_captures: 6,
+        last_missed_time: 1714510000,
+    }
+}
+
+#[test]
+fn store_and_load() {
+    let mut block = RtcStateBlock::ZERO;
+    block.store(&state());
+    assert_eq!(block.magic, RTC_STATE_MAGIC);
+    assert_eq!(block.version, RTC_STATE_VERSION);
+    assert_eq!(block.load().unwrap(), state());
+    let stopped = RetainedState { auto_capture: false, ..state() };
+    assert_eq!(RtcStateBlock::new(&stopped).load().unwrap(), stopped);
+}
+
+#[test]
+fn layout() {
+    assert_eq!(std::mem::size_of::<RtcStateBlock>(), RTC_STATE_SIZE);
+    let block = RtcStateBlock::new(&state());
+    let bytes = block.to_bytes();
+    assert_eq!(bytes[0..4], *b"TLRS");
+    assert_eq!(bytes[4..8], RTC_STATE_VERSION.to_le_bytes());
+    assert_eq!(bytes[8..16], 1714521600u64.to_le_bytes());
+    assert_eq!(bytes[64..68], 1u32.to_le_bytes());
+    assert_eq!(bytes[68..72], 1234u32.to_le_bytes());
+    assert_eq!(bytes[92..96], crc32fast::hash(&bytes[..92]).to_le_bytes());
+    assert_eq!(RtcStateBlock::from_bytes(&bytes), Some(block));
+    assert_eq!(RtcStateBlock::from_bytes(&bytes[..95]), None);
+}
+
+#[test]
+fn power_on_is_not_valid() {
+    assert!(RtcStateBlock::ZERO.load().is_err());
+    // garbage in the RTC memory
+    let garbage: Vec<u8> = (0..RTC_STATE_SIZE).map(|n| (n * 37 + 11) as u8).collect();
+    assert!(RtcStateBlock::from_bytes(&garbage).unwrap().load().is_err());
+}
+
+#[test]
+fn corrupted_block_is_not_loaded() {
+    let block = RtcStateBlock::new(&state());
+    for offset in 0..RTC_STATE_SIZE {
+        let mut bytes = block.to_bytes();
+        bytes[offset] ^= 0x10;
+        assert!(RtcStateBlock::from_bytes(&bytes).unwrap().load().is_err(), "offset {}", offset);
+    }
+    // a field changed without the checksum, e.g. a bad resolution
+    let mut changed = block;
+    changed.resolution = 99;
+    assert!(changed.load().unwrap_err().to_string().contains("checksum"));
+}
+
+#[test]
+fn other_version_is_not_loaded() {
+    let mut block = RtcStateBlock::new(&state());
+    block.version = RTC_STATE_VERSION + 1;
+    let mut bytes = block.to_bytes();
+    // a valid checksum of the other layout
+    let crc = crc32fast::hash(&bytes[..92]);
+    bytes[92..96].copy_from_slice(&crc.to_le_bytes());
+    let error = RtcStateBlock::from_bytes(&bytes).unwrap().load().unwrap_err();
+    assert!(error.to_string().contains("version"));
+}
@@ -0,0 +1,221 @@
+// Cron expressions and the next capture time
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use timeleapcam_core::schedule::{leap_time_expression, CaptureSchedule, CronSchedule};
+use timeleapcam_core::timezone::TimeZone;
+
+fn time(s: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+}
+
+fn next(expression: &str, after: &str) -> Option<String> {
+    CronSchedule::parse(expression).unwrap().next_after(time(after)).map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+}
+
+// fire times after the time
+fn fires(expression: &str, after: &str, count: usize) -> Vec<String> {
+    let cron = CronSchedule::parse(expression).unwrap();
+    let mut t = time(after);
+    let mut times = Vec::new();
+    for _ in 0..count {
+        t = cron.next_after(t).unwrap();
+        times.push(t.format("%a %Y-%m-%d %H:%M").to_string());
+    }
+    times
+}
+
+fn utc(s: &str) -> i64 {
+    time(s).and_utc().timestamp()
+}
+
+#[test]
+fn every_minute_and_fixed_times() {
+    assert_eq!(next("* * * * *", "2024-01-01 10:00"), Some("2024-01-01 10:01".to_string()));
+    assert_eq!(next("* * * * *", "2024-12-31 23:59"), Some("2025-01-01 00:00".to_string()));
+    assert_eq!(next("0 9,15 * * *", "2024-01-01 08:59"), Some("2024-01-01 09:00".to_string()));
+    // the given time itself does not fire
+    assert_eq!(next("0 9,15 * * *", "2024-01-01 09:00"), Some("2024-01-01 15:00".to_string()));
+    assert_eq!(next("0 9,15 * * *", "2024-01-01 15:00"), Some("2024-01-02 09:00".to_string()));
+    assert_eq!(next("30 12 1 * *", "2024-01-01 12:30"), Some("2024-02-01 12:30".to_string()));
+    assert_eq!(next("0 0 1 1 *", "2024-06-01 00:00"), Some("2025-01-01 00:00".to_string()));
+}
+
+#[test]
+fn seconds_are_ignored() {
+    let cron = CronSchedule::parse("*/5 * * * *").unwrap();
+    let after = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(10, 4, 59).unwrap();
+    assert_eq!(cron.next_after(after), NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(10, 5, 0));
+    let after = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(10, 5, 1).unwrap();
+    assert_eq!(cron.next_after(after), NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(10, 10, 0));
+}
+
+#[test]
+fn steps_and_ranges() {
+    // every 10 minutes between 06:00 and 18:00 on weekdays
+    assert_eq!(fires("*/10 6-17 * * 1-5", "2024-01-05 17:40", 3), vec![
+        "Fri 2024-01-05 17:50", "Mon 2024-01-08 06:00", "Mon 2024-01-08 06:10",
+    ]);
+    assert_eq!(fires("0-30/15 * * * *", "2024-01-01 10:00", 4), vec![
+        "Mon 2024-01-01 10:15", "Mon 2024-01-01 10:30", "Mon 2024-01-01 11:00", "Mon 2024-01-01 11:15",
+    ]);
+    // a/n runs to the end of the field
+    assert_eq!(fires("50/5 * * * *", "2024-01-01 10:00", 3), vec![
+        "Mon 2024-01-01 10:50", "Mon 2024-01-01 10:55", "Mon 2024-01-01 11:50",
+    ]);
+    assert_eq!(fires("0 */6 * * *", "2024-01-01 00:00", 4), vec![
+        "Mon 2024-01-01 06:00", "Mon 2024-01-01 12:00", "Mon 2024-01-01 18:00", "Tue 2024-01-02 00:00",
+    ]);
+    assert_eq!(fires("0 8 1-3,15 * *", "2024-01-02 09:00", 3), vec![
+        "Wed 2024-01-03 08:00", "Mon 2024-01-15 08:00", "Thu 2024-02-01 08:00",
+    ]);
+}
+
+#[test]
+fn step_of_the_whole_field() {
+    assert_eq!(CronSchedule::parse("*/60 * * * *").unwrap(), CronSchedule::parse("0 * * * *").unwrap());
+}
+
+#[test]
+fn names_and_sunday() {
+    assert_eq!(fires("0 12 * JAN,jul MON-fri", "2024-01-30 12:00", 3), vec![
+        "Wed 2024-01-31 12:00", "Mon 2024-07-01 12:00", "Tue 2024-07-02 12:00",
+    ]);
+    assert_eq!(CronSchedule::parse("0 0 * * 0").unwrap(), CronSchedule::parse("0 0 * * 7").unwrap());
+    assert_eq!(CronSchedule::parse("0 0 * * SUN").unwrap(), CronSchedule::parse("0 0 * * 7").unwrap());
+    assert_eq!(fires("0 0 * * 5-7", "2024-01-01 00:00", 4), vec![
+        "Fri 2024-01-05 00:00", "Sat 2024-01-06 00:00", "Sun 2024-01-07 00:00", "Fri 2024-01-12 00:00",
+    ]);
+}
+
+#[test]
+fn day_of_month_or_day_of_week() {
+    // both restricted: either matches
+    assert_eq!(fires("0 0 13 * FRI", "2024-09-01 00:00", 4), vec![
+        "Fri 2024-09-06 00:00", "Fri 2024-09-13 00:00", "Fri 2024-09-20 00:00", "Fri 2024-09-27 00:00",
+    ]);
+    assert_eq!(fires("0 0 10 * MON", "2024-09-01 00:00", 3), vec![
+        "Mon 2024-09-02 00:00", "Mon 2024-09-09 00:00", "Tue 2024-09-10 00:00",
+    ]);
+    // one restricted: only it
+    assert_eq!(fires("0 0 */10 * *", "2024-09-01 00:00", 3), vec![
+        "Wed 2024-09-11 00:00", "Sat 2024-09-21 00:00", "Tue 2024-10-01 00:00",
+    ]);
+    // a step of * is not restricted: both match
+    assert_eq!(fires("0 0 */2 * MON", "2024-09-01 00:00", 3), vec![
+        "Mon 2024-09-09 00:00", "Mon 2024-09-23 00:00", "Mon 2024-10-07 00:00",
+    ]);
+    assert_eq!(fires("0 0 1 * */3", "2024-09-01 00:00", 2), vec![
+        "Sun 2024-12-01 00:00", "Wed 2025-01-01 00:00",
+    ]);
+}
+
+#[test]
+fn short_months_and_leap_years() {
+    assert_eq!(next("0 0 31 * *", "2024-04-01 00:00"), Some("2024-05-31 00:00".to_string()));
+    assert_eq!(next("0 0 29 2 *", "2024-03-01 00:00"), Some("2028-02-29 00:00".to_string()));
+    // 2100 is not a leap year
+    assert_eq!(next("0 0 29 2 *", "2096-03-01 00:00"), Some("2104-02-29 00:00".to_string()));
+    assert_eq!(next("0 0 30 2 *", "2024-01-01 00:00"), None);
+}
+
+#[test]
+fn macros() {
+    for (name, expression) in [
+        ("@yearly", "0 0 1 1 *"), ("@annually", "0 0 1 1 *"), ("@monthly", "0 0 1 * *"),
+        ("@weekly", "0 0 * * 0"), ("@daily", "0 0 * * *"), ("@midnight", "0 0 * * *"), ("@hourly", "0 * * * *"),
+    ] {
+        assert_eq!(CronSchedule::parse(name).unwrap(), CronSchedule::parse(expression).unwrap(), "{}", name);
+    }
+    assert!(CronSchedule::parse(" @daily ").is_ok());
+}
+
+#[test]
+fn matches() {
+    let cron: CronSchedule = "*/15 9-17 * * MON-FRI".parse().unwrap();
+    assert!(cron.matches(time("2024-01-01 09:00")));
+    assert!(cron.matches(time("2024-01-01 17:45")));
+    assert!(!cron.matches(time("2024-01-01 18:00")));
+    assert!(!cron.matches(time("2024-01-01 09:10")));
+    assert!(!cron.matches(time("2024-01-06 09:00")));
+}
+
+#[test]
+fn invalid_expressions() {
+    for expression in [
+        "", "* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * 32 * *", "* * * 0 *",
+        "* * * 13 *", "* * * * 8", "*/0 * * * *", "5-1 * * * *", "a * * * *", "* * * FOO *", "1,,2 * * * *",
+        "*/x * * * *", "-1 * * * *", "@often",
+        // steps larger than the field, they must not overflow
+        "59/4294967295 * * * *", "* */25 * * *", "0 0 * * 7/9",
+    ] {
+        assert!(CronSchedule::parse(expression).is_err(), "{:?}", expression);
+    }
+}
+
+#[test]
+fn leap_time_conversion() {
+    assert_eq!(leap_time_expression(-1, -1, -1), None);
+    assert_eq!(leap_time_expression(-1, -1, 0), None);
+    assert_eq!(leap_time_expression(-1, -1, 30).as_deref(), Some("30 * * * *"));
+    assert_eq!(leap_time_expression(-1, 7, -1).as_deref(), Some("0 7 * * *"));
+    assert_eq!(leap_time_expression(-1, 7, 15).as_deref(), Some("15 7 * * *"));
+    assert_eq!(leap_time_expression(10, -1, -1).as_deref(), Some("0 0 10 * *"));
+    assert_eq!(leap_time_expression(10, 12, 5).as_deref(), Some("5 12 10 * *"));
+    for (day, hour, minute) in [(-1, -1, 30), (-1, 7, 15), (10, 12, 5)] {
+        assert!(CronSchedule::parse(&leap_time_expression(day, hour, minute).unwrap()).is_ok());
+    }
+}
+
+#[test]
+fn interval_schedule() {
+    let schedule = CaptureSchedule::new(90, "", TimeZone::fixed(0), 0, 0).unwrap();
+    assert_eq!(schedule.next_capture_time(1000, 1050), Some(1090));
+    // the expression is not used with the interval
+    let schedule = CaptureSchedule::new(90, "bad", TimeZone::fixed(0), 0, 0).unwrap();
+    assert_eq!(schedule.cron, None);
+    // window
+    let schedule = CaptureSchedule::new(60, "", TimeZone::fixed(0), 10_000, 20_000).unwrap();
+    assert_eq!(schedule.next_capture_time(1000, 1000), Some(10_000));
+    assert_eq!(schedule.next_capture_time(15_000, 15_000), Some(15_060));
+    assert_eq!(schedule.next_capture_time(19_940, 19_940), Some(20_000));
+    assert_eq!(schedule.next_capture_time(19_950, 19_950), None);
+}
+
+#[test]
+fn cron_schedule_in_local_time() {
+    // 09:00 and 15:00 at UTC+9
+    let schedule = CaptureSchedule::new(0, "0 9,15 * * *", TimeZone::fixed(9 * 3600), 0, 0).unwrap();
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 00:00")), Some(utc("2024-01-01 06:00")));
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 06:00")), Some(utc("2024-01-02 00:00")));
+    // UTC-5
+    let schedule = CaptureSchedule::new(0, "0 9 * * *", TimeZone::fixed(-5 * 3600), 0, 0).unwrap();
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 12:00")), Some(utc("2024-01-01 14:00")));
+    assert!(CaptureSchedule::new(0, "0 25 * * *", TimeZone::fixed(0), 0, 0).is_err());
+}
+
+#[test]
+fn cron_schedule_in_window() {
+    let start = utc("2024-01-10 00:00");
+    let end = utc("2024-01-12 12:00");
+    let schedule = CaptureSchedule::new(0, "0 12 * * *", TimeZone::fixed(0), start, end).unwrap();
+    // before the window, the first time in the window
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 00:00")), Some(utc("2024-01-10 12:00")));
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-11 13:00")), Some(utc("2024-01-12 12:00")));
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-12 12:00")), None);
+    // at the start of the window
+    let schedule = CaptureSchedule::new(0, "0 0 * * *", TimeZone::fixed(0), start, end).unwrap();
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 00:00")), Some(start));
+    // no window when the end is not after the start
+    let schedule = CaptureSchedule::new(0, "0 12 * * *", TimeZone::fixed(0), start, start).unwrap();
+    assert_eq!(schedule.next_capture_time(0, utc("2024-02-01 00:00")), Some(utc("2024-02-01 12:00")));
+    // never fires
+    let schedule = CaptureSchedule::new(0, "0 0 30 2 *", TimeZone::fixed(0), 0, 0).unwrap();
+    assert_eq!(schedule.next_capture_time(0, utc("2024-01-01 00:00")), None);
+}
+
+#[test]
+fn continuous_without_schedule() {
+    let schedule = CaptureSchedule::new(0, " ", TimeZone::fixed(0), 0, 0).unwrap();
+    assert_eq!(schedule.next_capture_time(100, 200), Some(200));
+}
@@ -0,0 +1,138 @@
+// Secrets store apart from the configuration
+
+use timeleapcam_core::config::{stored_version, ConfigData, CONFIG_VERSION, SECRET_KEYS};
+use timeleapcam_core::secrets::{is_sealed, secret_state, SecretKey, NONCE_SIZE};
+
+const DEVICE_ID: [u8; 6] = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+const NONCE: [u8; NONCE_SIZE] = [7; NONCE_SIZE];
+
+fn config() -> ConfigData {
+    ConfigData {
+        wifi_ssid: "camera-net".to_string(),
+        wifi_psk: "secret-psk".to_string(),
+        api_key: "sk-key".to_string(),
+        post_access_token: "line-token".to_string(),
+        storage_access_token: "cf-token".to_string(),
+        storage_signed_key: "cf-signed-key".to_string(),
+        jpeg_quality: 10,
+        ..ConfigData::default()
+    }
+}
+
+#[test]
+fn seal_and_open() {
+    let key = SecretKey::derive(&DEVICE_ID);
+    let text = config().secrets_toml();
+    let sealed = key.seal(&NONCE, &text);
+    assert!(is_sealed(&sealed));
+    assert!(!sealed.contains("sk-key"));
+    assert_eq!(key.open(&sealed).unwrap(), text);
+    // longer than a block of the key stream, and empty
+    let long = "x".repeat(1000);
+    assert_eq!(key.open(&key.seal(&NONCE, &long)).unwrap(), long);
+    assert_eq!(key.open(&key.seal(&NONCE, "")).unwrap(), "");
+    // another nonce
+    let other = key.seal(&[8; NONCE_SIZE], &text);
+    assert_ne!(other, sealed);
+    assert_eq!(key.open(&other).unwrap(), text);
+}
+
+#[test]
+fn other_device_cannot_open() {
+    let sealed = SecretKey::derive(&DEVICE_ID).seal(&NONCE, "APIKEY = \"sk-key\"\n");
+    let other = SecretKey::derive(&[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x57]);
+    let error = other.open(&sealed).unwrap_err().to_string();
+    assert!(!error.contains("sk-key"));
+}
+
+#[test]
+fn broken_value_is_not_opened() {
+    let key = SecretKey::derive(&DEVICE_ID);
+    let sealed = key.seal(&NONCE, "APIKEY = \"sk-key\"\n");
+    let hex_start = "enc1:".len();
+    for offset in [hex_start, hex_start + 40, sealed.len() - 1] {
+        let mut broken = sealed.clone().into_bytes();
+        broken[offset] = if broken[offset] == b'0' { b'1' } else { b'0' };
+        assert!(key.open(std::str::from_utf8(&broken).unwrap()).is_err(), "offset {}", offset);
+    }
+    assert!(key.open(&sealed[..sealed.len() - 2]).is_err());
+    assert!(key.open("enc1:00ff").is_err());
+    assert!(key.open("enc1:not hex").is_err());
+}
+
+#[test]
+fn plain_value_is_opened() {
+    // the encryption is disabled, or it was disabled when the secrets were saved
+    let key = SecretKey::derive(&DEVICE_ID);
+    let text = config().secrets_toml();
+    assert!(!is_sealed(&text));
+    assert_eq!(key.open(&text).unwrap(), text);
+}
+
+#[test]
+fn secrets_apart_from_config() {
+    let config = config();
+    let secrets = config.secrets_toml();
+    for key in SECRET_KEYS {
+        assert!(secrets.contains(&format!("{} = ", key.to_ascii_uppercase())), "{}", key);
+    }
+    assert!(!secrets.contains("SSID"));
+    assert!(!secrets.contains("JPEGQUALITY"));
+    let stored = config.export_toml(true);
+    assert!(!stored.contains("secret-psk"));
+
+    // the configuration and the secrets are loaded from the two stores
+    let (mut loaded, errors) = ConfigData::from_toml(&stored, &ConfigData::default()).unwrap();
+    assert!(errors.is_empty());
+    assert_eq!(loaded.api_key, "api-key");
+    assert_eq!(loaded.set_secrets(&secrets).unwrap(), []);
+    assert_eq!(loaded, config);
+}
+
+#[test]
+fn set_secrets_only() {
+    let mut config = config();
+    // other keys are not read from the secrets store, an invalid secret keeps the current one
+    let errors = config.set_secrets("APIKEY = \"sk-new\"\nJPEGQUALITY = \"20\"\nPSK = \"short\"\n").unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].field, "psk");
+    assert!(!errors[0].message.contains("short"));
+    assert_eq!(config.api_key, "sk-new");
+    assert_eq!(config.jpeg_quality, 10);
+    assert_eq!(config.wifi_psk, "secret-psk");
+    // the text of a broken store is not in the error
+    let error = config.set_secrets("APIKEY = \"sk-unterminated\n").unwrap_err().to_string();
+    assert!(!error.contains("sk-unterminated"), "{}", error);
+}
+
+#[test]
+fn secrets_are_not_in_debug_output() {
+    let debug = format!("{:?}", config());
+    assert!(debug.contains("camera-net"));
+    for secret in ["secret-psk", "sk-key", "line-token", "cf-token", "cf-signed-key"] {
+        assert!(!debug.contains(secret), "{}", secret);
+    }
+    assert_eq!(secret_state(&config().api_key), "set");
+    assert_eq!(secret_state(""), "not set");
+}
+
+#[test]
+fn secrets_of_older_version_are_moved() {
+    // the secrets were in the configuration before the version 8
+    let stored = "CONFIGVERSION = \"7\"\nSSID = \"camera-net\"\nPSK = \"secret-psk\"\nAPIKEY = \"sk-key\"\n";
+    assert!(stored_version(stored).unwrap() < CONFIG_VERSION);
+    let (config, errors) = ConfigData::from_toml(stored, &ConfigData::default()).unwrap();
+    assert!(errors.is_empty());
+    assert_eq!((config.wifi_psk.as_str(), config.api_key.as_str()), ("secret-psk", "sk-key"));
+    assert!(!config.export_toml(true).contains("sk-key"));
+    assert!(config.secrets_toml().contains("APIKEY = \"sk-key\""));
+}
+
+#[test]
+fn states_of_secrets() {
+    let config = ConfigData { post_access_token: String::new(), ..config() };
+    let states = config.secret_states();
+    assert_eq!(states.len(), SECRET_KEYS.len());
+    assert!(states.contains(&("apikey", "set")));
+    assert!(states.contains(&("postaccesstoken", "not set")));
+}
@@ -0,0 +1,139 @@
+// Track split into segment files (capture.dat, capture-0001.dat, ...)
+
+mod common;
+
+use std::path::Path;
+
+use common::{frame_info, image};
+use timeleapcam_core::imagefiles::{
+    recover_all, segment_file_path, segment_file_paths, ImageFiles, OpenMode, WriteThread,
+};
+
+fn write_images(path: &Path, mode: OpenMode, frames: std::ops::Range<u32>, max_size: u64, max_frames: u32) {
+    let mut files = ImageFiles::new(path, mode).unwrap();
+    files.set_segment_limit(max_size, max_frames);
+    for n in frames {
+        files.write_image(&image(n), &frame_info(n)).unwrap();
+    }
+    files.write_image_end().unwrap();
+}
+
+fn assert_sequence(path: &Path, frames: u32) {
+    let mut files = ImageFiles::new(path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_nof_images(), frames);
+    // sequential read across the segments
+    files.seek_image(0).unwrap();
+    for n in 0..frames {
+        let (info, data) = files.read_frame().unwrap();
+        assert_eq!(data, image(n), "frame {}", n);
+        assert_eq!(info.capture_time, frame_info(n).capture_time);
+    }
+    assert!(files.read_frame().is_err());
+    // random access
+    for n in (0..frames).rev() {
+        files.seek_image(n).unwrap();
+        assert_eq!(files.read_image().unwrap(), image(n), "frame {}", n);
+    }
+    assert!(files.seek_image(frames).is_err());
+}
+
+#[test]
+fn segment_file_names() {
+    let path = Path::new("/eMMC/T3/capture.dat");
+    assert_eq!(segment_file_path(path, 0), Path::new("/eMMC/T3/capture.dat"));
+    assert_eq!(segment_file_path(path, 1), Path::new("/eMMC/T3/capture-0001.dat"));
+    assert_eq!(segment_file_path(path, 12), Path::new("/eMMC/T3/capture-0012.dat"));
+}
+
+#[test]
+fn roll_over_by_frame_count() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    write_images(&path, OpenMode::Write, 0..10, 0, 3);
+    assert_eq!(segment_file_paths(&path).len(), 4);
+    assert!(dir.path().join("capture-0003.dat").is_file());
+    assert!(dir.path().join("capture-0003.idx").is_file());
+    assert_sequence(&path, 10);
+
+    let mut files = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    assert_eq!(files.get_nof_segments(), 4);
+    files.seek_image(7).unwrap();
+    assert_eq!(files.get_segment(), 2);
+    files.seek_image(9).unwrap();
+    assert_eq!(files.get_segment(), 3);
+}
+
+#[test]
+fn roll_over_by_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    // header(24) + 3 records of about 140 bytes + end(8) fit in 500 bytes
+    write_images(&path, OpenMode::Write, 0..8, 500, 0);
+    let paths = segment_file_paths(&path);
+    assert_eq!(paths.len(), 3);
+    for path in &paths {
+        assert!(std::fs::metadata(path).unwrap().len() <= 500);
+    }
+    assert_sequence(&path, 8);
+}
+
+#[test]
+fn append_continues_last_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    write_images(&path, OpenMode::Write, 0..4, 0, 3);
+    write_images(&path, OpenMode::Append, 4..5, 0, 3);
+    assert_eq!(segment_file_paths(&path).len(), 2);
+    write_images(&path, OpenMode::Append, 5..9, 0, 3);
+    assert_eq!(segment_file_paths(&path).len(), 3);
+    assert_sequence(&path, 9);
+}
+
+#[test]
+fn overwrite_removes_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    write_images(&path, OpenMode::Write, 0..10, 0, 2);
+    assert_eq!(segment_file_paths(&path).len(), 5);
+    write_images(&path, OpenMode::Write, 0..3, 0, 2);
+    assert_eq!(segment_file_paths(&path).len(), 2);
+    assert!(!dir.path().join("capture-0002.dat").exists());
+    assert!(!dir.path().join("capture-0002.idx").exists());
+    assert_sequence(&path, 3);
+}
+
+#[test]
+fn write_thread_rolls_over() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("capture.dat");
+    let mut write_thread = WriteThread::new(path.to_str().unwrap().to_string(), OpenMode::Write, false);
+    write_thread.set_segment_limit(0, 4);
+    write_thread.start();
+    for n in 0..10 {
+        write_thread.push_data(&image(n), &frame_info(n));
+    }
+    write_thread.stop();
+    write_thread.wait_thread();
+    assert_eq!(write_thread.get_nof_images(), 10);
+    assert_eq!(segment_file_paths(&path).len(), 3);
+    assert_sequence(&path, 10);
+}
+
+#[test]
+fn recover_last_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let track = dir.path().join("T0");
+    std::fs::create_dir(&track).unwrap();
+    let path = track.join("capture.dat");
+    write_images(&path, OpenMode::Write, 0..5, 0, 3);
+    // power loss during writing to the second segment
+    let mut files = ImageFiles::new(&path, OpenMode::Append).unwrap();
+    files.set_segment_limit(0, 3);
+    files.write_image(&image(5), &frame_info(5)).unwrap();
+    drop(files);
+    assert_eq!(ImageFiles::new(&path, OpenMode::Read).unwrap().get_nof_images(), 5);
+    let results = recover_all(dir.path());
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().any(|(p, r)| p.ends_with("capture-0001.dat") && r.as_ref().unwrap().repaired));
+    assert_sequence(&path, 6);
+}
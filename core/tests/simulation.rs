@@ -0,0 +1,316 @@
+// Simulation of the capture main loop with a simulated clock, the deep sleep restarts the loop from the stored state
+
+use chrono::NaiveDateTime;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use timeleapcam_core::capture_loop::{
+    CameraBackend, CaptureLoop, CaptureResult, Clock, Devices, LoopSettings, Reporter, RetainedState, Sleeper, Step,
+    Storage, from_seconds, to_seconds,
+};
+use timeleapcam_core::schedule::{CaptureSchedule, MissedPolicy};
+use timeleapcam_core::timezone::TimeZone;
+
+const DAY: u64 = 24 * 3600;
+
+fn utc(s: &str) -> SystemTime {
+    from_seconds(NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap().and_utc().timestamp() as u64)
+}
+
+struct SimClock(Rc<Cell<SystemTime>>);
+
+impl Clock for SimClock {
+    fn now(&self) -> SystemTime {
+        self.0.get()
+    }
+}
+
+struct SimSleeper {
+    clock: Rc<Cell<SystemTime>>,
+    sleeps: u32,
+    deep_sleeps: u32,
+}
+
+impl Sleeper for SimSleeper {
+    fn sleep(&mut self, duration: Duration) {
+        self.sleeps += 1;
+        self.clock.set(self.clock.get() + duration);
+    }
+
+    fn deep_sleep(&mut self, duration: Option<Duration>) {
+        self.deep_sleeps += 1;
+        if let Some(duration) = duration {
+            self.clock.set(self.clock.get() + duration);
+        }
+    }
+}
+
+struct SimCamera {
+    clock: Rc<Cell<SystemTime>>,
+    capture_time: Duration,
+    frames: Vec<(u32, u32, SystemTime)>,    // track, frame, time
+    requested: u32,
+    resolution: u32,
+}
+
+impl CameraBackend for SimCamera {
+    fn change_resolution(&mut self, resolution: u32) {
+        self.resolution = resolution;
+    }
+
+    fn autofocus(&mut self) {}
+
+    fn set_overwrite_saved(&mut self, _overwrite: bool) {}
+
+    fn request_capture(&mut self, track_id: u32, capture_id: u32) {
+        self.frames.push((track_id, capture_id, self.clock.get()));
+        self.requested = capture_id;
+    }
+
+    fn wait_capture(&mut self) -> CaptureResult {
+        self.clock.set(self.clock.get() + self.capture_time);
+        CaptureResult { capture_id: self.requested, written: true }
+    }
+}
+
+#[derive(Default)]
+struct SimStorage(Option<RetainedState>);
+
+impl Storage for SimStorage {
+    fn load(&mut self) -> Option<RetainedState> {
+        self.0
+    }
+
+    fn store(&mut self, state: &RetainedState) {
+        self.0 = Some(*state);
+    }
+}
+
+#[derive(Default)]
+struct SimReporter {
+    status_reports: Vec<SystemTime>,
+    missed_captures: u32,
+}
+
+impl Reporter for SimReporter {
+    fn captured(&mut self, _track_id: u32, _result: &CaptureResult, _time: SystemTime) -> Option<SystemTime> {
+        None
+    }
+
+    fn status_report(&mut self, _track_id: u32, _capture_id: u32, time: SystemTime) {
+        self.status_reports.push(time);
+    }
+
+    fn missed(&mut self, missed_captures: u32, _last_missed_time: SystemTime) {
+        self.missed_captures = missed_captures;
+    }
+}
+
+struct Simulator {
+    clock: Rc<Cell<SystemTime>>,
+    devices: Devices<SimClock, SimSleeper, SimCamera, SimStorage>,
+    reporter: SimReporter,
+    boot_time: Duration,            // from the wake up to the first loop
+    boots: u32,
+}
+
+impl Simulator {
+    fn new(start: SystemTime) -> Simulator {
+        let clock = Rc::new(Cell::new(start));
+        Simulator {
+            devices: Devices {
+                clock: SimClock(clock.clone()),
+                sleeper: SimSleeper { clock: clock.clone(), sleeps: 0, deep_sleeps: 0 },
+                camera: SimCamera { clock: clock.clone(), capture_time: Duration::ZERO, frames: Vec::new(), requested: 0, resolution: 0 },
+                storage: SimStorage::default(),
+            },
+            clock,
+            reporter: SimReporter::default(),
+            boot_time: Duration::ZERO,
+            boots: 0,
+        }
+    }
+
+    // Run the loop until the time or the end of the capture, returns the last step
+    fn run(&mut self, settings: &LoopSettings, until: SystemTime) -> Step {
+        let mut capture_loop = CaptureLoop::new(self.devices.storage.load().unwrap_or_default());
+        loop {
+            let step = capture_loop.step(settings, &mut self.devices, &mut self.reporter);
+            match step {
+                Step::Ended | Step::IdleSleep => return step,
+                Step::DeepSleep => {
+                    // wake up by the timer
+                    self.boots += 1;
+                    self.clock.set(self.clock.get() + self.boot_time);
+                    capture_loop = CaptureLoop::new(self.devices.storage.load().unwrap());
+                }
+                _ => (),
+            }
+            if self.clock.get() > until {
+                return step;
+            }
+        }
+    }
+
+    fn capture_times(&self) -> Vec<u64> {
+        self.devices.camera.frames.iter().map(|(_, _, time)| to_seconds(*time)).collect()
+    }
+}
+
+fn settings(schedule: CaptureSchedule) -> LoopSettings {
+    LoopSettings {
+        capture_started: true,
+        one_shot: false,
+        movie_mode: false,
+        resolution: 10,
+        track_id: 1,
+        duration: schedule.interval,
+        autofocus_once: true,
+        overwrite_saved: false,
+        status_report: false,
+        status_report_interval: 3600,
+        capture_start_time: from_seconds(schedule.start_time.max(0) as u64),
+        capture_end_time: from_seconds(schedule.end_time.max(0) as u64),
+        schedule: Some(schedule),
+        idle_in_sleep_time: None,
+        last_access_time: UNIX_EPOCH,
+    }
+}
+
+fn interval(seconds: u32) -> CaptureSchedule {
+    CaptureSchedule::new(seconds, "", TimeZone::fixed(0), 0, 0).unwrap()
+}
+
+#[test]
+fn interval_over_two_weeks() {
+    let start = utc("2024-05-01 00:00");
+    let mut sim = Simulator::new(start);
+    sim.run(&settings(interval(600)), start + Duration::from_secs(14 * DAY));
+    let times = sim.capture_times();
+    let expected: Vec<u64> = (0..=14 * DAY / 600).map(|n| to_seconds(start) + n * 600).collect();
+    assert_eq!(times, expected);
+    // every capture but the first one is after a deep sleep
+    assert_eq!(sim.boots as usize, times.len());
+    assert_eq!(sim.devices.sleeper.deep_sleeps, sim.boots);
+    assert_eq!(sim.devices.camera.resolution, 10);
+    let frames: Vec<u32> = sim.devices.camera.frames.iter().map(|(_, frame, _)| *frame).collect();
+    assert_eq!(frames, (0..times.len() as u32).collect::<Vec<u32>>());
+}
+
+#[test]
+fn boot_time_does_not_drift() {
+    let start = utc("2024-05-01 00:00");
+    let mut sim = Simulator::new(start);
+    sim.boot_time = Duration::from_secs(3);
+    sim.devices.camera.capture_time = Duration::from_secs(2);
+    sim.run(&settings(interval(300)), start + Duration::from_secs(7 * DAY));
+    let times = sim.capture_times();
+    assert_eq!(times.len() as u64, 7 * DAY / 300);
+    // each capture is late by the boot time, the schedule stays on the interval
+    for (n, time) in times.iter().enumerate().skip(1) {
+        assert_eq!(*time, to_seconds(start) + n as u64 * 300 + 3);
+    }
+    assert_eq!(sim.reporter.missed_captures, 0);
+}
+
+#[test]
+fn short_interval_stays_awake() {
+    let start = utc("2024-05-01 00:00");
+    let mut sim = Simulator::new(start);
+    sim.run(&settings(interval(30)), start + Duration::from_secs(3600));
+    assert_eq!(sim.capture_times().len(), 121);
+    assert_eq!(sim.devices.sleeper.deep_sleeps, 0);
+    assert_eq!(sim.devices.sleeper.sleeps, 121);
+}
+
+#[test]
+fn cron_schedule_over_four_weeks() {
+    // 09:00 and 15:00 in Tokyo
+    let start = utc("2024-05-01 00:00");
+    let schedule = CaptureSchedule::new(0, "0 9,15 * * *", TimeZone::parse("Asia/Tokyo").unwrap(), 0, 0).unwrap();
+    let mut sim = Simulator::new(start);
+    sim.run(&settings(schedule), start + Duration::from_secs(28 * DAY));
+    let times = sim.capture_times();
+    // the first capture is at the start, then 00:00 and 06:00 UTC
+    assert_eq!(times[0], to_seconds(start));
+    assert_eq!(times.len(), 1 + 28 * 2);
+    for (n, time) in times.iter().skip(1).enumerate() {
+        let n = n as u64;
+        assert_eq!(*time, to_seconds(start) + (n + 1) / 2 * DAY + if n % 2 == 0 { 6 * 3600 } else { 0 });
+    }
+}
+
+#[test]
+fn status_report_cadence() {
+    let start = utc("2024-05-01 00:00");
+    let mut config = settings(interval(600));
+    config.status_report = true;
+    config.status_report_interval = 3600;
+    let mut sim = Simulator::new(start);
+    sim.run(&config, start + Duration::from_secs(DAY));
+    let reports: Vec<u64> = sim.reporter.status_reports.iter().map(|time| to_seconds(*time) - to_seconds(start)).collect();
+    // the report is after more than the interval: every 70 minutes with the 10 minute captures
+    assert_eq!(reports[..4], [0, 4200, 8400, 12600]);
+    assert_eq!(reports.len(), (DAY / 4200 + 1) as usize);
+}
+
+#[test]
+fn capture_ends_at_end_time() {
+    let start = utc("2024-05-01 00:00");
+    let end = to_seconds(start) as i64 + 3 * 3600;
+    let schedule = CaptureSchedule::new(1800, "", TimeZone::fixed(0), to_seconds(start) as i64, end).unwrap();
+    let mut sim = Simulator::new(start);
+    assert_eq!(sim.run(&settings(schedule), start + Duration::from_secs(DAY)), Step::Ended);
+    assert_eq!(sim.capture_times().len(), 7);
+    let state = sim.devices.storage.load().unwrap();
+    assert!(!state.auto_capture);
+    assert_eq!(state.capture_id, 0);
+    assert_eq!(state.last_capture_time, end as u64);
+}
+
+#[test]
+fn outage_is_counted_as_missed() {
+    let start = utc("2024-05-01 00:00");
+    let mut sim = Simulator::new(start);
+    let config = settings(interval(600).with_missed_policy(MissedPolicy::Align));
+    sim.run(&config, start + Duration::from_secs(3600));
+    // the device wakes up 1 hour late
+    sim.clock.set(sim.clock.get() + Duration::from_secs(3600));
+    sim.run(&config, start + Duration::from_secs(3 * 3600));
+    let times: Vec<u64> = sim.capture_times().iter().map(|time| time - to_seconds(start)).collect();
+    assert_eq!(times[..9], [0, 600, 1200, 1800, 2400, 3000, 3600, 7800, 8400]);
+    assert_eq!(sim.reporter.missed_captures, 6);
+    assert_eq!(sim.devices.storage.load().unwrap().missed_captures, 6);
+}
+
+#[test]
+fn one_shot_and_movie() {
+    let start = utc("2024-05-01 00:00");
+    let mut sim = Simulator::new(start);
+    let mut config = settings(interval(600));
+    config.one_shot = true;
+    let mut capture_loop = CaptureLoop::new(RetainedState::default());
+    assert_eq!(capture_loop.step(&config, &mut sim.devices, &mut sim.reporter), Step::OneShot);
+    assert_eq!(capture_loop.state.capture_id, 0);
+    assert_eq!(sim.devices.camera.frames.len(), 1);
+
+    // the movie frames are captured by one request
+    config.one_shot = false;
+    config.movie_mode = true;
+    for _ in 0..10 {
+        assert_eq!(capture_loop.step(&config, &mut sim.devices, &mut sim.reporter), Step::Movie);
+    }
+    assert_eq!(capture_loop.state.capture_id, 10);
+    assert_eq!(sim.devices.camera.frames.len(), 2);
+
+    // stop and go to sleep after the idle time
+    config.capture_started = false;
+    config.idle_in_sleep_time = Some(300);
+    config.last_access_time = sim.clock.get();
+    assert_eq!(capture_loop.step(&config, &mut sim.devices, &mut sim.reporter), Step::Idle);
+    assert_eq!(capture_loop.state.capture_id, 0);
+    sim.clock.set(sim.clock.get() + Duration::from_secs(301));
+    assert_eq!(capture_loop.step(&config, &mut sim.devices, &mut sim.reporter), Step::IdleSleep);
+    assert_eq!(sim.devices.sleeper.deep_sleeps, 1);
+}
@@ -0,0 +1,130 @@
+// Sunrise, sunset and the daylight capture schedule
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use timeleapcam_core::schedule::CaptureSchedule;
+use timeleapcam_core::sun::{golden_hour, sunrise_sunset, Daylight, DaylightMode, SunEvent};
+use timeleapcam_core::timezone::TimeZone;
+
+const TOKYO: (f64, f64) = (35.6895, 139.6917);
+const LONDON: (f64, f64) = (51.5074, -0.1278);
+const TROMSO: (f64, f64) = (69.6492, 18.9553);
+
+fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(y, m, d).unwrap()
+}
+
+// UTC seconds of the local time
+fn local(s: &str, timezone_hours: i64) -> i64 {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap().and_utc().timestamp() - timezone_hours * 3600
+}
+
+fn times(event: SunEvent) -> (i64, i64) {
+    match event {
+        SunEvent::Times { rise, set } => (rise, set),
+        event => panic!("no sunrise: {:?}", event),
+    }
+}
+
+fn assert_near(time: i64, expected: i64) {
+    assert!((time - expected).abs() <= 180, "{} is not near {} ({}s)", time, expected, time - expected);
+}
+
+fn window(before_sunrise: i32, after_sunset: i32, (latitude, longitude): (f64, f64)) -> Option<Daylight> {
+    Some(Daylight { latitude, longitude, mode: DaylightMode::Window { before_sunrise, after_sunset } })
+}
+
+#[test]
+fn sunrise_and_sunset() {
+    let (rise, set) = times(sunrise_sunset(date(2024, 6, 21), TOKYO.0, TOKYO.1));
+    assert_near(rise, local("2024-06-21 04:25", 9));
+    assert_near(set, local("2024-06-21 19:00", 9));
+    let (rise, set) = times(sunrise_sunset(date(2024, 12, 21), LONDON.0, LONDON.1));
+    assert_near(rise, local("2024-12-21 08:04", 0));
+    assert_near(set, local("2024-12-21 15:53", 0));
+    // southern hemisphere and west longitude
+    let (rise, set) = times(sunrise_sunset(date(2024, 3, 20), 40.7128, -74.0060));
+    assert_near(rise, local("2024-03-20 07:00", -4));
+    assert_near(set, local("2024-03-20 19:09", -4));
+    let (rise, set) = times(sunrise_sunset(date(2024, 6, 21), -33.8688, 151.2093));
+    assert_near(rise, local("2024-06-21 07:00", 10));
+    assert_near(set, local("2024-06-21 16:54", 10));
+}
+
+#[test]
+fn polar_day_and_night() {
+    assert_eq!(sunrise_sunset(date(2024, 6, 21), TROMSO.0, TROMSO.1), SunEvent::AlwaysAbove);
+    assert_eq!(sunrise_sunset(date(2024, 12, 21), TROMSO.0, TROMSO.1), SunEvent::AlwaysBelow);
+}
+
+#[test]
+fn golden_hour_is_inside_the_day() {
+    let (rise, set) = times(sunrise_sunset(date(2024, 12, 21), LONDON.0, LONDON.1));
+    let (morning, evening) = times(golden_hour(date(2024, 12, 21), LONDON.0, LONDON.1));
+    assert!(rise < morning && morning < evening && evening < set);
+    // about an hour after sunrise in the London winter
+    assert!(morning - rise > 45 * 60 && morning - rise < 75 * 60);
+}
+
+#[test]
+fn interval_in_the_daylight_window() {
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(9 * 3600), 0, 0).unwrap().with_daylight(window(30, 30, TOKYO));
+    let noon = local("2024-06-21 12:00", 9);
+    assert_eq!(schedule.next_capture_time(noon, noon), Some(noon + 600));
+    // after sunset + 30 minutes, moved to sunrise - 30 minutes of the next day
+    let (rise, _) = times(sunrise_sunset(date(2024, 6, 22), TOKYO.0, TOKYO.1));
+    let evening = local("2024-06-21 19:25", 9);
+    assert_eq!(schedule.next_capture_time(evening, evening), Some(rise - 30 * 60));
+    // before the window in the morning
+    let night = local("2024-06-22 01:00", 9);
+    assert_eq!(schedule.next_capture_time(night, night), Some(rise - 30 * 60));
+}
+
+#[test]
+fn cron_in_the_daylight_window() {
+    let schedule = CaptureSchedule::new(0, "0 * * * *", TimeZone::fixed(9 * 3600), 0, 0).unwrap().with_daylight(window(30, 30, TOKYO));
+    let now = local("2024-06-21 10:10", 9);
+    assert_eq!(schedule.next_capture_time(now, now), Some(local("2024-06-21 11:00", 9)));
+    // 20:00 is after the window, the first hour in the next window is 04:00
+    let now = local("2024-06-21 19:10", 9);
+    assert_eq!(schedule.next_capture_time(now, now), Some(local("2024-06-22 04:00", 9)));
+    // only in the night
+    let schedule = CaptureSchedule::new(0, "0 0 * * *", TimeZone::fixed(9 * 3600), 0, 0).unwrap().with_daylight(window(0, 0, TOKYO));
+    assert_eq!(schedule.next_capture_time(now, now), None);
+}
+
+#[test]
+fn daylight_and_capture_window() {
+    let start = local("2024-06-21 00:00", 9);
+    let end = local("2024-06-21 23:59", 9);
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(9 * 3600), start, end).unwrap().with_daylight(window(0, 0, TOKYO));
+    let (rise, _) = times(sunrise_sunset(date(2024, 6, 21), TOKYO.0, TOKYO.1));
+    assert_eq!(schedule.next_capture_time(0, start), Some(rise));
+    // the next daylight is after the window
+    let evening = local("2024-06-21 19:30", 9);
+    assert_eq!(schedule.next_capture_time(evening, evening), None);
+}
+
+#[test]
+fn polar_window() {
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(3600), 0, 0).unwrap().with_daylight(window(0, 0, TROMSO));
+    // the midnight sun
+    let midnight = local("2024-06-21 23:55", 1);
+    assert_eq!(schedule.next_capture_time(midnight, midnight), Some(midnight + 600));
+    // the polar night, the sun rises in the middle of January
+    let night = local("2024-12-21 12:00", 1);
+    let next = schedule.next_capture_time(night, night).unwrap();
+    assert!(next > local("2025-01-10 00:00", 1) && next < local("2025-01-20 00:00", 1));
+}
+
+#[test]
+fn golden_hour_schedule() {
+    let golden = Some(Daylight { latitude: TOKYO.0, longitude: TOKYO.1, mode: DaylightMode::GoldenHour });
+    let schedule = CaptureSchedule::new(600, "", TimeZone::fixed(9 * 3600), 0, 0).unwrap().with_daylight(golden);
+    let (morning, evening) = times(golden_hour(date(2024, 6, 21), TOKYO.0, TOKYO.1));
+    let (next_morning, _) = times(golden_hour(date(2024, 6, 22), TOKYO.0, TOKYO.1));
+    let night = local("2024-06-21 00:30", 9);
+    assert_eq!(schedule.next_capture_time(night, night), Some(morning));
+    assert_eq!(schedule.next_capture_time(morning, morning), Some(evening));
+    assert_eq!(schedule.next_capture_time(evening, evening), Some(next_morning));
+}
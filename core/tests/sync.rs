@@ -0,0 +1,108 @@
+// Manifest, record runs and sync state of the incremental sync
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use common::{image, write_track};
+use timeleapcam_core::imagefiles::{delete_frames, segment_file_path, ImageFiles, OpenMode, FRAME_FLAG_CRC32};
+use timeleapcam_core::sync::{
+    local_file_name, manifest_entries, manifest_json, read_record, read_sync_state, record_runs, resume_frame,
+    write_sync_state, ManifestEntry, SyncState,
+};
+
+fn all_entries(path: &Path) -> Vec<ManifestEntry> {
+    let mut images = ImageFiles::new(path, OpenMode::Read).unwrap();
+    manifest_entries(&mut images, 0, u32::MAX).unwrap()
+}
+
+#[test]
+fn manifest_records_can_be_read_from_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 10, 4);
+    let mut images = ImageFiles::new(&path, OpenMode::Read).unwrap();
+    let entries = manifest_entries(&mut images, 3, 4).unwrap();
+    assert_eq!(entries.iter().map(|e| e.frame).collect::<Vec<u32>>(), vec![3, 4, 5, 6]);
+    assert_eq!(entries.iter().map(|e| e.segment).collect::<Vec<u32>>(), vec![0, 1, 1, 1]);
+    assert!(manifest_entries(&mut images, 10, 4).unwrap().is_empty());
+    for entry in &entries {
+        let mut file = fs::File::open(segment_file_path(&path, entry.segment)).unwrap();
+        file.seek(SeekFrom::Start(entry.offset)).unwrap();
+        let (info, data) = read_record(&mut file.take(entry.length), entry).unwrap();
+        assert_eq!(data, image(entry.frame));
+        assert_eq!(info.capture_time, entry.capture_time);
+        assert_ne!(info.flags & FRAME_FLAG_CRC32, 0);
+        assert!(entry.crc.is_some());
+    }
+    let json: serde_json::Value = serde_json::from_str(&manifest_json(2, 10, &[100, 200], &entries[0..2])).unwrap();
+    assert_eq!(json["trackid"], 2);
+    assert_eq!(json["frames"], 10);
+    assert_eq!(json["segments"][1], 200);
+    assert_eq!(json["entries"][1]["offset"], entries[1].offset);
+    assert_eq!(json["entries"][1]["length"], entries[1].length);
+    assert_eq!(json["entries"][1]["crc"], entries[1].crc.unwrap());
+}
+
+#[test]
+fn broken_record_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 3, 0);
+    let entries = all_entries(&path);
+    let mut data = fs::read(&path).unwrap();
+    let entry = &entries[1];
+    data[(entry.end() - 1) as usize] ^= 0xFF;
+    let record = &data[entry.offset as usize..entry.end() as usize];
+    assert!(read_record(&mut &record[..], entry).is_err());
+    // offset of another record
+    let record = &data[entries[2].offset as usize..entries[2].end() as usize];
+    assert!(read_record(&mut &record[..], &entries[0]).is_err());
+    // interrupted transfer
+    let record = &data[entries[0].offset as usize..entries[0].end() as usize - 10];
+    assert!(read_record(&mut &record[..], &entries[0]).is_err());
+}
+
+#[test]
+fn runs_are_split_at_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 10, 4);
+    let entries = all_entries(&path);
+    assert_eq!(record_runs(&entries), vec![0..4, 4..8, 8..10]);
+    assert_eq!(record_runs(&entries[2..7]), vec![0..2, 2..5]);
+    let gap = [entries[0].clone(), entries[2].clone()];
+    assert_eq!(record_runs(&gap), vec![0..1, 1..2]);
+    assert!(record_runs(&[]).is_empty());
+}
+
+#[test]
+fn resume_after_frames_are_deleted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_track(dir.path(), 10, 4);
+    let local = tempfile::tempdir().unwrap();
+    assert_eq!(read_sync_state(local.path()), SyncState::default());
+
+    let mut state = SyncState::default();
+    state.synced(&all_entries(&path)[5]);
+    write_sync_state(local.path(), &state).unwrap();
+    let state = read_sync_state(local.path());
+    assert_eq!(state.frames, 6);
+    assert!(state.last_crc.is_some());
+    assert_eq!(resume_frame(&state, &all_entries(&path)), Some(6));
+
+    delete_frames(&path, 1, 2).unwrap();
+    let entries = all_entries(&path);
+    assert!(!state.is_last(&entries[5]));
+    assert_eq!(resume_frame(&state, &entries), Some(4));
+
+    // the last synced frame was deleted
+    delete_frames(&path, 3, 3).unwrap();
+    assert_eq!(resume_frame(&state, &all_entries(&path)), None);
+}
+
+#[test]
+fn local_names_do_not_depend_on_frame_numbers() {
+    assert_eq!(local_file_name(12, 1_704_067_201_500), "20240101T000001.500Z.jpg");
+    assert_eq!(local_file_name(3, 1_704_067_201_500), "20240101T000001.500Z.jpg");
+    assert_eq!(local_file_name(12, 0), "frame_000012.jpg");
+}
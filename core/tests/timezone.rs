@@ -0,0 +1,130 @@
+// Time zones and DST transitions
+
+use chrono::NaiveDateTime;
+
+use timeleapcam_core::schedule::CaptureSchedule;
+use timeleapcam_core::timezone::{Rule, TimeZone, Transition, TIME_ZONES};
+
+fn time(s: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+}
+
+fn utc(s: &str) -> i64 {
+    time(s).and_utc().timestamp()
+}
+
+fn local(timezone: &TimeZone, t: i64) -> String {
+    timezone.local_time(t).unwrap().format("%Y-%m-%d %H:%M %z").to_string()
+}
+
+#[test]
+fn parse_settings() {
+    assert_eq!(TimeZone::parse("9").unwrap(), TimeZone::fixed(9 * 3600));
+    assert_eq!(TimeZone::parse("-3.5").unwrap().offset, -(3 * 3600 + 1800));
+    assert_eq!(TimeZone::fixed(-(3 * 3600 + 1800)).name, "UTC-3:30");
+    assert_eq!(TimeZone::parse("Asia/Kolkata").unwrap().offset, 5 * 3600 + 1800);
+    assert_eq!(TimeZone::parse("asia/tokyo").unwrap(), TimeZone::parse("JST-9").unwrap());
+    assert_eq!(TimeZone::parse("<+0545>-5:45").unwrap().offset, 5 * 3600 + 45 * 60);
+    // DST offset and rules of the US when omitted
+    assert_eq!(TimeZone::parse("EST5EDT").unwrap(), TimeZone::parse("America/New_York").unwrap());
+    let berlin = TimeZone::parse("Europe/Berlin").unwrap();
+    let dst = berlin.dst.unwrap();
+    assert_eq!((berlin.offset, dst.offset), (3600, 7200));
+    assert_eq!(dst.end, Transition { rule: Rule::Week { month: 10, week: 5, weekday: 0 }, time: 3 * 3600 });
+    for setting in ["Mars/Olympus", "JS-9", "CET-1CEST,M3.5.0", "CET-1CEST,M13.5.0,M10.5.0", "15", "JST-9x",
+        // oversized offsets and times are errors, not overflows
+        "ABC600000", "ABC-2147483647", "ABC168", "JST-9:60", "JST-9:00:4294967", "CET-1CEST,M3.5.0/9999999,M10.5.0/3"] {
+        assert!(TimeZone::parse(setting).is_err(), "{}", setting);
+    }
+    for (name, posix) in TIME_ZONES {
+        assert!(TimeZone::parse_posix(posix).is_ok(), "{}", name);
+    }
+}
+
+#[test]
+fn europe_transitions() {
+    let berlin = TimeZone::parse("Europe/Berlin").unwrap();
+    // 2024-03-31 02:00 CET -> 03:00 CEST, 2024-10-27 03:00 CEST -> 02:00 CET
+    assert_eq!(berlin.offset_at(utc("2024-03-31 00:59")), 3600);
+    assert_eq!(berlin.offset_at(utc("2024-03-31 01:00")), 7200);
+    assert_eq!(berlin.offset_at(utc("2024-10-27 00:59")), 7200);
+    assert_eq!(berlin.offset_at(utc("2024-10-27 01:00")), 3600);
+    assert_eq!(berlin.abbreviation_at(utc("2024-07-01 00:00")), "CEST");
+    assert_eq!(berlin.abbreviation_at(utc("2024-12-01 00:00")), "CET");
+    assert_eq!(local(&berlin, utc("2024-03-31 01:00")), "2024-03-31 03:00 +0200");
+    // London changes at 01:00 UTC too
+    let london = TimeZone::parse("Europe/London").unwrap();
+    assert_eq!(london.offset_at(utc("2025-03-30 00:59")), 0);
+    assert_eq!(london.offset_at(utc("2025-03-30 01:00")), 3600);
+    assert_eq!(london.offset_at(utc("2025-10-26 01:00")), 0);
+}
+
+#[test]
+fn america_and_southern_transitions() {
+    let new_york = TimeZone::parse("America/New_York").unwrap();
+    // 2024-03-10 02:00 EST, 2024-11-03 02:00 EDT
+    assert_eq!(new_york.offset_at(utc("2024-03-10 06:59")), -5 * 3600);
+    assert_eq!(new_york.offset_at(utc("2024-03-10 07:00")), -4 * 3600);
+    assert_eq!(new_york.offset_at(utc("2024-11-03 05:59")), -4 * 3600);
+    assert_eq!(new_york.offset_at(utc("2024-11-03 06:00")), -5 * 3600);
+    // DST over the new year, 2024-04-07 03:00 AEDT and 2024-10-06 02:00 AEST
+    let sydney = TimeZone::parse("Australia/Sydney").unwrap();
+    assert_eq!(sydney.offset_at(utc("2024-01-15 00:00")), 11 * 3600);
+    assert_eq!(sydney.offset_at(utc("2024-04-06 15:59")), 11 * 3600);
+    assert_eq!(sydney.offset_at(utc("2024-04-06 16:00")), 10 * 3600);
+    assert_eq!(sydney.offset_at(utc("2024-10-05 15:59")), 10 * 3600);
+    assert_eq!(sydney.offset_at(utc("2024-10-05 16:00")), 11 * 3600);
+    assert_eq!(sydney.offset_at(utc("2024-12-31 23:00")), 11 * 3600);
+    // Julian and zero based days: day 60 is Mar 1 in any year, day 59 is Feb 29 in a leap year
+    let julian = TimeZone::parse("XST0XDT,J60/0,J300/0").unwrap();
+    assert_eq!(julian.offset_at(utc("2024-02-29 23:59")), 0);
+    assert_eq!(julian.offset_at(utc("2024-03-01 00:00")), 3600);
+    let day = TimeZone::parse("XST0XDT,59/0,300/0").unwrap();
+    assert_eq!(day.offset_at(utc("2024-02-29 00:00")), 3600);
+    assert_eq!(day.offset_at(utc("2023-02-28 23:59")), 0);
+}
+
+#[test]
+fn local_time_in_the_transitions() {
+    let berlin = TimeZone::parse("Europe/Berlin").unwrap();
+    assert_eq!(berlin.to_utc(time("2024-07-01 12:00")), utc("2024-07-01 10:00"));
+    assert_eq!(berlin.to_utc(time("2024-12-01 12:00")), utc("2024-12-01 11:00"));
+    // skipped hour is moved forward, the earlier one of the repeated hour
+    assert_eq!(berlin.to_utc(time("2024-03-31 02:30")), utc("2024-03-31 01:30"));
+    assert_eq!(berlin.to_utc(time("2024-10-27 02:30")), utc("2024-10-27 00:30"));
+    let kolkata = TimeZone::parse("Asia/Kolkata").unwrap();
+    assert_eq!(kolkata.to_utc(time("2024-01-01 09:00")), utc("2024-01-01 03:30"));
+}
+
+#[test]
+fn schedule_across_dst() {
+    let berlin = || TimeZone::parse("Europe/Berlin").unwrap();
+    // 09:00 local before and after the change
+    let schedule = CaptureSchedule::new(0, "0 9 * * *", berlin(), 0, 0).unwrap();
+    let now = utc("2024-03-30 09:00");
+    assert_eq!(schedule.next_capture_time(now, now), Some(utc("2024-03-31 07:00")));
+    let now = utc("2024-10-26 08:00");
+    assert_eq!(schedule.next_capture_time(now, now), Some(utc("2024-10-27 08:00")));
+    // 02:30 does not exist on the day DST starts
+    let schedule = CaptureSchedule::new(0, "30 2 * * *", berlin(), 0, 0).unwrap();
+    let now = utc("2024-03-30 12:00");
+    let next = schedule.next_capture_time(now, now).unwrap();
+    assert_eq!(local(&berlin(), next), "2024-03-31 03:30 +0200");
+    assert_eq!(local(&berlin(), schedule.next_capture_time(next, next).unwrap()), "2024-04-01 02:30 +0200");
+    // the repeated hour fires once
+    let schedule = CaptureSchedule::new(0, "*/30 * * * *", berlin(), 0, 0).unwrap();
+    let mut t = utc("2024-10-26 23:45");
+    let mut times = Vec::new();
+    for _ in 0..4 {
+        t = schedule.next_capture_time(t, t).unwrap();
+        times.push(local(&berlin(), t));
+    }
+    assert_eq!(times, ["2024-10-27 02:00 +0200", "2024-10-27 02:30 +0200", "2024-10-27 03:00 +0100", "2024-10-27 03:30 +0100"]);
+    // in the second 02:xx, the next is 03:00
+    let now = utc("2024-10-27 01:10");
+    assert_eq!(local(&berlin(), schedule.next_capture_time(now, now).unwrap()), "2024-10-27 03:00 +0100");
+    // the interval is not changed by DST
+    let schedule = CaptureSchedule::new(3600, "", berlin(), 0, 0).unwrap();
+    let last = utc("2024-03-31 00:30");
+    assert_eq!(schedule.next_capture_time(last, last), Some(last + 3600));
+}
@@ -0,0 +1,135 @@
+// Track directories, labels and statistics
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use common::{capture_time, frame_info, write_frames};
+use timeleapcam_core::imagefiles::FrameInfo;
+use timeleapcam_core::tracks::{
+    list_tracks, read_track_meta, set_track_label, track_file_path, track_id, track_info, update_track_settings,
+    TrackLocks, MAX_LABEL_LEN, TRACK_FILE_NAME,
+};
+
+// T{id}/capture.dat with the segments of 4 frames, frame n has the JPEG quality 10 + n
+fn write_numbered_track(root: &Path, id: u32, frames: u32) {
+    let path = track_file_path(root, id);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    write_frames(&path, frames, 4, |n| FrameInfo { jpeg_quality: 10 + n as u8, ..frame_info(n) });
+}
+
+#[test]
+fn track_directory_names() {
+    assert_eq!(track_id("/eMMC/T0"), Some(0));
+    assert_eq!(track_id("/eMMC/T12"), Some(12));
+    assert_eq!(track_id("/eMMC/T"), None);
+    assert_eq!(track_id("/eMMC/T1a"), None);
+    assert_eq!(track_id("/eMMC/X1"), None);
+    assert_eq!(track_id("/eMMC/T+1"), None);
+}
+
+#[test]
+fn list_track_statistics() {
+    let dir = tempfile::tempdir().unwrap();
+    write_numbered_track(dir.path(), 10, 9);
+    write_numbered_track(dir.path(), 2, 3);
+    fs::create_dir(dir.path().join("T5")).unwrap();
+    fs::create_dir(dir.path().join("other")).unwrap();
+    fs::write(dir.path().join("T7"), b"not a directory").unwrap();
+    let tracks = list_tracks(dir.path());
+    assert_eq!(tracks.iter().map(|t| t.id).collect::<Vec<u32>>(), vec![2, 5, 10]);
+
+    let track = &tracks[2];
+    assert_eq!(track.frames, 9);
+    assert_eq!(track.segments, 3);
+    assert_eq!(track.first_capture_time, capture_time(0));
+    assert_eq!(track.last_capture_time, capture_time(8));
+    assert_eq!((track.width, track.height, track.jpeg_quality), (640, 480, 18));
+    let size: u64 = fs::read_dir(dir.path().join("T10")).unwrap().map(|e| e.unwrap().metadata().unwrap().len()).sum();
+    assert_eq!(track.bytes, size);
+    // empty track directory
+    assert_eq!(tracks[1].frames, 0);
+    assert_eq!(tracks[1].segments, 0);
+    assert_eq!(tracks[1].last_capture_time, 0);
+    assert!(track_info(dir.path(), 3).is_err());
+}
+
+#[test]
+fn label_and_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    write_numbered_track(dir.path(), 1, 2);
+    let track_dir = dir.path().join("T1");
+    set_track_label(&track_dir, "  Garden\nin spring ").unwrap();
+    let mut settings = BTreeMap::new();
+    settings.insert("resolution".to_string(), "UXGA".to_string());
+    settings.insert("duration".to_string(), "60".to_string());
+    assert!(update_track_settings(&track_dir, &settings).unwrap());
+    // not written again
+    assert!(!update_track_settings(&track_dir, &settings).unwrap());
+    let info = track_info(dir.path(), 1).unwrap();
+    assert_eq!(info.label, "Garden in spring");
+    assert_eq!(info.settings, settings);
+    // label is kept when the settings are changed
+    settings.insert("duration".to_string(), "30".to_string());
+    assert!(update_track_settings(&track_dir, &settings).unwrap());
+    let meta = read_track_meta(&track_dir);
+    assert_eq!(meta.label, "Garden in spring");
+    assert_eq!(meta.settings["duration"], "30");
+    set_track_label(&track_dir, &"x".repeat(100)).unwrap();
+    assert_eq!(read_track_meta(&track_dir).label.len(), MAX_LABEL_LEN);
+}
+
+#[test]
+fn interrupted_label_update() {
+    let dir = tempfile::tempdir().unwrap();
+    let track_dir = dir.path().join("T0");
+    fs::create_dir(&track_dir).unwrap();
+    // reset after the old file was removed
+    fs::write(track_dir.join("track.txt.tmp"), "label=Roof\nsetting.resolution=VGA\n").unwrap();
+    assert_eq!(read_track_meta(&track_dir).label, "Roof");
+    set_track_label(&track_dir, "Roof camera").unwrap();
+    assert!(!track_dir.join("track.txt.tmp").exists());
+    assert_eq!(fs::read_to_string(track_dir.join(TRACK_FILE_NAME)).unwrap(), "label=Roof camera\nsetting.resolution=VGA\n");
+}
+
+#[test]
+fn track_locks() {
+    let locks = TrackLocks::new();
+    let reader = locks.read(3).unwrap();
+    let other_reader = locks.clone().read(3).unwrap();
+    // not rewritten while it is read, the other tracks are free
+    assert!(locks.write(3).is_none());
+    assert!(locks.is_busy(3));
+    let writer = locks.write(4).unwrap();
+    assert!(locks.read(4).is_none());
+    assert!(locks.write(4).is_none());
+    drop(reader);
+    assert!(locks.write(3).is_none());
+    drop(other_reader);
+    assert!(!locks.is_busy(3));
+    let compaction = locks.write(3).unwrap();
+    assert!(locks.read(3).is_none());
+    drop(compaction);
+    drop(writer);
+    assert!(locks.read(3).is_some() && locks.read(4).is_some());
+    assert!(!locks.is_busy(3) && !locks.is_busy(4));
+}
+
+#[test]
+fn captured_track_is_not_rewritten() {
+    let locks = TrackLocks::new();
+    let capture = locks.append(2).unwrap();
+    // the frames are read while they are captured
+    let reader = locks.read(2).unwrap();
+    assert!(locks.write(2).is_none());
+    assert!(locks.append(2).is_none());
+    drop(reader);
+    assert!(locks.write(2).is_none());
+    drop(capture);
+    let delete = locks.write(2).unwrap();
+    assert!(locks.append(2).is_none());
+    drop(delete);
+    assert!(!locks.is_busy(2));
+}
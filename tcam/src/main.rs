@@ -0,0 +1,557 @@
+// tcam - inspect and extract Time Leap Cam capture files (T{n}/capture.dat) on the host,
+// and sync the new frames from the camera
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use timeleapcam_core::avi::{plan_avi, write_avi, MAX_FPS};
+use timeleapcam_core::imagefiles::{
+    index_file_path, segment_file_paths, verify_file, FrameInfo, ImageFiles, OpenMode, FOCUS_UNKNOWN,
+};
+use timeleapcam_core::sync::{
+    local_file_name, read_record, read_sync_state, record_runs, resume_frame, write_sync_state, ManifestEntry,
+    SyncState, MAX_MANIFEST_ENTRIES,
+};
+use timeleapcam_core::tls::{certificate_fingerprint, parse_fingerprint, pem_certificates};
+
+#[derive(Parser)]
+#[command(name = "tcam", version, about = "Inspect and extract Time Leap Cam capture files")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the file header and statistics
+    Info {
+        /// capture.dat file
+        file: PathBuf,
+    },
+    /// List the frames
+    List {
+        file: PathBuf,
+        #[command(flatten)]
+        range: FrameRange,
+    },
+    /// Extract the frames to numbered JPEG files
+    Extract {
+        file: PathBuf,
+        #[command(flatten)]
+        range: FrameRange,
+        /// Output directory
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// File name prefix of the JPEG files
+        #[arg(short, long, default_value = "frame_")]
+        prefix: String,
+    },
+    /// Make a MJPEG AVI time-lapse video
+    Avi {
+        file: PathBuf,
+        #[command(flatten)]
+        range: FrameRange,
+        /// Output AVI file
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Frames per second
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=MAX_FPS as i64))]
+        fps: u32,
+    },
+    /// Check the records, the checksums, the file header and the index
+    Verify { file: PathBuf },
+    /// Print the metadata and the size of each frame as CSV
+    Csv {
+        file: PathBuf,
+        #[command(flatten)]
+        range: FrameRange,
+    },
+    /// Download the new frames from the camera, an interrupted sync resumes at the next frame
+    Sync {
+        /// Address of the camera (http://192.168.1.10)
+        url: String,
+        /// Output directory, the frames are saved in T{n} subdirectories
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Track to sync (repeatable, default: all tracks)
+        #[arg(short, long)]
+        track: Vec<u32>,
+        /// Session token of the camera login (default: TCAM_TOKEN environment variable)
+        #[arg(long)]
+        token: Option<String>,
+        /// CA certificates (PEM) of the HTTPS server, e.g. the certificate of the camera or of your own CA
+        #[arg(long, conflicts_with = "fingerprint")]
+        cacert: Option<PathBuf>,
+        /// SHA-256 fingerprint of the certificate of the camera (shown on the configuration page)
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct FrameRange {
+    /// First frame number
+    #[arg(short, long, default_value_t = 0)]
+    from: u32,
+    /// Last frame number (inclusive, default: last frame)
+    #[arg(short, long)]
+    to: Option<u32>,
+}
+
+impl FrameRange {
+    // frame numbers in the file, None if the range is empty
+    fn resolve(&self, nimages: u32) -> Option<(u32, u32)> {
+        if nimages == 0 || self.from >= nimages {
+            return None;
+        }
+        let to = self.to.unwrap_or(nimages - 1).min(nimages - 1);
+        if to < self.from {
+            return None;
+        }
+        Some((self.from, to))
+    }
+}
+
+struct FrameEntry {
+    frame: u32,
+    segment: u32,
+    offset: u64,
+    size: usize,
+    info: FrameInfo,
+}
+
+fn open(file: &Path) -> anyhow::Result<ImageFiles> {
+    ImageFiles::new(file, OpenMode::Read).with_context(|| format!("cannot open {}", file.display()))
+}
+
+// Walk the record headers of the frames in the range without reading the images
+fn frame_entries(file: &Path, range: &FrameRange) -> anyhow::Result<Vec<FrameEntry>> {
+    let mut images = open(file)?;
+    let mut entries = Vec::new();
+    let (from, to) = match range.resolve(images.get_nof_images()) {
+        Some(range) => range,
+        None => return Ok(entries),
+    };
+    images.seek_image(from)?;
+    for frame in from..=to {
+        let info = images.get_frame_info().with_context(|| format!("frame {}", frame))?;
+        let size = images.get_image_size();
+        let segment = images.get_segment();
+        let offset = images.get_image_offset();
+        entries.push(FrameEntry { frame, segment, offset, size, info });
+        images.skip_image()?;
+    }
+    Ok(entries)
+}
+
+fn capture_time_string(info: &FrameInfo) -> String {
+    match info.capture_system_time() {
+        Some(time) => {
+            let time: DateTime<Utc> = time.into();
+            time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+        }
+        None => String::new(),
+    }
+}
+
+fn info(file: &Path) -> anyhow::Result<()> {
+    let images = open(file)?;
+    let nimages = images.get_nof_images();
+    let total_size = images.get_total_size();
+    let file_size = fs::metadata(file)?.len();
+    println!("File:           {}", file.display());
+    println!("Format:         v{}", images.get_version());
+    println!("Frames:         {}", nimages);
+    println!("Segments:       {}", images.get_nof_segments());
+    println!("File size:      {} bytes", file_size);
+    println!("Images size:    {} bytes", total_size);
+    if nimages > 0 {
+        println!("Average size:   {} bytes", total_size / nimages as u64);
+    }
+    for path in segment_file_paths(file) {
+        let index_path = index_file_path(&path);
+        match fs::metadata(&index_path) {
+            Ok(metadata) if images.get_version() >= 2 => println!(
+                "Index:          {} ({} entries)",
+                index_path.display(),
+                metadata.len().saturating_sub(8) / 8
+            ),
+            _ => println!("Index:          {} none", path.display()),
+        }
+    }
+    drop(images);
+    let entries = frame_entries(file, &FrameRange { from: 0, to: None })?;
+    if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+        let min = entries.iter().map(|e| e.size).min().unwrap_or(0);
+        let max = entries.iter().map(|e| e.size).max().unwrap_or(0);
+        println!("Min/Max size:   {} / {} bytes", min, max);
+        println!("First capture:  {}", capture_time_string(&first.info));
+        println!("Last capture:   {}", capture_time_string(&last.info));
+        if last.info.width > 0 {
+            println!("Resolution:     {}x{}", last.info.width, last.info.height);
+        }
+    }
+    Ok(())
+}
+
+fn list(file: &Path, range: &FrameRange) -> anyhow::Result<()> {
+    let entries = frame_entries(file, range)?;
+    println!(
+        "{:>7} {:>4} {:>12} {:>8}  {:<24} {:>9} {:>3} {:>5} {:>7} {:>8}",
+        "FRAME", "SEG", "OFFSET", "SIZE", "CAPTURE TIME (UTC)", "RES", "Q", "FOCUS", "BATTERY", "TEMP"
+    );
+    for entry in entries {
+        let info = &entry.info;
+        if info.capture_time == 0 {
+            println!("{:>7} {:>4} {:>12} {:>8}", entry.frame, entry.segment, entry.offset, entry.size);
+            continue;
+        }
+        println!(
+            "{:>7} {:>4} {:>12} {:>8}  {:<24} {:>9} {:>3} {:>5} {:>6.2}V {:>7.2}C",
+            entry.frame,
+            entry.segment,
+            entry.offset,
+            entry.size,
+            capture_time_string(info),
+            format!("{}x{}", info.width, info.height),
+            info.jpeg_quality,
+            if info.focus == FOCUS_UNKNOWN { "-".to_string() } else { info.focus.to_string() },
+            info.battery_voltage_volts(),
+            info.temperature_celsius()
+        );
+    }
+    Ok(())
+}
+
+fn extract(file: &Path, range: &FrameRange, output: &Path, prefix: &str) -> anyhow::Result<()> {
+    let mut images = open(file)?;
+    let (from, to) = match range.resolve(images.get_nof_images()) {
+        Some(range) => range,
+        None => anyhow::bail!("no frames in the range (file has {} frames)", images.get_nof_images()),
+    };
+    fs::create_dir_all(output)?;
+    images.seek_image(from)?;
+    for frame in from..=to {
+        let data = images.read_image().with_context(|| format!("frame {}", frame))?;
+        let path = output.join(format!("{}{:06}.jpg", prefix, frame));
+        fs::write(&path, data).with_context(|| format!("cannot write {}", path.display()))?;
+    }
+    println!("Extracted {} frames to {}", to - from + 1, output.display());
+    Ok(())
+}
+
+fn avi(file: &Path, range: &FrameRange, output: &Path, fps: u32) -> anyhow::Result<()> {
+    let mut images = open(file)?;
+    let (from, to) = match range.resolve(images.get_nof_images()) {
+        Some(range) => range,
+        None => anyhow::bail!("no frames in the range (file has {} frames)", images.get_nof_images()),
+    };
+    let plan = plan_avi(&mut images, from, to)?;
+    let nframes = plan.frame_sizes.len();
+    if plan.truncated {
+        eprintln!("tcam: AVI is limited to 4GB, frames after {} are not included", from as usize + nframes - 1);
+    }
+    let out = fs::File::create(output).with_context(|| format!("cannot create {}", output.display()))?;
+    write_avi(&mut images, plan, fps, std::io::BufWriter::new(out))?;
+    println!("Wrote {} frames ({}fps) to {}", nframes, fps, output.display());
+    Ok(())
+}
+
+fn verify(file: &Path) -> anyhow::Result<bool> {
+    let paths = segment_file_paths(file);
+    if paths.is_empty() {
+        anyhow::bail!("cannot open {}", file.display());
+    }
+    let mut all_ok = true;
+    for path in paths {
+        all_ok &= verify_segment(&path)?;
+    }
+    Ok(all_ok)
+}
+
+fn verify_segment(file: &Path) -> anyhow::Result<bool> {
+    let report = verify_file(file).with_context(|| format!("cannot verify {}", file.display()))?;
+    println!("Segment:         {}", file.display());
+    println!("Format:          v{}", report.version);
+    println!("Header frames:   {} ({} bytes)", report.header_frames, report.header_total_size);
+    println!("Records:         {} ({} bytes)", report.frames, report.records_size);
+    println!("Checksum OK:     {}", report.verified_frames);
+    println!("Not checksummed: {}", report.frames - report.verified_frames - report.checksum_errors.len() as u32);
+    println!("End marker:      {}", if report.end_marker { "found" } else { "missing" });
+    if report.trailing_bytes > 0 {
+        println!("Trailing bytes:  {}", report.trailing_bytes);
+    }
+    match report.index_entries {
+        Some(entries) => println!("Index entries:   {}", entries),
+        None => println!("Index entries:   none"),
+    }
+    for frame in &report.checksum_errors {
+        println!("Checksum error:  frame {}", frame);
+    }
+    for frame in &report.index_errors {
+        println!("Index error:     frame {}", frame);
+    }
+    if report.header_frames != report.frames || report.header_total_size != report.records_size {
+        println!("File header does not match the records");
+    }
+    let ok = report.is_ok();
+    println!("{}", if ok { "OK" } else { "NG" });
+    Ok(ok)
+}
+
+fn csv(file: &Path, range: &FrameRange) -> anyhow::Result<()> {
+    let entries = frame_entries(file, range)?;
+    println!("frame,segment,offset,size,capture_time,width,height,jpeg_quality,focus,battery_voltage,temperature");
+    for entry in entries {
+        let info = &entry.info;
+        if info.capture_time == 0 {
+            println!("{},{},{},{},,,,,,,", entry.frame, entry.segment, entry.offset, entry.size);
+            continue;
+        }
+        println!(
+            "{},{},{},{},{},{},{},{},{},{:.3},{:.2}",
+            entry.frame,
+            entry.segment,
+            entry.offset,
+            entry.size,
+            capture_time_string(info),
+            info.width,
+            info.height,
+            info.jpeg_quality,
+            if info.focus == FOCUS_UNKNOWN { String::new() } else { info.focus.to_string() },
+            info.battery_voltage_volts(),
+            info.temperature_celsius()
+        );
+    }
+    Ok(())
+}
+
+fn get_json(agent: &ureq::Agent, url: &str) -> anyhow::Result<serde_json::Value> {
+    let body = agent.get(url).call().with_context(|| format!("GET {}", url))?.into_string()?;
+    serde_json::from_str(&body).with_context(|| format!("invalid JSON from {}", url))
+}
+
+fn json_u64(json: &serde_json::Value, key: &str) -> anyhow::Result<u64> {
+    json[key].as_u64().with_context(|| format!("{} not found in the manifest", key))
+}
+
+// Frames of the track and the manifest entries from the frame
+fn fetch_manifest(agent: &ureq::Agent, base: &str, track: u32, from: u32, count: u32) -> anyhow::Result<(u32, Vec<ManifestEntry>)> {
+    let json = get_json(agent, &format!("{}/tracks/{}/manifest?from={}&count={}", base, track, from, count))?;
+    let frames = json_u64(&json, "frames")? as u32;
+    let mut entries = Vec::new();
+    for entry in json["entries"].as_array().context("entries not found in the manifest")? {
+        entries.push(ManifestEntry {
+            frame: json_u64(entry, "frame")? as u32,
+            segment: json_u64(entry, "segment")? as u32,
+            offset: json_u64(entry, "offset")?,
+            length: json_u64(entry, "length")?,
+            capture_time: entry["captureTime"].as_i64().unwrap_or(0),
+            crc: entry["crc"].as_u64().map(|crc| crc as u32),
+        });
+    }
+    Ok((frames, entries))
+}
+
+// Frame number to start the download. When the last synced frame moved (frames were deleted on the camera),
+// it is searched in the manifest, and the sync starts over if it is not found.
+fn sync_start(agent: &ureq::Agent, base: &str, track: u32, state: &mut SyncState) -> anyhow::Result<u32> {
+    if state.frames == 0 {
+        return Ok(0);
+    }
+    let (frames, entries) = fetch_manifest(agent, base, track, state.frames - 1, 1)?;
+    if entries.first().is_some_and(|entry| state.is_last(entry)) {
+        return Ok(state.frames);
+    }
+    let mut from = 0;
+    while from < frames.min(state.frames) {
+        let (_, entries) = fetch_manifest(agent, base, track, from, MAX_MANIFEST_ENTRIES)?;
+        if let Some(next) = resume_frame(state, &entries) {
+            state.frames = next;
+            return Ok(next);
+        }
+        match entries.last() {
+            Some(entry) => from = entry.frame + 1,
+            None => break,
+        }
+    }
+    eprintln!("tcam: T{}: last synced frame is not on the camera, syncing from frame 0", track);
+    *state = SyncState::default();
+    Ok(0)
+}
+
+fn sync_track(agent: &ureq::Agent, base: &str, track: u32, output: &Path) -> anyhow::Result<()> {
+    let directory = output.join(format!("T{}", track));
+    fs::create_dir_all(&directory).with_context(|| format!("cannot create {}", directory.display()))?;
+    let mut state = read_sync_state(&directory);
+    let saved_state = state.clone();
+    let mut next = sync_start(agent, base, track, &mut state)?;
+    if state != saved_state {
+        write_sync_state(&directory, &state)?;
+    }
+    let mut downloaded = 0;
+    loop {
+        let (frames, entries) = fetch_manifest(agent, base, track, next, MAX_MANIFEST_ENTRIES)?;
+        if entries.is_empty() {
+            println!("T{}: {} new frames, {} of {} frames synced to {}", track, downloaded, state.frames, frames, directory.display());
+            return Ok(());
+        }
+        for run in record_runs(&entries) {
+            let run = &entries[run];
+            let (first, last) = (&run[0], &run[run.len() - 1]);
+            let url = format!("{}/tracks/{}/segments/{}", base, track, first.segment);
+            let response = agent.get(&url)
+                .set("Range", &format!("bytes={}-{}", first.offset, last.end() - 1))
+                .call()
+                .with_context(|| format!("GET {}", url))?;
+            if response.status() != 206 {
+                anyhow::bail!("GET {}: range request is not supported", url);
+            }
+            let mut reader = response.into_reader();
+            for entry in run {
+                let (info, data) = read_record(&mut reader, entry)
+                    .with_context(|| format!("T{} frame {}", track, entry.frame))?;
+                let path = directory.join(local_file_name(entry.frame, info.capture_time));
+                let part_path = path.with_extension("jpg.part");
+                fs::write(&part_path, data).with_context(|| format!("cannot write {}", part_path.display()))?;
+                fs::rename(&part_path, &path)?;
+                state.synced(entry);
+                write_sync_state(&directory, &state)?;
+                downloaded += 1;
+            }
+        }
+        next = entries[entries.len() - 1].frame + 1;
+    }
+}
+
+// Accepts the certificate of the fingerprint, e.g. the self-signed certificate of the camera. The names and the dates
+// of the certificate are not checked, the signatures of the handshake are.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = certificate_fingerprint(end_entity);
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("certificate fingerprint {} is not {}", fingerprint, self.fingerprint)))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// TLS of the camera with its own certificate, None for the public CAs
+fn tls_config(cacert: Option<&Path>, fingerprint: Option<&str>) -> anyhow::Result<Option<Arc<ClientConfig>>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    let config = if let Some(fingerprint) = fingerprint {
+        let fingerprint = parse_fingerprint(fingerprint).context("fingerprint must be 64 hex digits of SHA-256")?;
+        builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(FingerprintVerifier { fingerprint, provider }))
+            .with_no_client_auth()
+    } else if let Some(cacert) = cacert {
+        let pem = fs::read_to_string(cacert).with_context(|| format!("cannot read {}", cacert.display()))?;
+        let mut roots = RootCertStore::empty();
+        for der in pem_certificates(&pem).with_context(|| format!("cannot read {}", cacert.display()))? {
+            roots.add(CertificateDer::from(der)).with_context(|| format!("invalid certificate in {}", cacert.display()))?;
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+// ureq::Error of the middleware is large, it is the signature of ureq
+#[allow(clippy::result_large_err)]
+fn sync(url: &str, output: &Path, tracks: &[u32], token: Option<&str>, cacert: Option<&Path>, fingerprint: Option<&str>)
+    -> anyhow::Result<bool> {
+    let base = url.trim_end_matches('/');
+    let base = if base.contains("://") { base.to_string() } else { format!("http://{}", base) };
+    // the token is kept when HTTP is redirected to HTTPS of the camera
+    let mut builder = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(10))
+        .timeout_read(Duration::from_secs(60))
+        .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost);
+    if let Some(config) = tls_config(cacert, fingerprint)? {
+        builder = builder.tls_config(config);
+    }
+    // the camera needs a login when the admin password is set
+    let token = token.map(|token| token.to_string()).or_else(|| std::env::var("TCAM_TOKEN").ok());
+    if let Some(token) = token.filter(|token| !token.is_empty()) {
+        let authorization = format!("Bearer {}", token);
+        builder = builder.middleware(move |request: ureq::Request, next: ureq::MiddlewareNext| {
+            next.handle(request.set("Authorization", &authorization))
+        });
+    }
+    let agent = builder.build();
+    let tracks = if tracks.is_empty() {
+        let json = get_json(&agent, &format!("{}/tracks", base))?;
+        json["tracks"].as_array().context("tracks not found")?
+            .iter()
+            .filter(|track| track["frames"].as_u64().unwrap_or(0) > 0)
+            .filter_map(|track| track["trackid"].as_u64().map(|id| id as u32))
+            .collect()
+    } else {
+        tracks.to_vec()
+    };
+    let mut all_ok = true;
+    for track in tracks {
+        if let Err(e) = sync_track(&agent, &base, track, output) {
+            eprintln!("tcam: {:#}", e);
+            all_ok = false;
+        }
+    }
+    Ok(all_ok)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Info { file } => info(file).map(|_| true),
+        Command::List { file, range } => list(file, range).map(|_| true),
+        Command::Extract { file, range, output, prefix } => extract(file, range, output, prefix).map(|_| true),
+        Command::Avi { file, range, output, fps } => avi(file, range, output, *fps).map(|_| true),
+        Command::Verify { file } => verify(file),
+        Command::Csv { file, range } => csv(file, range).map(|_| true),
+        Command::Sync { url, output, track, token, cacert, fingerprint } =>
+            sync(url, output, track, token.as_deref(), cacert.as_deref(), fingerprint.as_deref()),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("tcam: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}